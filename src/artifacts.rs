@@ -0,0 +1,98 @@
+use crate::exec::exec_command;
+use crate::shell::{shell_format, ShellSafe};
+use crate::HostConnection;
+use anyhow::Error;
+use std::fmt::{self, Display};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long cleaning up after a cancelled or failed run may take on a connection.
+pub(crate) const CLEANUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Remote files the crate created over a connection, which may still be there:
+/// staged commands and uploaded binaries of modules which didn't finish.
+/// Modules remove their own files, the ledger is cleared once they finished.
+#[derive(Debug, Default)]
+pub(crate) struct Artifacts {
+    paths: Mutex<Vec<String>>,
+}
+
+impl Artifacts {
+    pub(crate) fn track(&self, path: &str) {
+        self.paths.lock().expect("artifacts lock poisoned").push(path.to_string());
+    }
+
+    pub(crate) fn clear(&self) {
+        self.paths.lock().expect("artifacts lock poisoned").clear();
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.paths.lock().expect("artifacts lock poisoned").clone()
+    }
+}
+
+/// Remote files a cancelled or failed run couldn't remove from `host`, for operators
+/// to sweep by hand. Errors of such runs carry it as their outermost context, find it
+/// with `downcast_ref::<LeftBehind>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeftBehind {
+    pub host: String,
+    pub paths: Vec<String>,
+}
+
+impl Display for LeftBehind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Left behind on {}, remove by hand: {}", self.host, self.paths.join(" "))
+    }
+}
+
+impl HostConnection {
+    /// Remote files of modules which didn't finish on the connection, which
+    /// [`HostConnection::clean_up`] would remove.
+    pub fn artifacts(&self) -> Vec<String> {
+        self.ledger().list()
+    }
+
+    /// Removes [`HostConnection::artifacts`] with one `rm`, waiting at most `timeout`
+    /// for it. Returns the files which may still be there, all of them if the
+    /// connection is broken.
+    pub fn clean_up(&self, timeout: Duration) -> Vec<String> {
+        let paths = self.artifacts();
+        if paths.is_empty() {
+            return paths;
+        }
+        let session = self.session();
+        let previous = session.timeout();
+        session.set_blocking(true);
+        session.set_timeout(timeout.as_millis().min(u32::MAX as u128) as u32);
+        let remove = || -> Result<i32, Error> {
+            let files: String = paths.iter().map(|path| format!(" {}", ShellSafe::path(path))).collect();
+            let command = shell_format!("rm -f --{}", ShellSafe::command(&files));
+            let mut channel = self.open_channel()?;
+            let (session, exec) = channel.split();
+            exec_command(session, exec, command.as_str())?;
+            channel.wait_close()?;
+            Ok(channel.exit_status()?)
+        };
+        let removed = matches!(remove(), Ok(0));
+        session.set_timeout(previous);
+        if !removed {
+            return paths;
+        }
+        self.ledger().clear();
+        Vec::new()
+    }
+
+    /// `e`, after cleaning up the connection, with a [`LeftBehind`] context listing
+    /// what couldn't be removed, if anything.
+    pub(crate) fn clean_up_after(&self, e: Error) -> Error {
+        let paths = self.clean_up(CLEANUP_TIMEOUT);
+        if paths.is_empty() {
+            return e;
+        }
+        e.context(LeftBehind {
+            host: self.host().to_string(),
+            paths,
+        })
+    }
+}
@@ -0,0 +1,118 @@
+//! Lines of JSON which hosts keep about the modules run on them, see
+//! [`crate::Runner::with_remote_audit_log`].
+use crate::exec::exec_command;
+use crate::facts::epoch_ms;
+use crate::runner::controller_identity;
+use crate::schema::{deserialize_schema_version, unversioned};
+use crate::shell::{shell_format, ShellSafe};
+use crate::window::utc_timestamp;
+use crate::{
+    CommandOutput, ExecutionOptions, HostConnection, Module, Outcome, SkipReason, SCHEMA_VERSION,
+};
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::time::SystemTime;
+
+/// Module having run on a host, as the host's audit log has it.
+/// `run_id` is the [`crate::Runner::correlation_id`], `module` the name the module ran by,
+/// `None` for modules run on their own. `error` says why the module couldn't run.
+/// `schema_version` is [`crate::SCHEMA_VERSION`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    #[serde(default = "unversioned", deserialize_with = "deserialize_schema_version")]
+    pub schema_version: u32,
+    pub run_id: Option<String>,
+    pub module: Option<String>,
+    /// [`Module::fingerprint`] of what ran
+    pub fingerprint: String,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+    /// `user@host` of the controller
+    pub controller: String,
+    /// RFC 3339, in UTC
+    pub timestamp: String,
+}
+
+impl AuditEntry {
+    /// Shell command appending the entry to the file `path` as one line, opened with
+    /// `O_APPEND`. The line and the path are quoted, whatever names or errors the entry
+    /// holds end up in the file and run nothing.
+    pub fn append_command(&self, path: &str) -> String {
+        let line = serde_json::to_string(self).expect("audit entries serialize");
+        shell_format!("printf '%s\\n' {} >> {}", ShellSafe::quote(&line), ShellSafe::path(path)).into_string()
+    }
+}
+
+/// Runs `command`, failing with what it printed to stderr.
+fn append(connection: &HostConnection, command: &str) -> Result<(), String> {
+    let run = || -> Result<(i32, String), Error> {
+        let mut channel = connection.open_channel()?;
+        let (session, exec) = channel.split();
+        exec_command(session, exec, command)?;
+        let mut stderr = String::new();
+        channel.stderr().read_to_string(&mut stderr)?;
+        channel.wait_close()?;
+        Ok((channel.exit_status()?, stderr))
+    };
+    match run() {
+        Ok((0, _)) => Ok(()),
+        Ok((status, stderr)) if stderr.trim().is_empty() => Err(format!("exited with status {}", status)),
+        Ok((_, stderr)) => Err(stderr.trim().to_string()),
+        Err(e) => Err(format!("{:#}", e)),
+    }
+}
+
+/// Appends the [`AuditEntry`] of `module`, run as `name` with `output`, to the audit log
+/// of `options` on the host, again with `sudo -n` if the user can't write to it.
+/// Secrets are redacted from its error with the redactor of `options`, if there is one.
+/// Skipped modules did nothing to the host and aren't logged. If the line can't be
+/// appended, every result of the module gets a warning, or it is printed if the module
+/// failed to run. Returns `output`.
+pub(crate) fn record(
+    module: &Module,
+    name: Option<&str>,
+    connection: &HostConnection,
+    options: &ExecutionOptions,
+    mut output: Result<CommandOutput, Error>,
+) -> Result<CommandOutput, Error> {
+    let path = match options.remote_audit_log() {
+        Some(path) => path,
+        None => return output,
+    };
+    if matches!(&output, Err(e) if e.is::<SkipReason>()) {
+        return output;
+    }
+    let entry = AuditEntry {
+        schema_version: SCHEMA_VERSION,
+        run_id: options.correlation_id().map(str::to_string),
+        module: name.map(str::to_string),
+        fingerprint: module.fingerprint(),
+        outcome: output.as_ref().map_or(Outcome::Failed, CommandOutput::outcome),
+        error: output.as_ref().err().map(|e| {
+            let error = format!("{:#}", e);
+            match options.redactor() {
+                Some(redactor) => redactor.redact(&error).into_owned(),
+                None => error,
+            }
+        }),
+        controller: controller_identity(),
+        timestamp: utc_timestamp(epoch_ms(SystemTime::now())),
+    };
+    let command = options.prefix_command(&entry.append_command(path), None, None);
+    let appended = append(connection, &command)
+        .or_else(|_| append(connection, shell_format!("sudo -n sh -c {}", ShellSafe::quote(&command)).as_str()));
+    if let Err(message) = appended {
+        let warning = format!("remote audit log {} isn't writable: {}", path, message);
+        match &mut output {
+            Ok(CommandOutput::Single(result)) => result.warnings.push(warning),
+            Ok(CommandOutput::Multi(results)) => {
+                for result in results.values_mut() {
+                    result.warnings.push(warning.clone());
+                }
+            }
+            Err(_) => eprintln!("warning: {} on {}", warning, connection.host()),
+        }
+    }
+    output
+}
@@ -0,0 +1,151 @@
+//! Commands left running on the host, see [`BackgroundProcess`].
+use crate::exec::exec_command;
+use crate::shell::{shell_format, ShellSafe};
+use crate::HostConnection;
+use anyhow::Error;
+use serde::Serialize;
+use std::io::Read;
+
+/// Starts `command` detached from the channel, in a wrapper which writes the exit status
+/// of the command to `exit` in a new directory once it ended, next to its output in `log`
+/// and its pid in `pid`. Prints the pid of the wrapper and the path of the log, once the
+/// command's pid is written, so it can be signalled right away.
+pub fn background_command(command: &str) -> String {
+    let wrapper = "sh -c \"$1\" & echo $! > \"$2/pid\"; wait $!; \
+        echo $? > \"$2/exit.tmp\"; mv \"$2/exit.tmp\" \"$2/exit\"";
+    shell_format!(
+        "dir=$(mktemp -d \"${{TMPDIR:-/tmp}}/am-bg.XXXXXX\") || exit 1; \
+         nohup sh -c {} am-background {} \"$dir\" > \"$dir/log\" 2>&1 < /dev/null & \
+         pid=$!; \
+         while [ ! -s \"$dir/pid\" ] && kill -0 $pid 2>/dev/null; do sleep 0.01 2>/dev/null || sleep 1; done; \
+         echo \"$pid $dir/log\"",
+        ShellSafe::quote(wrapper),
+        ShellSafe::quote(command)
+    )
+    .into_string()
+}
+
+/// Command started with `background = true`, still running on `host` when the module
+/// ended. `pid` is the process of the wrapper waiting for it, `log_path` holds its
+/// output, `started_at` is in milliseconds since the epoch, on the controller's clock.
+/// See [`HostConnection::check_process`] and [`HostConnection::kill`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BackgroundProcess {
+    pub host: String,
+    pub pid: u32,
+    pub log_path: String,
+    pub started_at: i64,
+}
+
+/// State of a [`BackgroundProcess`]. `Exited` has the exit status of the command,
+/// 128 plus the signal for commands killed by one. `Vanished` processes are gone
+/// without an exit status, as the wrapper was killed itself or the host rebooted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum ProcessStatus {
+    Running,
+    Exited(i32),
+    Vanished,
+}
+
+impl BackgroundProcess {
+    /// Reads what [`background_command`] printed on `host`, started at `started_at`.
+    pub fn parse(host: &str, output: &str, started_at: i64) -> Result<Self, Error> {
+        let bad = || Error::msg(format!("Bad output of a background command: {:?}", output.trim()));
+        let (pid, log_path) = output.trim().split_once(' ').ok_or_else(bad)?;
+        Ok(BackgroundProcess {
+            host: host.to_string(),
+            pid: pid.parse().map_err(|_| bad())?,
+            log_path: log_path.to_string(),
+            started_at,
+        })
+    }
+
+    /// Directory of the files of the wrapper.
+    fn dir(&self) -> &str {
+        self.log_path.rsplit_once('/').map_or(".", |(dir, _)| dir)
+    }
+
+    /// Shell command printing the [`ProcessStatus`] of the process, for
+    /// [`ProcessStatus::parse`].
+    pub fn status_command(&self) -> String {
+        let exit = ShellSafe::path(&format!("{}/exit", self.dir()));
+        shell_format!(
+            "if kill -0 {} 2>/dev/null && [ ! -f {1} ]; then echo running; \
+             elif [ -f {1} ]; then echo exited $(cat {1}); else echo vanished; fi",
+            ShellSafe::from(self.pid),
+            exit
+        )
+        .into_string()
+    }
+
+    /// Shell command sending `signal`, like `TERM`, `SIGKILL` or `9`, to the command.
+    pub fn kill_command(&self, signal: &str) -> Result<String, Error> {
+        let name = signal.strip_prefix("SIG").unwrap_or(signal);
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(Error::msg(format!("Bad signal {:?}", signal)));
+        }
+        let pid = ShellSafe::path(&format!("{}/pid", self.dir()));
+        Ok(shell_format!("kill -{} \"$(cat {})\"", ShellSafe::word(name), pid).into_string())
+    }
+}
+
+impl ProcessStatus {
+    /// Reads what [`BackgroundProcess::status_command`] printed.
+    pub fn parse(output: &str) -> Result<Self, Error> {
+        let output = output.trim();
+        match output.split_once(' ') {
+            None if output == "running" => Ok(ProcessStatus::Running),
+            None if output == "vanished" => Ok(ProcessStatus::Vanished),
+            Some(("exited", status)) => status
+                .parse()
+                .map(ProcessStatus::Exited)
+                .map_err(|_| Error::msg(format!("Bad exit status {:?}", status))),
+            _ => Err(Error::msg(format!("Bad process status {:?}", output))),
+        }
+    }
+}
+
+/// Runs `command`, returning its stdout, or failing with its stderr unless it exits with 0.
+fn run(connection: &HostConnection, command: &str) -> Result<String, Error> {
+    let mut channel = connection.open_channel()?;
+    let (session, exec) = channel.split();
+    exec_command(session, exec, command)?;
+    let mut stdout = String::new();
+    channel.read_to_string(&mut stdout)?;
+    let mut stderr = String::new();
+    channel.stderr().read_to_string(&mut stderr)?;
+    channel.wait_close()?;
+    match channel.exit_status()? {
+        0 => Ok(stdout),
+        status => Err(Error::msg(format!("exited with status {}: {}", status, stderr.trim()))),
+    }
+}
+
+impl HostConnection {
+    /// Whether `process` still runs, or how it ended.
+    pub fn check_process(&self, process: &BackgroundProcess) -> Result<ProcessStatus, Error> {
+        self.refuse_other_host(process)?;
+        ProcessStatus::parse(&run(self, &process.status_command())?)
+    }
+
+    /// Sends `signal`, like `TERM` or `KILL`, to the command of `process`, whose
+    /// wrapper records how it ended. Fails if it isn't running anymore.
+    pub fn kill(&self, process: &BackgroundProcess, signal: &str) -> Result<(), Error> {
+        self.refuse_other_host(process)?;
+        run(self, &process.kill_command(signal)?)
+            .map(drop)
+            .map_err(|e| e.context(format!("Killing process {} on {} failed", process.pid, process.host)))
+    }
+
+    fn refuse_other_host(&self, process: &BackgroundProcess) -> Result<(), Error> {
+        if process.host != self.host() {
+            return Err(Error::msg(format!(
+                "Process {} runs on {}, not {}",
+                process.pid,
+                process.host,
+                self.host()
+            )));
+        }
+        Ok(())
+    }
+}
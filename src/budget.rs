@@ -0,0 +1,341 @@
+use crate::bundle::bundle_token;
+use crate::pipe::{idle, idle_within, would_block, Duplex};
+use crate::{PumpOutput, Redactor};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Bytes read from a spilled stream at a time.
+const SPILL_CHUNK: usize = 32 * 1024;
+
+/// Bytes of each spilled stream also kept in memory, which the command is evaluated on.
+const SPILL_PREVIEW: usize = 64 * 1024;
+
+/// Use of the output budget of a runner, see [`crate::Runner::output_budget_stats`].
+/// `in_use` is reserved by commands reading their output right now, `peak` the most
+/// that ever was. `spilled` counts commands whose output went to files as the budget
+/// was used up, `capped` commands which had to keep their output in memory and got
+/// a lower limit instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct BudgetStats {
+    pub limit: u64,
+    pub in_use: u64,
+    pub peak: u64,
+    pub spilled: u64,
+    pub waited: u64,
+    pub capped: u64,
+}
+
+/// Output of a command written to files instead of memory, see [`BudgetStats`].
+/// `stderr` is `None` for commands merging it into stdout.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SpilledOutput {
+    pub stdout: PathBuf,
+    pub stderr: Option<PathBuf>,
+}
+
+/// Bytes of captured output all commands of a runner may buffer at once.
+/// A command reserves twice its `max_output` before it reads anything, or the whole
+/// budget if it has no limit, and gives it back once its output is read.
+#[derive(Debug)]
+pub(crate) struct OutputBudget {
+    limit: u64,
+    dir: PathBuf,
+    in_use: AtomicU64,
+    peak: AtomicU64,
+    spilled: AtomicU64,
+    waited: AtomicU64,
+    capped: AtomicU64,
+}
+
+/// Bytes taken from an [`OutputBudget`], given back when dropped.
+pub(crate) struct Reservation<'a> {
+    budget: &'a OutputBudget,
+    bytes: u64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.budget.in_use.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+impl OutputBudget {
+    pub(crate) fn new(limit: u64, dir: &Path) -> Self {
+        OutputBudget {
+            limit,
+            dir: dir.to_path_buf(),
+            in_use: AtomicU64::new(0),
+            peak: AtomicU64::new(0),
+            spilled: AtomicU64::new(0),
+            waited: AtomicU64::new(0),
+            capped: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> BudgetStats {
+        BudgetStats {
+            limit: self.limit,
+            in_use: self.in_use.load(Ordering::SeqCst),
+            peak: self.peak.load(Ordering::SeqCst),
+            spilled: self.spilled.load(Ordering::SeqCst),
+            waited: self.waited.load(Ordering::SeqCst),
+            capped: self.capped.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Bytes a command with the per stream limit `max_output` reserves.
+    fn need(&self, max_output: Option<u64>) -> u64 {
+        max_output.map_or(self.limit, |max| max.saturating_mul(2))
+    }
+
+    fn take(&self, bytes: u64) -> Option<Reservation<'_>> {
+        let mut in_use = self.in_use.load(Ordering::SeqCst);
+        loop {
+            let total = in_use.checked_add(bytes).filter(|total| *total <= self.limit)?;
+            match self.in_use.compare_exchange(in_use, total, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => {
+                    self.peak.fetch_max(total, Ordering::SeqCst);
+                    return Some(Reservation { budget: self, bytes });
+                }
+                Err(current) => in_use = current,
+            }
+        }
+    }
+
+    /// Reserves for a command with the per stream limit `max_output`, `None` if the
+    /// budget doesn't have room, the output is spilled then.
+    pub(crate) fn reserve(&self, max_output: Option<u64>) -> Option<Reservation<'_>> {
+        self.take(self.need(max_output))
+    }
+
+    /// Reserves for a command which has to keep its output in memory, waiting while the
+    /// budget doesn't have room for it. A `max_output` needing more than the whole budget
+    /// is lowered to half of it, which is reserved once nothing else is. Returns the
+    /// `max_output` to keep.
+    pub(crate) fn reserve_waiting(&self, max_output: Option<u64>) -> (Reservation<'_>, Option<u64>) {
+        let (need, max_output) = match self.need(max_output) {
+            need if need <= self.limit => (need, max_output),
+            _ => {
+                self.capped.fetch_add(1, Ordering::SeqCst);
+                (self.limit, Some(self.limit / 2))
+            }
+        };
+        let mut waited = false;
+        loop {
+            if let Some(reservation) = self.take(need) {
+                return (reservation, max_output);
+            }
+            if !waited {
+                self.waited.fetch_add(1, Ordering::SeqCst);
+                waited = true;
+            }
+            idle();
+        }
+    }
+
+    /// Starts reading the output of a command into new files in the spill directory,
+    /// stderr too unless it is `merged` into stdout, see [`Spill`].
+    pub(crate) fn spill<'a>(
+        &self,
+        merged: bool,
+        limit: Option<u64>,
+        redactor: Option<&'a Redactor>,
+    ) -> io::Result<Spill<'a>> {
+        self.spilled.fetch_add(1, Ordering::SeqCst);
+        let token = bundle_token();
+        let path = |name: &str| self.dir.join(format!("am-spill-{}.{}", token, name));
+        let mut streams = vec![SpillFile::create(path("stdout"))?];
+        if !merged {
+            streams.push(SpillFile::create(path("stderr"))?);
+        }
+        Ok(Spill {
+            streams,
+            limit,
+            redactor,
+            close_input: false,
+            buf: vec![0; SPILL_CHUNK],
+        })
+    }
+}
+
+/// Output of a command going to files, stdout and stderr read side by side. Keeps at most
+/// `limit` bytes of each, as the command would have in memory, and the first
+/// [`SPILL_PREVIEW`] bytes of each in memory too, to evaluate the command on. With a
+/// redactor the files are redacted line by line, lines longer than [`SPILL_CHUNK`] in parts.
+pub(crate) struct Spill<'a> {
+    streams: Vec<SpillFile>,
+    limit: Option<u64>,
+    redactor: Option<&'a Redactor>,
+    close_input: bool,
+    buf: Vec<u8>,
+}
+
+impl Spill<'_> {
+    /// Closes the remote's stdin first, for commands without input.
+    pub(crate) fn closing_input(mut self) -> Self {
+        self.close_input = true;
+        self
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.streams.iter().all(|stream| stream.done)
+    }
+
+    /// Moves what the channel has, returns whether anything moved.
+    /// `channel` must be non-blocking.
+    pub(crate) fn step<D: Duplex>(&mut self, channel: &mut D) -> io::Result<bool> {
+        let mut progress = false;
+        if self.close_input && would_block(channel.close_input())?.is_some() {
+            self.close_input = false;
+            progress = true;
+        }
+        for (index, stream) in self.streams.iter_mut().enumerate().filter(|(_, stream)| !stream.done) {
+            let read = match index {
+                0 => channel.read_output(&mut self.buf),
+                _ => channel.read_error(&mut self.buf),
+            };
+            match would_block(read)? {
+                Some(0) => stream.done = true,
+                Some(read) => {
+                    stream.write(&self.buf[..read], self.limit, self.redactor)?;
+                    progress = true;
+                }
+                None => {}
+            }
+        }
+        Ok(progress)
+    }
+
+    /// Serves the channel until its output ends, giving up once nothing moved for
+    /// `timeout`, see [`idle_within`].
+    pub(crate) fn run_within<D: Duplex>(
+        mut self,
+        channel: &mut D,
+        timeout: Option<Duration>,
+    ) -> io::Result<(SpilledOutput, PumpOutput, bool)> {
+        let mut quiet_since = Instant::now();
+        while !self.is_done() {
+            if self.step(channel)? {
+                quiet_since = Instant::now();
+            } else {
+                idle_within(quiet_since, timeout)?;
+            }
+        }
+        self.into_output()
+    }
+
+    /// The files, what is kept of them in memory, with the bytes of each stream and
+    /// whether the limit was exceeded, and whether anything was redacted.
+    pub(crate) fn into_output(self) -> io::Result<(SpilledOutput, PumpOutput, bool)> {
+        let mut streams = self.streams.into_iter();
+        let mut stdout = streams.next().expect("stdout is always spilled");
+        stdout.flush(self.redactor)?;
+        let mut stderr = streams.next();
+        if let Some(stderr) = &mut stderr {
+            stderr.flush(self.redactor)?;
+        }
+        let output = PumpOutput {
+            stdout: stdout.preview,
+            stderr: stderr.as_mut().map(|stderr| std::mem::take(&mut stderr.preview)).unwrap_or_default(),
+            truncated: stdout.exceeded || stderr.as_ref().is_some_and(|stderr| stderr.exceeded),
+            prompt: None,
+            stdout_bytes: stdout.kept,
+            stderr_bytes: stderr.as_ref().map_or(0, |stderr| stderr.kept),
+        };
+        let redacted = stdout.redacted || stderr.as_ref().is_some_and(|stderr| stderr.redacted);
+        let spilled = SpilledOutput {
+            stdout: stdout.path,
+            stderr: stderr.map(|stderr| stderr.path),
+        };
+        Ok((spilled, output, redacted))
+    }
+}
+
+/// One stream of a [`Spill`].
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+    /// Bytes written to the file, at most the limit
+    kept: u64,
+    exceeded: bool,
+    done: bool,
+    /// The first [`SPILL_PREVIEW`] bytes
+    preview: Vec<u8>,
+    /// Bytes of a line not redacted yet
+    pending: Vec<u8>,
+    redacted: bool,
+}
+
+impl SpillFile {
+    /// Creates the file at `path`, which must not exist yet, readable by the owner only.
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options.open(&path)?;
+        Ok(SpillFile {
+            path,
+            file,
+            kept: 0,
+            exceeded: false,
+            done: false,
+            preview: Vec::new(),
+            pending: Vec::new(),
+            redacted: false,
+        })
+    }
+
+    /// Appends what of `data` fits within `limit`, the rest is drained and dropped.
+    /// With a `redactor` only whole lines are written, see [`Spill`].
+    fn write(&mut self, data: &[u8], limit: Option<u64>, redactor: Option<&Redactor>) -> io::Result<()> {
+        let room = limit.map_or(data.len() as u64, |limit| limit.saturating_sub(self.kept));
+        let keep = room.min(data.len() as u64);
+        let kept = &data[..keep as usize];
+        self.kept += keep;
+        self.exceeded |= keep < data.len() as u64;
+        let preview = SPILL_PREVIEW.saturating_sub(self.preview.len()).min(kept.len());
+        self.preview.extend_from_slice(&kept[..preview]);
+        let redactor = match redactor {
+            Some(redactor) => redactor,
+            None => return self.file.write_all(kept),
+        };
+        self.pending.extend_from_slice(kept);
+        let end = match self.pending.iter().rposition(|&b| b == b'\n') {
+            Some(newline) => newline + 1,
+            None if self.pending.len() > SPILL_CHUNK => self.pending.len(),
+            None => return Ok(()),
+        };
+        let lines: Vec<u8> = self.pending.drain(..end).collect();
+        self.write_redacted(&lines, redactor)
+    }
+
+    /// Writes the rest of a last line without a newline.
+    fn flush(&mut self, redactor: Option<&Redactor>) -> io::Result<()> {
+        match redactor {
+            Some(redactor) if !self.pending.is_empty() => {
+                let line = std::mem::take(&mut self.pending);
+                self.write_redacted(&line, redactor)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn write_redacted(&mut self, lines: &[u8], redactor: &Redactor) -> io::Result<()> {
+        match redactor.redact(&String::from_utf8_lossy(lines)) {
+            Cow::Owned(redacted) => {
+                self.redacted = true;
+                self.file.write_all(redacted.as_bytes())
+            }
+            Cow::Borrowed(_) => self.file.write_all(lines),
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use crate::modules::ModuleContent;
+use crate::{
+    parse_duration, parse_size, MaintenanceWindow, Module, PromptResponse, RegisterScope, RetryHint, ShellCommand,
+};
+use crate::WaitFor;
+use anyhow::Error;
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Builds shell modules in code, with everything a `.mod` file can declare:
+/// ```
+/// # use ansible_modules::ShellModuleBuilder;
+/// let module = ShellModuleBuilder::new()
+///     .cmd("update", "apt-get update")
+///     .cmd_with("install", "apt-get install -y {{ pkg }}", |c| c.merge_streams())
+///     .timeout("10m")
+///     .build()
+///     .unwrap();
+/// ```
+/// Mistakes, like a repeated command name or a bad regex, are reported by
+/// [`ShellModuleBuilder::build`], which checks the module as the loader would.
+#[derive(Default)]
+pub struct ShellModuleBuilder {
+    commands: Vec<(String, CommandBuilder)>,
+    env: HashMap<String, String>,
+    max_output: Option<String>,
+    timeout: Option<String>,
+    bundle: bool,
+    fail_fast: bool,
+    window: Option<MaintenanceWindow>,
+    retry_hint: Option<RetryHint>,
+    umask: Option<String>,
+    locale: Option<String>,
+    parser: Option<String>,
+    depends_on: Vec<String>,
+    normalize: Vec<String>,
+    read_only: bool,
+    lint_ignore: Vec<String>,
+    strict_lints: bool,
+    deprecated: bool,
+    replaced_by: Option<String>,
+}
+
+/// Options of a single command, see [`ShellModuleBuilder::cmd_with`].
+pub struct CommandBuilder {
+    command: ShellCommand,
+    error: Option<Error>,
+}
+
+impl CommandBuilder {
+    pub fn merge_streams(mut self) -> Self {
+        self.command.merge_streams = true;
+        self
+    }
+
+    pub fn stdin(mut self, stdin: &str) -> Self {
+        self.command.stdin = Some(stdin.to_string());
+        self
+    }
+
+    /// Streams the local file at `path` into the command's stdin.
+    pub fn stdin_file(mut self, path: &Path) -> Self {
+        self.command.stdin_file = Some(path.to_path_buf());
+        self
+    }
+
+    pub fn capture(mut self, pattern: &str) -> Self {
+        self.command.capture = self.regex(pattern);
+        self
+    }
+
+    pub fn require_output(mut self) -> Self {
+        self.command.require_output = true;
+        self
+    }
+
+    pub fn changed_when(mut self, pattern: &str) -> Self {
+        self.command.changed_when = self.regex(pattern);
+        self
+    }
+
+    pub fn parser(mut self, parser: &str) -> Self {
+        self.command.parser = Some(parser.to_string());
+        self
+    }
+
+    /// Declares that the command changes nothing, see [`crate::Runner::with_read_only`].
+    pub fn read_only(mut self) -> Self {
+        self.command.read_only = true;
+        self
+    }
+
+    /// Reuses the result of the same command line within a run, see [`ShellCommand`].
+    pub fn memoize(mut self) -> Self {
+        self.command.memoize = true;
+        self
+    }
+
+    /// Starts the command without waiting for it, see [`ShellCommand`].
+    pub fn background(mut self) -> Self {
+        self.command.background = true;
+        self
+    }
+
+    /// Runs the command on the controller, in `dir` or the current directory.
+    /// See [`ShellCommand`].
+    pub fn local(mut self, dir: Option<&Path>) -> Self {
+        self.command.local = true;
+        self.command.local_dir = dir.map(Path::to_path_buf);
+        self
+    }
+
+    /// Where the command's captures are kept, see [`ShellCommand`].
+    pub fn register_scope(mut self, scope: RegisterScope) -> Self {
+        self.command.register_scope = scope;
+        self
+    }
+
+    /// Keeps only the last `size` bytes of each stream, e.g. `64KiB`.
+    pub fn tail(mut self, size: &str) -> Self {
+        match parse_size(size) {
+            Ok(size) => self.command.tail = Some(size),
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+        self
+    }
+
+    /// Answers `prompt` with `answer`, see [`PromptResponse`].
+    pub fn respond(mut self, prompt: &str, answer: &str) -> Self {
+        self.command.responses.push(PromptResponse {
+            prompt: prompt.to_string(),
+            answer: answer.to_string(),
+        });
+        self
+    }
+
+    /// Compiles `pattern`, keeping the first error for [`ShellModuleBuilder::build`].
+    fn regex(&mut self, pattern: &str) -> Option<Regex> {
+        match Regex::new(pattern) {
+            Ok(regex) => Some(regex),
+            Err(e) => {
+                self.error.get_or_insert_with(|| e.into());
+                None
+            }
+        }
+    }
+}
+
+impl ShellModuleBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cmd(self, name: &str, cmd: &str) -> Self {
+        self.cmd_with(name, cmd, |command| command)
+    }
+
+    pub fn cmd_with<F>(mut self, name: &str, cmd: &str, options: F) -> Self
+    where
+        F: FnOnce(CommandBuilder) -> CommandBuilder,
+    {
+        let command = options(CommandBuilder {
+            command: ShellCommand::new(cmd),
+            error: None,
+        });
+        self.commands.push((name.to_string(), command));
+        self
+    }
+
+    /// Command which polls `wait`, see [`WaitFor`].
+    pub fn wait_for(self, name: &str, wait: WaitFor) -> Self {
+        self.cmd_with(name, "", |mut command| {
+            command.command.wait_for = Some(wait);
+            command.command.resolve_wait_for();
+            command
+        })
+    }
+
+    pub fn env(mut self, name: &str, value: &str) -> Self {
+        self.env.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Output limit per stream, like `max_output` in a `.mod` file, e.g. `10MiB`.
+    pub fn max_output(mut self, size: &str) -> Self {
+        self.max_output = Some(size.to_string());
+        self
+    }
+
+    /// Read timeout, like `timeout` in a `.mod` file, e.g. `30s`.
+    pub fn timeout(mut self, timeout: &str) -> Self {
+        self.timeout = Some(timeout.to_string());
+        self
+    }
+
+    pub fn bundle(mut self) -> Self {
+        self.bundle = true;
+        self
+    }
+
+    /// Stops at the first command which fails or exits non-zero, like `fail_fast`
+    /// in a `.mod` file. Commands run one at a time, in order of their names.
+    pub fn fail_fast(mut self) -> Self {
+        self.fail_fast = true;
+        self
+    }
+
+    /// Runs the module only in `window`, like `window` in a `.mod` file.
+    pub fn window(mut self, window: MaintenanceWindow) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Runs commands again which ask for it as `hint` says, like `retry_hint` in a `.mod` file.
+    pub fn retry_hint(mut self, hint: RetryHint) -> Self {
+        self.retry_hint = Some(hint);
+        self
+    }
+
+    pub fn umask(mut self, umask: &str) -> Self {
+        self.umask = Some(umask.to_string());
+        self
+    }
+
+    pub fn locale(mut self, locale: &str) -> Self {
+        self.locale = Some(locale.to_string());
+        self
+    }
+
+    pub fn parser(mut self, parser: &str) -> Self {
+        self.parser = Some(parser.to_string());
+        self
+    }
+
+    /// Runs the module after `module`, see [`crate::ModuleTree::dependency_order`].
+    pub fn depends_on(mut self, module: &str) -> Self {
+        self.depends_on.push(module.to_string());
+        self
+    }
+
+    /// Ignores matches of `pattern` when comparing outputs, see [`Module::normalize`].
+    pub fn normalize(mut self, pattern: &str) -> Self {
+        self.normalize.push(pattern.to_string());
+        self
+    }
+
+    /// Declares that no command of the module changes anything.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Leaves lint `id` out of [`Module::lints`], e.g. `AM002`.
+    pub fn lint_ignore(mut self, id: &str) -> Self {
+        self.lint_ignore.push(id.to_string());
+        self
+    }
+
+    /// Fails building the module if it has lints.
+    pub fn strict_lints(mut self) -> Self {
+        self.strict_lints = true;
+        self
+    }
+
+    /// Deprecates the module, for `replaced_by` if given, see [`Module::is_deprecated`].
+    pub fn deprecated(mut self, replaced_by: Option<&str>) -> Self {
+        self.deprecated = true;
+        self.replaced_by = replaced_by.map(str::to_string);
+        self
+    }
+
+    pub fn build(self) -> Result<Module, Error> {
+        let mut duplicates: Vec<_> = self
+            .commands
+            .iter()
+            .enumerate()
+            .filter(|(i, (name, _))| self.commands[..*i].iter().any(|(seen, _)| seen == name))
+            .map(|(_, (name, _))| name.as_str())
+            .collect();
+        if !duplicates.is_empty() {
+            duplicates.sort_unstable();
+            duplicates.dedup();
+            return Err(Error::msg(format!(
+                "Duplicate command names: {}",
+                duplicates.join(", ")
+            )));
+        }
+        let mut commands = HashMap::new();
+        for (name, builder) in self.commands {
+            if let Some(e) = builder.error {
+                return Err(Error::msg(format!("Command {}: {}", name, e)));
+            }
+            commands.insert(name, builder.command);
+        }
+        let module = Module {
+            module_content: Arc::new(ModuleContent::Shell(commands)),
+            env: self.env,
+            max_output: self.max_output.as_deref().map(parse_size).transpose()?,
+            timeout: self.timeout.as_deref().map(parse_duration).transpose()?,
+            bundle: self.bundle,
+            umask: self.umask,
+            remote_dir: None,
+            file_mode: None,
+            args: Vec::new(),
+            fail_fast: self.fail_fast,
+            window: self.window,
+            retry_hint: self.retry_hint,
+            locale: self.locale,
+            parser: self.parser,
+            depends_on: self.depends_on,
+            normalize: self
+                .normalize
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<_, _>>()?,
+            read_only: self.read_only,
+            lint_ignore: self.lint_ignore,
+            strict_lints: self.strict_lints,
+            deprecated: self.deprecated,
+            replaced_by: self.replaced_by,
+        };
+        module.check()?;
+        Ok(module)
+    }
+}
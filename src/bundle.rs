@@ -0,0 +1,62 @@
+use crate::shell::{shell_format, ShellSafe};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Tokens made so far, so threads never get the same one.
+static TOKENS: AtomicU64 = AtomicU64::new(0);
+
+/// Token, which makes bundle markers unlikely to appear in real output,
+/// and names of uploaded files unique. Its random part keeps other users of
+/// a host from guessing the name of a file before it is uploaded.
+pub(crate) fn bundle_token() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let nth = TOKENS.fetch_add(1, Ordering::Relaxed);
+    // every RandomState is seeded with fresh random keys
+    let mut random = RandomState::new().build_hasher();
+    random.write_u64(nth);
+    format!("{:x}{:x}{:x}{:016x}", nanos, std::process::id(), nth, random.finish())
+}
+
+fn marker(token: &str, index: usize, edge: &str) -> String {
+    format!("__AM_{}_{}_{}__", token, index, edge)
+}
+
+/// Joins commands into one script, which prints markers around the stdout and stderr
+/// of every command, so [`split_bundled`] can take the output apart again.
+/// Commands flagged with `true` get their stderr merged into stdout.
+pub fn bundle_commands(commands: &[(&str, bool)], token: &str) -> String {
+    let mut script = String::new();
+    for (index, (command, merge_streams)) in commands.iter().enumerate() {
+        let begin = marker(token, index, "BEGIN");
+        let end = marker(token, index, "END");
+        let redirect = if *merge_streams { " 2>&1" } else { "" };
+        let block = shell_format!(
+            "printf '%s\\n' {0}; printf '%s\\n' {0} >&2\n{{\n{2}\n}}{3}\nprintf '\\n%s\\n' {1}; printf '\\n%s\\n' {1} >&2\n",
+            ShellSafe::word(&begin),
+            ShellSafe::word(&end),
+            ShellSafe::command(command),
+            ShellSafe::command(redirect)
+        );
+        script.push_str(block.as_str());
+    }
+    script
+}
+
+/// Output of every bundled command, `None` for commands which didn't finish,
+/// e.g. because an earlier command called `exit`.
+pub fn split_bundled(output: &str, token: &str, count: usize) -> Vec<Option<String>> {
+    (0..count)
+        .map(|index| {
+            let begin = format!("{}\n", marker(token, index, "BEGIN"));
+            let end = format!("\n{}\n", marker(token, index, "END"));
+            let start = output.find(&begin)? + begin.len();
+            let len = output[start..].find(&end)?;
+            Some(output[start..start + len].to_string())
+        })
+        .collect()
+}
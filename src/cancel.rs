@@ -0,0 +1,46 @@
+use anyhow::Error;
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Flag cancelling a run from another thread, e.g. a Ctrl-C handler of the caller,
+/// see [`crate::Runner::with_cancel_token`]. Clones share the flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// Stops the run before its next module or command. What runs already isn't
+    /// interrupted, the run fails with [`Cancelled`] once it finished.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Fails with [`Cancelled`] if the token was cancelled.
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        match self.is_cancelled() {
+            true => Err(Cancelled.into()),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Work stopped by a [`CancelToken`]. Errors carry it, find it with
+/// `downcast_ref::<Cancelled>()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Run was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
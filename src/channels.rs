@@ -0,0 +1,200 @@
+use crate::exec::ExecError;
+use anyhow::Error;
+use serde::Serialize;
+use ssh2::{Channel, Session};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Channels a connection keeps open at once by default, below the `MaxSessions` of 10
+/// of OpenSSH, see [`crate::ConnectionProps::channel_limit_for`].
+pub const DEFAULT_CHANNEL_LIMIT: usize = 8;
+
+/// Channels of a connection so far, see [`crate::HostConnection::channel_stats`].
+/// `queued` counts channels which waited for another one to close, `refused` the times
+/// the host refused a channel as too many were open, lowering `limit` to what it took.
+/// `overflowed` counts channels opened over a second connection, as the wait on the
+/// first would have been too long.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ChannelStats {
+    pub limit: usize,
+    pub open: usize,
+    pub peak: usize,
+    pub opened: u64,
+    pub queued: u64,
+    pub refused: u64,
+    pub overflowed: u64,
+    /// Time channels spent queued
+    pub waited: Duration,
+}
+
+/// Counts the channels open on a session, making requests above the limit wait.
+pub(crate) struct ChannelGate {
+    stats: Mutex<ChannelStats>,
+    closed: Condvar,
+}
+
+/// Whether the host refused a channel because too many are open, as OpenSSH does
+/// above `MaxSessions` with "open failed: administratively prohibited".
+fn too_many_channels(e: &ssh2::Error) -> bool {
+    let message = e.message();
+    // LIBSSH2_ERROR_CHANNEL_FAILURE
+    e.code() == -21
+        && (message.contains("administratively prohibited") || message.contains("resource shortage"))
+}
+
+impl ChannelGate {
+    pub(crate) fn new(limit: usize) -> Arc<Self> {
+        Arc::new(ChannelGate {
+            stats: Mutex::new(ChannelStats {
+                limit: limit.max(1),
+                ..ChannelStats::default()
+            }),
+            closed: Condvar::new(),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ChannelStats> {
+        self.stats.lock().expect("channel stats lock poisoned")
+    }
+
+    pub(crate) fn stats(&self) -> ChannelStats {
+        *self.lock()
+    }
+
+    pub(crate) fn overflowed(&self) {
+        self.lock().overflowed += 1;
+    }
+
+    /// Whether a channel can be opened without waiting.
+    pub(crate) fn has_room(&self) -> bool {
+        let stats = self.lock();
+        stats.open < stats.limit
+    }
+
+    /// Takes a slot once one is free, or gives up after `wait`, returning whether
+    /// it got one.
+    fn acquire(&self, wait: Option<Duration>) -> bool {
+        let mut stats = self.lock();
+        if stats.open >= stats.limit {
+            stats.queued += 1;
+            let started = Instant::now();
+            while stats.open >= stats.limit {
+                stats = match wait {
+                    Some(wait) => {
+                        let left = match wait.checked_sub(started.elapsed()) {
+                            Some(left) if !left.is_zero() => left,
+                            _ => {
+                                stats.waited += started.elapsed();
+                                return false;
+                            }
+                        };
+                        self.closed.wait_timeout(stats, left).expect("channel stats lock poisoned").0
+                    }
+                    None => self.closed.wait(stats).expect("channel stats lock poisoned"),
+                };
+            }
+            stats.waited += started.elapsed();
+        }
+        stats.open += 1;
+        stats.opened += 1;
+        stats.peak = stats.peak.max(stats.open);
+        true
+    }
+
+    fn release(&self) {
+        let mut stats = self.lock();
+        stats.open -= 1;
+        self.closed.notify_one();
+    }
+
+    /// Opens a channel on `session` with `open` once the gate has room, waiting at most
+    /// `wait` for it. `None` if the wait ran out. A channel refused as one too many lowers the limit
+    /// to the channels which are open, then waits for one of them if `queue`, or fails
+    /// with [`ExecError::TooManyChannels`].
+    pub(crate) fn open(
+        self: &Arc<Self>,
+        session: &Session,
+        open: &dyn Fn(&Session) -> Result<Channel, ssh2::Error>,
+        wait: Option<Duration>,
+        queue: bool,
+    ) -> Result<Option<OpenChannel>, Error> {
+        loop {
+            if !self.acquire(wait) {
+                return Ok(None);
+            }
+            let e = match open(session) {
+                Ok(channel) => {
+                    return Ok(Some(OpenChannel {
+                        channel: Some(channel),
+                        session: session.clone(),
+                        gate: self.clone(),
+                        _overflow: None,
+                    }))
+                }
+                Err(e) => e,
+            };
+            let mut stats = self.lock();
+            stats.open -= 1;
+            if !too_many_channels(&e) || stats.open == 0 {
+                return Err(ExecError::ChannelRejected {
+                    message: e.message().to_string(),
+                }
+                .into());
+            }
+            stats.refused += 1;
+            stats.limit = stats.open;
+            if !queue {
+                return Err(ExecError::TooManyChannels { open: stats.open }.into());
+            }
+        }
+    }
+}
+
+/// Channel counted by the gate of its connection until it is dropped. Derefs to the
+/// channel, [`OpenChannel::session`] is the session it is on.
+pub(crate) struct OpenChannel {
+    channel: Option<Channel>,
+    session: Session,
+    gate: Arc<ChannelGate>,
+    /// Second connection the channel is on, kept open as long as the channel
+    _overflow: Option<Arc<crate::HostConnection>>,
+}
+
+impl OpenChannel {
+    pub(crate) fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// The session and the channel, to pass both where a channel is used mutably.
+    pub(crate) fn split(&mut self) -> (&Session, &mut Channel) {
+        let channel = self.channel.as_mut().expect("channel is open until dropped");
+        (&self.session, channel)
+    }
+
+    pub(crate) fn on_overflow(mut self, connection: Arc<crate::HostConnection>) -> Self {
+        self._overflow = Some(connection);
+        self
+    }
+}
+
+impl Deref for OpenChannel {
+    type Target = Channel;
+
+    fn deref(&self) -> &Channel {
+        self.channel.as_ref().expect("channel is open until dropped")
+    }
+}
+
+impl DerefMut for OpenChannel {
+    fn deref_mut(&mut self) -> &mut Channel {
+        self.channel.as_mut().expect("channel is open until dropped")
+    }
+}
+
+impl Drop for OpenChannel {
+    fn drop(&mut self) {
+        drop(self.channel.take());
+        self.gate.release();
+    }
+}
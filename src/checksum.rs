@@ -0,0 +1,220 @@
+//! SHA-256, for checksum manifests of module trees and host key fingerprints.
+#[cfg(feature = "discovery")]
+use anyhow::Error;
+#[cfg(feature = "discovery")]
+use std::collections::HashMap;
+#[cfg(feature = "discovery")]
+use std::fs::{self, File};
+use std::io::{self, Read};
+#[cfg(feature = "discovery")]
+use std::path::{Path, PathBuf};
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 of a stream, fed with [`Sha256::update`].
+struct Sha256 {
+    state: [u32; 8],
+    block: Vec<u8>,
+    len: u64,
+}
+
+impl Sha256 {
+    fn new() -> Self {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+            ],
+            block: Vec::with_capacity(64),
+            len: 0,
+        }
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        while !data.is_empty() {
+            let take = (64 - self.block.len()).min(data.len());
+            self.block.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.block.len() == 64 {
+                let block = std::mem::take(&mut self.block);
+                self.compress(&block);
+                self.block = block;
+                self.block.clear();
+            }
+        }
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let bits = self.len.wrapping_mul(8);
+        let mut padding = vec![0x80u8];
+        padding.resize((119 - (self.len % 64) as usize) % 64 + 1, 0);
+        padding.extend_from_slice(&bits.to_be_bytes());
+        self.update(&padding);
+        let mut digest = [0; 32];
+        for (bytes, word) in digest.chunks_mut(4).zip(self.state.iter()) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    /// [`Sha256::finish`] as lowercase hex, like `sha256sum` prints it.
+    fn hex(self) -> String {
+        self.finish().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+/// SHA-256 of `data`.
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+/// SHA-256 of `data`, as lowercase hex.
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.hex()
+}
+
+/// Reader hashing what is read through it, to check what was sent of a file against
+/// the digest it had when it was verified.
+pub(crate) struct Sha256Reader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Sha256Reader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Sha256Reader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// SHA-256 of what was read so far, as lowercase hex.
+    pub(crate) fn hex(self) -> String {
+        self.hasher.hex()
+    }
+}
+
+impl<R: Read> Read for Sha256Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// SHA-256 of the file at `path`, which must be a regular file, read in chunks.
+#[cfg(feature = "discovery")]
+pub(crate) fn sha256_file(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(hasher.hex()),
+            read => hasher.update(&buf[..read]),
+        }
+    }
+}
+
+/// Digests of the files of a tree, read from a `MANIFEST.sha256` as `sha256sum` writes it,
+/// `<digest>  <path>` per line, paths relative to the manifest's directory.
+#[cfg(feature = "discovery")]
+#[derive(Debug)]
+pub(crate) struct Checksums {
+    files: HashMap<PathBuf, String>,
+    /// Digest of the manifest itself
+    digest: String,
+}
+
+#[cfg(feature = "discovery")]
+impl Checksums {
+    pub(crate) fn load(manifest: &Path) -> Result<Self, Error> {
+        let content = fs::read(manifest)
+            .map_err(|e| Error::msg(format!("Can't read manifest {}: {}", manifest.display(), e)))?;
+        let dir = manifest.parent().unwrap_or_else(|| Path::new("."));
+        let mut files = HashMap::new();
+        for (index, line) in String::from_utf8_lossy(&content).lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let bad = || {
+                Error::msg(format!(
+                    "Bad line {} of manifest {}, expected `<sha256>  <path>`",
+                    index + 1,
+                    manifest.display()
+                ))
+            };
+            let (digest, path) = line.split_once(' ').ok_or_else(bad)?;
+            // sha256sum marks files hashed in binary mode with `*`
+            let path = path.strip_prefix(|c| c == ' ' || c == '*').unwrap_or(path);
+            if digest.len() != 64 || !digest.bytes().all(|b| b.is_ascii_hexdigit()) || path.is_empty() {
+                return Err(bad());
+            }
+            files.insert(normalize(&dir.join(path)), digest.to_lowercase());
+        }
+        Ok(Checksums {
+            files,
+            digest: sha256_hex(&content),
+        })
+    }
+
+    pub(crate) fn digest(&self) -> &str {
+        &self.digest
+    }
+
+    /// Digest `path` must have, `None` if the manifest doesn't list it.
+    pub(crate) fn expected(&self, path: &Path) -> Option<&str> {
+        self.files.get(&normalize(path)).map(String::as_str)
+    }
+}
+
+/// `path` with symlinks and `.` resolved, so the same file is found however it was joined.
+#[cfg(feature = "discovery")]
+fn normalize(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
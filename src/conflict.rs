@@ -0,0 +1,203 @@
+use crate::modules::ModuleContent;
+use crate::{ExecutionOptions, Module, RegisterScope, ShellCommand};
+use std::fmt::{self, Display};
+
+/// Where a conflict is found, with the check finding it.
+#[derive(Clone, Copy)]
+enum Check {
+    /// Options of a command, or of the command and its module
+    Command(fn(&Module, &ShellCommand) -> bool),
+    /// Options of the module itself
+    Module(fn(&Module) -> bool),
+    /// Options of the module and of the run
+    Run(fn(&Module, &ExecutionOptions) -> bool),
+}
+
+struct Rule {
+    id: &'static str,
+    message: &'static str,
+    check: Check,
+}
+
+fn takes_stdin(command: &ShellCommand) -> bool {
+    command.stdin.is_some() || command.stdin_file.is_some()
+}
+
+fn registers(command: &ShellCommand) -> bool {
+    command.register_scope == RegisterScope::Connection
+}
+
+fn streams(command: &ShellCommand) -> bool {
+    command.tail.is_some() || !command.responses.is_empty()
+}
+
+/// Every refused combination of options, with the check finding it, see [`CONFLICTS`].
+const RULES: &[Rule] = &[
+    Rule {
+        id: "AM101",
+        message: "stdin and stdin_file, only one can be written to the command",
+        check: Check::Command(|_, command| command.stdin.is_some() && command.stdin_file.is_some()),
+    },
+    Rule {
+        id: "AM102",
+        message: "memoize and stdin, the input would be ignored by later runs",
+        check: Check::Command(|_, command| command.memoize && takes_stdin(command)),
+    },
+    Rule {
+        id: "AM103",
+        message: "memoize and register_scope, later runs would register another module's captures",
+        check: Check::Command(|_, command| command.memoize && registers(command)),
+    },
+    Rule {
+        id: "AM104",
+        message: "register_scope without capture, there is nothing to register",
+        check: Check::Command(|_, command| registers(command) && command.capture.is_none()),
+    },
+    Rule {
+        id: "AM105",
+        message: "local in a bundled module, the bundle runs remotely as one script",
+        check: Check::Command(|module, command| module.bundle && command.local),
+    },
+    Rule {
+        id: "AM106",
+        message: "local and memoize, tail or responses, local commands support none of them",
+        check: Check::Command(|_, command| command.local && (command.memoize || streams(command))),
+    },
+    Rule {
+        id: "AM107",
+        message: "wait_for in a bundled module, the bundle can't poll",
+        check: Check::Command(|module, command| module.bundle && command.wait_for.is_some()),
+    },
+    Rule {
+        id: "AM108",
+        message: "wait_for and local, memoize, stdin, tail or responses",
+        check: Check::Command(|_, command| {
+            command.wait_for.is_some()
+                && (command.local || command.memoize || takes_stdin(command) || streams(command))
+        }),
+    },
+    Rule {
+        id: "AM109",
+        message: "stdin in a bundled module, commands share the script's input",
+        check: Check::Command(|module, command| module.bundle && takes_stdin(command)),
+    },
+    Rule {
+        id: "AM110",
+        message: "tail in a bundled module, the bundle's output is split after it ended",
+        check: Check::Command(|module, command| module.bundle && command.tail.is_some()),
+    },
+    Rule {
+        id: "AM111",
+        message: "responses in a bundled module, the bundle doesn't watch for prompts",
+        check: Check::Command(|module, command| module.bundle && !command.responses.is_empty()),
+    },
+    Rule {
+        id: "AM112",
+        message: "prompt_timeout of the run with a bundled module, the bundle doesn't watch for prompts",
+        check: Check::Run(|module, options| module.bundle && options.prompt_timeout.is_some()),
+    },
+    Rule {
+        id: "AM113",
+        message: "fail_fast in a bundled module, the bundle has no exit status per command",
+        check: Check::Module(|module| module.bundle && module.fail_fast),
+    },
+    Rule {
+        id: "AM114",
+        message: "background in a bundled module, the bundle runs as one script",
+        check: Check::Command(|module, command| module.bundle && command.background),
+    },
+    Rule {
+        id: "AM115",
+        message: "background and local, memoize, stdin, tail, responses or wait_for, the command isn't waited for",
+        check: Check::Command(|_, command| {
+            command.background
+                && (command.local
+                    || command.memoize
+                    || takes_stdin(command)
+                    || streams(command)
+                    || command.wait_for.is_some())
+        }),
+    },
+    Rule {
+        id: "AM116",
+        message: "retry_hint in a bundled module, the bundle has no exit status per command",
+        check: Check::Module(|module| module.bundle && module.retry_hint.is_some()),
+    },
+];
+
+/// Ids of every refused combination of options, with what it is.
+/// Nothing guesses which of two options should win, modules and runs declaring
+/// both fail before anything runs, with a [`Conflict`].
+pub const CONFLICTS: &[(&str, &str)] = &{
+    let mut conflicts = [("", ""); RULES.len()];
+    let mut i = 0;
+    while i < RULES.len() {
+        conflicts[i] = (RULES[i].id, RULES[i].message);
+        i += 1;
+    }
+    conflicts
+};
+
+/// Combination of options which is refused, found in `subject`, a command or module,
+/// which is `module` for conflicts between options of the module itself.
+/// Errors carry it, find it with `downcast_ref::<Conflict>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub id: &'static str,
+    pub subject: String,
+    pub message: &'static str,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.id, self.subject, self.message)
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+impl Rule {
+    fn conflict(&self, subject: &str) -> Conflict {
+        Conflict {
+            id: self.id,
+            subject: subject.to_string(),
+            message: self.message,
+        }
+    }
+}
+
+impl Module {
+    /// Conflicts between the options of the module, then those of its commands, in order
+    /// of their names and of [`CONFLICTS`]. Modules with any fail to load or build,
+    /// with the first one.
+    pub(crate) fn conflicts(&self) -> Vec<Conflict> {
+        let commands = match &*self.module_content {
+            ModuleContent::Shell(commands) => commands,
+            _ => return Vec::new(),
+        };
+        let own = RULES.iter().filter_map(|rule| match rule.check {
+            Check::Module(check) if check(self) => Some(rule.conflict("module")),
+            _ => None,
+        });
+        let mut commands: Vec<_> = commands.iter().collect();
+        commands.sort_by_key(|(name, _)| *name);
+        own.chain(commands.into_iter().flat_map(|(name, command)| {
+            RULES.iter().filter_map(move |rule| match rule.check {
+                Check::Command(check) if check(self, command) => Some(rule.conflict(name)),
+                _ => None,
+            })
+        }))
+        .collect()
+    }
+
+    /// Conflicts between the module, known as `name`, and the options of a run.
+    pub(crate) fn run_conflicts(&self, name: &str, options: &ExecutionOptions) -> Vec<Conflict> {
+        RULES
+            .iter()
+            .filter_map(|rule| match rule.check {
+                Check::Run(check) if check(self, options) => Some(rule.conflict(name)),
+                _ => None,
+            })
+            .collect()
+    }
+}
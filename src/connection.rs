@@ -0,0 +1,436 @@
+use crate::artifacts::Artifacts;
+use crate::channels::{ChannelGate, OpenChannel};
+use crate::exec::exec_command;
+#[cfg(feature = "testing")]
+use crate::fault::Faults;
+use crate::host_key::{check_host_key_type, prefer_host_key, verify_known_host};
+use crate::resolve::{connect_any, resolve_host};
+use crate::traffic::{count_traffic, TrafficCounter};
+use crate::{
+    AuthType, ChannelStats, CommandResult, ConnectionProps, RemoteDiskFull, StateStore,
+    TargetOs, TrafficStats,
+};
+use anyhow::Error;
+use ssh2::Session;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Authenticated session to a host.
+pub struct HostConnection {
+    host: String,
+    session: Session,
+    /// Results of memoized commands, by run and command line, with the command they came from
+    memo: Mutex<HashMap<String, (String, CommandResult)>>,
+    state: StateStore,
+    traffic: Option<Arc<TrafficCounter>>,
+    /// Injected output limit of every command, see [`crate::FaultInjector::truncate_output`]
+    #[cfg(feature = "testing")]
+    output_limit: Option<u64>,
+    channels: Arc<ChannelGate>,
+    /// Wait for a channel after which it is opened over the overflow connection instead
+    channel_wait: Option<Duration>,
+    /// Second connection to the host, for channels which waited too long on this one
+    overflow: Option<Arc<HostConnection>>,
+    disk_full: Mutex<Option<RemoteDiskFull>>,
+    artifacts: Artifacts,
+    target_os: Mutex<TargetOs>,
+}
+
+fn connect_internal<A>(
+    ip: A,
+    host: &str,
+    auth: AuthType,
+    sync: &dyn ConnectionProps,
+    #[cfg(feature = "testing")] faults: &Faults,
+) -> Result<(Session, Option<Arc<TrafficCounter>>, SocketAddr), Error>
+where
+    A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+{
+    let addresses = resolve_host(&ip, host, sync)?;
+    #[cfg(feature = "testing")]
+    if faults.fail_connect {
+        return Err(Error::msg("Injected failure of the tcp connection"));
+    }
+    let tcp = connect_any(host, &addresses, sync.connect_timeout_for(host))?;
+    let peer = tcp.peer_addr()?;
+    #[cfg(feature = "testing")]
+    let drop_after = faults.drop_after;
+    #[cfg(not(feature = "testing"))]
+    let drop_after = None;
+    // dropping the connection after some bytes needs the relay as well
+    let (tcp, traffic) = if sync.count_traffic_for(host) || drop_after.is_some() {
+        let (tcp, traffic) = count_traffic(tcp, drop_after)
+            .map_err(|e| Error::msg(format!("Setting up the traffic counter failed: {}", e)))?;
+        (tcp, Some(traffic))
+    } else {
+        (tcp, None)
+    };
+    let mut sess =
+        Session::new().map_err(|_e| Error::msg("Error initializing session".to_string()))?;
+    sess.set_tcp_stream(tcp);
+    sess.set_timeout(sync.handshake_timeout_for(host));
+    let key_type = sync.host_key_type_for(host);
+    if let Some(key_type) = key_type {
+        prefer_host_key(&sess, key_type)?;
+    }
+    sync.agent_synchronization_for(host); //todo fixme
+    #[cfg(feature = "testing")]
+    if let Some(delay) = faults.handshake_delay {
+        std::thread::sleep(delay);
+    }
+    if let Err(e) = sess.handshake() {
+        sync.agent_release_for(host);
+        return Err(match key_type {
+            Some(key_type) => Error::msg(format!(
+                "Handshake failed, does the host offer a {} host key? {}",
+                key_type, e
+            )),
+            None => e.into(),
+        });
+    }
+    let verified = key_type
+        .map_or(Ok(()), |key_type| check_host_key_type(&sess, key_type))
+        .and_then(|_| match sync.known_hosts_for(host) {
+            Some(known_hosts) => verify_known_host(&sess, host, peer, &known_hosts),
+            None => Ok(()),
+        });
+    if let Err(e) = verified {
+        sync.agent_release_for(host);
+        return Err(e);
+    }
+    sess.set_timeout(sync.auth_timeout_for(host));
+    #[cfg(feature = "testing")]
+    if faults.fail_auth {
+        sync.agent_release_for(host);
+        return Err(Error::msg("Injected failure of authentication"));
+    }
+    if let Err(e) = auth.auth(&sess) {
+        sync.agent_release_for(host);
+        return Err(e);
+    }
+    sync.agent_release_for(host);
+    sess.set_timeout(sync.read_timeout_for(host));
+    Ok((sess, traffic, peer))
+}
+
+impl HostConnection {
+    /// Connects and authenticates.
+    /// Takes a tcp permit from `sync`, releasing it is up to the caller.
+    pub fn connect<A>(ip: A, auth: AuthType, sync: &dyn ConnectionProps) -> Result<Self, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        HostConnection::establish(ip, auth, sync, #[cfg(feature = "testing")] &Faults::default())
+    }
+
+    /// [`HostConnection::connect`] with `faults` injected.
+    #[cfg(feature = "testing")]
+    pub(crate) fn connect_with_faults<A>(
+        ip: A,
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+        faults: &Faults,
+    ) -> Result<Self, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        let mut connection = HostConnection::establish(ip, auth, sync, faults)?;
+        connection.output_limit = faults.output_limit;
+        Ok(connection)
+    }
+
+    /// [`HostConnection::connect`], with `faults` injected in builds with the `testing` feature.
+    fn establish<A>(
+        ip: A,
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+        #[cfg(feature = "testing")] faults: &Faults,
+    ) -> Result<Self, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        let host = ip.to_string();
+        sync.tcp_synchronization_for(&host);
+        let (session, traffic, peer) =
+            connect_internal(ip, &host, auth.clone(), sync, #[cfg(feature = "testing")] faults)
+                .map_err(|e| e.context(format!("Failed connecting to {}", host)))?;
+        let mut connection = HostConnection::new(&host, session, sync.channel_limit_for(&host));
+        connection.traffic = traffic;
+        connection.channel_wait = sync.channel_wait_for(&host);
+        if connection.channel_wait.is_some() {
+            // one tcp permit at a time, the caller releases the last one as usual
+            sync.tcp_release_for(&host);
+            sync.tcp_synchronization_for(&host);
+            let (session, traffic, _) =
+                connect_internal(peer, &host, auth, sync, #[cfg(feature = "testing")] &Faults::default())
+                    .map_err(|e| e.context(format!("Failed opening a second connection to {}", host)))?;
+            let mut overflow = HostConnection::new(&host, session, sync.channel_limit_for(&host));
+            overflow.traffic = traffic;
+            connection.overflow = Some(Arc::new(overflow));
+        }
+        Ok(connection)
+    }
+
+    fn new(host: &str, session: Session, channel_limit: usize) -> Self {
+        HostConnection {
+            host: host.to_string(),
+            session,
+            memo: Mutex::new(HashMap::new()),
+            state: StateStore::default(),
+            traffic: None,
+            #[cfg(feature = "testing")]
+            output_limit: None,
+            channels: ChannelGate::new(channel_limit),
+            channel_wait: None,
+            overflow: None,
+            disk_full: Mutex::new(None),
+            artifacts: Artifacts::default(),
+            target_os: Mutex::new(TargetOs::Posix),
+        }
+    }
+
+    /// Wraps a session set up by the caller, e.g. with an authentication `AuthType`
+    /// can't express. `label` stands in for the host in results and errors.
+    ///
+    /// The session must be authenticated and in blocking mode, which is checked here.
+    /// Its timeout is kept, unless a module sets its own: with none set, reads from
+    /// a stuck command wait forever. Closing the session stays up to the caller.
+    pub fn from_session(session: Session, label: &str) -> Result<Self, Error> {
+        if !session.authenticated() {
+            return Err(Error::msg(format!("Session for {} isn't authenticated", label)));
+        }
+        if !session.is_blocking() {
+            return Err(Error::msg(format!("Session for {} isn't in blocking mode", label)));
+        }
+        Ok(HostConnection::new(label, session, crate::DEFAULT_CHANNEL_LIMIT))
+    }
+
+    /// Host as it was given to [`HostConnection::connect`], or the label of
+    /// [`HostConnection::from_session`].
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Bytes written to and read from the host so far. `None` unless
+    /// [`crate::ConnectionProps::count_traffic_for`] the host, and for sessions of
+    /// [`HostConnection::from_session`], whose socket the crate never saw.
+    pub fn traffic_stats(&self) -> Option<TrafficStats> {
+        self.traffic.as_ref().map(|traffic| traffic.stats())
+    }
+
+    /// Channels opened on the connection so far. Those of a second connection are
+    /// counted as `overflowed`, see [`crate::ConnectionProps::channel_wait_for`].
+    pub fn channel_stats(&self) -> ChannelStats {
+        self.channels.stats()
+    }
+
+    pub(crate) fn channel_gate(&self) -> Arc<ChannelGate> {
+        self.channels.clone()
+    }
+
+    /// Opens a session channel once fewer than the channel limit are open. One which
+    /// waited longer than the channel wait is opened over the second connection instead,
+    /// which the channel's `session()` is then of, with the timeout of this session.
+    pub(crate) fn open_channel(&self) -> Result<OpenChannel, Error> {
+        self.open_channel_with(&Session::channel_session)
+    }
+
+    /// [`HostConnection::open_channel`], opening it with `open`, e.g. for scp.
+    pub(crate) fn open_channel_with(
+        &self,
+        open: &dyn Fn(&Session) -> Result<ssh2::Channel, ssh2::Error>,
+    ) -> Result<OpenChannel, Error> {
+        let wait = self.overflow.as_ref().and(self.channel_wait);
+        if let Some(channel) = self.channels.open(&self.session, open, wait, true)? {
+            return Ok(channel);
+        }
+        let connection = self.overflow.clone().expect("channels only give up waiting with an overflow");
+        // commands on it run with the timeout their module set on this session
+        connection.session.set_timeout(self.session.timeout());
+        self.channels.overflowed();
+        Ok(connection.open_channel_with(open)?.on_overflow(connection))
+    }
+
+    /// Opens a session channel on this connection's session, waiting for room if
+    /// there is none, or fails with [`crate::ExecError::TooManyChannels`] if the host
+    /// refuses it as one too many. For channels served together, see
+    /// [`HostConnection::has_channel_room`].
+    pub(crate) fn open_own_channel(&self) -> Result<OpenChannel, Error> {
+        let channel = self.channels.open(&self.session, &Session::channel_session, None, false)?;
+        Ok(channel.expect("channels wait until there is room"))
+    }
+
+    /// Whether a channel can be opened without waiting for another to close.
+    pub(crate) fn has_channel_room(&self) -> bool {
+        self.channels.has_room()
+    }
+
+    pub(crate) fn traffic_counter(&self) -> Option<Arc<TrafficCounter>> {
+        self.traffic.clone()
+    }
+
+    #[cfg(feature = "testing")]
+    pub(crate) fn output_limit(&self) -> Option<u64> {
+        self.output_limit
+    }
+
+    /// Why the host has no space left, if an upload or command on the connection ran
+    /// out of it. Modules which upload files are skipped from then on, with a
+    /// [`crate::SkipReason::DiskFull`].
+    pub fn disk_full(&self) -> Option<RemoteDiskFull> {
+        self.disk_full.lock().expect("disk full lock poisoned").clone()
+    }
+
+    pub(crate) fn mark_disk_full(&self, full: RemoteDiskFull) {
+        *self.disk_full.lock().expect("disk full lock poisoned") = Some(full);
+    }
+
+    /// Operating system of the host, POSIX until [`HostConnection::detect_target_os`]
+    /// told otherwise.
+    pub fn target_os(&self) -> TargetOs {
+        *self.target_os.lock().expect("target os lock poisoned")
+    }
+
+    pub(crate) fn set_target_os(&self, os: TargetOs) {
+        *self.target_os.lock().expect("target os lock poisoned") = os;
+    }
+
+    /// Remote files which may still be there, see [`HostConnection::artifacts`].
+    pub(crate) fn ledger(&self) -> &Artifacts {
+        &self.artifacts
+    }
+
+    /// State modules registered on this connection, see [`StateStore`].
+    pub fn state(&self) -> &StateStore {
+        &self.state
+    }
+
+    pub(crate) fn memoized(&self, key: &str) -> Option<(String, CommandResult)> {
+        self.memo.lock().expect("memo lock poisoned").get(key).cloned()
+    }
+
+    pub(crate) fn memoize(&self, key: String, origin: String, result: CommandResult) {
+        self.memo
+            .lock()
+            .expect("memo lock poisoned")
+            .entry(key)
+            .or_insert((origin, result));
+    }
+
+    /// Runs `true`, to check that the session still works.
+    pub(crate) fn probe(&self) -> Result<(), Error> {
+        let mut channel = self.open_channel()?;
+        let (session, exec) = channel.split();
+        exec_command(session, exec, "true")?;
+        channel.wait_close()?;
+        match channel.exit_status()? {
+            0 => Ok(()),
+            status => Err(Error::msg(format!("Probe exited with status {}", status))),
+        }
+    }
+}
+
+pub(crate) type SharedConnection = Arc<Mutex<HostConnection>>;
+
+struct CachedConnection {
+    connection: SharedConnection,
+    last_used: Instant,
+}
+
+/// Connections kept open between runs, keyed by host.
+/// Eviction is lazy: expired entries are dropped whenever the cache is accessed.
+#[derive(Default)]
+pub(crate) struct ConnectionCache {
+    entries: HashMap<String, CachedConnection>,
+    /// Idle connections older than this are closed
+    pub(crate) idle_ttl: Option<Duration>,
+    /// Least recently used connections are closed above this count
+    pub(crate) max_connections: Option<usize>,
+}
+
+impl ConnectionCache {
+    fn evict_expired(&mut self) {
+        if let Some(ttl) = self.idle_ttl {
+            self.entries.retain(|_, entry| entry.last_used.elapsed() < ttl);
+        }
+    }
+
+    pub(crate) fn get(&mut self, host: &str) -> Option<SharedConnection> {
+        self.evict_expired();
+        self.entries.get_mut(host).map(|entry| {
+            entry.last_used = Instant::now();
+            entry.connection.clone()
+        })
+    }
+
+    pub(crate) fn insert(&mut self, host: String, connection: SharedConnection) {
+        self.evict_expired();
+        self.entries.insert(
+            host,
+            CachedConnection {
+                connection,
+                last_used: Instant::now(),
+            },
+        );
+        let max = match self.max_connections {
+            Some(max) => max,
+            None => return,
+        };
+        while self.entries.len() > max {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(host, _)| host.clone())
+                .expect("cache is not empty");
+            self.entries.remove(&oldest);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, host: &str) {
+        self.entries.remove(host);
+    }
+
+    /// Probes every cached connection which isn't in use and evicts those which fail.
+    /// The cache is only locked to list and evict connections, not while probing.
+    pub(crate) fn probe_idle(cache: &Mutex<ConnectionCache>) {
+        let entries: Vec<_> = {
+            let mut cache = cache.lock().expect("connections lock poisoned");
+            cache.evict_expired();
+            cache
+                .entries
+                .iter()
+                .map(|(host, entry)| (host.clone(), entry.connection.clone()))
+                .collect()
+        };
+        for (host, connection) in entries {
+            let alive = match connection.try_lock() {
+                Ok(connection) => connection.probe().is_ok(),
+                // busy connections are in use, so not idle
+                Err(_) => continue,
+            };
+            if !alive {
+                let mut cache = cache.lock().expect("connections lock poisoned");
+                // unless it was replaced meanwhile
+                if cache
+                    .entries
+                    .get(&host)
+                    .is_some_and(|entry| Arc::ptr_eq(&entry.connection, &connection))
+                {
+                    cache.entries.remove(&host);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,78 @@
+use crate::{AuthType, ConnectionProps, HostConnection, Runner};
+use anyhow::Error;
+use ssh2::HashType;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::net::ToSocketAddrs;
+use std::thread;
+
+impl Runner {
+    /// What tells the machine behind `connection` apart from others: its
+    /// `/etc/machine-id`, or the fingerprint of its host key if it has none.
+    pub(crate) fn host_identity(&self, connection: &HostConnection) -> Result<String, Error> {
+        let (machine_id, _, _) = self.run_command(connection, "cat /etc/machine-id 2>/dev/null")?;
+        let machine_id = machine_id.trim();
+        if !machine_id.is_empty() {
+            return Ok(format!("machine-id {}", machine_id));
+        }
+        let hash = connection
+            .session()
+            .host_key_hash(HashType::Sha256)
+            .ok_or_else(|| Error::msg("Session has no host key"))?;
+        let hex: String = hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+        Ok(format!("host key {}", hex))
+    }
+
+    /// Hosts which are the same machine as a host before them in `hosts`, with that
+    /// host, by `to_string()`. Empty unless [`Runner::with_host_dedup`] is set.
+    /// Hosts which can't be identified, e.g. as they are unreachable, are never duplicates.
+    pub(crate) fn duplicate_hosts<A>(
+        &self,
+        hosts: &[A],
+        auth: &AuthType,
+        sync: &(dyn ConnectionProps + Sync),
+    ) -> HashMap<String, String>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        if !self.options().dedup_hosts() {
+            return HashMap::new();
+        }
+        let identities: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = hosts
+                .iter()
+                .map(|host| {
+                    scope.spawn(move || {
+                        self.with_connection(host.clone(), auth.clone(), sync, |connection| {
+                            self.host_identity(connection)
+                        })
+                        .ok()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("host thread panicked"))
+                .collect()
+        });
+        let mut canonical: HashMap<String, String> = HashMap::new();
+        let mut duplicates = HashMap::new();
+        for (host, identity) in hosts.iter().zip(identities) {
+            let (host, identity) = match identity {
+                Some(identity) => (host.to_string(), identity),
+                None => continue,
+            };
+            match canonical.get(&identity) {
+                Some(first) if *first != host => {
+                    eprintln!("warning: {} is the same host as {}, skipping it", host, first);
+                    duplicates.insert(host, first.clone());
+                }
+                Some(_) => {}
+                None => {
+                    canonical.insert(identity, host);
+                }
+            }
+        }
+        duplicates
+    }
+}
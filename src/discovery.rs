@@ -0,0 +1,526 @@
+//! Loading modules from `.mod` files, enabled by the `discovery` feature.
+use crate::checksum::{sha256_file, sha256_hex, Checksums};
+use crate::modules::ModuleContent;
+use crate::{parse_duration, parse_size, MaintenanceWindow, Module, ModuleTree, RetryHint, ShellCommand};
+use anyhow::Error;
+use base64::encode;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use toml::from_str;
+use walkdir::{DirEntry, WalkDir};
+
+/// Files of a module larger than this aren't read, see [`LoadError::TooLarge`].
+const MAX_FILE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// File of a module which isn't read at all, as it could block loading or exhaust memory.
+/// Errors carry it, find it with `downcast_ref::<LoadError>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// A FIFO, device, socket or directory, which may block forever or never end.
+    NotRegularFile { path: PathBuf, kind: &'static str },
+    /// A regular file of more than `limit` bytes.
+    TooLarge { path: PathBuf, limit: u64 },
+    /// A module `name` declared by a flat `.mod` file and by a role, neither is loaded.
+    NameCollision { name: String, paths: Vec<PathBuf> },
+    /// A file of a verified tree which its manifest doesn't list.
+    Unlisted { path: PathBuf },
+    /// A file of a verified tree whose SHA-256 isn't the one in its manifest.
+    ChecksumMismatch { path: PathBuf, expected: String, actual: String },
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::NotRegularFile { path, kind } => {
+                write!(f, "{} is a {}, not a regular file", path.display(), kind)
+            }
+            LoadError::TooLarge { path, limit } => {
+                write!(f, "{} is larger than {} bytes", path.display(), limit)
+            }
+            LoadError::NameCollision { name, paths } => {
+                let paths: Vec<_> = paths.iter().map(|path| path.display().to_string()).collect();
+                write!(f, "{} is declared by {}", name, paths.join(" and "))
+            }
+            LoadError::Unlisted { path } => write!(f, "{} isn't listed in the manifest", path.display()),
+            LoadError::ChecksumMismatch { path, expected, actual } => write!(
+                f,
+                "{} has SHA-256 {}, the manifest lists {}",
+                path.display(),
+                actual,
+                expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// What kind of special file `file_type` is.
+fn special_kind(file_type: fs::FileType) -> &'static str {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            return "named pipe";
+        }
+        if file_type.is_char_device() || file_type.is_block_device() {
+            return "device";
+        }
+        if file_type.is_socket() {
+            return "socket";
+        }
+    }
+    if file_type.is_dir() {
+        "directory"
+    } else {
+        "special file"
+    }
+}
+
+/// Mode of an uploaded file, given in octal like `0755`.
+fn parse_file_mode(mode: &str) -> Result<i32, Error> {
+    let valid = (3..=4).contains(&mode.len()) && mode.chars().all(|c| ('0'..='7').contains(&c));
+    match i32::from_str_radix(mode, 8) {
+        Ok(parsed) if valid => Ok(parsed),
+        _ => Err(Error::msg(format!("Invalid file_mode: {}", mode))),
+    }
+}
+
+/// Reads a file of a module, refusing anything but regular files of at most
+/// [`MAX_FILE_SIZE`] bytes, before opening it. Symlinks are followed.
+fn read_module_file(path: &Path) -> Result<String, Error> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_file() {
+        return Err(LoadError::NotRegularFile {
+            path: path.to_path_buf(),
+            kind: special_kind(metadata.file_type()),
+        }
+        .into());
+    }
+    let mut content = String::new();
+    // the file may have grown since
+    if metadata.len() <= MAX_FILE_SIZE {
+        File::open(path)?
+            .take(MAX_FILE_SIZE + 1)
+            .read_to_string(&mut content)?;
+    }
+    if metadata.len() > MAX_FILE_SIZE || content.len() as u64 > MAX_FILE_SIZE {
+        return Err(LoadError::TooLarge {
+            path: path.to_path_buf(),
+            limit: MAX_FILE_SIZE,
+        }
+        .into());
+    }
+    Ok(content)
+}
+
+/// `module_type` of a `.mod` file, which only decides how its `exec_path` is loaded.
+enum ExecType {
+    Bin,
+    Python,
+    Bash,
+}
+
+impl<'de> Deserialize<'de> for ExecType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, <D as Deserializer<'de>>::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        match s.to_lowercase().as_str() {
+            "bin" => Ok(ExecType::Bin),
+            "bash" | "sh" => Ok(ExecType::Bash),
+            "py" | "python" => Ok(ExecType::Python),
+            _ => Err(serde::de::Error::custom(format!("Bad module type provided: {}", s))),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ModuleProps {
+    module_type: Option<ExecType>,
+    exec_path: Option<PathBuf>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    max_output: Option<String>,
+    timeout: Option<String>,
+    #[serde(default)]
+    bundle: bool,
+    #[serde(default)]
+    fail_fast: bool,
+    window: Option<MaintenanceWindow>,
+    retry_hint: Option<RetryHint>,
+    umask: Option<String>,
+    locale: Option<String>,
+    parser: Option<String>,
+    remote_dir: Option<String>,
+    file_mode: Option<String>,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    precompile_check: bool,
+    #[serde(default)]
+    depends_on: Vec<String>,
+    #[serde(default)]
+    normalize: Vec<String>,
+    #[serde(default)]
+    read_only: bool,
+    #[serde(default)]
+    lint_ignore: Vec<String>,
+    #[serde(default)]
+    strict_lints: bool,
+    #[serde(default)]
+    deprecated: bool,
+    replaced_by: Option<String>,
+}
+
+/// Directory the relative paths of a module resolve against. Within a role,
+/// bare file names which aren't in the role directory are looked up in its
+/// `files/` and then its `templates/`.
+struct Root<'a> {
+    dir: &'a Path,
+    role: bool,
+    /// Manifest every file of the module must be listed in, when verifying
+    checksums: Option<&'a Checksums>,
+}
+
+impl Root<'_> {
+    /// Checks `path` against the manifest, if any, with its content if it was read.
+    /// Returns the digest the manifest lists, for checking a file which is only read
+    /// when the module runs once more, against what is sent of it then.
+    fn verify(&self, path: &Path, content: Option<&[u8]>) -> Result<Option<String>, Error> {
+        let checksums = match self.checksums {
+            Some(checksums) => checksums,
+            None => return Ok(None),
+        };
+        let expected = checksums
+            .expected(path)
+            .ok_or_else(|| LoadError::Unlisted { path: path.to_path_buf() })?;
+        let actual = match content {
+            Some(content) => sha256_hex(content),
+            None => {
+                let metadata = fs::metadata(path)?;
+                if !metadata.is_file() {
+                    return Err(LoadError::NotRegularFile {
+                        path: path.to_path_buf(),
+                        kind: special_kind(metadata.file_type()),
+                    }
+                    .into());
+                }
+                sha256_file(path)?
+            }
+        };
+        if actual != expected {
+            return Err(LoadError::ChecksumMismatch {
+                path: path.to_path_buf(),
+                expected: expected.to_string(),
+                actual,
+            }
+            .into());
+        }
+        Ok(Some(actual))
+    }
+
+    /// [`read_module_file`], verified against the manifest.
+    fn read(&self, path: &Path) -> Result<String, Error> {
+        let content = read_module_file(path)?;
+        self.verify(path, Some(content.as_bytes()))?;
+        Ok(content)
+    }
+
+    fn join(&self, path: &Path) -> PathBuf {
+        let joined = self.dir.join(path);
+        if !self.role || path.components().count() != 1 || joined.exists() {
+            return joined;
+        }
+        ["files", "templates"]
+            .iter()
+            .map(|dir| self.dir.join(dir).join(path))
+            .find(|path| path.exists())
+            .unwrap_or(joined)
+    }
+}
+
+/// What a `.mod` file declares: a module, or, with nothing but a `replaced_by`,
+/// the name of the module replacing a removed one.
+enum Loaded {
+    Module(Box<Module>),
+    Replaced(String),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ShellCommandSpec {
+    Plain(String),
+    Detailed(Box<ShellCommand>),
+}
+
+impl From<ShellCommandSpec> for ShellCommand {
+    fn from(spec: ShellCommandSpec) -> Self {
+        match spec {
+            ShellCommandSpec::Plain(cmd) => ShellCommand::new(&cmd),
+            ShellCommandSpec::Detailed(command) => *command,
+        }
+    }
+}
+
+impl ModuleProps {
+    fn check_filename(filename: &DirEntry) -> bool {
+        let ext = match filename.path().extension() {
+            Some(a) => a.to_string_lossy().to_lowercase(),
+            None => return false,
+        };
+        ext == "mod"
+    }
+}
+
+/// Compiles a python module locally with `python2`, the interpreter it runs with remotely,
+/// so syntax errors show up before the module is sent anywhere.
+/// Does nothing if there is no local `python2`.
+fn precompile(path: &Path) -> Result<(), Error> {
+    let output = Command::new("python2")
+        .arg("-c")
+        .arg("import sys; compile(open(sys.argv[1]).read(), sys.argv[1], 'exec')")
+        .arg(path)
+        .output();
+    let output = match output {
+        Ok(output) => output,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    // 127 is the shell's "command not found", e.g. from a pyenv shim
+    if output.status.success() || output.status.code() == Some(127) {
+        return Ok(());
+    }
+    Err(Error::msg(format!(
+        "Python module {} doesn't compile:\n{}",
+        path.display(),
+        String::from_utf8_lossy(&output.stderr).trim_end()
+    )))
+}
+
+impl Module {
+    pub fn new(path: &Path, root: &Path) -> Result<Module, Error> {
+        match Module::load(path, &Root { dir: root, role: false, checksums: None })? {
+            Loaded::Module(module) => Ok(*module),
+            Loaded::Replaced(new) => Err(Error::msg(format!(
+                "{} only points to its replacement {}",
+                path.display(),
+                new
+            ))),
+        }
+    }
+
+    fn load(path: &Path, root: &Root) -> Result<Loaded, Error> {
+        let res: ModuleProps = from_str(&root.read(path)?)?;
+        let (module_type, exec_path, replaced_by) = match (res.module_type, res.exec_path, res.replaced_by) {
+            (Some(module_type), Some(exec_path), replaced_by) => {
+                (module_type, root.join(&exec_path), replaced_by)
+            }
+            (None, None, Some(new)) => return Ok(Loaded::Replaced(new)),
+            (None, _, _) => return Err(Error::msg("Module has no module_type")),
+            (_, None, _) => return Err(Error::msg("Module has no exec_path")),
+        };
+        let max_output = res.max_output.as_deref().map(parse_size).transpose()?;
+        let timeout = res.timeout.as_deref().map(parse_duration).transpose()?;
+        let content = match module_type {
+            ExecType::Bin => {
+                let sha256 = root.verify(&exec_path, None)?;
+                ModuleContent::Binary(exec_path, sha256)
+            }
+            ExecType::Python => {
+                let content = root.read(&exec_path)?;
+                if res.precompile_check {
+                    precompile(&exec_path)?;
+                }
+                let com64 = encode(content);
+                let script = format!("python2 -c \" exec('{}'.decode('base64'))\"", com64);
+                ModuleContent::Python(script)
+            }
+            ExecType::Bash => {
+                let unparsed = root.read(&exec_path)?;
+                let table: HashMap<String, ShellCommandSpec> = from_str(&unparsed)?;
+                let mut table = table
+                    .into_iter()
+                    .map(|(name, spec)| {
+                        let mut command = ShellCommand::from(spec);
+                        command.resolve_wait_for();
+                        command.stdin_file = command.stdin_file.map(|file| root.join(&file));
+                        if command.local {
+                            let dir = command.local_dir.map(|dir| root.dir.join(dir));
+                            command.local_dir = Some(dir.unwrap_or_else(|| root.dir.into()));
+                        }
+                        (name, command)
+                    })
+                    .collect::<HashMap<_, _>>();
+                for command in table.values_mut() {
+                    if let Some(file) = &command.stdin_file {
+                        command.stdin_sha256 = root.verify(file, None)?;
+                    }
+                }
+                ModuleContent::Shell(table)
+            }
+        };
+        let module = Module {
+            module_content: Arc::new(content),
+            env: res.env,
+            max_output,
+            timeout,
+            bundle: res.bundle,
+            umask: res.umask,
+            remote_dir: res.remote_dir,
+            file_mode: res.file_mode.as_deref().map(parse_file_mode).transpose()?,
+            args: res.args,
+            fail_fast: res.fail_fast,
+            window: res.window,
+            retry_hint: res.retry_hint,
+            locale: res.locale,
+            parser: res.parser,
+            depends_on: res.depends_on,
+            normalize: res
+                .normalize
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<_, _>>()?,
+            read_only: res.read_only,
+            lint_ignore: res.lint_ignore,
+            strict_lints: res.strict_lints,
+            deprecated: res.deprecated,
+            replaced_by,
+        };
+        module.check()?;
+        Ok(Loaded::Module(Box::new(module)))
+    }
+}
+
+/// Loads the `.mod` files directly in `path`, by their file names.
+fn discover_flat(path: &Path, checksums: Option<&Checksums>) -> Vec<(String, PathBuf, Result<Loaded, Error>)> {
+    let root = Root { dir: path, role: false, checksums };
+    WalkDir::new(path)
+        .max_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok()) //filter erros
+        .filter(ModuleProps::check_filename) //leave only mods
+        .map(|entry| {
+            let name = entry
+                .path()
+                .file_name()
+                .expect("Failed getting filename for module, which is strange")
+                .to_string_lossy()
+                .to_string();
+            let loaded = Module::load(entry.path(), &root);
+            (name, entry.into_path(), loaded)
+        })
+        .collect()
+}
+
+/// Loads the `module.mod` of every directory in `path/roles`, named like the file
+/// of a flat module would be, `<role>.mod`.
+fn discover_roles(path: &Path, checksums: Option<&Checksums>) -> Vec<(String, PathBuf, Result<Loaded, Error>)> {
+    WalkDir::new(path.join("roles"))
+        .min_depth(1)
+        .max_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|entry| entry.file_type().is_dir())
+        .map(|entry| {
+            let name = format!("{}.mod", entry.file_name().to_string_lossy());
+            let file = entry.path().join("module.mod");
+            let root = Root { dir: entry.path(), role: true, checksums };
+            let loaded = Module::load(&file, &root);
+            (name, file, loaded)
+        })
+        .collect()
+}
+
+impl ModuleTree {
+    pub fn new(path: &Path) -> Self {
+        ModuleTree::load(discover_flat(path, None))
+    }
+
+    /// [`ModuleTree::new`], with the roles in `path/roles` besides the flat modules.
+    /// A role is a directory `roles/<name>/` with a `module.mod`, run as `<name>.mod`.
+    /// Its relative paths resolve against the role directory, and bare file names
+    /// also against its `files/` and `templates/`:
+    /// ```text
+    /// roles/nginx/module.mod      exec_path = "commands.toml"
+    /// roles/nginx/commands.toml   [install] stdin_file = "nginx.conf"
+    /// roles/nginx/files/nginx.conf
+    /// ```
+    /// A role named like a flat module is a [`LoadError::NameCollision`], neither is loaded.
+    pub fn new_with_roles(path: &Path) -> Self {
+        let mut found = discover_flat(path, None);
+        found.extend(discover_roles(path, None));
+        ModuleTree::load(found)
+    }
+
+    /// [`ModuleTree::new_with_roles`], loading only files listed with their SHA-256 in
+    /// `manifest`, a `MANIFEST.sha256` as `sha256sum` writes it, with paths relative to
+    /// its directory. That is every `.mod` file, and the `exec_path` and `stdin_file`s
+    /// of each module. A module with a file which isn't listed, or doesn't match, isn't
+    /// loaded, its [`LoadError::Unlisted`] or [`LoadError::ChecksumMismatch`] is in
+    /// [`ModuleTree::load_errors`]. The digest of the manifest is the tree's
+    /// [`ModuleTree::manifest_digest`], recorded in the [`crate::drift::Manifest`]s of its runs.
+    ///
+    /// Binaries and `stdin_file`s are read again when their module runs. What is sent of
+    /// them then is checked against the manifest as well: a binary which changed since is
+    /// removed from the host instead of run, a `stdin_file` is read into memory and checked
+    /// before any of it is written. Either fails the module with a [`LoadError::ChecksumMismatch`].
+    pub fn new_verified(path: &Path, manifest: &Path) -> Result<Self, Error> {
+        let checksums = Checksums::load(manifest)?;
+        let mut found = discover_flat(path, Some(&checksums));
+        found.extend(discover_roles(path, Some(&checksums)));
+        Ok(ModuleTree::load(found).with_manifest_digest(checksums.digest()))
+    }
+
+    fn load(found: Vec<(String, PathBuf, Result<Loaded, Error>)>) -> Self {
+        let mut paths: HashMap<&str, Vec<PathBuf>> = HashMap::new();
+        for (name, path, _) in &found {
+            paths.entry(name.as_str()).or_default().push(path.clone());
+        }
+        // taken by the first of the colliding modules, which reports it
+        let mut collisions: HashMap<String, Option<Vec<PathBuf>>> = paths
+            .into_iter()
+            .filter(|(_, paths)| paths.len() > 1)
+            .map(|(name, paths)| (name.to_string(), Some(paths)))
+            .collect();
+        let mut modules = HashMap::new();
+        let mut replaced = HashMap::new();
+        let mut errors = Vec::new();
+        for (name, _, loaded) in found {
+            let loaded = match collisions.get_mut(&name).map(Option::take) {
+                Some(Some(paths)) => Err(LoadError::NameCollision {
+                    name: name.clone(),
+                    paths,
+                }
+                .into()),
+                Some(None) => continue,
+                None => loaded,
+            };
+            match loaded {
+                Ok(Loaded::Module(module)) => {
+                    modules.insert(name, *module);
+                }
+                Ok(Loaded::Replaced(new)) => {
+                    replaced.insert(name, new);
+                }
+                Err(e) => {
+                    eprintln!("Error parsing module {}: {}", name, e);
+                    errors.push((name, e));
+                }
+            }
+        }
+
+        replaced
+            .iter()
+            .fold(ModuleTree::from_modules(modules), |tree, (old, new)| tree.with_replaced(old, new))
+            .with_load_errors(errors)
+    }
+}
@@ -0,0 +1,177 @@
+use crate::exec::exec_command;
+use crate::shell::{shell_format, ShellSafe};
+use crate::{CommandOutput, HostConnection};
+use anyhow::Error;
+use regex::Regex;
+use std::fmt::{self, Display};
+use std::io::Read;
+
+/// What commands say when a write fails with ENOSPC.
+const NO_SPACE: &str = "No space left on device";
+
+/// Host whose disk filled up while the crate uploaded `path`, or a command wrote it.
+/// `filesystem` and `available` are from a `df` run after the failure, `None` if that
+/// failed too. The connection remembers it, see [`HostConnection::disk_full`].
+/// Errors carry it, find it with `downcast_ref::<RemoteDiskFull>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteDiskFull {
+    pub host: String,
+    pub path: String,
+    pub filesystem: Option<String>,
+    pub available: Option<u64>,
+}
+
+impl Display for RemoteDiskFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No space left on {} for {}", self.host, self.path)?;
+        if let (Some(filesystem), Some(available)) = (&self.filesystem, self.available) {
+            write!(f, ", {} has {} bytes free", filesystem, available)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RemoteDiskFull {}
+
+/// Upload of `needed` bytes to `path` refused before sending anything, as the filesystem
+/// it goes to has only `available` bytes free, see [`HostConnection::check_free_space`].
+/// Errors carry it, find it with `downcast_ref::<InsufficientDisk>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsufficientDisk {
+    pub host: String,
+    pub path: String,
+    pub filesystem: String,
+    pub available: u64,
+    pub needed: u64,
+}
+
+impl Display for InsufficientDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Insufficient disk on {} for {}: {} bytes needed, {} has {} bytes free",
+            self.host, self.path, self.needed, self.filesystem, self.available
+        )
+    }
+}
+
+impl std::error::Error for InsufficientDisk {}
+
+/// Upload of `path` into `dir` which failed, told apart from a full disk by [`classify`]
+/// where the connection is at hand.
+#[derive(Debug)]
+pub(crate) struct UploadFailed {
+    pub(crate) dir: String,
+    pub(crate) path: String,
+    pub(crate) message: String,
+}
+
+impl Display for UploadFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for UploadFailed {}
+
+/// Filesystem `dir` is on, as `<device> on <mount point>`, and its free bytes,
+/// from `df -P`. `None` if there is no channel to spare or `df` failed.
+fn df(connection: &HostConnection, dir: &str) -> Option<(String, u64)> {
+    if !connection.has_channel_room() {
+        return None;
+    }
+    let mut channel = connection.open_channel().ok()?;
+    let (session, exec) = channel.split();
+    exec_command(session, exec, shell_format!("df -P -k {}", ShellSafe::path(dir)).as_str()).ok()?;
+    let mut output = String::new();
+    channel.read_to_string(&mut output).ok()?;
+    channel.wait_close().ok()?;
+    let fields: Vec<_> = output.lines().nth(1)?.split_whitespace().collect();
+    match fields.as_slice() {
+        [device, _, _, available, _, mount @ ..] if !mount.is_empty() => {
+            Some((format!("{} on {}", device, mount.join(" ")), available.parse::<u64>().ok()? * 1024))
+        }
+        _ => None,
+    }
+}
+
+/// Marks the host of `connection` as full, writing `path` in `dir`.
+fn mark(connection: &HostConnection, dir: &str, path: &str, df: Option<(String, u64)>) -> RemoteDiskFull {
+    let df = df.or_else(|| self::df(connection, dir));
+    let full = RemoteDiskFull {
+        host: connection.host().to_string(),
+        path: path.to_string(),
+        filesystem: df.as_ref().map(|(filesystem, _)| filesystem.clone()),
+        available: df.map(|(_, available)| available),
+    };
+    connection.mark_disk_full(full.clone());
+    full
+}
+
+/// `e` of an upload of `path` into `dir`, as a [`RemoteDiskFull`] if the host said so,
+/// or `df` finds no space left, marking the host. As sftp status codes don't get through
+/// libssh2, the disk is checked after any failed upload.
+pub(crate) fn upload_failed(connection: &HostConnection, dir: &str, path: &str, e: Error) -> Error {
+    let says_full = e.chain().any(|cause| cause.to_string().contains(NO_SPACE));
+    let df = df(connection, dir);
+    if says_full || df.as_ref().is_some_and(|(_, available)| *available == 0) {
+        let full = mark(connection, dir, path, df);
+        return Error::new(full).context(format!("{:#}", e));
+    }
+    e
+}
+
+/// `e`, or if it is an [`UploadFailed`], [`upload_failed`].
+pub(crate) fn classify(connection: &HostConnection, e: Error) -> Error {
+    match e.downcast_ref::<UploadFailed>() {
+        Some(failed) => {
+            let (dir, path) = (failed.dir.clone(), failed.path.clone());
+            upload_failed(connection, &dir, &path, e)
+        }
+        None => e,
+    }
+}
+
+/// Fails the commands of `output` which exited non-zero as their host ran out of space
+/// with a [`RemoteDiskFull`] failure, instead of their exit status, marking the host.
+pub(crate) fn check_output(connection: &HostConnection, output: &mut CommandOutput) {
+    let written = Regex::new(r"([^\s:'`]+): No space left on device").expect("valid regex");
+    let results: Vec<_> = match output {
+        CommandOutput::Single(result) => vec![&mut **result],
+        CommandOutput::Multi(results) => results.values_mut().collect(),
+    };
+    for result in results {
+        let failed = result.is_failed() || result.exit_status.is_some_and(|status| status != 0);
+        let stderr = match &result.stderr {
+            Some(stderr) if failed && stderr.contains(NO_SPACE) => stderr,
+            _ => continue,
+        };
+        let path = written.captures(stderr).map(|captures| captures[1].to_string());
+        let dir = match path.as_deref().and_then(|path| path.rsplit_once('/')) {
+            Some(("", _)) => "/".to_string(),
+            Some((dir, _)) => dir.to_string(),
+            None => ".".to_string(),
+        };
+        let full = mark(connection, &dir, path.as_deref().unwrap_or("command output"), None);
+        result.failure = Some(full.to_string());
+    }
+}
+
+impl HostConnection {
+    /// Fails with an [`InsufficientDisk`] if the filesystem of `dir` has less than `needed`
+    /// bytes free, before uploading `path` there. It costs a round-trip for `df`, if that
+    /// can't run or its output can't be read the upload is let through.
+    pub fn check_free_space(&self, dir: &str, path: &str, needed: u64) -> Result<(), Error> {
+        match df(self, dir) {
+            Some((filesystem, available)) if available < needed => Err(InsufficientDisk {
+                host: self.host().to_string(),
+                path: path.to_string(),
+                filesystem,
+                available,
+                needed,
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Drift between the modules which ran on hosts and the current module tree.
+use crate::schema::{deserialize_schema_version, unversioned};
+use crate::{CommandOutput, ModuleTree, SCHEMA_VERSION};
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+/// Modules which succeeded on each host, with the [`crate::Module::fingerprint`]
+/// of the module as it ran. Serialize it to keep it between runs, manifests of a newer
+/// [`crate::SCHEMA_VERSION`] don't deserialize. `tree_digest` is the
+/// [`ModuleTree::manifest_digest`] of the tree the last run was recorded from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(default = "unversioned", deserialize_with = "deserialize_schema_version")]
+    pub schema_version: u32,
+    pub hosts: BTreeMap<String, BTreeMap<String, String>>,
+    #[serde(default)]
+    pub tree_digest: Option<String>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            schema_version: SCHEMA_VERSION,
+            hosts: BTreeMap::new(),
+            tree_digest: None,
+        }
+    }
+}
+
+impl Manifest {
+    /// Records a run of a module from `tree` on `host`. Failed runs aren't recorded,
+    /// the module keeps whatever fingerprint it succeeded with before.
+    pub fn record<H: ToString>(
+        &mut self,
+        tree: &ModuleTree,
+        host: &H,
+        module_name: &str,
+        output: &CommandOutput,
+    ) -> Result<(), Error> {
+        let fingerprint = tree.get_module(module_name)?.fingerprint();
+        self.tree_digest = tree.manifest_digest().map(str::to_string);
+        if !output.is_failed() {
+            self.hosts
+                .entry(host.to_string())
+                .or_default()
+                .insert(module_name.to_string(), fingerprint);
+        }
+        Ok(())
+    }
+}
+
+/// How a host differs from the tree, module names are sorted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct HostDrift {
+    /// In the tree, but never succeeded on the host.
+    pub missing: Vec<String>,
+    /// Succeeded on the host, but no longer in the tree.
+    pub extra: Vec<String>,
+    /// Succeeded on the host, but changed in the tree since.
+    pub changed: Vec<String>,
+}
+
+impl HostDrift {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Hosts of the inventory which drifted, see [`compare`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DriftReport {
+    pub schema_version: u32,
+    pub hosts: BTreeMap<String, HostDrift>,
+}
+
+impl Default for DriftReport {
+    fn default() -> Self {
+        DriftReport {
+            schema_version: SCHEMA_VERSION,
+            hosts: BTreeMap::new(),
+        }
+    }
+}
+
+impl DriftReport {
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+}
+
+impl Display for DriftReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (host, drift) in &self.hosts {
+            writeln!(f, "{}:", host)?;
+            for (label, modules) in [
+                ("missing", &drift.missing),
+                ("extra", &drift.extra),
+                ("changed", &drift.changed),
+            ] {
+                if !modules.is_empty() {
+                    writeln!(f, "  {}: {}", label, modules.join(", "))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compares every host of `inventory` against `tree` and the runs recorded in `baseline`.
+/// Hosts which aren't in the inventory are left out, hosts without drift too.
+pub fn compare<H: ToString>(inventory: &[H], tree: &ModuleTree, baseline: &Manifest) -> DriftReport {
+    let empty = BTreeMap::new();
+    let hosts = inventory
+        .iter()
+        .map(ToString::to_string)
+        .filter_map(|host| {
+            let ran = baseline.hosts.get(&host).unwrap_or(&empty);
+            let mut drift = HostDrift::default();
+            for name in tree.module_names() {
+                match ran.get(name) {
+                    None => drift.missing.push(name.to_string()),
+                    Some(fingerprint) => {
+                        let module = tree.get_module(name).expect("name from the tree");
+                        if *fingerprint != module.fingerprint() {
+                            drift.changed.push(name.to_string());
+                        }
+                    }
+                }
+            }
+            drift.extra = ran
+                .keys()
+                .filter(|name| !tree.check_module(name))
+                .cloned()
+                .collect();
+            Some((host, drift)).filter(|(_, drift)| !drift.is_empty())
+        })
+        .collect();
+    DriftReport {
+        schema_version: SCHEMA_VERSION,
+        hosts,
+    }
+}
@@ -0,0 +1,229 @@
+//! Rendering errors for people: the chain of causes, what they happened on, and a hint.
+#[cfg(feature = "discovery")]
+use crate::LoadError;
+use crate::{
+    AuthRejected, Cancelled, Conflict, DnsError, ExecError, HostKeyMismatch, InsufficientDisk, InteractivePromptDetected, RemoteDiskFull, RunFailed, SkipReason,
+};
+use anyhow::Error;
+
+/// What went wrong, from the first error of the chain the crate knows,
+/// which decides the hint of [`render`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    AuthRejected,
+    HostKeyMismatch,
+    DnsTimeout,
+    DnsFailed,
+    ChannelRejected,
+    TooManyChannels,
+    ExecRejected,
+    InteractivePrompt,
+    RemoteDiskFull,
+    InsufficientDisk,
+    Conflict,
+    Skipped,
+    Cancelled,
+    LoadFailed,
+    RunFailed,
+    Other,
+}
+
+impl ErrorKind {
+    pub fn of(error: &Error) -> Self {
+        error
+            .chain()
+            .find_map(|cause| {
+                if cause.is::<AuthRejected>() {
+                    return Some(ErrorKind::AuthRejected);
+                }
+                if cause.is::<HostKeyMismatch>() {
+                    return Some(ErrorKind::HostKeyMismatch);
+                }
+                #[cfg(feature = "discovery")]
+                if cause.is::<LoadError>() {
+                    return Some(ErrorKind::LoadFailed);
+                }
+                if let Some(e) = cause.downcast_ref::<DnsError>() {
+                    return Some(match e {
+                        DnsError::Timeout { .. } => ErrorKind::DnsTimeout,
+                        DnsError::Failed { .. } => ErrorKind::DnsFailed,
+                    });
+                }
+                if let Some(e) = cause.downcast_ref::<ExecError>() {
+                    return Some(match e {
+                        ExecError::ChannelRejected { .. } => ErrorKind::ChannelRejected,
+                        ExecError::TooManyChannels { .. } => ErrorKind::TooManyChannels,
+                        ExecError::ExecRejected { .. } => ErrorKind::ExecRejected,
+                    });
+                }
+                if cause.is::<InteractivePromptDetected>() {
+                    return Some(ErrorKind::InteractivePrompt);
+                }
+                if cause.is::<RemoteDiskFull>() {
+                    return Some(ErrorKind::RemoteDiskFull);
+                }
+                if cause.is::<InsufficientDisk>() {
+                    return Some(ErrorKind::InsufficientDisk);
+                }
+                if cause.is::<Conflict>() {
+                    return Some(ErrorKind::Conflict);
+                }
+                if cause.is::<Cancelled>() {
+                    return Some(ErrorKind::Cancelled);
+                }
+                if cause.is::<SkipReason>() {
+                    return Some(ErrorKind::Skipped);
+                }
+                if cause.is::<RunFailed>() {
+                    return Some(ErrorKind::RunFailed);
+                }
+                None
+            })
+            .unwrap_or(ErrorKind::Other)
+    }
+
+    /// What to try about errors of the kind, if there is anything to suggest.
+    pub fn hint(self) -> Option<&'static str> {
+        Some(match self {
+            ErrorKind::AuthRejected => "check that your agent has the right key: ssh-add -l",
+            ErrorKind::HostKeyMismatch => {
+                "the host key changed since it was recorded, if the host was reinstalled \
+                 remove the old key with ssh-keygen -R <host>, otherwise don't connect"
+            }
+            ErrorKind::DnsTimeout => "the resolver didn't answer in time, check /etc/resolv.conf or raise the dns timeout",
+            ErrorKind::DnsFailed => "check the spelling of the host name, or list the host by its address",
+            ErrorKind::ChannelRejected | ErrorKind::ExecRejected => {
+                "the account may have a restricted shell or a ForceCommand, try: ssh <host> true"
+            }
+            ErrorKind::TooManyChannels => "lower ConnectionProps::channel_limit_for below the MaxSessions of the host",
+            ErrorKind::InteractivePrompt => {
+                "add the prompt and its answer to the responses of the command, \
+                 or make it non-interactive, e.g. with -y"
+            }
+            ErrorKind::RemoteDiskFull => {
+                "free space on the host, or set a remote_dir on a larger filesystem, \
+                 modules which upload files are skipped on it for the rest of the connection"
+            }
+            ErrorKind::InsufficientDisk => "free space on the host, or set a remote_dir on a larger filesystem",
+            ErrorKind::Conflict => "remove one of the options, CONFLICTS says what each id refuses",
+            ErrorKind::LoadFailed => "fix or remove the file, the module isn't in the tree until it loads",
+            ErrorKind::Skipped | ErrorKind::Cancelled | ErrorKind::RunFailed | ErrorKind::Other => return None,
+        })
+    }
+}
+
+/// Error of running a module, with the host, module and command it happened in,
+/// as far as the caller knows them. The host is taken from the error when it names one.
+#[derive(Debug)]
+pub struct ModuleError {
+    pub error: Error,
+    pub host: Option<String>,
+    pub module: Option<String>,
+    pub command: Option<String>,
+}
+
+impl ModuleError {
+    pub fn new(error: Error) -> Self {
+        ModuleError {
+            error,
+            host: None,
+            module: None,
+            command: None,
+        }
+    }
+
+    pub fn on_host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_string());
+        self
+    }
+
+    pub fn in_module(mut self, module: &str) -> Self {
+        self.module = Some(module.to_string());
+        self
+    }
+
+    pub fn in_command(mut self, command: &str) -> Self {
+        self.command = Some(command.to_string());
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::of(&self.error)
+    }
+
+    /// The host given, or the one the error names.
+    fn host(&self) -> Option<&str> {
+        self.host.as_deref().or_else(|| {
+            self.error.chain().find_map(|cause| {
+                if let Some(e) = cause.downcast_ref::<RunFailed>() {
+                    return Some(e.host.as_str());
+                }
+                if let Some(e) = cause.downcast_ref::<HostKeyMismatch>() {
+                    return Some(e.host.as_str());
+                }
+                if let Some(e) = cause.downcast_ref::<RemoteDiskFull>() {
+                    return Some(e.host.as_str());
+                }
+                if let Some(e) = cause.downcast_ref::<InsufficientDisk>() {
+                    return Some(e.host.as_str());
+                }
+                match cause.downcast_ref::<DnsError>() {
+                    Some(DnsError::Timeout { host, .. }) | Some(DnsError::Failed { host, .. }) => Some(host.as_str()),
+                    None => None,
+                }
+            })
+        })
+    }
+}
+
+impl From<Error> for ModuleError {
+    fn from(error: Error) -> Self {
+        ModuleError::new(error)
+    }
+}
+
+/// `error` for a terminal: the error, each of its causes, the host, module and command,
+/// details of the error like the fingerprints of a [`HostKeyMismatch`], and the hint
+/// of its [`ErrorKind`]. Ends with a newline.
+/// ```text
+/// error: Failed connecting to web01
+///   caused by: Password was rejected for root: Authentication failed
+///   host: web01
+/// hint: check that your agent has the right key: ssh-add -l
+/// ```
+pub fn render(error: &ModuleError) -> String {
+    let mut chain = error.error.chain();
+    let mut out = format!("error: {}\n", chain.next().expect("errors have a first cause"));
+    for cause in chain {
+        out += &format!("  caused by: {}\n", cause);
+    }
+    let context = [
+        ("host", error.host()),
+        ("module", error.module.as_deref()),
+        ("command", error.command.as_deref()),
+    ];
+    for (label, value) in context.iter() {
+        if let Some(value) = value {
+            out += &format!("  {}: {}\n", label, value);
+        }
+    }
+    if let Some(mismatch) = error.error.chain().find_map(|cause| cause.downcast_ref::<HostKeyMismatch>()) {
+        let known = mismatch.known.as_deref().unwrap_or("unknown, the host name may be hashed");
+        match mismatch.line {
+            Some(line) => {
+                out += &format!(
+                    "  known key: {} ({} line {})\n",
+                    known,
+                    mismatch.known_hosts.display(),
+                    line
+                )
+            }
+            None => out += &format!("  known key: {}\n", known),
+        }
+        out += &format!("  presented key: {}\n", mismatch.presented);
+    }
+    if let Some(hint) = error.kind().hint() {
+        out += &format!("hint: {}\n", hint);
+    }
+    out
+}
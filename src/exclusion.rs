@@ -0,0 +1,176 @@
+//! Hosts the operators parked for maintenance, see [`Exclusions`].
+use crate::facts::epoch_ms;
+use crate::inventory::expand_range;
+use crate::window::{parse_utc_timestamp, utc_timestamp};
+use crate::SkipReason;
+use anyhow::Error;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Exclusions file every run on several hosts honors, if it exists, see [`Exclusions`].
+pub const DEFAULT_EXCLUSIONS_FILE: &str = "/etc/am/excluded_hosts";
+
+/// Line of an [`Exclusions`] file. `reason` is its comment, `expires` an RFC 3339
+/// time in UTC, `None` for exclusions until the line is removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exclusion {
+    pub pattern: String,
+    pub reason: Option<String>,
+    pub expires: Option<String>,
+    /// The pattern with its ranges expanded, in lowercase
+    globs: Vec<String>,
+}
+
+impl Exclusion {
+    /// Error the hosts it matches are skipped with.
+    pub fn skip_reason(&self) -> SkipReason {
+        SkipReason::ExcludedByOperator {
+            reason: self.reason.clone(),
+            expires: self.expires.clone(),
+        }
+    }
+}
+
+/// Hosts no run on several hosts may touch, like
+/// [`crate::Runner::run_module_on_hosts`] or [`crate::Playbook::run`]. They are
+/// skipped with a [`SkipReason::ExcludedByOperator`] error instead, without connecting,
+/// and show up as skipped in the results. Read from [`DEFAULT_EXCLUSIONS_FILE`] or
+/// [`crate::Runner::with_exclusions_file`] anew for every run:
+/// ```text
+/// # parked for maintenance
+/// db01
+/// web[01:04]   2026-10-20T06:00:00Z   # disk replacement
+/// cache-*      2026-11-01
+/// ```
+/// One host or pattern per line, then optionally when the exclusion ends, and a
+/// comment saying why. Patterns have ranges like the host lines of an [`crate::Inventory`],
+/// `*` matches any characters and `?` any one. Hosts match by name, case aside, with
+/// or without their port, and by address. The end is an RFC 3339 time or a date,
+/// meaning its start in UTC. Lines which ended are ignored, with a warning.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Exclusions {
+    entries: Vec<Exclusion>,
+    warnings: Vec<String>,
+}
+
+impl Exclusions {
+    /// Reads the exclusions file `path`, see [`Exclusions::parse`].
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| Error::msg(format!("Failed reading exclusions {}: {}", path.display(), e)))?;
+        Exclusions::parse(&content, epoch_ms(SystemTime::now()))
+            .map_err(|e| e.context(format!("Failed reading exclusions {}", path.display())))
+    }
+
+    /// Parses an exclusions file, leaving out the lines which ended by `now_ms`,
+    /// in milliseconds since the epoch.
+    pub fn parse(content: &str, now_ms: i64) -> Result<Self, Error> {
+        let mut exclusions = Exclusions::default();
+        for (i, line) in content.lines().enumerate() {
+            let line_error = |message: String| Error::msg(format!("Line {}: {}", i + 1, message));
+            let (entry, reason) = match line.split_once('#') {
+                Some((entry, reason)) => (entry, Some(reason.trim()).filter(|reason| !reason.is_empty())),
+                None => (line, None),
+            };
+            let mut tokens = entry.split_whitespace();
+            let pattern = match tokens.next() {
+                Some(pattern) => pattern,
+                None => continue,
+            };
+            let expires = tokens
+                .next()
+                .map(parse_utc_timestamp)
+                .transpose()
+                .map_err(|e| line_error(e.to_string()))?;
+            if let Some(extra) = tokens.next() {
+                return Err(line_error(format!("Unexpected {} after the end of the exclusion", extra)));
+            }
+            let globs = expand_range(pattern).map_err(line_error)?;
+            if let Some(expires) = expires.filter(|expires| *expires <= now_ms) {
+                exclusions.warnings.push(format!(
+                    "exclusion of {} on line {} ended at {}, the line can be removed",
+                    pattern,
+                    i + 1,
+                    utc_timestamp(expires)
+                ));
+                continue;
+            }
+            exclusions.entries.push(Exclusion {
+                pattern: pattern.to_string(),
+                reason: reason.map(str::to_string),
+                expires: expires.map(utc_timestamp),
+                globs: globs.iter().map(|glob| glob.to_ascii_lowercase()).collect(),
+            });
+        }
+        Ok(exclusions)
+    }
+
+    /// Exclusions in effect, in file order.
+    pub fn entries(&self) -> &[Exclusion] {
+        &self.entries
+    }
+
+    /// Lines which ended, and can be removed.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// First exclusion matching `host`, which is a host's `to_string()`.
+    pub fn matching(&self, host: &str) -> Option<&Exclusion> {
+        let names = host_names(host);
+        self.entries.iter().find(|entry| {
+            entry
+                .globs
+                .iter()
+                .any(|glob| names.iter().any(|name| glob_match(glob, name)))
+        })
+    }
+}
+
+/// What `host` matches by, in lowercase: itself, without its port, and the name and
+/// the address of hosts like `web01[10.0.0.5]:22`.
+fn host_names(host: &str) -> Vec<String> {
+    let host = host.to_ascii_lowercase();
+    let bare = match host.rsplit_once(':') {
+        Some((bare, port)) if port.parse::<u16>().is_ok() && (!bare.contains(':') || bare.ends_with(']')) => {
+            bare
+        }
+        _ => &host,
+    };
+    let mut names = vec![host.clone(), bare.to_string()];
+    if let Some((name, address)) = bare.strip_suffix(']').and_then(|bare| bare.split_once('[')) {
+        names.extend([name, address].iter().filter(|part| !part.is_empty()).map(|part| part.to_string()));
+    }
+    names
+}
+
+/// Whether `text` matches `glob`, where `*` is any characters and `?` any one.
+fn glob_match(glob: &str, text: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut g, mut t) = (0, 0);
+    // where the last `*` was, and where in `text` it stopped matching
+    let mut star = None;
+    while t < text.len() {
+        match glob.get(g) {
+            Some('*') => {
+                star = Some((g, t));
+                g += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                g += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((at, from)) => {
+                    star = Some((at, from + 1));
+                    g = at + 1;
+                    t = from + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|c| *c == '*')
+}
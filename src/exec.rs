@@ -0,0 +1,122 @@
+use crate::artifacts::Artifacts;
+use crate::bundle::bundle_token;
+use crate::disk::UploadFailed;
+use crate::progress::UploadReporter;
+use crate::shell::{shell_format, ShellSafe};
+use anyhow::Error;
+use ssh2::{Channel, ExitSignal, OpenFlags, OpenType, Session};
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Commands longer than this are run from an uploaded file by default, as `sh -c`
+/// fails with "argument list too long" above 128KiB on Linux.
+pub const DEFAULT_INLINE_LIMIT: usize = 96 * 1024;
+
+/// Host refusing to run commands at all, as appliances and restricted shells do
+/// after a successful auth. Errors carry it, find it with `downcast_ref::<ExecError>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecError {
+    /// The host wouldn't open a session channel, or closed the connection instead.
+    ChannelRejected { message: String },
+    /// The host refused another channel while `open` were open on the connection,
+    /// as OpenSSH does above its `MaxSessions`. The connection keeps below it from then on.
+    TooManyChannels { open: usize },
+    /// The host opened a channel, but refused to exec the command on it.
+    /// `remote_message` is what it said about it: stderr, exit status or signal.
+    ExecRejected { remote_message: String },
+}
+
+impl Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecError::ChannelRejected { message } => {
+                write!(f, "host refused to open a channel (restricted shell?): {}", message)
+            }
+            ExecError::TooManyChannels { open } => {
+                write!(f, "host refused more than {} channels on one connection (MaxSessions?)", open)
+            }
+            ExecError::ExecRejected { remote_message } => {
+                write!(f, "host refused exec (restricted shell?): {}", remote_message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+/// Execs `command` on a fresh channel, collecting what the host said if it refuses.
+/// `session` must be blocking.
+pub(crate) fn exec_command(session: &Session, channel: &mut Channel, command: &str) -> Result<(), Error> {
+    let e = match channel.exec(command) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    // whatever the host already sent, without waiting for more
+    let mut banner = [0; 4096];
+    session.set_blocking(false);
+    let read = channel.stderr().read(&mut banner).unwrap_or(0);
+    session.set_blocking(true);
+    let banner = String::from_utf8_lossy(&banner[..read]).trim().to_string();
+    let signal = channel.exit_signal().ok();
+    let remote_message = match (signal, channel.exit_status()) {
+        _ if !banner.is_empty() => banner,
+        (Some(ExitSignal { error_message: Some(message), .. }), _) if !message.is_empty() => message,
+        (Some(ExitSignal { exit_signal: Some(signal), .. }), _) => {
+            format!("killed by signal {}", signal)
+        }
+        (_, Ok(status)) if status != 0 => format!("exited with status {}", status),
+        _ => e.message().to_string(),
+    };
+    Err(ExecError::ExecRejected { remote_message }.into())
+}
+
+/// Execs `command` like [`exec_command`], or if it is longer than `inline_limit`
+/// uploads it to `/tmp` over sftp and execs the file with the user's shell,
+/// removing it once it finished. Returns whether the command was uploaded.
+/// The file gets a fresh random name and must not exist yet, a failed upload removes it.
+/// The upload reports to `uploads`, if there is one, the file is tracked in `ledger`.
+pub(crate) fn exec_staged(
+    session: &Session,
+    channel: &mut Channel,
+    command: &str,
+    inline_limit: usize,
+    uploads: Option<&UploadReporter>,
+    ledger: &Artifacts,
+) -> Result<bool, Error> {
+    if command.len() <= inline_limit {
+        exec_command(session, channel, command)?;
+        return Ok(false);
+    }
+    let path = format!("/tmp/am_cmd-{}", bundle_token());
+    let upload = || -> Result<(), Error> {
+        let sftp = session.sftp()?;
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE;
+        let mut file = sftp.open_mode(Path::new(&path), flags, 0o600, OpenType::File)?;
+        ledger.track(&path);
+        let written = match uploads {
+            Some(uploads) => {
+                let total = command.len() as u64;
+                io::copy(&mut uploads.track(&path, total, command.as_bytes()), &mut file).map(|_| ())
+            }
+            None => file.write_all(command.as_bytes()),
+        };
+        if let Err(e) = written {
+            drop(file);
+            let _ = sftp.unlink(Path::new(&path));
+            return Err(e.into());
+        }
+        Ok(())
+    };
+    upload().map_err(|e| UploadFailed {
+        dir: "/tmp".to_string(),
+        message: format!("Uploading command to {}: {}", path, e),
+        path: path.clone(),
+    })?;
+    let run = shell_format!(
+        "\"${{SHELL:-sh}}\" {0}; status=$?; rm -f {0}; exit $status",
+        ShellSafe::path(&path)
+    );
+    exec_command(session, channel, run.as_str())?;
+    Ok(true)
+}
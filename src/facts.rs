@@ -0,0 +1,361 @@
+use crate::{AuthType, ConnectionProps, Runner, TargetOs};
+use anyhow::Error;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::net::ToSocketAddrs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Prints the remote clock as `key=value` lines, with `timedatectl` where there is one.
+pub(crate) const CLOCK_COMMAND: &str = "echo epoch=$(date +%s%N); \
+    { command -v timedatectl >/dev/null 2>&1 \
+    && timedatectl show -p Timezone -p NTPSynchronized 2>/dev/null; } \
+    || echo Timezone=$(date +%Z)";
+
+/// Prints the memory, disks, CPUs and load of the host, each after an `@<section>` line.
+/// Falls back on what busybox and the BSDs have where `/proc` or `nproc` are missing.
+pub(crate) const HOST_FACTS_COMMAND: &str = "echo @meminfo; cat /proc/meminfo 2>/dev/null; \
+    echo @df; df -P -k 2>/dev/null; \
+    echo @cpus; nproc 2>/dev/null || getconf _NPROCESSORS_ONLN 2>/dev/null \
+    || grep -c ^processor /proc/cpuinfo 2>/dev/null; \
+    echo @load; cat /proc/loadavg 2>/dev/null || uptime 2>/dev/null";
+
+/// Clock of a host, see [`Runner::gather_clock_facts`].
+/// `ntp_synchronized` is known only on hosts with `timedatectl`.
+/// `clock_drift_ms` is how far the host's clock is ahead of the controller's,
+/// negative if it is behind. `drifting` is set if that is more than the run allows.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClockFacts {
+    pub epoch_ms: i64,
+    pub timezone: Option<String>,
+    pub ntp_synchronized: Option<bool>,
+    pub clock_drift_ms: i64,
+    pub drifting: bool,
+}
+
+pub(crate) fn epoch_ms(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+impl ClockFacts {
+    /// Reads the output of the clock command, taken when the controller's clock showed
+    /// `controller_ms`. Hosts whose `date` has no `%N` give seconds only.
+    pub fn parse(output: &str, controller_ms: i64) -> Result<Self, Error> {
+        let values: HashMap<_, _> = output
+            .lines()
+            .filter_map(|line| line.trim().split_once('='))
+            .collect();
+        let epoch = values
+            .get("epoch")
+            .ok_or_else(|| Error::msg(format!("No remote time in {:?}", output)))?;
+        let digits = epoch.trim_end_matches(|c: char| !c.is_ascii_digit());
+        let value: i64 = digits
+            .parse()
+            .map_err(|_| Error::msg(format!("Bad remote time {:?}", epoch)))?;
+        let epoch_ms = if digits.len() == epoch.len() && digits.len() > 12 {
+            value / 1_000_000
+        } else {
+            value * 1000
+        };
+        let timezone = values
+            .get("Timezone")
+            .filter(|timezone| !timezone.is_empty())
+            .map(|timezone| timezone.to_string());
+        let ntp_synchronized = values.get("NTPSynchronized").map(|synced| *synced == "yes");
+        Ok(ClockFacts {
+            epoch_ms,
+            timezone,
+            ntp_synchronized,
+            clock_drift_ms: epoch_ms - controller_ms,
+            drifting: false,
+        })
+    }
+
+    /// Template variables `facts.epoch_ms`, `facts.timezone`, `facts.ntp_synchronized`,
+    /// `facts.clock_drift_ms` and `facts.clock_drifting`, unknown ones left out.
+    pub fn vars(&self) -> HashMap<String, String> {
+        let mut vars = HashMap::new();
+        vars.insert("facts.epoch_ms".to_string(), self.epoch_ms.to_string());
+        vars.insert("facts.clock_drift_ms".to_string(), self.clock_drift_ms.to_string());
+        vars.insert("facts.clock_drifting".to_string(), self.drifting.to_string());
+        if let Some(timezone) = &self.timezone {
+            vars.insert("facts.timezone".to_string(), timezone.clone());
+        }
+        if let Some(synced) = self.ntp_synchronized {
+            vars.insert("facts.ntp_synchronized".to_string(), synced.to_string());
+        }
+        vars
+    }
+}
+
+/// Memory of a host, from `/proc/meminfo`. `available_bytes` is estimated from the
+/// free memory and the caches on kernels older than 3.14, which don't report it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MemoryFacts {
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+}
+
+/// Usage of a mounted filesystem, from `df -P`. `use_percent` is `None` for
+/// filesystems `df` has no size of, like most pseudo filesystems.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DiskUsage {
+    pub filesystem: String,
+    pub mount: String,
+    pub size_bytes: u64,
+    pub used_bytes: u64,
+    pub available_bytes: u64,
+    pub use_percent: Option<f64>,
+}
+
+/// Output [`HostFacts`] were parsed from, by section.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RawFacts {
+    pub meminfo: String,
+    pub df: String,
+    pub cpus: String,
+    pub load: String,
+}
+
+/// Operating system, memory, disks, CPUs and load of a host, see [`Runner::gather_host_facts`].
+/// Facts the host has no command for are `None`, or no disks, and what they were
+/// parsed from is kept in `raw`. Windows hosts have only their `os`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HostFacts {
+    pub os: TargetOs,
+    pub memory: Option<MemoryFacts>,
+    pub disks: Vec<DiskUsage>,
+    pub cpu_count: Option<u32>,
+    /// 1, 5 and 15 minute load averages
+    pub load_average: Option<[f64; 3]>,
+    pub raw: RawFacts,
+}
+
+/// `value` as a number, with a `.` or a `,` as decimal separator.
+fn parse_decimal(value: &str) -> Option<f64> {
+    value.replace(',', ".").parse().ok()
+}
+
+fn parse_meminfo(meminfo: &str) -> Option<MemoryFacts> {
+    let values: HashMap<_, _> = meminfo
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            let mut fields = value.split_whitespace();
+            let number: u64 = fields.next()?.parse().ok()?;
+            let scale = match fields.next() {
+                Some(unit) if unit.eq_ignore_ascii_case("kb") => 1024,
+                Some(unit) if unit.eq_ignore_ascii_case("mb") => 1024 * 1024,
+                _ => 1,
+            };
+            Some((key.trim(), number * scale))
+        })
+        .collect();
+    let available = values.get("MemAvailable").copied().or_else(|| {
+        let cached = ["Buffers", "Cached", "SReclaimable"].iter().filter_map(|key| values.get(key)).sum::<u64>();
+        Some(values.get("MemFree")? + cached)
+    })?;
+    Some(MemoryFacts {
+        total_bytes: *values.get("MemTotal")?,
+        available_bytes: available,
+    })
+}
+
+/// Bytes per block of the size column of the header of `df -P`, like `1024-blocks`
+/// of GNU and busybox, `1K-blocks`, or the 512 byte blocks of the BSDs, in any language.
+fn block_size(header: &str) -> u64 {
+    let column = header.split_whitespace().nth(1).unwrap_or_default().to_lowercase();
+    let digits = column.chars().take_while(char::is_ascii_digit).count();
+    let number = match column[..digits].parse::<u64>() {
+        Ok(number) => number,
+        Err(_) => return 1024,
+    };
+    match column[digits..].chars().next() {
+        Some('k') => number * 1024,
+        Some('m') => number * 1024 * 1024,
+        _ => number,
+    }
+}
+
+fn parse_df(df: &str) -> Vec<DiskUsage> {
+    let mut lines = df.lines().filter(|line| !line.trim().is_empty());
+    let block = match lines.next() {
+        Some(header) => block_size(header),
+        None => return Vec::new(),
+    };
+    let mut disks = Vec::new();
+    let mut wrapped: Option<String> = None;
+    for line in lines {
+        let mut fields: Vec<_> = line.split_whitespace().collect();
+        // busybox puts the columns of long device names on the next line
+        if fields.len() == 1 && wrapped.is_none() {
+            wrapped = Some(fields[0].to_string());
+            continue;
+        }
+        let filesystem = match wrapped.take() {
+            Some(filesystem) => filesystem,
+            None if fields.len() >= 6 => fields.remove(0).to_string(),
+            None => continue,
+        };
+        if fields.len() < 5 {
+            continue;
+        }
+        let blocks = |field: &str| field.parse::<u64>().ok().map(|blocks| blocks * block);
+        let (size, used, available) = match (blocks(fields[0]), blocks(fields[1]), blocks(fields[2])) {
+            (Some(size), Some(used), Some(available)) => (size, used, available),
+            _ => continue,
+        };
+        disks.push(DiskUsage {
+            filesystem,
+            mount: fields[4..].join(" "),
+            size_bytes: size,
+            used_bytes: used,
+            available_bytes: available,
+            use_percent: parse_decimal(fields[3].trim_end_matches('%')),
+        });
+    }
+    disks
+}
+
+/// Load averages, the first three numbers of `/proc/loadavg`, or the last three
+/// of `uptime`, which starts with the time and names them in the host's language.
+fn parse_load(load: &str) -> Option<[f64; 3]> {
+    let numbers = Regex::new(r"\d+(?:[.,]\d+)?").expect("valid regex");
+    let mut averages: Vec<_> = numbers.find_iter(load).filter_map(|m| parse_decimal(m.as_str())).collect();
+    if load.contains(':') {
+        averages.drain(..averages.len().saturating_sub(3));
+    }
+    match averages.as_slice() {
+        [one, five, fifteen, ..] => Some([*one, *five, *fifteen]),
+        _ => None,
+    }
+}
+
+impl HostFacts {
+    /// Reads the output of the host facts command, which runs on POSIX hosts.
+    pub fn parse(output: &str) -> Self {
+        let mut sections: HashMap<&str, String> = HashMap::new();
+        let mut section = None;
+        for line in output.lines() {
+            match line.trim().strip_prefix('@') {
+                Some(name) if ["meminfo", "df", "cpus", "load"].contains(&name) => section = Some(name),
+                _ => {
+                    if let Some(name) = section {
+                        let content = sections.entry(name).or_default();
+                        content.push_str(line);
+                        content.push('\n');
+                    }
+                }
+            }
+        }
+        let mut take = |name| sections.remove(name).unwrap_or_default();
+        let raw = RawFacts {
+            meminfo: take("meminfo"),
+            df: take("df"),
+            cpus: take("cpus"),
+            load: take("load"),
+        };
+        HostFacts {
+            os: TargetOs::Posix,
+            memory: parse_meminfo(&raw.meminfo),
+            disks: parse_df(&raw.df),
+            cpu_count: raw.cpus.trim().parse().ok().filter(|count| *count > 0),
+            load_average: parse_load(&raw.load),
+            raw,
+        }
+    }
+
+    /// Usage of the filesystem mounted at `mount`.
+    pub fn disk(&self, mount: &str) -> Option<&DiskUsage> {
+        self.disks.iter().find(|disk| disk.mount == mount)
+    }
+
+    /// Template variables `facts.os`, `facts.memory_total_bytes`, `facts.memory_available_bytes`,
+    /// `facts.cpu_count`, `facts.load_1`, `facts.load_5`, `facts.load_15`, and
+    /// `facts.root_available_bytes` and `facts.root_use_percent` of `/`, unknown ones left out.
+    pub fn vars(&self) -> HashMap<String, String> {
+        let mut vars = HashMap::new();
+        vars.insert("facts.os".to_string(), self.os.as_str().to_string());
+        if let Some(memory) = &self.memory {
+            vars.insert("facts.memory_total_bytes".to_string(), memory.total_bytes.to_string());
+            vars.insert("facts.memory_available_bytes".to_string(), memory.available_bytes.to_string());
+        }
+        if let Some(count) = self.cpu_count {
+            vars.insert("facts.cpu_count".to_string(), count.to_string());
+        }
+        if let Some([one, five, fifteen]) = self.load_average {
+            vars.insert("facts.load_1".to_string(), one.to_string());
+            vars.insert("facts.load_5".to_string(), five.to_string());
+            vars.insert("facts.load_15".to_string(), fifteen.to_string());
+        }
+        if let Some(root) = self.disk("/") {
+            vars.insert("facts.root_available_bytes".to_string(), root.available_bytes.to_string());
+            if let Some(percent) = root.use_percent {
+                vars.insert("facts.root_use_percent".to_string(), percent.to_string());
+            }
+        }
+        vars
+    }
+}
+
+impl Runner {
+    /// Reads the clock of the host and compares it with the controller's, taken halfway
+    /// through the round trip. With [`Runner::with_clock_drift_threshold`], a host off
+    /// by more is flagged as `drifting` and a warning is printed to stderr.
+    pub fn gather_clock_facts<A>(
+        &self,
+        ip: A,
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+    ) -> Result<ClockFacts, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        let host = ip.to_string();
+        let mut facts = self.with_connection(ip, auth, sync, |connection| {
+            let sent = epoch_ms(SystemTime::now());
+            let (stdout, stderr, status) = self.run_command(connection, CLOCK_COMMAND)?;
+            let received = epoch_ms(SystemTime::now());
+            if status != 0 {
+                return Err(Error::msg(format!(
+                    "Reading the clock failed: {}",
+                    stderr.trim_end()
+                )));
+            }
+            ClockFacts::parse(&stdout, sent + (received - sent) / 2)
+        })?;
+        if let Some(threshold) = self.options().clock_drift_threshold() {
+            let drift = Duration::from_millis(facts.clock_drift_ms.unsigned_abs());
+            facts.drifting = drift > threshold;
+            if facts.drifting {
+                eprintln!(
+                    "warning: clock of {} is off by {}ms, more than {:?}",
+                    host, facts.clock_drift_ms, threshold
+                );
+            }
+        }
+        Ok(facts)
+    }
+
+    /// Reads the operating system, memory, disks, CPUs and load of the host, see [`HostFacts`].
+    /// Facts the host has no command for are left out, it fails only if the host
+    /// can't be reached. The cached connection remembers the operating system, see
+    /// [`crate::HostConnection::detect_target_os`].
+    pub fn gather_host_facts<A>(&self, ip: A, auth: AuthType, sync: &dyn ConnectionProps) -> Result<HostFacts, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        self.with_connection(ip, auth, sync, |connection| {
+            if connection.detect_target_os()? == TargetOs::Windows {
+                let parsed = HostFacts::parse("");
+                return Ok(HostFacts { os: TargetOs::Windows, ..parsed });
+            }
+            let (stdout, _, _) = self.run_command(connection, HOST_FACTS_COMMAND)?;
+            Ok(HostFacts::parse(&stdout))
+        })
+    }
+}
@@ -0,0 +1,127 @@
+//! Failures injected into connections, for testing orchestration logic against hosts
+//! which misbehave. Only built with the `testing` feature, release builds have no faults.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Faults of one connection attempt, nothing by default.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Faults {
+    /// Fails the attempt where it would connect over tcp
+    pub(crate) fail_connect: bool,
+    /// Fails the attempt where it would authenticate
+    pub(crate) fail_auth: bool,
+    pub(crate) handshake_delay: Option<Duration>,
+    /// Drops the connection once this many bytes were read from the host
+    pub(crate) drop_after: Option<u64>,
+    /// Output limit per stream of every command run over the connection
+    pub(crate) output_limit: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+enum Fault {
+    FailConnect(usize),
+    FailAuth(usize),
+    DelayHandshake(Duration),
+    DropAfter(u64),
+    TruncateOutput(u64),
+}
+
+/// Scripted failures of the connections a [`crate::Runner`] opens, see
+/// [`crate::Runner::with_fault_injector`]. Faults are injected where the connection
+/// would fail for real, so retries, cleanup and permits go through their usual paths:
+/// ```
+/// # use ansible_modules::FaultInjector;
+/// # use std::time::Duration;
+/// let faults = FaultInjector::new()
+///     .fail_connection("web01:22", 1)
+///     .delay_handshake("db01:22", Duration::from_secs(5));
+/// ```
+/// Hosts are matched by their `to_string()`. Attempts count every connection
+/// the runner tries to a host, from 1.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    faults: Vec<(String, Fault)>,
+    attempts: Mutex<HashMap<String, usize>>,
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        FaultInjector::default()
+    }
+
+    /// Fails the `attempt`th connection to `host` before it reaches the host.
+    pub fn fail_connection(self, host: &str, attempt: usize) -> Self {
+        self.with(host, Fault::FailConnect(attempt))
+    }
+
+    /// Fails authentication of the `attempt`th connection to `host`, after the handshake.
+    pub fn fail_auth(self, host: &str, attempt: usize) -> Self {
+        self.with(host, Fault::FailAuth(attempt))
+    }
+
+    /// Waits `delay` before every handshake with `host`, counting against no timeout.
+    pub fn delay_handshake(self, host: &str, delay: Duration) -> Self {
+        self.with(host, Fault::DelayHandshake(delay))
+    }
+
+    /// Cuts every connection to `host` once `bytes` were read from it,
+    /// handshake and authentication included.
+    pub fn drop_after(self, host: &str, bytes: u64) -> Self {
+        self.with(host, Fault::DropAfter(bytes))
+    }
+
+    /// Limits the output of every command on `host` to `bytes` per stream, as if
+    /// it had a `max_output` ceiling, so commands printing more fail as truncated.
+    pub fn truncate_output(self, host: &str, bytes: u64) -> Self {
+        self.with(host, Fault::TruncateOutput(bytes))
+    }
+
+    /// Authentication to `host` fails once, the next connection works.
+    pub fn flaky_auth(host: &str) -> Self {
+        FaultInjector::new().fail_auth(host, 1)
+    }
+
+    /// Every handshake with `host` takes `delay` longer.
+    pub fn slow_host(host: &str, delay: Duration) -> Self {
+        FaultInjector::new().delay_handshake(host, delay)
+    }
+
+    /// Connections to `host` drop after `bytes` were read, typically in the middle
+    /// of the first module with some output.
+    pub fn mid_run_disconnect(host: &str, bytes: u64) -> Self {
+        FaultInjector::new().drop_after(host, bytes)
+    }
+
+    /// Connections to `host` tried so far.
+    pub fn attempts(&self, host: &str) -> usize {
+        let attempts = self.attempts.lock().expect("attempts lock poisoned");
+        attempts.get(host).copied().unwrap_or(0)
+    }
+
+    fn with(mut self, host: &str, fault: Fault) -> Self {
+        self.faults.push((host.to_string(), fault));
+        self
+    }
+
+    /// Counts a new connection attempt to `host`, returning its faults.
+    pub(crate) fn next_attempt(&self, host: &str) -> Faults {
+        let attempt = {
+            let mut attempts = self.attempts.lock().expect("attempts lock poisoned");
+            let attempt = attempts.entry(host.to_string()).or_insert(0);
+            *attempt += 1;
+            *attempt
+        };
+        let mut faults = Faults::default();
+        for (_, fault) in self.faults.iter().filter(|(target, _)| target == host) {
+            match *fault {
+                Fault::FailConnect(nth) => faults.fail_connect |= nth == attempt,
+                Fault::FailAuth(nth) => faults.fail_auth |= nth == attempt,
+                Fault::DelayHandshake(delay) => faults.handshake_delay = Some(delay),
+                Fault::DropAfter(bytes) => faults.drop_after = Some(bytes),
+                Fault::TruncateOutput(bytes) => faults.output_limit = Some(bytes),
+            }
+        }
+        faults
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt::{self, Display};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::vec;
+
+const DEFAULT_PORT: u16 = 22;
+
+/// Target host, formatted canonically as `name[address]:port`,
+/// or `address:port` for unnamed hosts.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Host {
+    name: Option<String>,
+    address: String,
+    port: u16,
+    input: String,
+}
+
+impl Host {
+    pub fn new(address: &str, port: u16) -> Self {
+        Host {
+            name: None,
+            address: address.to_string(),
+            port,
+            input: format!("{}:{}", address, port),
+        }
+    }
+
+    pub fn named(name: &str, address: &str, port: u16) -> Self {
+        Host {
+            name: Some(name.to_string()),
+            ..Host::new(address, port)
+        }
+    }
+
+    /// Parses `address`, `address:port` or `[v6 address]:port`, port defaults to 22,
+    /// optionally prefixed by a name as in `name[address]:port`.
+    /// The original string is kept in [`Host::input`].
+    pub fn parse(input: &str) -> Self {
+        if let Some((name, rest)) = input.split_once('[') {
+            if !name.is_empty() && rest.contains(']') {
+                return Host {
+                    name: Some(name.to_string()),
+                    input: input.to_string(),
+                    ..Host::parse(&input[name.len()..])
+                };
+            }
+        }
+        let (address, port) = match input.rsplit_once(':') {
+            Some((address, port)) if !address.contains(':') || address.starts_with('[') => {
+                match port.parse() {
+                    Ok(port) => (address, port),
+                    Err(_) => (input, DEFAULT_PORT),
+                }
+            }
+            _ => (input, DEFAULT_PORT),
+        };
+        let address = address.trim_start_matches('[').trim_end_matches(']');
+        Host {
+            input: input.to_string(),
+            ..Host::new(address, port)
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// String the host was created from, for correlating with user input.
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+}
+
+impl Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{}[{}]:{}", name, self.address, self.port)
+        } else if self.address.contains(':') {
+            write!(f, "[{}]:{}", self.address, self.port)
+        } else {
+            write!(f, "{}:{}", self.address, self.port)
+        }
+    }
+}
+
+impl ToSocketAddrs for Host {
+    type Iter = vec::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        (self.address.as_str(), self.port).to_socket_addrs()
+    }
+}
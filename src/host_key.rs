@@ -0,0 +1,209 @@
+use crate::Host;
+use anyhow::Error;
+use ssh2::{CheckResult, KnownHostFileKind, MethodType, Session};
+use std::fmt::{self, Display};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Host presenting another key than `known_hosts` has for it, see
+/// [`crate::ConnectionProps::known_hosts_for`]. Fingerprints are `SHA256:` ones, like
+/// `ssh-keygen -l` shows. `line` and `known` are of the entry for the host, `None` if it
+/// couldn't be found, as with hashed host names.
+/// Errors carry it, find it with `downcast_ref::<HostKeyMismatch>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostKeyMismatch {
+    pub host: String,
+    pub known_hosts: PathBuf,
+    pub line: Option<usize>,
+    pub known: Option<String>,
+    pub presented: String,
+}
+
+impl Display for HostKeyMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Host key of {} doesn't match the one in {}",
+            self.host,
+            self.known_hosts.display()
+        )
+    }
+}
+
+impl std::error::Error for HostKeyMismatch {}
+
+/// `SHA256:` fingerprint of a host key, as OpenSSH prints it.
+pub(crate) fn fingerprint(key: &[u8]) -> String {
+    format!(
+        "SHA256:{}",
+        base64::encode_config(crate::checksum::sha256(key), base64::STANDARD_NO_PAD)
+    )
+}
+
+/// Line and fingerprint of the entry for `name` in `known_hosts`, whose key isn't `key`.
+/// Hashed names aren't matched.
+fn known_entry(known_hosts: &Path, name: &str, port: u16, key: &[u8]) -> Option<(usize, String)> {
+    let content = fs::read_to_string(known_hosts).ok()?;
+    let presented = base64::encode(key);
+    let names = [name.to_string(), format!("[{}]:{}", name, port)];
+    content.lines().enumerate().find_map(|(index, line)| {
+        let mut fields = line.split_whitespace().skip_while(|field| field.starts_with('@'));
+        let (hosts, _, known) = (fields.next()?, fields.next()?, fields.next()?);
+        let listed = hosts
+            .split(',')
+            .any(|pattern| names.iter().any(|name| name == pattern) && (pattern.starts_with('[') || port == 22));
+        if !listed || line.trim_start().starts_with('#') || known == presented {
+            return None;
+        }
+        let known = base64::decode(known).ok()?;
+        Some((index + 1, fingerprint(&known)))
+    })
+}
+
+/// Host key algorithm to pin for the handshake, see [`crate::ConnectionProps::host_key_type_for`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HostKeyType {
+    Rsa,
+    Ecdsa256,
+    Ecdsa384,
+    Ecdsa521,
+    Ed25519,
+}
+
+impl HostKeyType {
+    pub const ALL: [HostKeyType; 5] = [
+        HostKeyType::Rsa,
+        HostKeyType::Ecdsa256,
+        HostKeyType::Ecdsa384,
+        HostKeyType::Ecdsa521,
+        HostKeyType::Ed25519,
+    ];
+
+    /// Name of the algorithm, as in `known_hosts`, e.g. `ssh-ed25519`.
+    pub fn name(self) -> &'static str {
+        match self {
+            HostKeyType::Rsa => "ssh-rsa",
+            HostKeyType::Ecdsa256 => "ecdsa-sha2-nistp256",
+            HostKeyType::Ecdsa384 => "ecdsa-sha2-nistp384",
+            HostKeyType::Ecdsa521 => "ecdsa-sha2-nistp521",
+            HostKeyType::Ed25519 => "ssh-ed25519",
+        }
+    }
+
+    fn matches(self, kind: ssh2::HostKeyType) -> bool {
+        matches!(
+            (self, kind),
+            (HostKeyType::Rsa, ssh2::HostKeyType::Rsa)
+                | (HostKeyType::Ecdsa256, ssh2::HostKeyType::Ecdsa256)
+                | (HostKeyType::Ecdsa384, ssh2::HostKeyType::Ecdsa384)
+                | (HostKeyType::Ecdsa521, ssh2::HostKeyType::Ecdsa521)
+                | (HostKeyType::Ed25519, ssh2::HostKeyType::Ed255219)
+        )
+    }
+}
+
+impl Display for HostKeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HostKeyType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        HostKeyType::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| {
+                Error::msg(format!(
+                    "Unknown host key type {:?}, expected one of: {}",
+                    s,
+                    HostKeyType::ALL.iter().map(|kind| kind.name()).collect::<Vec<_>>().join(", ")
+                ))
+            })
+    }
+}
+
+/// Restricts the handshake of `session` to `kind`. Call before the handshake.
+pub(crate) fn prefer_host_key(session: &Session, kind: HostKeyType) -> Result<(), Error> {
+    let supported = session.supported_algs(MethodType::HostKey)?;
+    if !supported.contains(&kind.name()) {
+        return Err(Error::msg(format!(
+            "{} host keys aren't supported by this build of libssh2",
+            kind
+        )));
+    }
+    session.method_pref(MethodType::HostKey, kind.name())?;
+    Ok(())
+}
+
+/// Checks that the host presented a `kind` key, after the handshake.
+pub(crate) fn check_host_key_type(session: &Session, kind: HostKeyType) -> Result<(), Error> {
+    match session.host_key() {
+        Some((_, presented)) if kind.matches(presented) => Ok(()),
+        _ => Err(Error::msg(format!("Host didn't present a {} host key", kind))),
+    }
+}
+
+/// Names the host may be listed under in `known_hosts`: the name and address of `host`,
+/// and the address actually connected to.
+fn known_names(host: &str, peer: SocketAddr) -> Vec<String> {
+    let host = Host::parse(host);
+    let mut names: Vec<String> = host.name().into_iter().map(str::to_string).collect();
+    names.push(host.address().to_string());
+    names.push(peer.ip().to_string());
+    names.dedup();
+    names
+}
+
+/// Verifies the key presented by the host against an OpenSSH `known_hosts` file.
+/// Every name the host may be listed under has to match or be missing, a key
+/// listed for the address doesn't make up for a different one listed for the name.
+pub(crate) fn verify_known_host(
+    session: &Session,
+    host: &str,
+    peer: SocketAddr,
+    known_hosts: &Path,
+) -> Result<(), Error> {
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| Error::msg("Host presented no host key"))?;
+    let mut known = session.known_hosts()?;
+    known
+        .read_file(known_hosts, KnownHostFileKind::OpenSSH)
+        .map_err(|e| Error::msg(format!("Reading {}: {}", known_hosts.display(), e)))?;
+    let mut matched = false;
+    let mut mismatch = None;
+    for name in known_names(host, peer) {
+        match known.check_port(&name, peer.port(), key) {
+            CheckResult::Match => matched = true,
+            CheckResult::Mismatch => {
+                mismatch = Some(name);
+                break;
+            }
+            CheckResult::NotFound => {}
+            CheckResult::Failure => {
+                return Err(Error::msg(format!("Checking host key of {} failed", name)))
+            }
+        }
+    }
+    match mismatch {
+        Some(name) => {
+            let entry = known_entry(known_hosts, &name, peer.port(), key);
+            Err(HostKeyMismatch {
+                host: name,
+                known_hosts: known_hosts.to_path_buf(),
+                line: entry.as_ref().map(|(line, _)| *line),
+                known: entry.map(|(_, known)| known),
+                presented: fingerprint(key),
+            }
+            .into())
+        }
+        None if matched => Ok(()),
+        None => Err(Error::msg(format!("Host isn't listed in {}", known_hosts.display()))),
+    }
+}
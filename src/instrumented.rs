@@ -0,0 +1,224 @@
+use crate::{ConnectionProps, HostKeyType, Resolver};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PermitKind {
+    Tcp,
+    Agent,
+}
+
+/// Permit taken and not yet released, see [`InstrumentedConnectionProps::outstanding`].
+/// `host` is `None` for permits taken through the methods without a host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeldPermit {
+    pub kind: PermitKind,
+    pub host: Option<String>,
+    pub held_for: Duration,
+}
+
+struct Held {
+    kind: PermitKind,
+    host: Option<String>,
+    since: Instant,
+    warned: bool,
+}
+
+#[derive(Default)]
+struct Accounts {
+    held: Vec<Held>,
+    unmatched_releases: usize,
+}
+
+/// [`ConnectionProps`] wrapper, which keeps track of the permits taken from `inner`.
+///
+/// Helps to find permit leaks: [`InstrumentedConnectionProps::outstanding`] lists
+/// the permits held right now and who took them, and a warning is printed to stderr
+/// for a permit held longer than `warn_after`, or released without being taken.
+pub struct InstrumentedConnectionProps<T> {
+    inner: T,
+    warn_after: Option<Duration>,
+    accounts: Mutex<Accounts>,
+}
+
+impl<T: ConnectionProps> InstrumentedConnectionProps<T> {
+    pub fn new(inner: T, warn_after: Option<Duration>) -> Self {
+        InstrumentedConnectionProps {
+            inner,
+            warn_after,
+            accounts: Mutex::new(Accounts::default()),
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Permits held right now, oldest first.
+    pub fn outstanding(&self) -> Vec<HeldPermit> {
+        let mut accounts = self.accounts();
+        self.warn_overdue(&mut accounts);
+        accounts
+            .held
+            .iter()
+            .map(|held| HeldPermit {
+                kind: held.kind,
+                host: held.host.clone(),
+                held_for: held.since.elapsed(),
+            })
+            .collect()
+    }
+
+    /// Releases which had no permit to release.
+    pub fn unmatched_releases(&self) -> usize {
+        self.accounts().unmatched_releases
+    }
+
+    fn accounts(&self) -> std::sync::MutexGuard<'_, Accounts> {
+        self.accounts.lock().expect("permit accounts lock poisoned")
+    }
+
+    fn acquired(&self, kind: PermitKind, host: Option<&str>) {
+        let mut accounts = self.accounts();
+        accounts.held.push(Held {
+            kind,
+            host: host.map(str::to_string),
+            since: Instant::now(),
+            warned: false,
+        });
+        self.warn_overdue(&mut accounts);
+    }
+
+    /// Forgets the oldest permit of `kind` for `host`, or for any host if there is none.
+    fn released(&self, kind: PermitKind, host: Option<&str>) {
+        let mut accounts = self.accounts();
+        let position = accounts
+            .held
+            .iter()
+            .position(|held| held.kind == kind && held.host.as_deref() == host)
+            .or_else(|| accounts.held.iter().position(|held| held.kind == kind));
+        match position {
+            Some(position) => {
+                accounts.held.remove(position);
+            }
+            None => {
+                accounts.unmatched_releases += 1;
+                eprintln!(
+                    "warning: {:?} permit released for {}, but none was held",
+                    kind,
+                    host.unwrap_or("unknown host")
+                );
+            }
+        }
+        self.warn_overdue(&mut accounts);
+    }
+
+    fn warn_overdue(&self, accounts: &mut Accounts) {
+        let warn_after = match self.warn_after {
+            Some(warn_after) => warn_after,
+            None => return,
+        };
+        for held in accounts.held.iter_mut() {
+            if !held.warned && held.since.elapsed() > warn_after {
+                held.warned = true;
+                eprintln!(
+                    "warning: {:?} permit for {} held for more than {:?}",
+                    held.kind,
+                    held.host.as_deref().unwrap_or("unknown host"),
+                    warn_after
+                );
+            }
+        }
+    }
+}
+
+impl<T: ConnectionProps> ConnectionProps for InstrumentedConnectionProps<T> {
+    fn get_timeout(&self) -> u32 {
+        self.inner.get_timeout()
+    }
+
+    fn tcp_synchronization(&self) {
+        self.inner.tcp_synchronization();
+        self.acquired(PermitKind::Tcp, None);
+    }
+
+    fn agent_synchronization(&self) {
+        self.inner.agent_synchronization();
+        self.acquired(PermitKind::Agent, None);
+    }
+
+    fn tcp_release(&self) {
+        self.released(PermitKind::Tcp, None);
+        self.inner.tcp_release();
+    }
+
+    fn agent_release(&self) {
+        self.released(PermitKind::Agent, None);
+        self.inner.agent_release();
+    }
+
+    fn tcp_synchronization_for(&self, host: &str) {
+        self.inner.tcp_synchronization_for(host);
+        self.acquired(PermitKind::Tcp, Some(host));
+    }
+
+    fn agent_synchronization_for(&self, host: &str) {
+        self.inner.agent_synchronization_for(host);
+        self.acquired(PermitKind::Agent, Some(host));
+    }
+
+    fn tcp_release_for(&self, host: &str) {
+        self.released(PermitKind::Tcp, Some(host));
+        self.inner.tcp_release_for(host);
+    }
+
+    fn agent_release_for(&self, host: &str) {
+        self.released(PermitKind::Agent, Some(host));
+        self.inner.agent_release_for(host);
+    }
+
+    fn host_key_type_for(&self, host: &str) -> Option<HostKeyType> {
+        self.inner.host_key_type_for(host)
+    }
+
+    fn known_hosts_for(&self, host: &str) -> Option<PathBuf> {
+        self.inner.known_hosts_for(host)
+    }
+
+    fn resolver(&self) -> Option<&dyn Resolver> {
+        self.inner.resolver()
+    }
+
+    fn dns_timeout_for(&self, host: &str) -> Option<u32> {
+        self.inner.dns_timeout_for(host)
+    }
+
+    fn connect_timeout_for(&self, host: &str) -> Option<u32> {
+        self.inner.connect_timeout_for(host)
+    }
+
+    fn handshake_timeout_for(&self, host: &str) -> u32 {
+        self.inner.handshake_timeout_for(host)
+    }
+
+    fn auth_timeout_for(&self, host: &str) -> u32 {
+        self.inner.auth_timeout_for(host)
+    }
+
+    fn read_timeout_for(&self, host: &str) -> u32 {
+        self.inner.read_timeout_for(host)
+    }
+
+    fn channel_limit_for(&self, host: &str) -> usize {
+        self.inner.channel_limit_for(host)
+    }
+
+    fn channel_wait_for(&self, host: &str) -> Option<Duration> {
+        self.inner.channel_wait_for(host)
+    }
+
+    fn count_traffic_for(&self, host: &str) -> bool {
+        self.inner.count_traffic_for(host)
+    }
+}
@@ -0,0 +1,432 @@
+use crate::{AuthType, Host};
+use anyhow::Error;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+const DEFAULT_PORT: u16 = 22;
+/// Host variables with a meaning here, the legacy `ansible_ssh_` forms included
+const SUPPORTED_VARS: &[&str] = &[
+    "ansible_host",
+    "ansible_port",
+    "ansible_user",
+    "ansible_ssh_host",
+    "ansible_ssh_port",
+    "ansible_ssh_user",
+];
+
+/// Group of hosts, with the hosts and child groups declared for it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Group {
+    pub hosts: BTreeSet<String>,
+    pub children: BTreeSet<String>,
+    pub vars: BTreeMap<String, String>,
+}
+
+/// Host of an [`Inventory`], with the variables of its groups applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InventoryHost {
+    /// Name the inventory knows the host by.
+    pub name: String,
+    /// Where to connect, from `ansible_host` and `ansible_port`.
+    pub host: Host,
+    /// From `ansible_user`.
+    pub user: Option<String>,
+    /// From `canary`, see [`crate::CanaryPolicy`].
+    pub canary: bool,
+    pub vars: BTreeMap<String, String>,
+}
+
+impl InventoryHost {
+    /// Agent auth as the host's user, if it has one.
+    pub fn auth(&self) -> Option<AuthType> {
+        self.user.clone().map(AuthType::AgentFirst)
+    }
+}
+
+/// Hosts and the groups they are in.
+///
+/// Group `all` holds every host and `[all:vars]`. Host variables win over
+/// group variables, child groups over their parents, like ansible does it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Inventory {
+    hosts: BTreeMap<String, BTreeMap<String, String>>,
+    groups: BTreeMap<String, Group>,
+    warnings: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Section {
+    Hosts,
+    Children,
+    Vars,
+}
+
+impl Inventory {
+    /// Reads an ansible INI inventory, see [`Inventory::parse_ini`].
+    pub fn from_ini(path: &Path) -> Result<Inventory, Error> {
+        let content = fs::read_to_string(path)?;
+        Inventory::parse_ini(&content)
+            .map_err(|e| e.context(format!("Failed reading inventory {}", path.display())))
+    }
+
+    /// Parses the common subset of ansible INI inventories: groups of hosts
+    /// with `key=value` variables, ranges like `web[01:20]`, `host:port`,
+    /// `[group:children]` and `[group:vars]`.
+    ///
+    /// Variables of connection settings other than the host, port and user,
+    /// like `ansible_become`, are kept, but do nothing here. Each of them is
+    /// reported in [`Inventory::warnings`] instead of failing the whole inventory.
+    pub fn parse_ini(content: &str) -> Result<Inventory, Error> {
+        let mut inventory = Inventory::default();
+        let mut group = "ungrouped".to_string();
+        let mut section = Section::Hosts;
+        for (i, line) in content.lines().enumerate() {
+            let line_error = |message: String| Error::msg(format!("Line {}: {}", i + 1, message));
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[') {
+                let header = header
+                    .split_once(']')
+                    .map(|(header, _)| header.trim())
+                    .ok_or_else(|| line_error(format!("Unterminated section {}", line)))?;
+                let (name, kind) = match header.split_once(':') {
+                    Some((name, "children")) => (name, Section::Children),
+                    Some((name, "vars")) => (name, Section::Vars),
+                    Some((_, kind)) => {
+                        return Err(line_error(format!("Unknown section kind {}", kind)))
+                    }
+                    None => (header, Section::Hosts),
+                };
+                group = name.to_string();
+                section = kind;
+                inventory.groups.entry(group.clone()).or_default();
+                continue;
+            }
+            match section {
+                Section::Hosts => {
+                    let tokens = split_tokens(line).map_err(line_error)?;
+                    let (pattern, vars) = tokens.split_first().expect("line is not empty");
+                    let mut host_vars = BTreeMap::new();
+                    for var in vars {
+                        let (key, value) = var
+                            .split_once('=')
+                            .ok_or_else(|| line_error(format!("Expected key=value, got {}", var)))?;
+                        host_vars.insert(key.to_string(), value.to_string());
+                    }
+                    for name in expand_range(pattern).map_err(line_error)? {
+                        let (name, port) = split_port(&name);
+                        let own_vars = inventory.hosts.entry(name.to_string()).or_default();
+                        if let Some(port) = port {
+                            own_vars.insert("ansible_port".to_string(), port.to_string());
+                        }
+                        for (key, value) in &host_vars {
+                            let owner = format!("host {}", name);
+                            check_var(key, value, &owner, &mut inventory.warnings)
+                                .map_err(line_error)?;
+                            own_vars.insert(key.clone(), value.clone());
+                        }
+                        if group != "ungrouped" {
+                            let group = inventory.groups.entry(group.clone()).or_default();
+                            group.hosts.insert(name.to_string());
+                        }
+                    }
+                }
+                Section::Children => {
+                    let child = split_tokens(line).map_err(line_error)?.remove(0);
+                    inventory.groups.entry(child.clone()).or_default();
+                    let group = inventory.groups.entry(group.clone()).or_default();
+                    group.children.insert(child);
+                }
+                Section::Vars => {
+                    let (key, value) = line
+                        .split_once('=')
+                        .ok_or_else(|| line_error(format!("Expected key=value, got {}", line)))?;
+                    let key = key.trim();
+                    let value = unquote(value.trim()).map_err(line_error)?;
+                    let owner = format!("group {}", group);
+                    check_var(key, &value, &owner, &mut inventory.warnings).map_err(line_error)?;
+                    let group = inventory.groups.entry(group.clone()).or_default();
+                    group.vars.insert(key.to_string(), value);
+                }
+            }
+        }
+        inventory.groups.remove("ungrouped");
+        Ok(inventory)
+    }
+
+    /// Problems which didn't fail parsing, like unsupported variables.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Names of the groups, `all` is only listed if it has variables.
+    pub fn groups(&self) -> Vec<&str> {
+        self.groups.keys().map(String::as_str).collect()
+    }
+
+    pub fn group(&self, name: &str) -> Option<&Group> {
+        self.groups.get(name)
+    }
+
+    /// Every host, sorted by name.
+    pub fn hosts(&self) -> Vec<InventoryHost> {
+        self.hosts.keys().filter_map(|name| self.host(name)).collect()
+    }
+
+    pub fn host(&self, name: &str) -> Option<InventoryHost> {
+        let own_vars = self.hosts.get(name)?;
+        let mut groups: Vec<_> = self
+            .groups
+            .keys()
+            .filter(|group| self.contains(group, name, 0))
+            .map(|group| (self.depth(group, 0), group))
+            .collect();
+        groups.sort();
+        let mut vars = BTreeMap::new();
+        for (_, group) in groups {
+            vars.extend(self.groups[group].vars.clone());
+        }
+        vars.extend(own_vars.clone());
+        let var = |keys: &[&str]| keys.iter().find_map(|key| vars.get(*key)).cloned();
+        let address = var(&["ansible_host", "ansible_ssh_host"]);
+        let port = match var(&["ansible_port", "ansible_ssh_port"]) {
+            Some(port) => port.parse().ok()?,
+            None => DEFAULT_PORT,
+        };
+        let host = match address {
+            Some(address) if address != name => Host::named(name, &address, port),
+            _ => Host::new(name, port),
+        };
+        Some(InventoryHost {
+            name: name.to_string(),
+            host,
+            user: var(&["ansible_user", "ansible_ssh_user"]),
+            canary: var(&["canary"]).is_some_and(|canary| is_true(&canary)),
+            vars,
+        })
+    }
+
+    /// Hosts of a group and of its child groups, sorted by name.
+    pub fn group_hosts(&self, group: &str) -> Vec<InventoryHost> {
+        self.hosts
+            .keys()
+            .filter(|name| self.contains(group, name, 0))
+            .filter_map(|name| self.host(name))
+            .collect()
+    }
+
+    fn contains(&self, group: &str, host: &str, level: usize) -> bool {
+        if group == "all" {
+            return true;
+        }
+        match self.groups.get(group) {
+            // child groups may form a cycle, which ansible rejects
+            Some(group) if level <= self.groups.len() => {
+                group.hosts.contains(host)
+                    || group
+                        .children
+                        .iter()
+                        .any(|child| self.contains(child, host, level + 1))
+            }
+            _ => false,
+        }
+    }
+
+    /// Distance from `all`, deeper groups override the variables of shallower ones.
+    fn depth(&self, group: &str, level: usize) -> usize {
+        if group == "all" || level > self.groups.len() {
+            return 0;
+        }
+        self.groups
+            .iter()
+            .filter(|(_, parent)| parent.children.contains(group))
+            .map(|(parent, _)| self.depth(parent, level + 1))
+            .max()
+            .unwrap_or(0)
+            + 1
+    }
+
+    /// Writes the inventory back as INI, which parses into the same inventory.
+    /// Host variables are written with the first group of the host, or before
+    /// any group for hosts without one. Comments and ranges aren't preserved.
+    pub fn to_ini(&self) -> String {
+        let mut ini = String::new();
+        let mut written = BTreeSet::new();
+        let mut host_line = |ini: &mut String, name: &str| {
+            ini.push_str(name);
+            if written.insert(name.to_string()) {
+                for (key, value) in &self.hosts[name] {
+                    let _ = write!(ini, " {}={}", key, quote(value));
+                }
+            }
+            ini.push('\n');
+        };
+        for name in self.hosts.keys() {
+            if !self.groups.values().any(|group| group.hosts.contains(name)) {
+                host_line(&mut ini, name);
+            }
+        }
+        for (name, group) in &self.groups {
+            if name != "all" {
+                let _ = writeln!(ini, "\n[{}]", name);
+                for host in &group.hosts {
+                    host_line(&mut ini, host);
+                }
+            }
+            if !group.children.is_empty() {
+                let _ = writeln!(ini, "\n[{}:children]", name);
+                for child in &group.children {
+                    let _ = writeln!(ini, "{}", child);
+                }
+            }
+            if !group.vars.is_empty() {
+                let _ = writeln!(ini, "\n[{}:vars]", name);
+                for (key, value) in &group.vars {
+                    let _ = writeln!(ini, "{}={}", key, quote(value));
+                }
+            }
+        }
+        ini
+    }
+}
+
+/// Booleans as ansible reads them.
+fn is_true(value: &str) -> bool {
+    ["true", "yes", "on", "1"].iter().any(|word| value.eq_ignore_ascii_case(word))
+}
+
+fn is_false(value: &str) -> bool {
+    ["false", "no", "off", "0"].iter().any(|word| value.eq_ignore_ascii_case(word))
+}
+
+/// Checks a variable of `owner`, warning about connection settings which aren't supported.
+fn check_var(
+    key: &str,
+    value: &str,
+    owner: &str,
+    warnings: &mut Vec<String>,
+) -> Result<(), String> {
+    if key == "ansible_port" || key == "ansible_ssh_port" {
+        value
+            .parse::<u16>()
+            .map_err(|_| format!("Bad {} {} of {}", key, value, owner))?;
+    } else if key == "canary" && !is_true(value) && !is_false(value) {
+        return Err(format!("Bad canary {} of {}, expected true or false", value, owner));
+    } else if key.starts_with("ansible_") && !SUPPORTED_VARS.contains(&key) {
+        warnings.push(format!("{} of {} isn't supported, ignored", key, owner));
+    }
+    Ok(())
+}
+
+/// Splits a line on whitespace outside of quotes, removing the quotes.
+/// A `#` or `;` starting a token starts a comment.
+fn split_tokens(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut token: Option<String> = None;
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => token.get_or_insert_with(String::new).push(c),
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                token.get_or_insert_with(String::new);
+            }
+            (None, '#') | (None, ';') if token.is_none() => break,
+            (None, c) if c.is_whitespace() => tokens.extend(token.take()),
+            (None, c) => token.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(format!("Unterminated quote in {}", line));
+    }
+    tokens.extend(token);
+    Ok(tokens)
+}
+
+fn unquote(value: &str) -> Result<String, String> {
+    let mut tokens = split_tokens(value)?;
+    match tokens.len() {
+        0 => Ok(String::new()),
+        1 => Ok(tokens.remove(0)),
+        // an unquoted value with spaces
+        _ => Ok(value.to_string()),
+    }
+}
+
+fn quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && !value.contains(|c: char| c.is_whitespace() || c == '\'' || c == '"' || c == '#');
+    if plain {
+        value.to_string()
+    } else if value.contains('"') {
+        format!("'{}'", value)
+    } else {
+        format!("\"{}\"", value)
+    }
+}
+
+/// Splits `host:port`, leaving ipv6 addresses alone.
+fn split_port(name: &str) -> (&str, Option<u16>) {
+    match name.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => match port.parse() {
+            Ok(port) => (host, Some(port)),
+            Err(_) => (name, None),
+        },
+        _ => (name, None),
+    }
+}
+
+/// Expands ranges like `web[01:03]`, `db-[a:c]` or `node[0:10:5]`.
+/// Numeric ranges keep the width of their start, `[01:03]` gives `01`, `02` and `03`.
+pub(crate) fn expand_range(pattern: &str) -> Result<Vec<String>, String> {
+    let (prefix, rest) = match pattern.split_once('[') {
+        Some(split) => split,
+        None => return Ok(vec![pattern.to_string()]),
+    };
+    let (range, suffix) = rest
+        .split_once(']')
+        .ok_or_else(|| format!("Unterminated range in {}", pattern))?;
+    let bounds: Vec<&str> = range.split(':').collect();
+    let (start, end, stride) = match bounds[..] {
+        [start, end] => (start, end, "1"),
+        [start, end, stride] => (start, end, stride),
+        _ => return Err(format!("Expected [start:end] or [start:end:stride] in {}", pattern)),
+    };
+    let stride: usize = stride
+        .parse()
+        .ok()
+        .filter(|stride| *stride > 0)
+        .ok_or_else(|| format!("Bad range stride in {}", pattern))?;
+    let items: Vec<String> = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(first), Ok(last)) => (first..=last)
+            .step_by(stride)
+            .map(|i| format!("{:0width$}", i, width = start.len()))
+            .collect(),
+        _ => match (start.as_bytes(), end.as_bytes()) {
+            ([first], [last]) if first.is_ascii_alphabetic() && last.is_ascii_alphabetic() => {
+                (*first..=*last)
+                    .step_by(stride)
+                    .map(|c| (c as char).to_string())
+                    .collect()
+            }
+            _ => return Err(format!("Bad range [{}] in {}", range, pattern)),
+        },
+    };
+    if items.is_empty() {
+        return Err(format!("Empty range [{}] in {}", range, pattern));
+    }
+    let suffixes = expand_range(suffix)?;
+    Ok(items
+        .iter()
+        .flat_map(|item| {
+            suffixes
+                .iter()
+                .map(move |suffix| format!("{}{}{}", prefix, item, suffix))
+        })
+        .collect())
+}
@@ -1,2 +1,123 @@
+mod artifacts;
+mod audit;
+mod background;
+mod budget;
+mod builder;
+mod bundle;
+mod cancel;
+mod channels;
+mod checksum;
+mod conflict;
+mod connection;
+mod dedup;
+#[cfg(feature = "discovery")]
+mod discovery;
+mod disk;
+mod exclusion;
+mod exec;
+mod facts;
+#[cfg(feature = "testing")]
+mod fault;
+mod host;
+mod host_key;
+mod instrumented;
+mod inventory;
+mod lint;
+mod local;
+mod marker;
 mod modules;
-pub use modules::*;
+mod parse;
+mod pipe;
+#[cfg(feature = "discovery")]
+mod playbook;
+mod progress;
+mod redact;
+mod report;
+mod resolve;
+mod resume;
+mod retry;
+mod run_report;
+mod runner;
+mod schedule;
+mod schema;
+mod selftest;
+mod shell;
+mod sink;
+mod spec;
+mod state;
+mod target;
+mod template;
+mod traffic;
+mod units;
+mod upload;
+mod wait;
+#[cfg(feature = "http")]
+mod webhook;
+mod window;
+
+pub mod drift;
+pub mod errors;
+pub mod prelude;
+
+pub use anyhow::Error;
+pub use artifacts::LeftBehind;
+pub use audit::AuditEntry;
+pub use background::{background_command, BackgroundProcess, ProcessStatus};
+pub use budget::{BudgetStats, SpilledOutput};
+pub use builder::{CommandBuilder, ShellModuleBuilder};
+pub use bundle::{bundle_commands, split_bundled};
+pub use cancel::{CancelToken, Cancelled};
+pub use channels::{ChannelStats, DEFAULT_CHANNEL_LIMIT};
+pub use conflict::{Conflict, CONFLICTS};
+pub use connection::HostConnection;
+#[cfg(feature = "discovery")]
+pub use discovery::LoadError;
+pub use disk::{InsufficientDisk, RemoteDiskFull};
+pub use exclusion::{Exclusion, Exclusions, DEFAULT_EXCLUSIONS_FILE};
+pub use exec::{ExecError, DEFAULT_INLINE_LIMIT};
+pub use facts::{ClockFacts, DiskUsage, HostFacts, MemoryFacts, RawFacts};
+#[cfg(feature = "testing")]
+pub use fault::FaultInjector;
+pub use host::Host;
+pub use host_key::{HostKeyMismatch, HostKeyType};
+pub use instrumented::{HeldPermit, InstrumentedConnectionProps, PermitKind};
+pub use inventory::{Group, Inventory, InventoryHost};
+pub(crate) use lint::check_lint_ids;
+pub use lint::{Lint, LINTS};
+pub use modules::{
+    AuthRejected, AuthType, CommandOutput, CommandResult, ConnectionProps, DefaultConnectionProps, ItemResult,
+    Module, ModuleTree, OnError, Outcome, PromptResponse, ShellCommand, SkipReason,
+};
+pub use parse::{builtin_parser, parse_json, parse_key_value, parse_table, OutputParser};
+pub use pipe::{pump, pump_interactive, pump_tail, Duplex, InteractivePromptDetected, PumpOutput};
+#[cfg(feature = "discovery")]
+pub use playbook::{Play, PlayReport, Playbook};
+pub use progress::{UploadProgress, UploadStats};
+pub use redact::Redactor;
+pub use report::{
+    group_by_fingerprint, group_by_output, FingerprintGroup, FingerprintGroups, OutputGroup,
+};
+pub use resolve::{CachingResolver, DnsError, Resolver, StaticResolver, SystemResolver};
+pub use resume::{PairRecord, ResumedRun};
+pub use retry::{RetryAttempt, RetryHint};
+pub use run_report::{RunFailed, RunReport, DEFAULT_MAX_CONCURRENT_HOSTS};
+pub use runner::{
+    BatchEntry, CanaryGate, CanaryPolicy, CommandWrapper, ExecutionOptions, HostHooks, IdempotencyCheck, Limits, Runner,
+};
+pub use schedule::{Schedule, ScheduledJob};
+pub use schema::{parse_versioned, SCHEMA_VERSION};
+pub use selftest::{CheckStatus, SelftestCheck, SelftestReport};
+pub use shell::{check_env_name, check_umask, shell_quote, ShellSafe};
+pub use sink::{JsonlSink, ModuleFailure, ModuleRecord, ReportSink, Retain, RunSummary};
+pub use spec::RunSpec;
+pub use ssh2::Session;
+pub use state::{RegisterScope, StateStore, UnsetState};
+pub use target::TargetOs;
+pub use template::render_template;
+pub use traffic::TrafficStats;
+pub use units::{parse_duration, parse_size};
+pub use upload::{tar_dir, DirUpload, UploadStrategy, DEFAULT_TAR_MIN_FILES};
+pub use wait::{WaitFor, Waited};
+#[cfg(feature = "http")]
+pub use webhook::WebhookReporter;
+pub use window::MaintenanceWindow;
@@ -0,0 +1,235 @@
+use crate::modules::ModuleContent;
+use crate::template::is_variable;
+use crate::{Module, ModuleTree};
+use anyhow::Error;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+/// Commands longer than this may not fit into the argument of `sh -c`.
+const MAX_COMMAND_LENGTH: usize = 128 * 1024;
+
+/// Ids of every lint, with what they flag.
+pub const LINTS: &[(&str, &str)] = &[
+    ("AM001", "unquoted template variable, fails on values which aren't plain words"),
+    ("AM002", "trailing &, the command is left running and its output lost"),
+    ("AM003", "rm -r on a templated path"),
+    ("AM004", "cd not followed by &&, later commands run elsewhere if it fails"),
+    ("AM005", "command too long for one shell argument"),
+];
+
+/// Problem found in a shell command by [`ModuleTree::validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    pub id: &'static str,
+    pub command: String,
+    pub message: String,
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.id, self.command, self.message)
+    }
+}
+
+/// Fails for lint ids which don't exist.
+pub(crate) fn check_lint_ids(ids: &[String]) -> Result<(), Error> {
+    match ids.iter().find(|id| !LINTS.iter().any(|(known, _)| known == id)) {
+        Some(id) => Err(Error::msg(format!("Unknown lint {}", id))),
+        None => Ok(()),
+    }
+}
+
+/// Words of a command between two separators, and the separator ending them.
+struct Segment {
+    words: Vec<String>,
+    separator: Option<&'static str>,
+}
+
+/// Splits `cmd` into segments the way the shell would, as far as lints care,
+/// also returning the template variables outside of quotes.
+fn scan(cmd: &str) -> (Vec<Segment>, Vec<String>) {
+    let mut segments = Vec::new();
+    let mut unquoted = Vec::new();
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    let mut chars = cmd.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if quote.is_none() && cmd[i..].starts_with("{{") {
+            if let Some(end) = cmd[i..].find("}}") {
+                let name = cmd[i + 2..i + end].trim();
+                if is_variable(name) {
+                    unquoted.push(name.to_string());
+                }
+                word.push_str(&cmd[i..i + end + 2]);
+                while chars.peek().is_some_and(|(j, _)| *j < i + end + 2) {
+                    chars.next();
+                }
+                continue;
+            }
+        }
+        let separator = match (quote, c) {
+            (Some(q), c) if c == q => {
+                quote = None;
+                None
+            }
+            (Some('"'), '\\') | (None, '\\') => {
+                word.push(c);
+                if let Some((_, next)) = chars.next() {
+                    word.push(next);
+                }
+                continue;
+            }
+            (Some(_), _) => None,
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                None
+            }
+            (None, ';') => Some(";"),
+            (None, '\n') => Some("\n"),
+            (None, '&') | (None, '|') => {
+                let doubled = chars.peek().map(|(_, next)| *next) == Some(c);
+                if doubled {
+                    chars.next();
+                }
+                Some(match (c, doubled) {
+                    ('&', true) => "&&",
+                    ('&', false) => "&",
+                    (_, true) => "||",
+                    _ => "|",
+                })
+            }
+            (None, c) if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                continue;
+            }
+            _ => None,
+        };
+        match separator {
+            Some(separator) => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                segments.push(Segment {
+                    words: std::mem::take(&mut words),
+                    separator: Some(separator),
+                });
+            }
+            None => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    segments.push(Segment {
+        words,
+        separator: None,
+    });
+    (segments, unquoted)
+}
+
+/// Whether `words` remove recursively, as `rm -rf`, `rm -r -f` or `rm --recursive` do.
+fn is_recursive_rm(words: &[String]) -> bool {
+    words.first().map(String::as_str) == Some("rm")
+        && words[1..].iter().any(|word| {
+            word == "--recursive"
+                || (word.starts_with('-') && !word.starts_with("--") && word.contains(['r', 'R']))
+        })
+}
+
+/// Lints of a single command, ignored ones left out.
+fn lint_command(name: &str, cmd: &str, ignore: &[String]) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    let mut flag = |id: &'static str, message: String| {
+        if !ignore.iter().any(|ignored| ignored == id) {
+            lints.push(Lint {
+                id,
+                command: name.to_string(),
+                message,
+            });
+        }
+    };
+    let (segments, unquoted) = scan(cmd);
+    if !unquoted.is_empty() {
+        flag("AM001", format!("{{{{ {} }}}} should be quoted", unquoted.join(" }}, {{ ")));
+    }
+    let last = segments.iter().rposition(|segment| !segment.words.is_empty());
+    if let Some(last) = last {
+        if segments[last].separator == Some("&") {
+            flag("AM002", "ends with &, it is left running in the background".to_string());
+        }
+    }
+    for (i, segment) in segments.iter().enumerate() {
+        if is_recursive_rm(&segment.words)
+            && segment.words[1..].iter().any(|word| word.contains("{{"))
+        {
+            flag(
+                "AM003",
+                "rm -r on a templated path removes more than meant if the value is empty"
+                    .to_string(),
+            );
+        }
+        let followed = last.is_some_and(|last| i < last);
+        if segment.words.first().map(String::as_str) == Some("cd")
+            && matches!(segment.separator, Some(";") | Some("\n"))
+            && followed
+        {
+            flag("AM004", "cd should be followed by &&".to_string());
+        }
+    }
+    if cmd.len() > MAX_COMMAND_LENGTH {
+        flag(
+            "AM005",
+            format!("{} bytes, more than {} fit into one argument", cmd.len(), MAX_COMMAND_LENGTH),
+        );
+    }
+    lints
+}
+
+impl Module {
+    /// Lints of the module's shell commands, by command name, except those in
+    /// `lint_ignore`. See [`LINTS`] for what they flag.
+    pub fn lints(&self) -> Vec<Lint> {
+        let commands = match &*self.module_content {
+            ModuleContent::Shell(commands) => commands,
+            _ => return Vec::new(),
+        };
+        let mut commands: Vec<_> = commands.iter().collect();
+        commands.sort_by_key(|(name, _)| *name);
+        commands
+            .into_iter()
+            .flat_map(|(name, command)| lint_command(name, &command.cmd, &self.lint_ignore))
+            .collect()
+    }
+
+    /// With `strict_lints`, fails with the module's lints, if there are any.
+    pub(crate) fn check_lints(&self) -> Result<(), Error> {
+        let lints = if self.strict_lints {
+            self.lints()
+        } else {
+            Vec::new()
+        };
+        if lints.is_empty() {
+            return Ok(());
+        }
+        let lints: Vec<_> = lints.iter().map(Lint::to_string).collect();
+        Err(Error::msg(format!("Module fails lints: {}", lints.join("; "))))
+    }
+}
+
+impl ModuleTree {
+    /// Lints of every module which has some, by module name, see [`Module::lints`].
+    /// They are only reported, unless a module sets `strict_lints`, which makes
+    /// them fail loading it.
+    pub fn validate(&self) -> BTreeMap<&str, Vec<Lint>> {
+        self.module_names()
+            .into_iter()
+            .filter_map(|name| {
+                let lints = self.module(name)?.lints();
+                Some((name, lints)).filter(|(_, lints)| !lints.is_empty())
+            })
+            .collect()
+    }
+}
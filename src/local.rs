@@ -0,0 +1,109 @@
+use crate::modules::command_input;
+use crate::pipe::read_limited;
+use crate::shell::{shell_format, ShellSafe};
+use crate::{ExecutionOptions, Limits, Module, PumpOutput, ShellCommand};
+use anyhow::Error;
+use std::io::{self, Read};
+use std::process::{Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Reads `stream` up to `limit` on a thread, draining the rest so the child never blocks.
+fn collect<R: Read + Send + 'static>(
+    stream: Option<R>,
+    limit: Option<u64>,
+) -> JoinHandle<io::Result<(Vec<u8>, bool)>> {
+    thread::spawn(move || {
+        let mut stream = match stream {
+            Some(stream) => stream,
+            None => return Ok((Vec::new(), false)),
+        };
+        let read = read_limited(&mut stream, limit)?;
+        io::copy(&mut stream, &mut io::sink())?;
+        Ok(read)
+    })
+}
+
+/// Waits for `child` until `timeout`, killing it then. Returns whether it was killed.
+fn wait_until(child: &mut Child, timeout: Option<Duration>) -> Result<bool, Error> {
+    let deadline = match timeout {
+        Some(timeout) => Instant::now() + timeout,
+        None => {
+            child.wait()?;
+            return Ok(false);
+        }
+    };
+    while child.try_wait()?.is_none() {
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(true);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(false)
+}
+
+impl Module {
+    /// Runs a `local` command on the controller with `sh -c`, prefixed like a remote one
+    /// but without the command wrapper, in its `local_dir` if it has one.
+    pub(crate) fn run_local(
+        &self,
+        command_name: &str,
+        command: &ShellCommand,
+        options: &ExecutionOptions,
+        render: &dyn Fn(&str) -> Result<String, Error>,
+        limits: Limits,
+    ) -> Result<crate::CommandResult, Error> {
+        let mut cmd = options.prepare_local(&render(&command.cmd)?, self, command_name);
+        if command.merge_streams {
+            cmd = shell_format!("exec 2>&1; {}", ShellSafe::command(&cmd)).into_string();
+        }
+        let mut input = command_input(command, render, None)?;
+        let mut process = Command::new("sh");
+        process
+            .arg("-c")
+            .arg(&cmd)
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(dir) = &command.local_dir {
+            process.current_dir(dir);
+        }
+        let mut child = process
+            .spawn()
+            .map_err(|e| Error::msg(format!("Starting local command {}: {}", command_name, e)))?;
+        let stdout = collect(child.stdout.take(), limits.max_output);
+        let stderr = collect(child.stderr.take(), limits.max_output);
+        if let (Some(input), Some(mut stdin)) = (input.as_mut(), child.stdin.take()) {
+            // a command which exits without reading all of its input is fine
+            match io::copy(input, &mut stdin) {
+                Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+                _ => {}
+            }
+        }
+        let killed = wait_until(&mut child, limits.timeout)?;
+        let (stdout, stdout_truncated) = stdout.join().expect("stdout reader panicked")?;
+        let (stderr, stderr_truncated) = stderr.join().expect("stderr reader panicked")?;
+        let mut result = self.finish_command(
+            command_name,
+            command,
+            options,
+            limits,
+            PumpOutput {
+                stdout_bytes: stdout.len() as u64,
+                stderr_bytes: stderr.len() as u64,
+                stdout,
+                stderr,
+                truncated: stdout_truncated || stderr_truncated,
+                prompt: None,
+            },
+            child.try_wait()?.and_then(|status| status.code()),
+        )?;
+        if let (true, Some(timeout)) = (killed, limits.timeout) {
+            result.failure = Some(format!("local command timed out after {:?}", timeout));
+            result.changed = false;
+        }
+        Ok(result)
+    }
+}
@@ -0,0 +1,108 @@
+use crate::shell::{shell_format, ShellSafe};
+use crate::{AuthType, CommandOutput, ConnectionProps, Runner, SkipReason};
+use anyhow::Error;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::net::ToSocketAddrs;
+use std::thread;
+
+/// Path of the marker of `module_name` in `dir`.
+fn marker_path(dir: &str, module_name: &str) -> String {
+    format!("{}/{}.done", dir.trim_end_matches('/'), module_name.replace('/', "_"))
+}
+
+impl Runner {
+    /// Runs a module on every host concurrently, returning its output per host
+    /// (by `to_string()`), or why it couldn't run.
+    ///
+    /// With [`Runner::with_completion_markers`], hosts which completed the module in a
+    /// run with the same correlation id are skipped, with a [`SkipReason::AlreadyDone`]
+    /// error. A marker is written only when the module succeeded, failing to write it
+    /// fails the host. With [`Runner::with_host_dedup`], hosts which are the same
+    /// machine as an earlier one are skipped too, as are hosts in the [`crate::Exclusions`].
+    pub fn run_module_on_hosts<A>(
+        &self,
+        module_name: &str,
+        hosts: &[A],
+        auth: AuthType,
+        sync: &(dyn ConnectionProps + Sync),
+    ) -> Result<HashMap<String, Result<CommandOutput, Error>>, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        self.check_plan(&[module_name])?;
+        let module = self.tree().get_module(module_name)?;
+        let marker = match self.options().completion_markers() {
+            Some(dir) => {
+                self.refuse_if_read_only("completion markers")?;
+                Some(marker_path(dir, module_name))
+            }
+            None => None,
+        };
+        let id = self.correlation_id();
+        let exclusions = self.options().exclusions()?;
+        let included: Vec<A> = hosts
+            .iter()
+            .filter(|host| exclusions.matching(&host.to_string()).is_none())
+            .cloned()
+            .collect();
+        let duplicates = self.duplicate_hosts(&included, &auth, sync);
+        let run_on = |host: &A| {
+            if let Some(exclusion) = exclusions.matching(&host.to_string()) {
+                return self.record(&host.to_string(), module_name, Err(exclusion.skip_reason().into()));
+            }
+            if let Some(canonical) = duplicates.get(&host.to_string()) {
+                let skipped = SkipReason::DuplicateHost(canonical.clone());
+                return self.record(&host.to_string(), module_name, Err(skipped.into()));
+            }
+            let output = self.with_connection(host.clone(), auth.clone(), sync, |connection| {
+                if let Some(marker) = &marker {
+                    let check = shell_format!("cat {} 2>/dev/null", ShellSafe::path(marker));
+                    let (done, _, _) = self.run_command(connection, check.as_str())?;
+                    if done == id {
+                        return Ok(None);
+                    }
+                }
+                let output = module.execute_named_on(Some(module_name), connection, self.options())?;
+                if let Some(marker) = marker.as_ref().filter(|_| !output.is_failed()) {
+                    let dir = match marker.rsplit_once('/') {
+                        Some(("", _)) => "/",
+                        Some((dir, _)) => dir,
+                        None => ".",
+                    };
+                    let write = shell_format!(
+                        "mkdir -p {} && printf '%s' {} > {}",
+                        ShellSafe::path(dir),
+                        ShellSafe::quote(id),
+                        ShellSafe::path(marker)
+                    );
+                    let (_, stderr, status) = self.run_command(connection, write.as_str())?;
+                    if status != 0 {
+                        return Err(Error::msg(format!(
+                            "Writing completion marker {} failed: {}",
+                            marker,
+                            stderr.trim_end()
+                        )));
+                    }
+                }
+                Ok(Some(output))
+            });
+            let output = match output {
+                Ok(Some(output)) => Ok(output),
+                Ok(None) => Err(SkipReason::AlreadyDone(id.to_string()).into()),
+                Err(e) => Err(e),
+            };
+            self.record(&host.to_string(), module_name, output)
+        };
+        Ok(thread::scope(|scope| {
+            let handles: Vec<_> = hosts
+                .iter()
+                .map(|host| scope.spawn(move || (host.to_string(), run_on(host))))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("host thread panicked"))
+                .collect()
+        }))
+    }
+}
@@ -1,300 +1,2492 @@
+use crate::audit;
+use crate::background::background_command;
+use crate::budget::{OutputBudget, Reservation, Spill, SpilledOutput};
+use crate::bundle::bundle_token;
+use crate::checksum::{sha256_hex, Sha256Reader};
+use crate::channels::{OpenChannel, DEFAULT_CHANNEL_LIMIT};
+use crate::disk::{check_output, classify, upload_failed};
+use crate::facts::epoch_ms;
+use crate::exec::{exec_command, exec_staged, ExecError};
+use crate::pipe::{idle, read_timeout, InteractivePromptDetected, Transfer};
+use crate::progress::UploadReporter;
+use crate::retry::{RetryAttempt, RetryHint, MAX_RETRY_DELAY};
+use crate::state::render_state;
+use crate::target::is_absolute;
+use crate::shell::{shell_format, ShellSafe};
+use crate::template::raw_placeholders;
+use crate::window::window_clock;
+use crate::{
+    builtin_parser, bundle_commands, check_env_name, BackgroundProcess, check_lint_ids, check_umask, parse_size,
+    render_template, split_bundled, ExecutionOptions, HostConnection, HostKeyType, TargetOs,
+    Limits, MaintenanceWindow, OutputParser, PumpOutput, RegisterScope, Resolver, StaticResolver, WaitFor, Waited,
+};
 use anyhow::Error;
-use base64::encode;
-use serde::{Deserialize, Deserializer};
-use ssh2::Session;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use ssh2::{Channel, ExtendedData, Session};
 use std::collections::HashMap;
 use std::fmt::{Debug, Display};
 use std::fs::File;
-use std::io::Read;
-use std::net::{TcpStream, ToSocketAddrs};
+use std::io::{self, Cursor, Read};
+use std::net::ToSocketAddrs;
 use std::path::{Path, PathBuf};
-use toml::from_str;
-use walkdir::{DirEntry, WalkDir};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Single command of a shell module.
+/// Either a plain string or a table with options:
+/// ```toml
+/// first = "ls"
+/// [second]
+/// cmd = "make"
+/// merge_streams = true
+/// ```
+/// `merge_streams` asks the server to interleave stderr into stdout,
+/// so the output reads as it would in a terminal.
+/// `stdin` is written to the command's standard input.
+/// `stdin_file` streams a local file into it instead, chunk by chunk, e.g. a dump for
+/// `mysql`. A relative path is relative to the module tree.
+/// `capture` is a regex whose named groups are extracted from stdout,
+/// e.g. `capture = '(?P<free_pct>\d+)%'`.
+/// `require_output` fails the command, if it prints nothing but whitespace.
+/// `changed_when` is a regex, the command reports a change only if stdout matches it.
+/// Without it every run of the command counts as a change.
+/// `responses` answers expected prompts, see [`PromptResponse`].
+/// `parser` names a built-in output parser (`json`, `key_value` or `table`),
+/// overriding the module's `parser`.
+/// `read_only` declares that the command changes nothing, see [`crate::Runner::with_read_only`].
+/// `memoize` runs the command once per host and run: later modules sending the exact same
+/// command line over the same connection get the first successful result back, marked
+/// with [`SkipReason::Memoized`]. Memoized commands can't take stdin or register.
+/// `register_scope = "connection"` also keeps the captures of a successful run in the
+/// [`crate::StateStore`] of the connection, where templates of later modules read them
+/// as `{{ state.name }}`. Commands of the same module don't see each other's state.
+/// `tail` keeps only the last bytes of each stream, e.g. `tail = "64KiB"`, for huge logs
+/// where only the end matters. The rest is read and dropped, `max_output` doesn't apply.
+/// `local` runs the command on the controller instead, e.g. to build an artifact between
+/// remote steps. It gets the same env and limits, but not the command wrapper, and runs
+/// in `local_dir`, by default the directory of the module tree.
+/// `wait_for` polls a probe instead of running once, see [`crate::WaitFor`].
+/// Local commands and waits run in order of their names, between the remote commands
+/// sorting before and after them.
+/// `background` starts the command detached from the connection and doesn't wait for it,
+/// its result has a [`crate::BackgroundProcess`] to check on it or kill it later.
+/// Options which can't work together fail loading the module, see [`crate::CONFLICTS`].
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ShellCommand {
+    #[serde(default)]
+    pub(crate) cmd: String,
+    #[serde(default)]
+    pub(crate) merge_streams: bool,
+    #[serde(default)]
+    pub(crate) stdin: Option<String>,
+    #[serde(default)]
+    pub(crate) stdin_file: Option<PathBuf>,
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub(crate) capture: Option<Regex>,
+    #[serde(default)]
+    pub(crate) require_output: bool,
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub(crate) changed_when: Option<Regex>,
+    #[serde(default)]
+    pub(crate) parser: Option<String>,
+    #[serde(default)]
+    pub(crate) responses: Vec<PromptResponse>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub(crate) tail: Option<u64>,
+    #[serde(default)]
+    pub(crate) read_only: bool,
+    #[serde(default)]
+    pub(crate) memoize: bool,
+    #[serde(default)]
+    pub(crate) register_scope: RegisterScope,
+    #[serde(default)]
+    pub(crate) local: bool,
+    #[serde(default)]
+    pub(crate) local_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub(crate) wait_for: Option<WaitFor>,
+    #[serde(default)]
+    pub(crate) background: bool,
+    /// SHA-256 a verified tree listed for `stdin_file`, see [`check_sent`]
+    #[serde(skip)]
+    pub(crate) stdin_sha256: Option<String>,
+}
+
+/// Answer to a prompt a command is expected to ask:
+/// ```toml
+/// responses = [{ prompt = "continue?", answer = "y" }]
+/// ```
+/// `answer` and a newline are written to stdin whenever the current line
+/// of output contains `prompt`. Stdin is kept open for answers, so a command
+/// with `stdin` and `responses` never sees the end of its input.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PromptResponse {
+    pub prompt: String,
+    pub answer: String,
+}
+
+fn deserialize_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let size = String::deserialize(deserializer)?;
+    parse_size(&size).map(Some).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+impl ShellCommand {
+    pub fn new(cmd: &str) -> Self {
+        ShellCommand {
+            cmd: cmd.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Result of the command, had it printed `stdout` and `stderr`,
+    /// judged like every executor judges it, with its own `parser`.
+    pub fn evaluate(&self, stdout: &str, stderr: Option<&str>) -> Result<CommandResult, Error> {
+        let parser = self.parser.as_deref().map(builtin_parser).transpose()?;
+        let observed = Observed::new(stdout.to_string(), stderr.map(str::to_string));
+        Ok(evaluate(self, observed, parser, false))
+    }
+
+    fn prompt_responses(&self) -> Vec<(String, String)> {
+        self.responses
+            .iter()
+            .map(|response| (response.prompt.clone(), response.answer.clone()))
+            .collect()
+    }
+
+    /// Reason why a command with this output counts as failed, if it does.
+    pub fn failure(&self, stdout: &str) -> Option<String> {
+        if self.require_output && stdout.trim().is_empty() {
+            return Some("command produced no output".to_string());
+        }
+        None
+    }
+
+    /// Whether a command with this output changed something on the host.
+    pub fn changed(&self, stdout: &str) -> bool {
+        match &self.changed_when {
+            Some(regex) => regex.is_match(stdout),
+            None => true,
+        }
+    }
+
+    /// Named groups of the first `capture` match in `stdout`.
+    /// Groups which didn't participate in the match are omitted.
+    pub fn captures(&self, stdout: &str) -> HashMap<String, String> {
+        let regex = match &self.capture {
+            Some(regex) => regex,
+            None => return HashMap::new(),
+        };
+        let found = match regex.captures(stdout) {
+            Some(found) => found,
+            None => return HashMap::new(),
+        };
+        regex
+            .capture_names()
+            .flatten()
+            .filter_map(|name| {
+                found
+                    .name(name)
+                    .map(|m| (name.to_string(), m.as_str().to_string()))
+            })
+            .collect()
+    }
+}
+
+///Shell: Toml of modules
+/// like
+/// ```toml
+/// first = "ls"
+/// second = "uptime"
+/// ```
+/// Binary: Upload binary and execute
+/// Python:
+/// ```bash
+/// python -c python code
+/// ```
 
 #[derive(Debug,Clone)]
-enum ExecType {
-    Bin,
-    Python,
-    Bash,
+// binary and python modules are only loaded from files
+#[cfg_attr(not(feature = "discovery"), allow(dead_code))]
+pub(crate) enum ModuleContent {
+    Shell(HashMap<String, ShellCommand>),
+    /// With the SHA-256 a verified tree listed for the binary, see [`check_sent`]
+    Binary(PathBuf, Option<String>),
+    Python(String),
+}
+
+/// Output of a single command.
+/// `stderr` is `None` when the streams were merged into `stdout`.
+/// `exit_status` is the command's exit status, `None` where it isn't known:
+/// for bundled commands, commands killed by a signal and those given up on.
+/// `captures` holds the named groups of the command's `capture` regex.
+/// `failure` explains why the command is considered failed.
+/// `parsed` is stdout run through the command's output parser, if it has one.
+/// `warnings` are problems which didn't fail the command, like unparsable output.
+/// `stdout_bytes` and `stderr_bytes` count the bytes received, which is more than
+/// kept with a `tail`. `truncated` is set if an output limit cut them short.
+/// `skipped` is set if the command didn't run, e.g. its result was memoized.
+/// `uploaded` is set if it was too long to exec inline and ran from a file,
+/// see [`ExecutionOptions::inline_command_limit`].
+/// `waited` says how long a `wait_for` command polled.
+/// `redacted` is set if secrets were removed from the output,
+/// see [`crate::Runner::with_redacted_output`].
+/// `background` is the process a `background` command left running.
+/// `spilled` holds the files the output went to, `stdout` and `stderr` then only hold
+/// the start of it, see [`crate::Runner::with_output_budget`].
+/// `attempts` lists every run of a command which asked to be retried, see [`RetryHint`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CommandResult {
+    pub stdout: String,
+    pub stderr: Option<String>,
+    pub exit_status: Option<i32>,
+    pub captures: HashMap<String, String>,
+    pub failure: Option<String>,
+    pub changed: bool,
+    pub parsed: Option<Value>,
+    pub warnings: Vec<String>,
+    pub stdout_bytes: u64,
+    pub stderr_bytes: u64,
+    pub truncated: bool,
+    pub skipped: Option<SkipReason>,
+    pub uploaded: bool,
+    pub waited: Option<Waited>,
+    pub redacted: bool,
+    pub background: Option<BackgroundProcess>,
+    pub spilled: Option<SpilledOutput>,
+    pub attempts: Vec<RetryAttempt>,
 }
-impl From<&str> for ExecType {
-    fn from(a: &str) -> Self {
-        match a.to_lowercase().as_str() {
-            "bin" => Self::Bin,
-            "bash" => Self::Bash,
-            "sh" => Self::Bash,
-            "py" => Self::Python,
-            "python" => Self::Python,
-            _ => panic!(format!("Bad module type provided: {}", a)),
+
+impl CommandResult {
+    pub fn is_failed(&self) -> bool {
+        self.failure.is_some()
+    }
+
+    pub fn outcome(&self) -> Outcome {
+        if self.is_failed() {
+            Outcome::Failed
+        } else if !self.warnings.is_empty() {
+            Outcome::Warning
+        } else if self.changed {
+            Outcome::Changed
+        } else {
+            Outcome::Ok
+        }
+    }
+
+    /// Result of a command which didn't run, for `reason`.
+    fn skipped(reason: SkipReason) -> CommandResult {
+        CommandResult {
+            changed: false,
+            skipped: Some(reason),
+            ..evaluate(&ShellCommand::new(""), Observed::new(String::new(), None), None, false)
+        }
+    }
+
+    /// Result of a script or binary module, which is judged by its exit status alone.
+    /// Fails it too if its output exceeded `max_output`.
+    fn of_script(output: PumpOutput, exit_status: Option<i32>, max_output: Option<u64>) -> CommandResult {
+        let failure = match max_output {
+            Some(max_output) if output.truncated => Some(format!("script output exceeded {} bytes", max_output)),
+            _ => None,
+        };
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        let observed = Observed {
+            stdout_bytes: output.stdout_bytes,
+            stderr_bytes: output.stderr_bytes,
+            truncated: output.truncated,
+            exit_status,
+            failure,
+            ..Observed::new(stdout, Some(stderr))
+        };
+        let mut result = evaluate(&ShellCommand::new(""), observed, None, false);
+        result.fail_on_status();
+        result
+    }
+
+    /// Fails the result if the command exited non-zero.
+    fn fail_on_status(&mut self) {
+        if let Some(status) = self.exit_status.filter(|status| *status != 0) {
+            self.failure.get_or_insert_with(|| format!("exited with status {}", status));
+            self.changed = false;
+        }
+    }
+
+    /// Copy of a memoized result, standing in for a command which didn't run.
+    fn memoized_from(&self, origin: &str) -> CommandResult {
+        CommandResult {
+            changed: false,
+            skipped: Some(SkipReason::Memoized(origin.to_string())),
+            ..self.clone()
         }
     }
 }
 
-impl<'de> Deserialize<'de> for ExecType {
-    fn deserialize<D>(deserializer: D) -> Result<Self, <D as Deserializer<'de>>::Error>
+/// Why work was skipped instead of run.
+/// Errors carry it, find it with `downcast_ref::<SkipReason>()`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SkipReason {
+    /// A module this one depends on failed, or was skipped itself.
+    DependencyFailed(String),
+    /// The host has the completion marker of this run id, see
+    /// [`crate::Runner::with_completion_markers`].
+    AlreadyDone(String),
+    /// The same command already ran on the host in this run, as `module/command`.
+    Memoized(String),
+    /// The host is the same machine as this host, listed before it,
+    /// see [`crate::Runner::with_host_dedup`].
+    DuplicateHost(String),
+    /// This earlier command of a module with `fail_fast` exited non-zero.
+    FailedFast(String),
+    /// The module has a [`crate::MaintenanceWindow`] which isn't open, it opens next
+    /// at `next_window`, in its local time.
+    OutsideMaintenanceWindow { next_window: String },
+    /// The module uploads files, and the host ran out of space earlier on the connection,
+    /// as the [`crate::RemoteDiskFull`] says.
+    DiskFull(String),
+    /// A canary of the play failed, or the [`crate::Runner::with_canary_gate`] hook
+    /// declined the play if it lists none, see [`crate::CanaryPolicy`].
+    CanaryFailed(Vec<String>),
+    /// The host is parked in the operators' exclusions file, see [`crate::Exclusions`],
+    /// with the comment of its line, until `expires` if it says.
+    ExcludedByOperator { reason: Option<String>, expires: Option<String> },
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::DependencyFailed(module) => write!(f, "skipped: dependency {} failed", module),
+            SkipReason::AlreadyDone(id) => write!(f, "skipped: already done in run {}", id),
+            SkipReason::Memoized(origin) => write!(f, "skipped: memoized from {}", origin),
+            SkipReason::DuplicateHost(canonical) => write!(f, "skipped: same host as {}", canonical),
+            SkipReason::FailedFast(command) => write!(f, "skipped: command {} failed", command),
+            SkipReason::OutsideMaintenanceWindow { next_window } => {
+                write!(f, "skipped: outside the maintenance window, which opens {}", next_window)
+            }
+            SkipReason::DiskFull(full) => write!(f, "skipped: {}", full),
+            SkipReason::CanaryFailed(canaries) if canaries.is_empty() => {
+                write!(f, "skipped: the canaries were declined")
+            }
+            SkipReason::CanaryFailed(canaries) => write!(f, "skipped: canary {} failed", canaries.join(", ")),
+            SkipReason::ExcludedByOperator { reason, expires } => {
+                write!(f, "skipped: excluded by the operators")?;
+                if let Some(reason) = reason {
+                    write!(f, " for {}", reason)?;
+                }
+                match expires {
+                    Some(expires) => write!(f, " until {}", expires),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for SkipReason {}
+
+/// Verdict on a command, ordered from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Outcome {
+    Ok,
+    Changed,
+    Warning,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+/// Results of a shell module's commands, by name, or the result of a python
+/// or binary module, which failed if it exited non-zero.
+pub enum CommandOutput {
+    Multi(HashMap<String, CommandResult>),
+    Single(Box<CommandResult>),
+}
+
+impl CommandOutput {
+    pub fn is_failed(&self) -> bool {
+        match self {
+            CommandOutput::Multi(map) => map.values().any(CommandResult::is_failed),
+            CommandOutput::Single(result) => result.is_failed(),
+        }
+    }
+
+    pub fn is_changed(&self) -> bool {
+        match self {
+            CommandOutput::Multi(map) => map.values().any(|res| res.changed),
+            CommandOutput::Single(result) => result.changed,
+        }
+    }
+
+    /// Worst outcome of all commands.
+    pub fn outcome(&self) -> Outcome {
+        match self {
+            CommandOutput::Multi(map) => map
+                .values()
+                .map(CommandResult::outcome)
+                .max()
+                .unwrap_or(Outcome::Ok),
+            CommandOutput::Single(result) => result.outcome(),
+        }
+    }
+
+    /// Turns failed commands into an error naming each of them with its failure,
+    /// for pipelines which stop at the first failed module.
+    pub fn into_result(self) -> Result<CommandOutput, Error> {
+        let map = match &self {
+            CommandOutput::Multi(map) => map,
+            CommandOutput::Single(result) => match &result.failure {
+                Some(failure) => return Err(Error::msg(format!("module failed: {}", failure))),
+                None => return Ok(self),
+            },
+        };
+        let mut failed: Vec<_> = map
+            .iter()
+            .filter_map(|(name, res)| res.failure.as_ref().map(|failure| (name, failure)))
+            .collect();
+        if failed.is_empty() {
+            return Ok(self);
+        }
+        failed.sort();
+        Err(Error::msg(format!(
+            "{} of {} commands failed: {}",
+            failed.len(),
+            map.len(),
+            failed
+                .iter()
+                .map(|(name, failure)| format!("{} ({})", name, failure))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )))
+    }
+}
+
+/// What to do with the remaining work once something failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    Abort,
+    Continue,
+}
+
+/// Output of one iteration of [`Module::execute_foreach`].
+pub struct ItemResult {
+    pub index: usize,
+    pub item: HashMap<String, String>,
+    pub output: Result<CommandOutput, Error>,
+}
+
+#[derive(Debug,Clone)]
+pub struct Module {
+    pub(crate) module_content: Arc<ModuleContent>,
+    pub(crate) env: HashMap<String, String>,
+    pub(crate) max_output: Option<u64>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) bundle: bool,
+    pub(crate) umask: Option<String>,
+    /// Remote directory binary modules are uploaded to, `/tmp` by default
+    pub(crate) remote_dir: Option<String>,
+    /// Mode of uploaded binary modules, `0700` by default
+    pub(crate) file_mode: Option<i32>,
+    /// Arguments binary modules are run with
+    pub(crate) args: Vec<String>,
+    /// Stops a shell module at the first command which fails or exits non-zero
+    pub(crate) fail_fast: bool,
+    /// When the module may run, any time if unset
+    pub(crate) window: Option<MaintenanceWindow>,
+    /// When commands run again instead of failing
+    pub(crate) retry_hint: Option<RetryHint>,
+    pub(crate) locale: Option<String>,
+    pub(crate) parser: Option<String>,
+    pub(crate) depends_on: Vec<String>,
+    pub(crate) normalize: Vec<Regex>,
+    pub(crate) read_only: bool,
+    pub(crate) lint_ignore: Vec<String>,
+    pub(crate) strict_lints: bool,
+    pub(crate) deprecated: bool,
+    pub(crate) replaced_by: Option<String>,
+}
+
+/// Host refusing the credentials of an [`AuthType`] for `username`. `method` is what was
+/// offered, like `agent key deploy` or `password`, `message` what libssh2 said.
+/// Errors carry it, find it with `downcast_ref::<AuthRejected>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthRejected {
+    pub username: String,
+    pub method: String,
+    pub message: String,
+}
+
+impl Display for AuthRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} was rejected for {}: {}", self.method, self.username, self.message)
+    }
+}
+
+impl std::error::Error for AuthRejected {}
+
+/// How to authenticate to hosts, as the user named first.
+#[derive(Clone)]
+pub enum AuthType {
+    /// With the first key of the ssh-agent which the host accepts.
+    AgentFirst(String),
+    /// With the key of the ssh-agent whose comment is the key name.
+    AgentWithKeyName(String, String),
+    /// With a private key file, whose public key is derived from it.
+    KeyFile {
+        username: String,
+        private_key: PathBuf,
+        passphrase: Option<String>,
+    },
+    /// With a password, the second field, for hosts which allow password authentication.
+    Password(String, String),
+}
+
+impl Debug for AuthType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthType::AgentFirst(username) => f.debug_tuple("AgentFirst").field(username).finish(),
+            AuthType::AgentWithKeyName(username, key) => {
+                f.debug_tuple("AgentWithKeyName").field(username).field(key).finish()
+            }
+            AuthType::KeyFile {
+                username,
+                private_key,
+                passphrase,
+            } => f
+                .debug_struct("KeyFile")
+                .field("username", username)
+                .field("private_key", private_key)
+                .field("passphrase", &passphrase.as_ref().map(|_| "<redacted>"))
+                .finish(),
+            AuthType::Password(username, _) => {
+                f.debug_tuple("Password").field(username).field(&"<redacted>").finish()
+            }
+        }
+    }
+}
+
+impl AuthType {
+    /// Parses how to authenticate from a config string:
+    /// - `agent:<user>`, with the first agent key the host accepts
+    /// - `agent:<user>:<key name>`, with the agent key of that comment
+    /// - `key:<user>:<path>`, with a private key file without a passphrase
+    /// - `password:<user>:<password>`, with a password, where the host allows that
+    pub fn parse(spec: &str) -> Result<AuthType, Error> {
+        let mut parts = spec.splitn(3, ':');
+        let (mode, username, rest) = (parts.next(), parts.next(), parts.next());
+        let username = match username.filter(|username| !username.is_empty()) {
+            Some(username) => username.to_string(),
+            None => return Err(Error::msg(format!("Auth {} names no user", spec))),
+        };
+        match (mode, rest) {
+            (Some("agent"), None) => Ok(AuthType::AgentFirst(username)),
+            (Some("agent"), Some(key)) => Ok(AuthType::AgentWithKeyName(username, key.to_string())),
+            (Some("key"), Some(path)) => Ok(AuthType::KeyFile {
+                username,
+                private_key: PathBuf::from(path),
+                passphrase: None,
+            }),
+            (Some("password"), Some(password)) => Ok(AuthType::Password(username, password.to_string())),
+            (Some(mode @ ("key" | "password")), None) => Err(Error::msg(format!(
+                "Auth {}:{} needs a {}",
+                mode,
+                username,
+                if mode == "key" { "key file" } else { "password" }
+            ))),
+            _ => Err(Error::msg(format!(
+                "Unknown auth {}, expected agent, key or password",
+                mode.unwrap_or_default()
+            ))),
+        }
+    }
+
+    pub fn auth(&self, sess: &Session) -> Result<(), Error> {
+        let rejected = |username: &str, method: String| {
+            let username = username.to_string();
+            move |e: ssh2::Error| AuthRejected {
+                username,
+                method,
+                message: e.message().to_string(),
+            }
+        };
+        match self {
+            AuthType::AgentFirst(username) => {
+                sess.userauth_agent(username)
+                    .map_err(rejected(username, "Every ssh-agent key".to_string()))?;
+            }
+            AuthType::AgentWithKeyName(username, key) => {
+                let mut agent = sess.agent()?;
+                agent.connect()?;
+                agent.list_identities()?;
+                let identities = agent.identities()?;
+                let identity = identities.iter().find(|identity| identity.comment() == key);
+                let identity = match identity {
+                    Some(identity) => identity,
+                    None => {
+                        let known: Vec<_> = identities.iter().map(|identity| identity.comment()).collect();
+                        return Err(Error::msg(format!(
+                            "The ssh-agent has no key {}, only: {}",
+                            key,
+                            known.join(", ")
+                        )));
+                    }
+                };
+                let authenticated = agent.userauth(username, identity);
+                let _ = agent.disconnect();
+                authenticated.map_err(rejected(username, format!("Agent key {}", key)))?;
+            }
+            AuthType::KeyFile {
+                username,
+                private_key,
+                passphrase,
+            } => {
+                if !private_key.is_file() {
+                    return Err(Error::msg(format!(
+                        "Private key {} doesn't exist",
+                        private_key.display()
+                    )));
+                }
+                sess.userauth_pubkey_file(username, None, private_key, passphrase.as_deref())
+                    .map_err(rejected(username, format!("Key {} (or its passphrase)", private_key.display())))?;
+            }
+            AuthType::Password(username, password) => {
+                sess.userauth_password(username, password)
+                    .map_err(rejected(username, "Password".to_string()))?;
+            }
+        };
+        Ok(())
+    }
+}
+
+pub trait ConnectionProps {
+    /// Session timeout in milliseconds, of every phase without a timeout of its own.
+    fn get_timeout(&self) -> u32;
+    fn tcp_synchronization(&self);
+    fn agent_synchronization(&self);
+    fn tcp_release(&self);
+    fn agent_release(&self);
+
+    /// The crate takes and releases every permit through the `_for` methods,
+    /// naming the host it is for. By default they ignore the host.
+    fn tcp_synchronization_for(&self, _host: &str) {
+        self.tcp_synchronization()
+    }
+
+    fn agent_synchronization_for(&self, _host: &str) {
+        self.agent_synchronization()
+    }
+
+    fn tcp_release_for(&self, _host: &str) {
+        self.tcp_release()
+    }
+
+    fn agent_release_for(&self, _host: &str) {
+        self.agent_release()
+    }
+
+    /// Host key type the handshake with `host` is restricted to. The host must offer it.
+    fn host_key_type_for(&self, _host: &str) -> Option<HostKeyType> {
+        None
+    }
+
+    /// OpenSSH `known_hosts` file to verify the key of `host` with.
+    /// By default host keys aren't verified.
+    fn known_hosts_for(&self, _host: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// Resolves hosts instead of the host's own [`std::net::ToSocketAddrs`].
+    fn resolver(&self) -> Option<&dyn Resolver> {
+        None
+    }
+
+    /// Timeouts in milliseconds of the phases of connecting to `host`.
+    /// By default name resolution and tcp connects wait as long as the system lets them,
+    /// and the others take [`ConnectionProps::get_timeout`].
+    /// A resolution which times out fails with a [`crate::DnsError::Timeout`].
+    fn dns_timeout_for(&self, _host: &str) -> Option<u32> {
+        None
+    }
+
+    fn connect_timeout_for(&self, _host: &str) -> Option<u32> {
+        None
+    }
+
+    fn handshake_timeout_for(&self, _host: &str) -> u32 {
+        self.get_timeout()
+    }
+
+    fn auth_timeout_for(&self, _host: &str) -> u32 {
+        self.get_timeout()
+    }
+
+    /// Timeout of reading from the session once connected.
+    fn read_timeout_for(&self, _host: &str) -> u32 {
+        self.get_timeout()
+    }
+
+    /// Channels a connection to `host` keeps open at once, more wait for one to close.
+    fn channel_limit_for(&self, _host: &str) -> usize {
+        DEFAULT_CHANNEL_LIMIT
+    }
+
+    /// How long a channel may wait for another one to close before it is opened over
+    /// a second connection to `host` instead. By default channels wait as long as it takes.
+    /// The second connection is opened right after the first, through the same permits,
+    /// and closed along with it.
+    fn channel_wait_for(&self, _host: &str) -> Option<Duration> {
+        None
+    }
+
+    /// Whether connections to `host` count their traffic, see
+    /// [`HostConnection::traffic_stats`]. Counting puts a loopback relay with two threads
+    /// between the session and the host, so it is off by default.
+    fn count_traffic_for(&self, _host: &str) -> bool {
+        false
+    }
+}
+
+/// [`ConnectionProps`] without any synchronization, for single host runs.
+#[derive(Debug, Clone)]
+pub struct DefaultConnectionProps {
+    /// Session timeout in milliseconds
+    pub timeout: u32,
+    /// Timeouts of the phases of connecting, in milliseconds, instead of `timeout`
+    pub dns_timeout: Option<u32>,
+    pub connect_timeout: Option<u32>,
+    pub handshake_timeout: Option<u32>,
+    pub auth_timeout: Option<u32>,
+    pub read_timeout: Option<u32>,
+    /// Host key type to pin for every host
+    pub host_key_type: Option<HostKeyType>,
+    /// `known_hosts` file to verify host keys with
+    pub known_hosts: Option<PathBuf>,
+    /// Addresses to connect to instead of what the system resolves
+    pub resolve: StaticResolver,
+    /// Channels per connection, [`DEFAULT_CHANNEL_LIMIT`] if unset
+    pub channel_limit: Option<usize>,
+    /// See [`ConnectionProps::channel_wait_for`]
+    pub channel_wait: Option<Duration>,
+    /// See [`ConnectionProps::count_traffic_for`]
+    pub count_traffic: bool,
+}
+
+impl Default for DefaultConnectionProps {
+    fn default() -> Self {
+        DefaultConnectionProps {
+            timeout: 60_000,
+            dns_timeout: None,
+            connect_timeout: None,
+            handshake_timeout: None,
+            auth_timeout: None,
+            read_timeout: None,
+            host_key_type: None,
+            known_hosts: None,
+            resolve: StaticResolver::new(),
+            channel_limit: None,
+            channel_wait: None,
+            count_traffic: false,
+        }
+    }
+}
+
+impl ConnectionProps for DefaultConnectionProps {
+    fn get_timeout(&self) -> u32 {
+        self.timeout
+    }
+    fn tcp_synchronization(&self) {}
+    fn agent_synchronization(&self) {}
+    fn tcp_release(&self) {}
+    fn agent_release(&self) {}
+
+    fn host_key_type_for(&self, _host: &str) -> Option<HostKeyType> {
+        self.host_key_type
+    }
+
+    fn known_hosts_for(&self, _host: &str) -> Option<PathBuf> {
+        self.known_hosts.clone()
+    }
+
+    fn resolver(&self) -> Option<&dyn Resolver> {
+        if self.resolve.is_empty() {
+            None
+        } else {
+            Some(&self.resolve)
+        }
+    }
+
+    fn dns_timeout_for(&self, _host: &str) -> Option<u32> {
+        self.dns_timeout
+    }
+
+    fn connect_timeout_for(&self, _host: &str) -> Option<u32> {
+        self.connect_timeout
+    }
+
+    fn handshake_timeout_for(&self, _host: &str) -> u32 {
+        self.handshake_timeout.unwrap_or(self.timeout)
+    }
+
+    fn auth_timeout_for(&self, _host: &str) -> u32 {
+        self.auth_timeout.unwrap_or(self.timeout)
+    }
+
+    fn read_timeout_for(&self, _host: &str) -> u32 {
+        self.read_timeout.unwrap_or(self.timeout)
+    }
+
+    fn channel_limit_for(&self, _host: &str) -> usize {
+        self.channel_limit.unwrap_or(DEFAULT_CHANNEL_LIMIT)
+    }
+
+    fn channel_wait_for(&self, _host: &str) -> Option<Duration> {
+        self.channel_wait
+    }
+
+    fn count_traffic_for(&self, _host: &str) -> bool {
+        self.count_traffic
+    }
+}
+
+/// Whether the host refused a channel as one too many, see [`ExecError::TooManyChannels`].
+fn is_too_many_channels(e: &Error) -> bool {
+    matches!(e.downcast_ref::<ExecError>(), Some(ExecError::TooManyChannels { .. }))
+}
+
+/// Commands of a shell module to run, borrowed from it, by name.
+pub(crate) type Commands<'a> = HashMap<&'a str, &'a ShellCommand>;
+
+/// `commands` in the order they run in, the order of their names.
+fn sorted_commands<'a>(commands: &Commands<'a>) -> Vec<(&'a str, &'a ShellCommand)> {
+    let mut sorted: Vec<_> = commands.iter().map(|(name, command)| (*name, *command)).collect();
+    sorted.sort_by_key(|(name, _)| *name);
+    sorted
+}
+
+/// What to write to a command's stdin: rendered `stdin`, its values put in as they are, or `stdin_file` opened for streaming,
+/// which reports to `uploads` if there is one. A `stdin_file` of a verified tree is read
+/// and checked up front instead, so what is written is what was checked.
+pub(crate) fn command_input(
+    command: &ShellCommand,
+    render: &dyn Fn(&str) -> Result<String, Error>,
+    uploads: Option<&UploadReporter>,
+) -> Result<Option<Box<dyn Read>>, Error> {
+    if let Some(path) = &command.stdin_file {
+        let open = |path: &Path| -> io::Result<(File, u64)> {
+            let file = File::open(path)?;
+            let total = file.metadata()?.len();
+            Ok((file, total))
+        };
+        let (mut file, total) = open(path)
+            .map_err(|e| Error::msg(format!("Opening stdin_file {}: {}", path.display(), e)))?;
+        let input: Box<dyn Read> = match &command.stdin_sha256 {
+            Some(expected) => {
+                let mut content = Vec::with_capacity(total as usize);
+                file.read_to_end(&mut content)
+                    .map_err(|e| Error::msg(format!("Reading stdin_file {}: {}", path.display(), e)))?;
+                check_sent(path, Some(expected), sha256_hex(&content))?;
+                Box::new(Cursor::new(content))
+            }
+            None => Box::new(file),
+        };
+        return Ok(Some(match uploads {
+            Some(uploads) => Box::new(uploads.track(&path.display().to_string(), total, input)),
+            None => input,
+        }));
+    }
+    Ok(match &command.stdin {
+        Some(stdin) => Some(Box::new(Cursor::new(render(&raw_placeholders(stdin))?.into_bytes()))),
+        None => None,
+    })
+}
+
+/// Fails with a [`crate::LoadError::ChecksumMismatch`] if `actual`, the SHA-256 of what
+/// was sent of `path`, isn't the `expected` one a verified tree listed for it: the file
+/// changed since the tree was loaded.
+#[cfg_attr(not(feature = "discovery"), allow(unused_variables))]
+fn check_sent(path: &Path, expected: Option<&str>, actual: String) -> Result<(), Error> {
+    match expected {
+        #[cfg(feature = "discovery")]
+        Some(expected) if expected != actual => Err(crate::LoadError::ChecksumMismatch {
+            path: path.to_path_buf(),
+            expected: expected.to_string(),
+            actual,
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Exit status of the command on `channel`, once its `output` was read to the end.
+/// `None` for a command which was given up on, as it may still be running. The channel
+/// of a command waiting at a prompt is closed, so it doesn't wait any longer.
+fn exit_status(channel: &mut Channel, output: &PumpOutput) -> Result<Option<i32>, Error> {
+    if output.prompt.is_some() {
+        let _ = channel.close();
+        return Ok(None);
+    }
+    if output.truncated {
+        return Ok(None);
+    }
+    channel.wait_close()?;
+    Ok(Some(channel.exit_status()?))
+}
+
+/// 64-bit FNV-1a of `content` in hex, a short hash which is stable across builds.
+pub(crate) fn fnv1a_hex(content: &str) -> String {
+    let hash = content.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Reads stdout and stderr of a command side by side, keeping at most `limit` bytes of
+/// each. Reading one to the end first would stall a remote filling the window of the other.
+pub(crate) fn read_channel(
+    channel: &mut OpenChannel,
+    limit: Option<u64>,
+) -> Result<(Vec<u8>, Vec<u8>, bool), Error> {
+    let (session, channel) = channel.split();
+    session.set_blocking(false);
+    let output = Transfer::<io::Empty>::new(None, limit).run_within(channel, read_timeout(session));
+    session.set_blocking(true);
+    let output = output?;
+    Ok((output.stdout, output.stderr, output.truncated))
+}
+
+/// Whether the output of `command` is looked at while it runs, for a `tail`, prompts or
+/// its input, so it has to be kept in memory rather than spilled.
+fn watches_output(command: &ShellCommand, options: &ExecutionOptions) -> bool {
+    command.stdin.is_some()
+        || command.stdin_file.is_some()
+        || command.tail.is_some()
+        || !command.responses.is_empty()
+        || options.prompt_timeout.is_some()
+}
+
+/// Reserves the output budget of `options`, if there is one, for a command which keeps
+/// its output in memory, waiting for room, see [`crate::budget::OutputBudget::reserve_waiting`].
+/// Returns the limits to run it with. The reservation is to be held until the output
+/// became a result.
+fn reserve_in_memory(options: &ExecutionOptions, limits: Limits) -> (Option<Reservation<'_>>, Limits) {
+    match options.output_budget() {
+        Some(budget) => {
+            let (reservation, max_output) = budget.reserve_waiting(limits.max_output);
+            (Some(reservation), Limits { max_output, ..limits })
+        }
+        None => (None, limits),
+    }
+}
+
+/// Reads the output of a command which isn't watched while it runs, see [`watches_output`],
+/// keeping at most `limit` bytes of each stream: into new files of `spill` if given,
+/// into memory otherwise. Returns the files it went to, and whether any was redacted.
+fn read_unwatched(
+    channel: &mut OpenChannel,
+    spill: Option<&OutputBudget>,
+    merged: bool,
+    limit: Option<u64>,
+    options: &ExecutionOptions,
+) -> Result<(PumpOutput, Option<SpilledOutput>, bool), Error> {
+    let budget = match spill {
+        Some(budget) => budget,
+        None => {
+            let (stdout, stderr, truncated) = read_channel(channel, limit)?;
+            let output = PumpOutput {
+                stdout_bytes: stdout.len() as u64,
+                stderr_bytes: stderr.len() as u64,
+                stdout,
+                stderr,
+                truncated,
+                prompt: None,
+            };
+            return Ok((output, None, false));
+        }
+    };
+    let spill = budget.spill(merged, limit, options.redactor())?;
+    let session = channel.session().clone();
+    session.set_blocking(false);
+    let output = spill.run_within(&mut **channel, read_timeout(&session));
+    session.set_blocking(true);
+    let (files, output, redacted) = output?;
+    Ok((output, Some(files), redacted))
+}
+
+/// How a command run among others on one session reads its output, see
+/// [`Module::run_multiplexed`].
+enum Reading<'a> {
+    Memory(Box<Transfer<Box<dyn Read>>>),
+    Spill(Spill<'a>),
+}
+
+impl Reading<'_> {
+    fn step(&mut self, channel: &mut Channel) -> io::Result<bool> {
+        match self {
+            Reading::Memory(transfer) => transfer.step(channel),
+            Reading::Spill(spill) => spill.step(channel),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        match self {
+            Reading::Memory(transfer) => transfer.is_done(),
+            Reading::Spill(spill) => spill.is_done(),
+        }
+    }
+}
+
+/// Warns on `result` if the output budget lowered its `limits` to `budgeted`.
+fn note_budget_cap(result: &mut CommandResult, limits: Limits, budgeted: Limits) {
+    if let (Some(max_output), true) = (budgeted.max_output, budgeted != limits) {
+        result
+            .warnings
+            .push(format!("the output budget of the run lowered max_output to {} bytes", max_output));
+    }
+}
+
+/// What an executor saw of one command, before the command's own checks.
+struct Observed {
+    stdout: String,
+    stderr: Option<String>,
+    stdout_bytes: u64,
+    stderr_bytes: u64,
+    truncated: bool,
+    exit_status: Option<i32>,
+    /// Why the executor gave up on the command, if it did
+    failure: Option<String>,
+}
+
+impl Observed {
+    fn new(stdout: String, stderr: Option<String>) -> Self {
+        Observed {
+            stdout_bytes: stdout.len() as u64,
+            stderr_bytes: stderr.as_ref().map_or(0, |stderr| stderr.len() as u64),
+            stdout,
+            stderr,
+            truncated: false,
+            exit_status: None,
+            failure: None,
+        }
+    }
+}
+
+/// Sets the [`BackgroundProcess`] a `background` command on `host` printed, or fails it
+/// if it couldn't be started.
+fn started(result: &mut CommandResult, host: &str, started_at: i64) {
+    if result.is_failed() {
+        return;
+    }
+    if let Some(status) = result.exit_status.filter(|status| *status != 0) {
+        let stderr = result.stderr.as_deref().unwrap_or_default().trim();
+        result.failure = Some(format!("starting in the background exited with status {}: {}", status, stderr));
+        return;
+    }
+    match BackgroundProcess::parse(host, &result.stdout, started_at) {
+        Ok(process) => result.background = Some(process),
+        Err(e) => result.failure = Some(e.to_string()),
+    }
+}
+
+/// Judges a command by what its executor saw. Every executor goes through here,
+/// so `require_output`, `changed_when`, `capture` and parsers mean the same everywhere.
+///
+/// A failure reported by the executor wins: the command is failed, unchanged
+/// and its output isn't parsed. Otherwise the command's own checks run, then
+/// `parser`, whose errors are warnings, or failures if `strict`.
+fn evaluate(
+    command: &ShellCommand,
+    observed: Observed,
+    parser: Option<OutputParser>,
+    strict: bool,
+) -> CommandResult {
+    let Observed {
+        stdout,
+        stderr,
+        stdout_bytes,
+        stderr_bytes,
+        truncated,
+        exit_status,
+        failure,
+    } = observed;
+    let changed = failure.is_none() && command.changed(&stdout);
+    let mut result = CommandResult {
+        captures: command.captures(&stdout),
+        failure: failure.or_else(|| command.failure(&stdout)),
+        changed,
+        stdout,
+        stderr,
+        exit_status,
+        parsed: None,
+        warnings: Vec::new(),
+        stdout_bytes,
+        stderr_bytes,
+        truncated,
+        skipped: None,
+        uploaded: false,
+        waited: None,
+        redacted: false,
+        background: None,
+        spilled: None,
+        attempts: Vec::new(),
+    };
+    let parser = match parser {
+        Some(parser) if !result.is_failed() => parser,
+        _ => return result,
+    };
+    match parser(&result.stdout) {
+        Ok(parsed) => result.parsed = Some(parsed),
+        Err(e) => {
+            let message = format!("failed to parse output: {}", e);
+            if strict {
+                result.failure = Some(message);
+            } else {
+                result.warnings.push(message);
+            }
+        }
+    }
+    result
+}
+
+impl Module {
+    /// Shell module built in code, for users which don't load modules from files.
+    /// See [`crate::ShellModuleBuilder`] for shell modules with more than commands.
+    pub fn shell(mut commands: HashMap<String, ShellCommand>) -> Result<Module, Error> {
+        commands.values_mut().for_each(ShellCommand::resolve_wait_for);
+        let module = Module {
+            module_content: Arc::new(ModuleContent::Shell(commands)),
+            env: HashMap::new(),
+            max_output: None,
+            timeout: None,
+            bundle: false,
+            umask: None,
+            remote_dir: None,
+            file_mode: None,
+            args: Vec::new(),
+            fail_fast: false,
+            window: None,
+            retry_hint: None,
+            locale: None,
+            parser: None,
+            depends_on: Vec::new(),
+            normalize: Vec::new(),
+            read_only: false,
+            lint_ignore: Vec::new(),
+            strict_lints: false,
+            deprecated: false,
+            replaced_by: None,
+        };
+        module.check()?;
+        Ok(module)
+    }
+
+    /// Checks what the module declares, the same way for loaded and built modules.
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        for name in self.env.keys() {
+            check_env_name(name)?;
+        }
+        if let Some(umask) = &self.umask {
+            check_umask(umask)?;
+        }
+        if let Some(dir) = self.remote_dir.as_deref().filter(|dir| !is_absolute(dir)) {
+            return Err(Error::msg(format!("remote_dir must be an absolute path: {}", dir)));
+        }
+        if let Some(parser) = &self.parser {
+            builtin_parser(parser)?;
+        }
+        if let ModuleContent::Shell(commands) = &*self.module_content {
+            for (name, command) in commands {
+                let probe = match &command.wait_for {
+                    Some(wait) => wait.check().map(|_| wait.probe()),
+                    None if command.cmd.is_empty() => {
+                        return Err(Error::msg(format!("Command {} has no cmd", name)))
+                    }
+                    None => continue,
+                };
+                let probe = probe.map_err(|e| Error::msg(format!("Command {}: {}", name, e)))?;
+                if probe.as_ref() != Some(&command.cmd) {
+                    return Err(Error::msg(format!(
+                        "Command {} has both a cmd and a wait_for",
+                        name
+                    )));
+                }
+            }
+            for parser in commands.values().filter_map(|command| command.parser.as_deref()) {
+                builtin_parser(parser)?;
+            }
+        }
+        if let Some(conflict) = self.conflicts().into_iter().next() {
+            return Err(conflict.into());
+        }
+        check_lint_ids(&self.lint_ignore)?;
+        self.check_lints()
+    }
+
+    /// Output limit per stream declared by the module, in bytes.
+    pub fn max_output(&self) -> Option<u64> {
+        self.max_output
+    }
+
+    /// Read timeout declared by the module.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub(crate) fn env(&self) -> &HashMap<String, String> {
+        &self.env
+    }
+
+    pub(crate) fn umask(&self) -> Option<&str> {
+        self.umask.as_deref()
+    }
+
+    pub(crate) fn locale(&self) -> Option<&str> {
+        self.locale.as_deref()
+    }
+
+    /// Regexes whose matches in stdout are noise, like timestamps or host names,
+    /// ignored by [`Module::output_fingerprint`].
+    pub fn normalize(&self) -> &[Regex] {
+        &self.normalize
+    }
+
+    /// Hash of a command's stdout with the [`Module::normalize`] matches removed,
+    /// equal for hosts whose output differs only by noise.
+    pub fn output_fingerprint(&self, result: &CommandResult) -> String {
+        let stdout = self
+            .normalize
+            .iter()
+            .fold(result.stdout.clone(), |stdout, regex| regex.replace_all(&stdout, "").into_owned());
+        fnv1a_hex(&stdout)
+    }
+
+    /// Kind of the module, as `module_type` of a `.mod` file names it: `bash` for shell
+    /// modules, `python` or `bin`. It follows from what the module runs.
+    pub fn module_type(&self) -> &'static str {
+        match &*self.module_content {
+            ModuleContent::Shell(_) => "bash",
+            ModuleContent::Python(_) => "python",
+            ModuleContent::Binary(..) => "bin",
+        }
+    }
+
+    /// Whether the module is declared to change nothing: with `read_only = true`
+    /// in its `.mod` file, or in every one of its commands. Binary modules never are,
+    /// running them means uploading them.
+    pub fn is_read_only(&self) -> bool {
+        match &*self.module_content {
+            ModuleContent::Binary(..) => false,
+            _ if self.read_only => true,
+            ModuleContent::Shell(commands) => {
+                !commands.is_empty() && commands.values().all(|command| command.read_only)
+            }
+            ModuleContent::Python(_) => false,
+        }
+    }
+
+    /// Modules which have to run before this one, see [`ModuleTree::dependency_order`].
+    pub fn depends_on(&self) -> &[String] {
+        &self.depends_on
+    }
+
+    /// Whether the module is declared `deprecated`, or names a `replaced_by`.
+    /// It still runs, with a warning in every result, which reaches report sinks like
+    /// any other.
+    pub fn is_deprecated(&self) -> bool {
+        self.deprecated || self.replaced_by.is_some()
+    }
+
+    /// Module to use instead of this one, if it is deprecated for a replacement.
+    pub fn replaced_by(&self) -> Option<&str> {
+        self.replaced_by.as_deref()
+    }
+
+    /// Warning about running the module, named `name`, if it is deprecated.
+    fn deprecation_warning(&self, name: Option<&str>) -> Option<String> {
+        if !self.is_deprecated() {
+            return None;
+        }
+        let name = name.map(|name| format!(" {}", name)).unwrap_or_default();
+        Some(match &self.replaced_by {
+            Some(replacement) => format!("module{} is deprecated, use {}", name, replacement),
+            None => format!("module{} is deprecated", name),
+        })
+    }
+
+    /// Parser of a command: its own `parser`, then one registered
+    /// with the run for the command's name, then the module's `parser`.
+    fn output_parser(
+        &self,
+        command_name: &str,
+        command: &ShellCommand,
+        options: &ExecutionOptions,
+    ) -> Result<Option<OutputParser>, Error> {
+        if let Some(name) = &command.parser {
+            return builtin_parser(name).map(Some);
+        }
+        if let Some(parser) = options.parser(command_name) {
+            return Ok(Some(parser.clone()));
+        }
+        self.parser.as_deref().map(builtin_parser).transpose()
+    }
+
+    /// Names of a shell module's commands, sorted, none for other modules.
+    pub(crate) fn command_names(&self) -> Vec<&str> {
+        match &*self.module_content {
+            ModuleContent::Shell(map) => {
+                let mut names: Vec<_> = map.keys().map(String::as_str).collect();
+                names.sort_unstable();
+                names
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Command line of a shell module's command, as written in the module.
+    pub(crate) fn command(&self, name: &str) -> Option<&str> {
+        match &*self.module_content {
+            ModuleContent::Shell(map) => map.get(name).map(|command| command.cmd.as_str()),
+            _ => None,
+        }
+    }
+
+    fn obtain_connection_and_auth<A>(
+        &self,
+        ip: A,
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+    ) -> Result<HostConnection, Error>
     where
-        D: Deserializer<'de>,
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
     {
-        let s = String::deserialize(deserializer)?;
-        Ok(ExecType::from(s.as_str()))
+        HostConnection::connect(ip, auth, sync)
     }
-}
 
-#[derive(Deserialize)]
-pub struct ModuleProps {
-    module_type: ExecType,
-    exec_path: PathBuf,
-}
+    /// Runs the script of a python module over an established connection.
+    fn run_python_script(
+        &self,
+        connection: &HostConnection,
+        script: &str,
+        options: &ExecutionOptions,
+    ) -> Result<CommandResult, Error> {
+        let mut channel = connection.open_channel()?;
+        let script = options.prepare_command(script, self);
+        let (session, exec) = channel.split();
+        exec_staged(session, exec, &script, options.inline_limit(), options.uploads(), connection.ledger())?;
+        self.finish_script(&mut channel, options)
+    }
 
-///Shell: Toml of modules
-/// like
-/// ```toml
-/// first = "ls"
-/// second = "uptime"
-/// ```
-/// Binary: Upload binary and execute
-/// Python:
-/// ```bash
-/// python -c python code
-/// ```
+    /// Reads the output of the script or binary run on `channel` like that of a shell command,
+    /// within the output budget and the module's `max_output`, and evaluates it.
+    fn finish_script(&self, channel: &mut OpenChannel, options: &ExecutionOptions) -> Result<CommandResult, Error> {
+        let limits = options.effective_limits(self);
+        let budget = options.output_budget();
+        let reserved = budget.and_then(|budget| budget.reserve(limits.max_output));
+        let spill = budget.filter(|_| reserved.is_none());
+        let (output, spilled, redacted) = read_unwatched(channel, spill, false, limits.max_output, options)?;
+        let status = exit_status(channel, &output)?;
+        let mut result = CommandResult::of_script(output, status, limits.max_output);
+        result.spilled = spilled;
+        result.redacted |= redacted;
+        drop(reserved);
+        Ok(result)
+    }
 
-#[derive(Debug,Clone)]enum ModuleContent {
-    Shell(HashMap<String, String>),
-    Binary(PathBuf),
-    Python(String),
-}
+    /// Uploads the binary at `path` over scp, in chunks, runs it with the module's `args`
+    /// and removes it. With the `sha256` of a verified tree, what was uploaded is hashed
+    /// and removed again instead of run, if it doesn't match.
+    /// On Windows hosts it runs with `cmd /s /c`, see [`TargetOs`].
+    fn run_binary(
+        &self,
+        connection: &HostConnection,
+        path: &Path,
+        sha256: Option<&str>,
+        options: &ExecutionOptions,
+    ) -> Result<CommandResult, Error> {
+        let open = |path: &Path| -> io::Result<(File, u64)> {
+            let file = File::open(path)?;
+            let size = file.metadata()?.len();
+            Ok((file, size))
+        };
+        let (file, size) = open(path)
+            .map_err(|e| Error::msg(format!("Opening binary {}: {}", path.display(), e)))?;
+        let mut file = Sha256Reader::new(file);
+        let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        let os = connection.target_os();
+        let dir = self.remote_dir.as_deref().unwrap_or_else(|| os.temp_dir()).trim_end_matches('/');
+        let remote = os.join(dir, &format!("am_bin-{}-{}", bundle_token(), name));
+        let mode = self.file_mode.unwrap_or(0o700);
+        let (run, rm) = match os {
+            TargetOs::Posix => {
+                let quoted = ShellSafe::path(&remote);
+                let args: String = self.args.iter().map(|arg| format!(" {}", ShellSafe::quote(arg))).collect();
+                let run = shell_format!(
+                    "chmod {0} {1} && {1}{2}; status=$?; rm -f {1}; exit $status",
+                    ShellSafe::octal(mode),
+                    quoted,
+                    ShellSafe::command(&args)
+                );
+                let run = options.prepare_command(run.as_str(), self);
+                (run, shell_format!("rm -f {}", quoted).into_string())
+            }
+            // cmd.exe can't take the prefixes, and the binary is removed after it ran
+            TargetOs::Windows => {
+                if !options.prepare_command("", self).is_empty() {
+                    return Err(Error::msg(format!(
+                        "Binary {} can't run with an env, umask, locale or command wrapper on Windows",
+                        path.display()
+                    )));
+                }
+                let quoted = os.quote(&remote)?;
+                let args = self
+                    .args
+                    .iter()
+                    .map(|arg| os.quote(arg).map(|arg| format!(" {}", arg)))
+                    .collect::<Result<String, _>>()?;
+                (os.command(&format!("{}{}", quoted, args)), os.command(&format!("del /f /q {}", quoted)))
+            }
+        };
+        if options.checks_free_space() {
+            connection.check_free_space(dir, &remote, size)?;
+        }
+        connection.ledger().track(&remote);
+        let mut upload = || -> Result<(), Error> {
+            let scp = |session: &Session| session.scp_send(Path::new(&remote), mode, size, None);
+            let mut channel = connection.open_channel_with(&scp)?;
+            match options.uploads() {
+                Some(uploads) => io::copy(&mut uploads.track(&remote, size, &mut file), &mut *channel)?,
+                None => io::copy(&mut file, &mut *channel)?,
+            };
+            channel.send_eof()?;
+            channel.wait_eof()?;
+            channel.close()?;
+            channel.wait_close()?;
+            Ok(())
+        };
+        // the file is left behind if the run failed before it could remove it
+        let remove = || -> Result<(), Error> {
+            let mut channel = connection.open_channel()?;
+            let (session, exec) = channel.split();
+            exec_command(session, exec, &rm)?;
+            Ok(channel.wait_close()?)
+        };
+        if let Err(e) = upload() {
+            let _ = remove();
+            let message = format!("Uploading binary to {}: {}", remote, e);
+            return Err(upload_failed(connection, dir, &remote, Error::msg(message)));
+        }
+        if let Err(e) = check_sent(path, sha256, file.hex()) {
+            let _ = remove();
+            return Err(e);
+        }
+        let execute = || -> Result<CommandResult, Error> {
+            let mut channel = connection.open_channel()?;
+            let (session, exec) = channel.split();
+            exec_command(session, exec, &run)?;
+            self.finish_script(&mut channel, options)
+        };
+        let result = execute().map_err(|e| {
+            let _ = remove();
+            Error::msg(format!("Running binary {}: {}", remote, e))
+        })?;
+        if os == TargetOs::Windows {
+            // like `rm -f` after the run on POSIX, a failure doesn't fail the module
+            let _ = remove();
+        }
+        Ok(result)
+    }
 
-pub enum CommandOutput {
-    Multi(HashMap<String, String>),
-    Single(String),
-}
+    /// Runs every shell command over an established connection.
+    /// With `vars`, commands and their stdin are rendered as templates first.
+    /// Memoized commands are looked up in the connection first, and recorded there
+    /// if they succeed, as commands of `module`.
+    fn run_shell_commands(
+        &self,
+        connection: &HostConnection,
+        content: &HashMap<String, ShellCommand>,
+        options: &ExecutionOptions,
+        vars: Option<&HashMap<String, String>>,
+        module: Option<&str>,
+    ) -> Result<HashMap<String, CommandResult>, Error> {
+        let state = connection.state().snapshot();
+        let render = |text: &str| {
+            let text = render_state(text, &state)?;
+            match vars {
+                Some(vars) => render_template(&text, vars),
+                None => Ok(text),
+            }
+        };
+        let mut memoized = HashMap::new();
+        let mut keys = Vec::new();
+        for (command_name, command) in content.iter().filter(|(_, command)| command.memoize) {
+            let cmd = options.prepare_command(&render(&command.cmd)?, self);
+            let key = format!("{}\n{}", options.correlation_id().unwrap_or_default(), cmd);
+            match connection.memoized(&key) {
+                Some((origin, result)) => {
+                    memoized.insert(command_name.clone(), result.memoized_from(&origin));
+                }
+                None => keys.push((command_name, key)),
+            }
+        }
+        let mut options = options.in_module(module);
+        #[cfg(feature = "testing")]
+        if let Some(limit) = connection.output_limit() {
+            let ceiling = options.max_output_ceiling.map_or(limit, |ceiling| ceiling.min(limit));
+            options.to_mut().max_output_ceiling = Some(ceiling);
+        }
+        if options.uploads().is_some() {
+            options.to_mut().set_upload_host(connection.host());
+        }
+        let options = options.as_ref();
+        let started_at = epoch_ms(SystemTime::now());
+        let unmemoized: Commands = content
+            .iter()
+            .filter(|(command_name, _)| !memoized.contains_key(*command_name))
+            .map(|(command_name, command)| (command_name.as_str(), command))
+            .collect();
+        let mut results = if self.fail_fast {
+            self.run_failing_fast(connection, options, &unmemoized, &render)?
+        } else {
+            self.run_retrying(connection, options, &unmemoized, &render)?
+        };
+        for (command_name, result) in results.iter_mut() {
+            if content.get(command_name).is_some_and(|command| command.background) {
+                started(result, connection.host(), started_at);
+            }
+        }
+        for (command_name, key) in keys {
+            if let Some(result) = results.get(command_name).filter(|result| !result.is_failed()) {
+                let origin = match module {
+                    Some(module) => format!("{}/{}", module, command_name),
+                    None => command_name.clone(),
+                };
+                connection.memoize(key, origin, result.clone());
+            }
+        }
+        results.extend(memoized);
+        for (command_name, result) in &results {
+            let registers = content
+                .get(command_name)
+                .is_some_and(|command| command.register_scope == RegisterScope::Connection);
+            if registers && !result.is_failed() {
+                for (key, value) in &result.captures {
+                    connection.state().set(key, value);
+                }
+            }
+        }
+        Ok(results)
+    }
 
-#[derive(Debug,Clone)]
-struct Module {
-    module_type: ExecType,
-    module_content: ModuleContent,
-}
+    /// Runs `content` over `connection`, the way the module is set up to, with the
+    /// timeout of the module on the session. The session gets its timeout back after,
+    /// whether the commands failed or not.
+    fn run_commands(
+        &self,
+        connection: &HostConnection,
+        options: &ExecutionOptions,
+        content: &Commands,
+        render: &dyn Fn(&str) -> Result<String, Error>,
+    ) -> Result<HashMap<String, CommandResult>, Error> {
+        let limits = options.effective_limits(self);
+        let session = connection.session();
+        let previous = session.timeout();
+        if let Some(timeout) = limits.timeout {
+            session.set_timeout(timeout.as_millis().min(u32::MAX as u128) as u32);
+        }
+        let results = self.run_commands_within(connection, options, content, render, limits);
+        session.set_timeout(previous);
+        results
+    }
+
+    /// [`Module::run_commands`], once the session has the timeout of `limits`.
+    fn run_commands_within(
+        &self,
+        connection: &HostConnection,
+        options: &ExecutionOptions,
+        content: &Commands,
+        render: &dyn Fn(&str) -> Result<String, Error>,
+        limits: Limits,
+    ) -> Result<HashMap<String, CommandResult>, Error> {
+        let special = |command: &ShellCommand| command.local || command.wait_for.is_some();
+        if content.values().any(|command| special(command)) {
+            let mut res_map = HashMap::new();
+            let mut remote: Commands = HashMap::new();
+            for (command_name, command) in sorted_commands(content) {
+                if !special(command) {
+                    remote.insert(command_name, command);
+                    continue;
+                }
+                // the remote commands sorting before this one run first, together
+                let before = std::mem::take(&mut remote);
+                if !before.is_empty() {
+                    res_map.extend(self.run_commands_within(connection, options, &before, render, limits)?);
+                }
+                let result = match command.local {
+                    true => self.run_local(command_name, command, options, render, limits)?,
+                    false => self.run_wait_for(connection, options, command_name, command, render, limits)?,
+                };
+                res_map.insert(command_name.to_string(), result);
+            }
+            if !remote.is_empty() {
+                res_map.extend(self.run_commands_within(connection, options, &remote, render, limits)?);
+            }
+            return Ok(res_map);
+        }
+        if self.bundle {
+            return self.run_bundled(connection, options, content, render, limits);
+        }
+        if options.channels_per_session > 1 {
+            return self.run_multiplexed(connection, options, content, render, limits);
+        }
+        let mut res_map = HashMap::new();
+        for (command_name, command) in sorted_commands(content) {
+            options.check_cancelled()?;
+            let cmd = render(&command.cmd)?;
+            let input = command_input(command, render, options.uploads())?;
+            let (mut channel, uploaded) =
+                self.open_channel(connection, options, command_name, command, &cmd)?;
+            let session = channel.session().clone();
+            let streaming = watches_output(command, options);
+            let budget = options.output_budget();
+            let (reserved, command_limits) = if streaming {
+                reserve_in_memory(options, limits)
+            } else {
+                (budget.and_then(|budget| budget.reserve(limits.max_output)), limits)
+            };
+            let spill = budget.filter(|_| !streaming && reserved.is_none());
+            let (output, spilled, redacted) = if streaming {
+                let transfer = Transfer::new(input, command_limits.max_output)
+                    .keep_tail(command.tail)
+                    .watch_prompts(command.prompt_responses(), options.prompt_timeout);
+                session.set_blocking(false);
+                let output = transfer.run(&mut *channel);
+                session.set_blocking(true);
+                (output?, None, false)
+            } else {
+                read_unwatched(&mut channel, spill, command.merge_streams, limits.max_output, options)?
+            };
+            let status = exit_status(&mut channel, &output)?;
+            let mut result =
+                self.finish_command(command_name, command, options, command_limits, output, status)?;
+            result.uploaded = uploaded;
+            result.spilled = spilled;
+            result.redacted |= redacted;
+            note_budget_cap(&mut result, limits, command_limits);
+            drop(reserved);
+            res_map.insert(command_name.to_string(), result);
+        }
+        Ok(res_map)
+    }
 
-impl ModuleProps {
-    fn check_filename(filename: &DirEntry) -> bool {
-        let ext = match filename.path().extension() {
-            Some(a) => a.to_string_lossy().to_lowercase(),
-            None => return false,
+    /// Runs `content` like [`Module::run_commands`]. With a [`RetryHint`] the commands run
+    /// one at a time in order of their names instead, each retried as it asks before the
+    /// next one runs.
+    fn run_retrying(
+        &self,
+        connection: &HostConnection,
+        options: &ExecutionOptions,
+        content: &Commands,
+        render: &dyn Fn(&str) -> Result<String, Error>,
+    ) -> Result<HashMap<String, CommandResult>, Error> {
+        let hint = match &self.retry_hint {
+            Some(hint) => hint,
+            None => return self.run_commands(connection, options, content, render),
         };
-        ext == "mod"
+        let timeout = options.effective_limits(self).timeout;
+        let mut results = HashMap::new();
+        for (command_name, command) in sorted_commands(content) {
+            let single = std::iter::once((command_name, command)).collect();
+            let started = Instant::now();
+            let mut attempts = Vec::new();
+            let mut attempt_started_at = epoch_ms(SystemTime::now());
+            let mut ran = self.run_commands(connection, options, &single, render)?;
+            while let Some(result) = ran.get_mut(command_name) {
+                let delay = hint.delay(result);
+                attempts.push(RetryAttempt {
+                    started_at: attempt_started_at,
+                    exit_status: result.exit_status,
+                    retry_after_ms: delay.map(|delay| delay.as_millis() as u64),
+                });
+                let delay = match delay {
+                    Some(delay) => delay,
+                    None => break,
+                };
+                let gave_up = if attempts.len() >= hint.max_attempts as usize {
+                    Some(format!("still busy after {} attempts", attempts.len()))
+                } else if delay > MAX_RETRY_DELAY {
+                    Some(format!("still busy, retrying after {:?} would wait longer than {:?}", delay, MAX_RETRY_DELAY))
+                } else {
+                    let past = |timeout: &Duration| match started.elapsed().checked_add(delay) {
+                        Some(at) => at > *timeout,
+                        None => true,
+                    };
+                    timeout.filter(past).map(|timeout| {
+                        format!("still busy, retrying after {:?} would pass the timeout of {:?}", delay, timeout)
+                    })
+                };
+                if let Some(gave_up) = gave_up {
+                    result.failure.get_or_insert(gave_up);
+                    result.changed = false;
+                    break;
+                }
+                thread::sleep(delay);
+                attempt_started_at = epoch_ms(SystemTime::now());
+                ran = self.run_commands(connection, options, &single, render)?;
+            }
+            if attempts.iter().any(|attempt| attempt.retry_after_ms.is_some()) {
+                if let Some(result) = ran.get_mut(command_name) {
+                    result.attempts = attempts;
+                }
+            }
+            results.extend(ran);
+        }
+        Ok(results)
     }
-}
-pub enum AuthType {
-    AgentFirst(String),
-    AgentWithKeyName(String, String),
-}
 
-impl AuthType {
-    pub fn auth(&self, sess: &Session) -> Result<(), Error> {
-        match self {
-            AuthType::AgentFirst(username) => {
-                sess.userauth_agent(&username)?;
+    /// Runs commands one at a time, in order of their names, until one exits non-zero,
+    /// which fails it. The commands after it are skipped.
+    fn run_failing_fast(
+        &self,
+        connection: &HostConnection,
+        options: &ExecutionOptions,
+        content: &Commands,
+        render: &dyn Fn(&str) -> Result<String, Error>,
+    ) -> Result<HashMap<String, CommandResult>, Error> {
+        let mut res_map = HashMap::new();
+        let mut failed: Option<String> = None;
+        for (command_name, command) in sorted_commands(content) {
+            if let Some(failed) = &failed {
+                let skipped = CommandResult::skipped(SkipReason::FailedFast(failed.clone()));
+                res_map.insert(command_name.to_string(), skipped);
+                continue;
             }
-            AuthType::AgentWithKeyName(username, key) => unimplemented!(),
-        };
-        Ok(())
+            let single = std::iter::once((command_name, command)).collect();
+            for (name, mut result) in self.run_retrying(connection, options, &single, render)? {
+                result.fail_on_status();
+                if result.is_failed() {
+                    failed = Some(name.clone());
+                }
+                res_map.insert(name, result);
+            }
+        }
+        Ok(res_map)
     }
-}
 
-pub trait ConnectionProps {
-    fn get_timeout(&self) -> u32;
-    fn tcp_synchronization(&self);
-    fn agent_synchronization(&self);
-    fn tcp_release(&self);
-    fn agent_release(&self);
-}
+    /// Opens a channel over `connection` and starts the command `cmd` on it,
+    /// returning whether the command was uploaded.
+    pub(crate) fn open_channel(
+        &self,
+        connection: &HostConnection,
+        options: &ExecutionOptions,
+        command_name: &str,
+        command: &ShellCommand,
+        cmd: &str,
+    ) -> Result<(OpenChannel, bool), Error> {
+        self.start_command(connection, connection.open_channel()?, options, command_name, command, cmd)
+    }
 
-impl Module {
-    pub fn new(path: &Path, root: &Path) -> Result<Module, Error> {
-        let file_2_string = |p: &Path| -> Result<String, std::io::Error> {
-            let mut file = File::open(p)?;
-            let mut content = String::new();
-            file.read_to_string(&mut content)?;
-            Ok(content)
+    /// Starts the command `cmd` on `channel`, of `connection`.
+    fn start_command(
+        &self,
+        connection: &HostConnection,
+        mut channel: OpenChannel,
+        options: &ExecutionOptions,
+        command_name: &str,
+        command: &ShellCommand,
+        cmd: &str,
+    ) -> Result<(OpenChannel, bool), Error> {
+        if command.merge_streams {
+            channel.handle_extended_data(ExtendedData::Merge)?;
+        }
+        let cmd = match command.background {
+            true => options.prepare_named(&background_command(cmd), self, command_name),
+            false => options.prepare_named(cmd, self, command_name),
         };
+        let (session, exec) = channel.split();
+        let uploaded =
+            exec_staged(session, exec, &cmd, options.inline_limit(), options.uploads(), connection.ledger())?;
+        Ok((channel, uploaded))
+    }
 
-        let res: ModuleProps = from_str(&file_2_string(path)?)?;
-        let res = ModuleProps{
-            module_type: res.module_type,
-            exec_path: root.join(res.exec_path)
+    /// Evaluates the collected output of a command which ran on its own channel,
+    /// and exited with `exit_status`. Fails with an [`InteractivePromptDetected`]
+    /// if the command was given up on at a prompt.
+    pub(crate) fn finish_command(
+        &self,
+        command_name: &str,
+        command: &ShellCommand,
+        options: &ExecutionOptions,
+        limits: Limits,
+        output: PumpOutput,
+        exit_status: Option<i32>,
+    ) -> Result<CommandResult, Error> {
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let stderr = if command.merge_streams {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&output.stderr).into_owned())
         };
-        let content = match res.module_type {
-            ExecType::Bin => ModuleContent::Binary(res.exec_path),
-            ExecType::Python => {
-                let content = file_2_string(&res.exec_path)?;
-                let com64 = encode(content);
-                let script = format!("python2 -c \" exec('{}'.decode('base64'))\"", com64);
-                ModuleContent::Python(script)
-            }
-            ExecType::Bash => {
-                let unparsed = file_2_string(&res.exec_path)?;
-                let table: HashMap<_, _> = from_str(&unparsed)?;
-                ModuleContent::Shell(table)
-            }
+        if let Some(tail) = output.prompt {
+            let command = command_name.to_string();
+            return Err(InteractivePromptDetected { command, tail }.into());
+        }
+        let failure = match limits.max_output {
+            Some(max_output) if output.truncated => Some(format!("command output exceeded {} bytes", max_output)),
+            _ => None,
         };
-        Ok(Module {
-            module_type: res.module_type,
-            module_content: content,
-        })
+        let observed = Observed {
+            stdout_bytes: output.stdout_bytes,
+            stderr_bytes: output.stderr_bytes,
+            truncated: output.truncated,
+            exit_status,
+            failure,
+            ..Observed::new(stdout, stderr)
+        };
+        let parser = self.output_parser(command_name, command, options)?;
+        Ok(evaluate(command, observed, parser, options.strict_parsing()))
     }
 
-    fn connection_inernal<A>(
+    /// Runs commands concurrently, each on its own channel of the session,
+    /// at most `channels_per_session` at a time.
+    /// Commands without `stdin` or `stdin_file` get their standard input closed right away.
+    fn run_multiplexed(
         &self,
-        ip: A,
-        auth: AuthType,
-        sync: &dyn ConnectionProps,
-    ) -> Result<Session, Error>
-        where
-            A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
-    {
-        sync.tcp_synchronization();
-        let tcp = TcpStream::connect(ip)?;
-        let mut sess =
-            Session::new().map_err(|_e| Error::msg("Error initializing session".to_string()))?;
-        sess.set_tcp_stream(tcp);
-        sess.set_timeout(sync.get_timeout());
-        sync.agent_synchronization(); //todo fixme
-        if let Err(e)=  sess.handshake()
-        {
-            sync.agent_release();
-            return Err(e.into());
-        }
-        if let Err(e) =
-        auth.auth(&sess){
-            sync.agent_release();
-            return Err(e);
+        connection: &HostConnection,
+        options: &ExecutionOptions,
+        content: &Commands,
+        render: &dyn Fn(&str) -> Result<String, Error>,
+        limits: Limits,
+    ) -> Result<HashMap<String, CommandResult>, Error> {
+        let mut queue = Vec::with_capacity(content.len());
+        for (command_name, command) in sorted_commands(content) {
+            let cmd = render(&command.cmd)?;
+            let input = command_input(command, render, options.uploads())?.unwrap_or_else(|| Box::new(io::empty()));
+            queue.push((command_name, command, cmd, input));
         }
-        sync.agent_release();
-        Ok(sess)
+        let result = self.serve_channels(connection, options, queue, limits);
+        connection.session().set_blocking(true);
+        result
     }
 
-    fn obtain_connection_and_auth<A>(
+    /// Channels are served in turn by one non-blocking loop, like [`crate::pump`] does for one.
+    /// They are all opened on the connection's own session, and only while it has room,
+    /// unless none is running. Commands the output budget has no room for spill, or wait
+    /// for room if their output has to stay in memory. Leaves the session non-blocking.
+    fn serve_channels(
+        &self,
+        connection: &HostConnection,
+        options: &ExecutionOptions,
+        queue: Vec<(&str, &ShellCommand, String, Box<dyn Read>)>,
+        limits: Limits,
+    ) -> Result<HashMap<String, CommandResult>, Error> {
+        let session = connection.session();
+        let budget = options.output_budget();
+        let mut queue = queue.into_iter().peekable();
+        let mut running = Vec::with_capacity(options.channels_per_session);
+        let mut res_map = HashMap::new();
+        loop {
+            while running.len() < options.channels_per_session {
+                let spills = match queue.peek() {
+                    Some((_, command, _, _)) => !watches_output(command, options),
+                    None => break,
+                };
+                if !running.is_empty() && !connection.has_channel_room() {
+                    break;
+                }
+                let reserved = budget.map(|budget| budget.reserve(limits.max_output));
+                let (reserved, command_limits) = match reserved {
+                    Some(None) if !spills && !running.is_empty() => break,
+                    Some(None) if !spills => reserve_in_memory(options, limits),
+                    reserved => (reserved.flatten(), limits),
+                };
+                options.check_cancelled()?;
+                session.set_blocking(true);
+                let channel = match connection.open_own_channel() {
+                    Ok(channel) => channel,
+                    Err(e) if !running.is_empty() && is_too_many_channels(&e) => break,
+                    Err(e) => return Err(e),
+                };
+                let (command_name, command, cmd, input) = queue.next().expect("queue was peeked");
+                let (channel, uploaded) = self.start_command(connection, channel, options, command_name, command, &cmd)?;
+                let reading = match budget.filter(|_| reserved.is_none()) {
+                    Some(budget) => Reading::Spill(
+                        budget
+                            .spill(command.merge_streams, limits.max_output, options.redactor())?
+                            .closing_input(),
+                    ),
+                    None => Reading::Memory(Box::new(
+                        Transfer::new(Some(input), command_limits.max_output)
+                            .keep_tail(command.tail)
+                            .watch_prompts(command.prompt_responses(), options.prompt_timeout),
+                    )),
+                };
+                let budgeted = (command_limits, reserved);
+                running.push((command_name, command, channel, reading, uploaded, budgeted));
+            }
+            if running.is_empty() {
+                return Ok(res_map);
+            }
+            session.set_blocking(false);
+            let mut progress = false;
+            for (_, _, channel, reading, _, _) in running.iter_mut() {
+                progress |= reading.step(channel)?;
+            }
+            let (finished, unfinished): (Vec<_>, Vec<_>) = running
+                .drain(..)
+                .partition(|(_, _, _, reading, _, _)| reading.is_done());
+            running = unfinished;
+            for (command_name, command, mut channel, reading, uploaded, budgeted) in finished {
+                let (command_limits, reserved) = budgeted;
+                let (output, spilled, redacted) = match reading {
+                    Reading::Memory(transfer) => (transfer.into_output(), None, false),
+                    Reading::Spill(spill) => {
+                        let (files, output, redacted) = spill.into_output()?;
+                        (output, Some(files), redacted)
+                    }
+                };
+                session.set_blocking(true);
+                let status = exit_status(&mut channel, &output)?;
+                let mut result =
+                    self.finish_command(command_name, command, options, command_limits, output, status)?;
+                result.uploaded = uploaded;
+                result.spilled = spilled;
+                result.redacted |= redacted;
+                note_budget_cap(&mut result, limits, command_limits);
+                drop(reserved);
+                res_map.insert(command_name.to_string(), result);
+            }
+            if !progress {
+                idle();
+            }
+        }
+    }
+
+    /// Runs all commands, in order of their names, as one script over a single channel.
+    fn run_bundled(
+        &self,
+        connection: &HostConnection,
+        options: &ExecutionOptions,
+        content: &Commands,
+        render: &dyn Fn(&str) -> Result<String, Error>,
+        limits: Limits,
+    ) -> Result<HashMap<String, CommandResult>, Error> {
+        let mut commands: Vec<_> = content.iter().collect();
+        commands.sort_by_key(|(name, _)| *name);
+        let rendered = commands
+            .iter()
+            .map(|(name, command)| Ok(options.command_context(name) + &render(&command.cmd)?))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let specs: Vec<_> = rendered
+            .iter()
+            .zip(&commands)
+            .map(|(cmd, (_, command))| (cmd.as_str(), command.merge_streams))
+            .collect();
+        let token = bundle_token();
+        let mut channel = connection.open_channel()?;
+        let script = options.prepare_command(&bundle_commands(&specs, &token), self);
+        let (session, exec) = channel.split();
+        let uploaded =
+            exec_staged(session, exec, &script, options.inline_limit(), options.uploads(), connection.ledger())?;
+        let (reserved, bundle_limits) = reserve_in_memory(options, limits);
+        let (stdout, stderr, truncated) = read_channel(&mut channel, bundle_limits.max_output)?;
+        let stdouts = split_bundled(&String::from_utf8_lossy(&stdout), &token, commands.len());
+        let stderrs = split_bundled(&String::from_utf8_lossy(&stderr), &token, commands.len());
+        let unfinished = if truncated {
+            "bundled script output exceeded the limit"
+        } else {
+            "command did not finish, bundled script ended early"
+        };
+        let mut res_map = HashMap::new();
+        let outputs = stdouts.into_iter().zip(stderrs);
+        for ((name, command), (stdout, stderr)) in commands.into_iter().zip(outputs) {
+            let observed = match stdout {
+                Some(stdout) => {
+                    let stderr = if command.merge_streams {
+                        None
+                    } else {
+                        Some(stderr.unwrap_or_default())
+                    };
+                    Observed::new(stdout, stderr)
+                }
+                None => {
+                    let stderr = stderr.filter(|_| !command.merge_streams);
+                    Observed {
+                        truncated,
+                        failure: Some(unfinished.to_string()),
+                        ..Observed::new(String::new(), stderr)
+                    }
+                }
+            };
+            let parser = self.output_parser(name, command, options)?;
+            let mut result = evaluate(command, observed, parser, options.strict_parsing());
+            result.uploaded = uploaded;
+            note_budget_cap(&mut result, limits, bundle_limits);
+            res_map.insert(name.to_string(), result);
+        }
+        drop(reserved);
+        Ok(res_map)
+    }
+
+    /// Runs a shell module once per item over a single connection.
+    /// Keys of the item and `item_index` are available as template variables.
+    /// With [`OnError::Abort`] iterations stop after the first failed item.
+    pub fn execute_foreach<A>(
         &self,
         ip: A,
         auth: AuthType,
         sync: &dyn ConnectionProps,
-    ) -> Result<Session, Error>
+        options: &ExecutionOptions,
+        items: &[HashMap<String, String>],
+        on_error: OnError,
+    ) -> Result<Vec<ItemResult>, Error>
     where
         A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
     {
-       let res =self.connection_inernal(ip,auth,sync);
-        res
+        let host = ip.to_string();
+        let results = self.obtain_connection_and_auth(ip, auth, sync).and_then(|connection| {
+            self.execute_foreach_on(&connection, options, items, on_error)
+                .map_err(|e| connection.clean_up_after(e))
+        });
+        sync.tcp_release_for(&host);
+        results
+    }
+
+    /// [`Module::execute_foreach`] over an established connection.
+    pub fn execute_foreach_on(
+        &self,
+        connection: &HostConnection,
+        options: &ExecutionOptions,
+        items: &[HashMap<String, String>],
+        on_error: OnError,
+    ) -> Result<Vec<ItemResult>, Error> {
+        let content = match &*self.module_content {
+            ModuleContent::Shell(commands) => commands,
+            ModuleContent::Python(_) | ModuleContent::Binary(..) => {
+                return Err(Error::msg("Loops are supported only for shell modules"))
+            }
+        };
+        let mut results = Vec::with_capacity(items.len());
+        for (index, item) in items.iter().enumerate() {
+            options.check_cancelled()?;
+            let mut vars = item.clone();
+            vars.insert("item_index".to_string(), index.to_string());
+            let output = self
+                .run_shell_commands(connection, content, options, Some(&vars), None)
+                .map(CommandOutput::Multi)
+                .map_err(|e| connection.clean_up_after(e));
+            if output.is_ok() {
+                connection.ledger().clear();
+            }
+            let failed = output.as_ref().map_or(true, CommandOutput::is_failed);
+            results.push(ItemResult {
+                index,
+                item: item.clone(),
+                output,
+            });
+            if failed && on_error == OnError::Abort {
+                break;
+            }
+        }
+        Ok(results)
     }
 
-    fn execute_python_script<A>(
+    pub fn execute<A>(
         &self,
         ip: A,
         auth: AuthType,
         sync: &dyn ConnectionProps,
-    ) -> Result<String, Error>
+    ) -> Result<CommandOutput, Error>
     where
         A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
     {
-        let session = self.obtain_connection_and_auth(ip, auth, sync)?;
-        sync.agent_release();
-        let content = match &self.module_content {
-            ModuleContent::Python(script) => script,
-            _ => unreachable!(),
-        };
-        let mut channel = session.channel_session()?;
-        channel.exec(&content)?;
-        let mut result = String::new();
-        channel.read_to_string(&mut result)?;
-        Ok(result)
+        self.execute_with_options(ip, auth, sync, &ExecutionOptions::default())
     }
 
-    fn execute_bash_script<A>(
+    pub fn execute_with_options<A>(
         &self,
         ip: A,
         auth: AuthType,
         sync: &dyn ConnectionProps,
-    ) -> Result<HashMap<String, String>, Error>
+        options: &ExecutionOptions,
+    ) -> Result<CommandOutput, Error>
     where
         A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
     {
-        let session = self.obtain_connection_and_auth(ip, auth, sync)?;
-        let content = match &self.module_content {
+        let host = ip.to_string();
+        let result = self.obtain_connection_and_auth(ip, auth, sync).and_then(|connection| {
+            self.execute_named_on(None, &connection, options)
+                .map_err(|e| connection.clean_up_after(e))
+        });
+        sync.tcp_release_for(&host);
+        result
+    }
+
+    /// Standalone script running the same commands as the module would, for audit
+    /// or for running it by hand. Commands are rendered with `vars`, if given.
+    ///
+    /// Every command gets its own subshell, as it would get its own channel,
+    /// and they run in order of their names. A bundled module is a single subshell.
+    pub fn to_shell_script(
+        &self,
+        options: &ExecutionOptions,
+        vars: Option<&HashMap<String, String>>,
+    ) -> Result<String, Error> {
+        let content = match &*self.module_content {
             ModuleContent::Shell(map) => map,
-            _ => unreachable!(),
+            ModuleContent::Python(script) => {
+                return Ok(format!("#!/bin/sh\n{}\n", options.prepare_command(script, self)))
+            }
+            ModuleContent::Binary(..) => {
+                return Err(Error::msg("Binary modules can't be exported as a script"))
+            }
         };
-        let mut res_map = HashMap::new();
-        for (command_name, command) in content {
-            let mut channel = session.channel_session()?;
-            let mut result_string = String::new();
-            channel.exec(&command)?;
-            channel.read_to_string(&mut result_string)?;
-            res_map.insert(command_name.to_string(), result_string);
+        let render = |text: &str| match vars {
+            Some(vars) => render_template(text, vars),
+            None => Ok(text.to_string()),
+        };
+        let mut commands: Vec<_> = content.iter().collect();
+        commands.sort_by_key(|(name, _)| *name);
+        let mut script = String::from("#!/bin/sh\n");
+        if self.bundle {
+            let mut bundled = String::new();
+            for (name, command) in commands {
+                let redirect = if command.merge_streams { " 2>&1" } else { "" };
+                let cmd = render(&command.cmd)?;
+                let block = shell_format!(
+                    "# {}\n{{\n{}\n}}{}\n",
+                    ShellSafe::comment(name),
+                    ShellSafe::command(&cmd),
+                    ShellSafe::command(redirect)
+                );
+                bundled.push_str(block.as_str());
+            }
+            let bundled = options.prepare_command(&bundled, self);
+            script.push_str(&format!("(\n{}\n)\n", bundled));
+            return Ok(script);
         }
-        Ok(res_map)
+        for (name, command) in commands {
+            if command.local {
+                return Err(Error::msg(format!(
+                    "Command {} runs on the controller, it can't be exported as a script",
+                    name
+                )));
+            }
+            if command.wait_for.is_some() {
+                return Err(Error::msg(format!(
+                    "Command {} waits for a probe, it can't be exported as a script",
+                    name
+                )));
+            }
+            if command.stdin_file.is_some() {
+                return Err(Error::msg(format!(
+                    "Command {} streams a local file, it can't be exported as a script",
+                    name
+                )));
+            }
+            let cmd = options.prepare_command(&render(&command.cmd)?, self);
+            let stdin = match &command.stdin {
+                Some(stdin) => shell_format!("printf '%s' {} | ", ShellSafe::quote(&render(&raw_placeholders(stdin))?)),
+                None => ShellSafe::command(""),
+            };
+            let redirect = if command.merge_streams { " 2>&1" } else { "" };
+            let block = shell_format!(
+                "# {}\n{}(\n{}\n){}\n",
+                ShellSafe::comment(name),
+                stdin,
+                ShellSafe::command(&cmd),
+                ShellSafe::command(redirect)
+            );
+            script.push_str(block.as_str());
+        }
+        Ok(script)
     }
 
-    pub fn execute<A>(
+    /// Short hash of what the module runs and how its results are judged, changing
+    /// whenever its commands or options do. Stable across runs and builds, so it can be stored.
+    pub fn fingerprint(&self) -> String {
+        let mut content = match &*self.module_content {
+            ModuleContent::Binary(path, _) => {
+                let (dir, mode, args) = (&self.remote_dir, self.file_mode, &self.args);
+                format!("binary {} {:?} {:?} {:?}\n", path.display(), dir, mode, args)
+            }
+            ModuleContent::Python(script) => format!("python {:?}\n", script),
+            ModuleContent::Shell(_) => format!("shell bundle={}\n", self.bundle),
+        };
+        let mut env: Vec<_> = self.env.iter().collect();
+        env.sort();
+        content.push_str(&format!("{:?} {:?} {:?}\n", env, self.umask, self.locale));
+        content.push_str(&format!("{:?} {:?} {:?}\n", self.max_output, self.timeout, self.parser));
+        let normalize: Vec<_> = self.normalize.iter().map(Regex::as_str).collect();
+        content.push_str(&format!("normalize {:?}\n", normalize));
+        if self.fail_fast {
+            content.push_str("fail_fast\n");
+        }
+        if let Some(hint) = &self.retry_hint {
+            let (code, regex, attempts) = (hint.exit_code, hint.delay_regex.as_str(), hint.max_attempts);
+            content.push_str(&format!("retry_hint {} {:?} {}\n", code, regex, attempts));
+        }
+        if let ModuleContent::Shell(commands) = &*self.module_content {
+            let mut commands: Vec<_> = commands.iter().collect();
+            commands.sort_by_key(|(name, _)| *name);
+            for (name, command) in commands {
+                let responses: Vec<_> = command
+                    .responses
+                    .iter()
+                    .map(|response| (&response.prompt, &response.answer))
+                    .collect();
+                content.push_str(&format!(
+                    "{} {:?} merge={} {:?} < {:?} {:?}\n",
+                    name, command.cmd, command.merge_streams, command.stdin, command.stdin_file, responses,
+                ));
+                content.push_str(&format!(
+                    "{} {:?} {:?} {} {:?} {:?} {} {:?}\n",
+                    name,
+                    command.capture.as_ref().map(Regex::as_str),
+                    command.changed_when.as_ref().map(Regex::as_str),
+                    command.require_output,
+                    command.parser,
+                    command.tail,
+                    command.memoize,
+                    command.register_scope,
+                ));
+                content.push_str(&format!(
+                    "{} local={} {:?} {:?} background={}\n",
+                    name, command.local, command.local_dir, command.wait_for, command.background,
+                ));
+            }
+        }
+        fnv1a_hex(&content)
+    }
+
+    /// Executes the module over an established connection.
+    pub fn execute_on(
         &self,
-        ip: A,
-        auth: AuthType,
-        sync: &dyn ConnectionProps,
-    ) -> Result<CommandOutput, Error>
-    where
-        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
-    {
-        let result =match self.module_type {
-            ExecType::Bash => self
-                .execute_bash_script(ip, auth, sync)
+        connection: &HostConnection,
+        options: &ExecutionOptions,
+    ) -> Result<CommandOutput, Error> {
+        self.execute_named_on(None, connection, options)
+    }
+
+    /// [`Module::execute_named_on`] with commands and their stdin rendered with `vars`.
+    /// Python and binary modules can't use variables, they run as long as there are none.
+    #[cfg(feature = "discovery")]
+    pub(crate) fn execute_with_vars_on(
+        &self,
+        name: &str,
+        connection: &HostConnection,
+        options: &ExecutionOptions,
+        vars: &HashMap<String, String>,
+    ) -> Result<CommandOutput, Error> {
+        let vars = Some(vars).filter(|vars| !vars.is_empty());
+        if vars.is_some() && !matches!(&*self.module_content, ModuleContent::Shell(_)) {
+            return Err(Error::msg("Variables are supported only for shell modules"));
+        }
+        let output = self.run_on(Some(name), connection, options, vars);
+        if output.is_ok() {
+            connection.ledger().clear();
+        }
+        audit::record(self, Some(name), connection, options, output)
+    }
+
+    /// [`Module::execute_on`] for a module of the tree, which memoized results name.
+    /// Runs logged to a [`crate::Runner::with_remote_audit_log`] are logged by `name`.
+    /// Once the module finished, the files it left are its own to remove, and no longer
+    /// [`HostConnection::artifacts`].
+    pub(crate) fn execute_named_on(
+        &self,
+        name: Option<&str>,
+        connection: &HostConnection,
+        options: &ExecutionOptions,
+    ) -> Result<CommandOutput, Error> {
+        let output = self.run_on(name, connection, options, None);
+        if output.is_ok() {
+            connection.ledger().clear();
+        }
+        audit::record(self, name, connection, options, output)
+    }
+
+    fn run_on(
+        &self,
+        name: Option<&str>,
+        connection: &HostConnection,
+        options: &ExecutionOptions,
+        vars: Option<&HashMap<String, String>>,
+    ) -> Result<CommandOutput, Error> {
+        options.check_cancelled()?;
+        if let Some(window) = self.window.as_ref().filter(|_| !options.overrides_windows()) {
+            let now = window_clock(connection, options.remote_window_clock())?;
+            if !window.contains(now) {
+                let next_window = window.local_time(window.next_opening(now));
+                return Err(SkipReason::OutsideMaintenanceWindow { next_window }.into());
+            }
+        }
+        if let Some(full) = connection.disk_full().filter(|_| self.uploads(options)) {
+            return Err(SkipReason::DiskFull(full.to_string()).into());
+        }
+        let output = match &*self.module_content {
+            ModuleContent::Shell(commands) => self
+                .run_shell_commands(connection, commands, options, vars, name)
                 .map(CommandOutput::Multi),
-            ExecType::Python => unimplemented!(),
-            ExecType::Bin => unimplemented!(),
+            ModuleContent::Python(script) => self
+                .run_python_script(connection, script, options)
+                .map(|result| CommandOutput::Single(Box::new(result))),
+            ModuleContent::Binary(path, sha256) => self
+                .run_binary(connection, path, sha256.as_deref(), options)
+                .map(|result| CommandOutput::Single(Box::new(result))),
         };
-        sync.tcp_release();
-        result
+        let mut output = output.map_err(|e| classify(connection, e))?;
+        check_output(connection, &mut output);
+        if let Some(warning) = self.deprecation_warning(name) {
+            let results: Vec<_> = match &mut output {
+                CommandOutput::Single(result) => vec![&mut **result],
+                CommandOutput::Multi(results) => results.values_mut().collect(),
+            };
+            for result in results {
+                result.warnings.push(warning.clone());
+            }
+        }
+        Ok(output)
+    }
+
+    /// Whether running the module uploads files, which hosts whose disk is full skip:
+    /// binaries, `stdin_file`s, and scripts or commands above the inline limit.
+    fn uploads(&self, options: &ExecutionOptions) -> bool {
+        match &*self.module_content {
+            ModuleContent::Binary(..) => true,
+            ModuleContent::Python(script) => script.len() > options.inline_limit(),
+            ModuleContent::Shell(commands) => commands
+                .values()
+                .any(|command| command.stdin_file.is_some() || command.cmd.len() > options.inline_limit()),
+        }
     }
 }
+/// Modules by the names they are run by.
+///
+/// A tree doesn't change once built and is `Send + Sync`, so one tree behind an `Arc`
+/// can run modules from many threads at once. Each run allocates only what it renders
+/// and reads, cloning a tree or a module shares the content of its modules.
+/// ```no_run
+/// # use ansible_modules::prelude::*;
+/// # use std::collections::HashMap;
+/// # use std::sync::Arc;
+/// let tree = Arc::new(ModuleTree::from_modules(HashMap::new()));
+/// let handles: Vec<_> = ["10.0.0.1:22", "10.0.0.2:22"]
+///     .iter()
+///     .map(|host| {
+///         let tree = tree.clone();
+///         std::thread::spawn(move || {
+///             let auth = AuthType::AgentFirst("deploy".to_string());
+///             tree.run_module("uptime.mod", *host, auth, &DefaultConnectionProps::default())
+///         })
+///     })
+///     .collect();
+/// for handle in handles {
+///     handle.join().unwrap().unwrap();
+/// }
+/// ```
 #[derive(Debug,Clone)]
 pub struct ModuleTree {
     tree: HashMap<String, Module>,
+    /// Names of removed modules, with the module replacing each
+    replaced: HashMap<String, String>,
+    /// Modules which failed to load, by name
+    load_errors: Vec<(String, Arc<Error>)>,
+    /// SHA-256 of the manifest the tree was verified against
+    manifest_digest: Option<String>,
 }
 
 impl ModuleTree {
-    pub fn check_module(&self, module_name: &str) -> bool
-    {
-        self.tree.contains_key(module_name)
+    /// Tree of modules built in code, keyed by the names they are run by.
+    pub fn from_modules(modules: HashMap<String, Module>) -> Self {
+        ModuleTree {
+            tree: modules,
+            replaced: HashMap::new(),
+            load_errors: Vec::new(),
+            manifest_digest: None,
+        }
+    }
 
+    #[cfg(feature = "discovery")]
+    pub(crate) fn with_load_errors(mut self, errors: Vec<(String, Error)>) -> Self {
+        self.load_errors = errors.into_iter().map(|(name, e)| (name, Arc::new(e))).collect();
+        self.load_errors.sort_by(|a, b| a.0.cmp(&b.0));
+        self
     }
-    pub fn new(path: &Path) -> Self {
-        let root = WalkDir::new(&path).max_depth(1);
-        let map: HashMap<_, _> = root
-            .into_iter()
-            .filter_map(|e| e.ok()) //filter erros
-            .filter(|f| ModuleProps::check_filename(f)) //leave only mods
-            .map(|name| (Module::new(name.path(), &path), name)) //try to create module
-            .filter_map(|(x, name)| {
-                if let Err(e) = x {
-                    eprintln!("Error parsing module {}: {}",name.file_name().to_string_lossy(), e);
-                    None
-                } else {
-                    Some((
-                        name.path()
-                            .file_name()
-                            .expect("Failed getting filename for module, which is strange")
-                            .to_string_lossy()
-                            .to_string(),
-                        x,
-                    ))
-                }
-            })
-            .filter_map(|(name, module)| {
-                if let Err(e) = &module {
-                    eprintln!("{}", e);
-                }
-                module.map(|x| (name, x)).ok()
-            })
+
+    #[cfg(feature = "discovery")]
+    pub(crate) fn with_manifest_digest(mut self, digest: &str) -> Self {
+        self.manifest_digest = Some(digest.to_string());
+        self
+    }
+
+    /// Modules of the directory which failed to load, sorted by name, with why.
+    /// Find the files which were refused with `downcast_ref::<LoadError>()`.
+    pub fn load_errors(&self) -> Vec<(&str, &Error)> {
+        self.load_errors.iter().map(|(name, e)| (name.as_str(), &**e)).collect()
+    }
+
+    /// SHA-256 of the manifest the tree was loaded with, see `ModuleTree::new_verified`.
+    pub fn manifest_digest(&self) -> Option<&str> {
+        self.manifest_digest.as_deref()
+    }
+
+    /// Remembers that the module `old`, no longer in the tree, was replaced by `new`,
+    /// so running `old` fails with an error naming `new`. Loaded trees get these from
+    /// `.mod` files with nothing but a `replaced_by`.
+    pub fn with_replaced(mut self, old: &str, new: &str) -> Self {
+        self.replaced.insert(old.to_string(), new.to_string());
+        self
+    }
+
+    /// Module replacing `module_name`: the `replaced_by` of a deprecated module,
+    /// or of one removed from the tree, see [`ModuleTree::with_replaced`].
+    pub fn replaced_by(&self, module_name: &str) -> Option<&str> {
+        match self.tree.get(module_name) {
+            Some(module) => module.replaced_by(),
+            None => self.replaced.get(module_name).map(String::as_str),
+        }
+    }
+
+    /// Names of the deprecated modules in the tree, sorted, so UIs can set them apart.
+    pub fn deprecated_modules(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self
+            .tree
+            .iter()
+            .filter(|(_, module)| module.is_deprecated())
+            .map(|(name, _)| name.as_str())
             .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Names of the modules in the tree, sorted.
+    pub fn module_names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.tree.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn module(&self, module_name: &str) -> Option<&Module> {
+        self.tree.get(module_name)
+    }
+
+    pub fn check_module(&self, module_name: &str) -> bool
+    {
+        self.tree.contains_key(module_name)
 
-        ModuleTree { tree: map }
     }
     pub fn run_module<A>(
         &self,
@@ -306,16 +2498,78 @@ impl ModuleTree {
     where
         A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
     {
-        self.tree
-            .get(module_name)
-            .ok_or_else(|| Error::msg(format!("Module {} not found", &module_name)))?
-            .execute(ip, auth, sync)
+        self.get_module(module_name)?.execute(ip, auth, sync)
     }
-    pub fn run_all<A>(&self, ip: A, auth: AuthType, sync: &dyn ConnectionProps) -> Result<(), Error>
+    pub fn run_module_foreach<A>(
+        &self,
+        module_name: &str,
+        ip: A,
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+        items: &[HashMap<String, String>],
+        on_error: OnError,
+    ) -> Result<Vec<ItemResult>, Error>
     where
         A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
     {
-        unimplemented!();
+        self.get_module(module_name)?.execute_foreach(
+            ip,
+            auth,
+            sync,
+            &ExecutionOptions::default(),
+            items,
+            on_error,
+        )
+    }
+    /// `module_names` with every module they depend on, dependencies first.
+    /// Otherwise modules keep their order, dependencies not listed are added
+    /// right before the first module needing them. Cycles are an error.
+    pub fn dependency_order<'a>(&'a self, module_names: &[&'a str]) -> Result<Vec<&'a str>, Error> {
+        fn visit<'a>(
+            tree: &'a ModuleTree,
+            name: &'a str,
+            path: &mut Vec<&'a str>,
+            order: &mut Vec<&'a str>,
+        ) -> Result<(), Error> {
+            if order.contains(&name) {
+                return Ok(());
+            }
+            if let Some(start) = path.iter().position(|seen| *seen == name) {
+                let mut cycle = path[start..].to_vec();
+                cycle.push(name);
+                return Err(Error::msg(format!("Dependency cycle: {}", cycle.join(" -> "))));
+            }
+            let module = tree.get_module(name)?;
+            path.push(name);
+            for dependency in module.depends_on() {
+                visit(tree, dependency, path, order).map_err(|e| {
+                    if tree.check_module(dependency) {
+                        e
+                    } else {
+                        Error::msg(format!("Module {} depends on missing {}", name, dependency))
+                    }
+                })?;
+            }
+            path.pop();
+            order.push(name);
+            Ok(())
+        }
+
+        let mut order = Vec::new();
+        for name in module_names {
+            visit(self, name, &mut Vec::new(), &mut order)?;
+        }
+        Ok(order)
+    }
+
+    pub(crate) fn get_module(&self, module_name: &str) -> Result<&Module, Error> {
+        self.tree.get(module_name).ok_or_else(|| match self.replaced.get(module_name) {
+            Some(new) => Error::msg(format!(
+                "Module {} not found, it was replaced by {}",
+                module_name, new
+            )),
+            None => Error::msg(format!("Module {} not found", &module_name)),
+        })
     }
 }
 
@@ -0,0 +1,88 @@
+use anyhow::Error;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+
+/// Turns a command's stdout into structured data, see [`crate::Runner::with_parser`].
+pub type OutputParser = Arc<dyn Fn(&str) -> Result<Value, Error> + Send + Sync>;
+
+/// Built-in parser by the name used in module files: `json`, `key_value` or `table`.
+pub fn builtin_parser(name: &str) -> Result<OutputParser, Error> {
+    match name {
+        "json" => Ok(Arc::new(parse_json)),
+        "key_value" => Ok(Arc::new(parse_key_value)),
+        "table" => Ok(Arc::new(parse_table)),
+        _ => Err(Error::msg(format!("Unknown output parser {}", name))),
+    }
+}
+
+/// Parses the whole output as a single json document.
+pub fn parse_json(output: &str) -> Result<Value, Error> {
+    Ok(serde_json::from_str(output)?)
+}
+
+/// Parses `key=value` lines, as printed by `systemctl show`, into an object of strings.
+/// Blank lines and `#` comments are skipped, a repeated key keeps its last value.
+pub fn parse_key_value(output: &str) -> Result<Value, Error> {
+    let mut map = Map::new();
+    for (i, line) in output.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| Error::msg(format!("Line {} is not key=value", i + 1)))?;
+        map.insert(key.trim().to_string(), Value::String(value.to_string()));
+    }
+    Ok(Value::Object(map))
+}
+
+/// Parses a whitespace separated table with a header row into an array of objects.
+///
+/// The last column takes the rest of its line, so it may contain spaces.
+/// A row with fewer fields than the header is an error, which matters for headers
+/// with spaces in a column name, like the `Mounted on` of `df -P`.
+pub fn parse_table(output: &str) -> Result<Value, Error> {
+    let mut lines = output.lines().filter(|line| !line.trim().is_empty());
+    let header: Vec<&str> = match lines.next() {
+        Some(header) => header.split_whitespace().collect(),
+        None => return Err(Error::msg("Table has no header row")),
+    };
+    let rows = lines
+        .enumerate()
+        .map(|(i, line)| {
+            let fields = split_fields(line, header.len());
+            if fields.len() < header.len() {
+                return Err(Error::msg(format!(
+                    "Row {} has {} fields, the header has {}",
+                    i + 1,
+                    fields.len(),
+                    header.len()
+                )));
+            }
+            let row = header
+                .iter()
+                .zip(fields)
+                .map(|(column, field)| (column.to_string(), Value::String(field.to_string())))
+                .collect();
+            Ok(Value::Object(row))
+        })
+        .collect::<Result<_, Error>>()?;
+    Ok(Value::Array(rows))
+}
+
+/// Splits `line` into at most `count` whitespace separated fields.
+fn split_fields(line: &str, count: usize) -> Vec<&str> {
+    let mut fields = Vec::with_capacity(count);
+    let mut rest = line.trim();
+    while !rest.is_empty() {
+        if fields.len() + 1 == count {
+            fields.push(rest);
+            break;
+        }
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        fields.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    fields
+}
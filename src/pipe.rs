@@ -0,0 +1,445 @@
+use ssh2::{Channel, Session};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::ops::Range;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const CHUNK_SIZE: usize = 32 * 1024;
+const IDLE_SLEEP: Duration = Duration::from_millis(1);
+
+/// Non-blocking channel, as seen by [`pump`].
+/// Every method may fail with `ErrorKind::WouldBlock`, reads return `Ok(0)` on eof.
+pub trait Duplex {
+    fn write_input(&mut self, buf: &[u8]) -> Result<usize>;
+    fn close_input(&mut self) -> Result<()>;
+    fn read_output(&mut self, buf: &mut [u8]) -> Result<usize>;
+    fn read_error(&mut self, buf: &mut [u8]) -> Result<usize>;
+}
+
+impl Duplex for Channel {
+    fn write_input(&mut self, buf: &[u8]) -> Result<usize> {
+        self.write(buf)
+    }
+
+    fn close_input(&mut self) -> Result<()> {
+        self.send_eof().map_err(Into::into)
+    }
+
+    fn read_output(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.read(buf)
+    }
+
+    fn read_error(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.stderr().read(buf)
+    }
+}
+
+/// Output collected by [`pump`].
+/// `truncated` is set when either stream went over the limit.
+/// `prompt` is the line the command stopped at, if it was given up on
+/// for waiting at an interactive prompt.
+/// `stdout_bytes` and `stderr_bytes` count all bytes received, kept or not.
+#[derive(Debug, Default)]
+pub struct PumpOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub truncated: bool,
+    pub prompt: Option<String>,
+    pub stdout_bytes: u64,
+    pub stderr_bytes: u64,
+}
+
+fn append_limited(buf: &mut Vec<u8>, data: &[u8], limit: Option<u64>) -> bool {
+    let room = match limit {
+        Some(limit) => (limit as usize).saturating_sub(buf.len()),
+        None => data.len(),
+    };
+    buf.extend_from_slice(&data[..room.min(data.len())]);
+    room < data.len()
+}
+
+/// Reads `reader` to the end, keeping at most `limit` bytes.
+/// Stops reading as soon as the limit is exceeded and reports whether it was.
+pub(crate) fn read_limited<R: Read>(reader: R, limit: Option<u64>) -> Result<(Vec<u8>, bool)> {
+    let mut buf = Vec::new();
+    match limit {
+        None => {
+            let mut reader = reader;
+            reader.read_to_end(&mut buf)?;
+            Ok((buf, false))
+        }
+        Some(limit) => {
+            reader.take(limit + 1).read_to_end(&mut buf)?;
+            let exceeded = buf.len() as u64 > limit;
+            buf.truncate(limit as usize);
+            Ok((buf, exceeded))
+        }
+    }
+}
+
+pub(crate) fn would_block<T>(res: Result<T>) -> Result<Option<T>> {
+    match res {
+        Ok(v) => Ok(Some(v)),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Watches the output of a channel for prompts, answering the expected ones.
+struct PromptWatch {
+    responses: Vec<(String, String)>,
+    timeout: Option<Duration>,
+    quiet_since: Instant,
+    /// Bytes of stdout and stderr already answered
+    answered: [usize; 2],
+}
+
+fn last_line(buf: &[u8]) -> &[u8] {
+    match buf.iter().rposition(|&b| b == b'\n') {
+        Some(newline) => &buf[newline + 1..],
+        None => buf,
+    }
+}
+
+/// Command given up on, as it sat at a prompt no response of its module answers,
+/// see [`pump_interactive`]. `tail` is the line it stopped at.
+/// Errors carry it, find it with `downcast_ref::<InteractivePromptDetected>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InteractivePromptDetected {
+    pub command: String,
+    pub tail: String,
+}
+
+impl std::fmt::Display for InteractivePromptDetected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Command {} is waiting at an interactive prompt: {:?}", self.command, self.tail)
+    }
+}
+
+impl std::error::Error for InteractivePromptDetected {}
+
+/// Tail of the output which looks like a prompt waiting for an answer.
+fn looks_like_prompt(tail: &str) -> bool {
+    let tail = tail.trim_end().to_lowercase();
+    tail.ends_with('?')
+        || tail.ends_with("[y/n]")
+        || tail.ends_with("(y/n)")
+        || tail.ends_with("(yes/no)")
+        || tail.ends_with("password:")
+        || (tail.ends_with(':') && tail.contains("passphrase"))
+}
+
+/// Progress of a single channel, so that several channels can be served in turn.
+pub(crate) struct Transfer<R> {
+    input: Option<R>,
+    limit: Option<u64>,
+    /// Whether `limit` keeps the end of the output rather than its start
+    tail: bool,
+    chunk: Vec<u8>,
+    pending: Range<usize>,
+    input_done: bool,
+    eof_sent: bool,
+    stdout_done: bool,
+    stderr_done: bool,
+    buf: Vec<u8>,
+    answers: Vec<u8>,
+    watch: Option<PromptWatch>,
+    output: PumpOutput,
+}
+
+impl<R: Read> Transfer<R> {
+    /// Without `input` the remote's stdin is left open, as a plain exec leaves it.
+    pub(crate) fn new(input: Option<R>, limit: Option<u64>) -> Self {
+        Transfer {
+            input,
+            limit,
+            tail: false,
+            chunk: vec![0; CHUNK_SIZE],
+            pending: 0..0,
+            input_done: false,
+            eof_sent: false,
+            stdout_done: false,
+            stderr_done: false,
+            buf: vec![0; CHUNK_SIZE],
+            answers: Vec::new(),
+            watch: None,
+            output: PumpOutput::default(),
+        }
+    }
+
+    /// Writes `answer` and a newline, whenever the current line of output contains `prompt`.
+    /// Stops on any other prompt, once no newline arrived for `timeout`,
+    /// see [`PumpOutput::prompt`]. Stdin stays open with `responses`, so they can be written.
+    pub(crate) fn watch_prompts(
+        mut self,
+        responses: Vec<(String, String)>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        if responses.is_empty() && timeout.is_none() {
+            return self;
+        }
+        self.watch = Some(PromptWatch {
+            responses,
+            timeout,
+            quiet_since: Instant::now(),
+            answered: [0, 0],
+        });
+        self
+    }
+
+    /// Keeps only the last `tail` bytes of each stream instead of the first ones,
+    /// the output is still read to the end. Doesn't count as truncation.
+    pub(crate) fn keep_tail(mut self, tail: Option<u64>) -> Self {
+        if tail.is_some() {
+            self.limit = tail;
+            self.tail = true;
+        }
+        self
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.stdout_done && self.stderr_done
+    }
+
+    pub(crate) fn into_output(mut self) -> PumpOutput {
+        if self.tail {
+            for index in 0..2 {
+                self.drop_head(index, 0);
+            }
+        }
+        self.output
+    }
+
+    /// Serves the channel until its output ends.
+    pub(crate) fn run<D: Duplex>(mut self, channel: &mut D) -> Result<PumpOutput> {
+        while !self.is_done() {
+            if !self.step(channel)? {
+                idle();
+            }
+        }
+        Ok(self.into_output())
+    }
+
+    /// [`Transfer::run`], giving up once nothing moved for `timeout`, see [`idle_within`].
+    pub(crate) fn run_within<D: Duplex>(mut self, channel: &mut D, timeout: Option<Duration>) -> Result<PumpOutput> {
+        let mut quiet_since = Instant::now();
+        while !self.is_done() {
+            if self.step(channel)? {
+                quiet_since = Instant::now();
+            } else {
+                idle_within(quiet_since, timeout)?;
+            }
+        }
+        Ok(self.into_output())
+    }
+
+    /// Moves data in every direction which isn't blocked, returns whether any moved.
+    pub(crate) fn step<D: Duplex>(&mut self, channel: &mut D) -> Result<bool> {
+        let mut progress = false;
+        if !self.answers.is_empty() {
+            if let Some(written) = would_block(channel.write_input(&self.answers))? {
+                self.answers.drain(..written);
+                progress = written > 0;
+            }
+        } else if !self.eof_sent {
+            let wants_input = self.pending.is_empty() && !self.input_done;
+            if let Some(input) = self.input.as_mut().filter(|_| wants_input) {
+                let read = input.read(&mut self.chunk)?;
+                self.input_done = read == 0;
+                self.pending = 0..read;
+            }
+            let keep_open = self.input.is_none()
+                || self.watch.as_ref().is_some_and(|watch| !watch.responses.is_empty());
+            if !self.pending.is_empty() {
+                let pending = &self.chunk[self.pending.clone()];
+                if let Some(written) = would_block(channel.write_input(pending))? {
+                    self.pending.start += written;
+                    progress = written > 0;
+                }
+            } else if !keep_open && would_block(channel.close_input())?.is_some() {
+                self.eof_sent = true;
+                progress = true;
+            }
+        }
+        if !self.stdout_done {
+            match would_block(channel.read_output(&mut self.buf))? {
+                Some(0) => self.stdout_done = true,
+                Some(read) => {
+                    self.append(0, read);
+                    self.watch_output(0, read);
+                    progress = true;
+                }
+                None => {}
+            }
+        }
+        if !self.stderr_done {
+            match would_block(channel.read_error(&mut self.buf))? {
+                Some(0) => self.stderr_done = true,
+                Some(read) => {
+                    self.append(1, read);
+                    self.watch_output(1, read);
+                    progress = true;
+                }
+                None => {}
+            }
+        }
+        if !progress {
+            self.check_prompt();
+        }
+        Ok(progress)
+    }
+
+    /// Adds `read` new bytes from `buf` to stream `index`, within the limit.
+    fn append(&mut self, index: usize, read: usize) {
+        let data = &self.buf[..read];
+        let (stream, received) = if index == 0 {
+            (&mut self.output.stdout, &mut self.output.stdout_bytes)
+        } else {
+            (&mut self.output.stderr, &mut self.output.stderr_bytes)
+        };
+        *received += read as u64;
+        if !self.tail {
+            self.output.truncated |= append_limited(stream, data, self.limit);
+            return;
+        }
+        stream.extend_from_slice(data);
+        // letting the buffer grow to twice the tail keeps dropping the head cheap
+        self.drop_head(index, CHUNK_SIZE.max(self.tail_len()));
+    }
+
+    fn tail_len(&self) -> usize {
+        self.limit.map_or(usize::MAX, |limit| limit as usize)
+    }
+
+    /// Drops the start of stream `index`, once it is more than `slack` bytes
+    /// over the tail, so that only the tail remains.
+    fn drop_head(&mut self, index: usize, slack: usize) {
+        let keep = self.tail_len();
+        let stream = if index == 0 {
+            &mut self.output.stdout
+        } else {
+            &mut self.output.stderr
+        };
+        if stream.len() > keep.saturating_add(slack) {
+            let dropped = stream.len() - keep;
+            stream.drain(..dropped);
+            if let Some(watch) = &mut self.watch {
+                watch.answered[index] = watch.answered[index].saturating_sub(dropped);
+            }
+        }
+    }
+
+    /// Answers an expected prompt on the current line of stream `index`,
+    /// after `read` new bytes arrived in `buf`.
+    fn watch_output(&mut self, index: usize, read: usize) {
+        let watch = match &mut self.watch {
+            Some(watch) => watch,
+            None => return,
+        };
+        if self.buf[..read].contains(&b'\n') {
+            watch.quiet_since = Instant::now();
+        }
+        let stream = if index == 0 {
+            &self.output.stdout
+        } else {
+            &self.output.stderr
+        };
+        let line_start = stream.len() - last_line(stream).len();
+        let tail = String::from_utf8_lossy(&stream[line_start.max(watch.answered[index])..]);
+        let answer = watch
+            .responses
+            .iter()
+            .find(|(prompt, _)| tail.contains(prompt.as_str()))
+            .map(|(_, answer)| answer);
+        if let Some(answer) = answer {
+            self.answers.extend_from_slice(answer.as_bytes());
+            self.answers.push(b'\n');
+            watch.answered[index] = stream.len();
+        }
+    }
+
+    /// Gives up on the channel, if its output has been sitting at an unexpected prompt.
+    fn check_prompt(&mut self) {
+        let watch = match &self.watch {
+            Some(watch) => watch,
+            None => return,
+        };
+        match watch.timeout {
+            Some(timeout) if watch.quiet_since.elapsed() >= timeout => {}
+            _ => return,
+        }
+        for (index, stream) in [&self.output.stdout, &self.output.stderr].iter().enumerate() {
+            let line_start = stream.len() - last_line(stream).len();
+            let tail = &stream[line_start.max(watch.answered[index])..];
+            let tail = String::from_utf8_lossy(tail);
+            if looks_like_prompt(&tail) {
+                self.output.prompt = Some(tail.into_owned());
+                self.stdout_done = true;
+                self.stderr_done = true;
+                return;
+            }
+        }
+    }
+}
+
+/// Sleeps a little, for polling loops which made no progress.
+pub(crate) fn idle() {
+    sleep(IDLE_SLEEP);
+}
+
+/// [`idle`] for a polling loop which made no progress since `quiet_since`, failing with
+/// `ErrorKind::TimedOut` once that was `timeout` ago, as a blocking read would have.
+pub(crate) fn idle_within(quiet_since: Instant, timeout: Option<Duration>) -> Result<()> {
+    match timeout {
+        Some(timeout) if quiet_since.elapsed() >= timeout => {
+            Err(Error::new(ErrorKind::TimedOut, "Timed out waiting for output"))
+        }
+        _ => {
+            idle();
+            Ok(())
+        }
+    }
+}
+
+/// Timeout of blocking reads of `session`, `None` if they wait forever.
+pub(crate) fn read_timeout(session: &Session) -> Option<Duration> {
+    Some(session.timeout()).filter(|ms| *ms > 0).map(|ms| Duration::from_millis(ms.into()))
+}
+
+/// Streams `input` into the channel while draining its stdout and stderr.
+///
+/// Writing everything first and reading afterwards deadlocks as soon as the remote
+/// fills its output window while we are still writing, so both directions are
+/// served in one loop. At most one chunk of input is held in memory.
+/// If the remote closes its output before consuming all input, the rest is dropped.
+/// Output over `limit` bytes per stream is drained and discarded.
+pub fn pump<D: Duplex, R: Read>(channel: &mut D, input: R, limit: Option<u64>) -> Result<PumpOutput> {
+    Transfer::new(Some(input), limit).run(channel)
+}
+
+/// [`pump`], which keeps only the last `tail` bytes of each stream, while still reading
+/// them to the end. For huge output where only the end matters, like logs.
+pub fn pump_tail<D: Duplex, R: Read>(channel: &mut D, input: R, tail: u64) -> Result<PumpOutput> {
+    Transfer::new(Some(input), None)
+        .keep_tail(Some(tail))
+        .run(channel)
+}
+
+/// [`pump`], which also answers expected prompts and gives up on unexpected ones.
+///
+/// Whenever the current line of output contains the prompt of one of `responses`,
+/// its answer and a newline are written to stdin, which is kept open for that.
+/// Output which looks like a prompt, e.g. ends in `?`, `[y/N]` or `password:`,
+/// and isn't followed by a newline within `prompt_timeout` ends the transfer,
+/// with the line in [`PumpOutput::prompt`]. Without `input` stdin is left open.
+pub fn pump_interactive<D: Duplex, R: Read>(
+    channel: &mut D,
+    input: Option<R>,
+    limit: Option<u64>,
+    responses: Vec<(String, String)>,
+    prompt_timeout: Option<Duration>,
+) -> Result<PumpOutput> {
+    Transfer::new(input, limit)
+        .watch_prompts(responses, prompt_timeout)
+        .run(channel)
+}
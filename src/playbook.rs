@@ -0,0 +1,365 @@
+//! Plays tying hosts of an inventory to modules, enabled by the `discovery` feature.
+use crate::{
+    AuthType, BatchEntry, CanaryPolicy, ConnectionProps, Exclusion, Exclusions, ExecutionOptions, HostConnection,
+    Inventory, InventoryHost, Runner, SkipReason,
+};
+use anyhow::Error;
+use serde::{Deserialize, Deserializer};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use toml::Value;
+
+fn deserialize_vars<'de, D>(deserializer: D) -> Result<BTreeMap<String, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let vars = BTreeMap::<String, Value>::deserialize(deserializer)?;
+    vars.into_iter()
+        .map(|(key, value)| match value {
+            Value::String(value) => Ok((key, value)),
+            Value::Integer(_) | Value::Float(_) | Value::Boolean(_) | Value::Datetime(_) => {
+                Ok((key, value.to_string()))
+            }
+            _ => Err(serde::de::Error::custom(format!(
+                "Variable {} isn't a string, number or boolean",
+                key
+            ))),
+        })
+        .collect()
+}
+
+/// Modules to run on some hosts of an inventory:
+/// ```toml
+/// [[play]]
+/// hosts = "webservers,db01"
+/// modules = ["base.mod", "nginx.mod"]
+/// vars = { port = 8080 }
+/// serial = 2
+/// ```
+/// `hosts` lists groups and hosts, `all` being every host. `vars` become template
+/// variables of the modules' commands, overriding the host's inventory variables.
+/// `serial` runs the hosts in batches of that many, one batch after another,
+/// otherwise all of them at once.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Play {
+    pub hosts: String,
+    pub modules: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_vars")]
+    pub vars: BTreeMap<String, String>,
+    #[serde(default)]
+    pub serial: Option<usize>,
+}
+
+impl Play {
+    /// Hosts of `inventory` the play runs on, sorted by name.
+    pub fn select(&self, inventory: &Inventory) -> Result<Vec<InventoryHost>, Error> {
+        let mut hosts = BTreeMap::new();
+        for pattern in self.hosts.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let matched = if pattern == "all" || inventory.group(pattern).is_some() {
+                inventory.group_hosts(pattern)
+            } else {
+                inventory.host(pattern).into_iter().collect()
+            };
+            if matched.is_empty() {
+                return Err(Error::msg(format!("No hosts match {}", pattern)));
+            }
+            hosts.extend(matched.into_iter().map(|host| (host.name.clone(), host)));
+        }
+        Ok(hosts.into_values().collect())
+    }
+
+    /// Template variables of `host` in this play: its inventory variables,
+    /// overridden by the play's.
+    pub fn vars_for(&self, host: &InventoryHost) -> HashMap<String, String> {
+        let mut vars: HashMap<_, _> = host.vars.clone().into_iter().collect();
+        vars.extend(self.vars.clone());
+        vars
+    }
+}
+
+/// What a play did, see [`Playbook::run`].
+/// `results` holds the output of each host's modules in run order, or why it
+/// couldn't connect or was skipped, `canaries` the same of the canary hosts.
+/// `skipped` lists hosts left out for failing an earlier play.
+#[derive(Debug)]
+pub struct PlayReport {
+    pub hosts: String,
+    pub canaries: BTreeMap<String, Result<Vec<BatchEntry>, Error>>,
+    pub results: BTreeMap<String, Result<Vec<BatchEntry>, Error>>,
+    pub skipped: Vec<String>,
+}
+
+impl PlayReport {
+    /// Hosts skipped as canaries failed, see [`crate::CanaryPolicy`].
+    pub fn canary_skipped(&self) -> Vec<&str> {
+        self.results
+            .iter()
+            .filter(|(_, entries)| canary_skip(entries))
+            .map(|(host, _)| host.as_str())
+            .collect()
+    }
+
+    /// Hosts skipped as they are in the [`crate::Exclusions`].
+    pub fn excluded(&self) -> Vec<&str> {
+        self.results
+            .iter()
+            .filter(|(_, entries)| operator_excluded(entries))
+            .map(|(host, _)| host.as_str())
+            .collect()
+    }
+
+    /// Hosts, canaries included, which couldn't connect, or on which a module failed.
+    pub fn failed_hosts(&self) -> Vec<&str> {
+        self.canaries
+            .iter()
+            .chain(&self.results)
+            .filter(|(_, entries)| !canary_skip(entries) && !operator_excluded(entries))
+            .filter(|(_, entries)| match entries {
+                Ok(entries) => entries
+                    .iter()
+                    .any(|entry| !matches!(&entry.output, Ok(output) if !output.is_failed())),
+                Err(_) => true,
+            })
+            .map(|(host, _)| host.as_str())
+            .collect()
+    }
+}
+
+fn canary_skip(entries: &Result<Vec<BatchEntry>, Error>) -> bool {
+    matches!(entries, Err(e) if matches!(e.downcast_ref(), Some(SkipReason::CanaryFailed(_))))
+}
+
+fn operator_excluded(entries: &Result<Vec<BatchEntry>, Error>) -> bool {
+    matches!(entries, Err(e) if matches!(e.downcast_ref(), Some(SkipReason::ExcludedByOperator { .. })))
+}
+
+/// Exclusion `host` matches by its inventory name or where it connects to.
+fn exclusion<'a>(exclusions: &'a Exclusions, host: &InventoryHost) -> Option<&'a Exclusion> {
+    exclusions
+        .matching(&host.name)
+        .or_else(|| exclusions.matching(&host.host.to_string()))
+}
+
+/// Plays run in order, see [`Play`]. `remote_audit_log` is a file each host logs
+/// the modules run on it to, see [`crate::Runner::with_remote_audit_log`],
+/// `canary_policy` the [`CanaryPolicy`] of every play:
+/// ```toml
+/// remote_audit_log = "/var/log/am-audit.log"
+/// canary_policy = { pause_on_failure = true }
+///
+/// [[play]]
+/// hosts = "all"
+/// modules = ["base.mod"]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct Playbook {
+    #[serde(default)]
+    pub remote_audit_log: Option<String>,
+    #[serde(default)]
+    pub canary_policy: Option<CanaryPolicy>,
+    #[serde(rename = "play", default)]
+    pub plays: Vec<Play>,
+}
+
+impl Playbook {
+    pub fn load(path: &Path) -> Result<Playbook, Error> {
+        let content = fs::read_to_string(path)?;
+        Playbook::parse(&content)
+            .map_err(|e| e.context(format!("Failed reading playbook {}", path.display())))
+    }
+
+    pub fn parse(content: &str) -> Result<Playbook, Error> {
+        let playbook: Playbook = toml::from_str(content)?;
+        for (i, play) in playbook.plays.iter().enumerate() {
+            if play.serial == Some(0) {
+                return Err(Error::msg(format!("Play {}: serial must be at least 1", i + 1)));
+            }
+        }
+        Ok(playbook)
+    }
+
+    /// Runs the plays in order, each after the previous one finished on all of its hosts.
+    ///
+    /// Every host connects once per play, as its `ansible_user` or with `auth`, and runs
+    /// the play's modules in order, stopping at the first one failing. A host which
+    /// failed a play is left out of the later ones, like ansible does it.
+    /// Unknown modules and host patterns matching nothing fail before anything runs.
+    ///
+    /// The canaries of a play run it before any other host, as one batch. The other
+    /// hosts run it only if the [`CanaryPolicy`] and the [`crate::Runner::with_canary_gate`]
+    /// hook let them, otherwise they are skipped and left out of the later plays.
+    ///
+    /// Hosts in the [`crate::Exclusions`] of the runner are skipped in every play, canaries
+    /// too, see [`PlayReport::excluded`].
+    ///
+    /// The modules of all plays are checked like [`Runner::run_module`] checks its module,
+    /// before anything runs, and every result goes through [`Runner`]'s redaction and
+    /// report sink.
+    pub fn run(
+        &self,
+        runner: &Runner,
+        inventory: &Inventory,
+        auth: AuthType,
+        sync: &(dyn ConnectionProps + Sync),
+    ) -> Result<Vec<PlayReport>, Error> {
+        let tree = runner.tree();
+        let modules: Vec<_> = self.plays.iter().flat_map(|play| play.modules.iter().map(String::as_str)).collect();
+        runner.check_plan(&modules)?;
+        let mut options = Cow::Borrowed(runner.options());
+        if let Some(path) = &self.remote_audit_log {
+            options = Cow::Owned(options.audited_to(path));
+        }
+        if let Some(policy) = self.canary_policy {
+            options = Cow::Owned(options.with_canary_policy(policy));
+        }
+        let options = &*options;
+        let mut selected = Vec::with_capacity(self.plays.len());
+        for (i, play) in self.plays.iter().enumerate() {
+            for module in &play.modules {
+                tree.get_module(module)?;
+            }
+            let hosts = play
+                .select(inventory)
+                .map_err(|e| e.context(format!("Play {}", i + 1)))?;
+            selected.push(hosts);
+        }
+        let exclusions = options.exclusions()?;
+        let mut failed = BTreeSet::new();
+        let mut reports = Vec::with_capacity(self.plays.len());
+        for (play, hosts) in self.plays.iter().zip(selected) {
+            let mut excluded = Vec::new();
+            let mut included = Vec::with_capacity(hosts.len());
+            for host in hosts {
+                match exclusion(&exclusions, &host) {
+                    Some(exclusion) => excluded.push((host.name, exclusion.skip_reason())),
+                    None => included.push(host),
+                }
+            }
+            let (skipped, hosts): (Vec<_>, Vec<_>) =
+                included.into_iter().partition(|host| failed.contains(&host.name));
+            let (canaries, hosts): (Vec<_>, Vec<_>) = hosts.into_iter().partition(|host| host.canary);
+            let mut report = PlayReport {
+                hosts: play.hosts.clone(),
+                canaries: run_batch(play, &canaries, &auth, runner, sync, options).into_iter().collect(),
+                results: BTreeMap::new(),
+                skipped: skipped.into_iter().map(|host| host.name).collect(),
+            };
+            for (host, skipped) in excluded {
+                let skipped = recorded(runner, &host, play, skipped.into());
+                report.results.insert(host, Err(skipped));
+            }
+            if let Some(halted) = halt(&report.canaries, options) {
+                for host in hosts {
+                    let skipped = recorded(runner, &host.name, play, SkipReason::CanaryFailed(halted.clone()).into());
+                    report.results.insert(host.name, Err(skipped));
+                }
+            } else {
+                let serial = play.serial.unwrap_or(hosts.len()).max(1);
+                for batch in hosts.chunks(serial) {
+                    report.results.extend(run_batch(play, batch, &auth, runner, sync, options));
+                }
+            }
+            failed.extend(report.failed_hosts().into_iter().map(str::to_string));
+            failed.extend(report.canary_skipped().into_iter().map(str::to_string));
+            reports.push(report);
+        }
+        Ok(reports)
+    }
+}
+
+/// Canaries of a play which failed, if that stops the play, or none if the
+/// gate declined it.
+fn halt(
+    canaries: &BTreeMap<String, Result<Vec<BatchEntry>, Error>>,
+    options: &ExecutionOptions,
+) -> Option<Vec<String>> {
+    if canaries.is_empty() {
+        return None;
+    }
+    if let Some(policy) = options.canary_policy().filter(|policy| policy.pause_on_failure) {
+        let failed: Vec<_> = canaries
+            .iter()
+            .filter(|(_, entries)| !policy.passed(entries))
+            .map(|(host, _)| host.clone())
+            .collect();
+        if !failed.is_empty() {
+            return Some(failed);
+        }
+    }
+    match options.canary_gate() {
+        Some(gate) if !gate(canaries) => Some(Vec::new()),
+        _ => None,
+    }
+}
+
+/// Runs `play` on every host of `batch` at once.
+fn run_batch(
+    play: &Play,
+    batch: &[InventoryHost],
+    auth: &AuthType,
+    runner: &Runner,
+    sync: &(dyn ConnectionProps + Sync),
+    options: &ExecutionOptions,
+) -> Vec<(String, Result<Vec<BatchEntry>, Error>)> {
+    thread::scope(|scope| {
+        let handles: Vec<_> = batch
+            .iter()
+            .map(|host| {
+                let auth = host.auth().unwrap_or_else(|| auth.clone());
+                scope.spawn(move || {
+                    let results = run_play_on(play, host, auth, runner, sync, options);
+                    (host.name.clone(), results)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("host thread panicked"))
+            .collect()
+    })
+}
+
+/// Runs the modules of `play` on `host`, over one connection, recording their results
+/// with `runner`.
+fn run_play_on(
+    play: &Play,
+    host: &InventoryHost,
+    auth: AuthType,
+    runner: &Runner,
+    sync: &dyn ConnectionProps,
+    options: &ExecutionOptions,
+) -> Result<Vec<BatchEntry>, Error> {
+    let key = host.host.to_string();
+    let vars = play.vars_for(host);
+    let entries = HostConnection::connect(host.host.clone(), auth, sync).map(|connection| {
+        let mut entries = Vec::with_capacity(play.modules.len());
+        for name in &play.modules {
+            let output = runner
+                .tree()
+                .get_module(name)
+                .and_then(|module| module.execute_with_vars_on(name, &connection, options, &vars));
+            let output = runner.record(&host.name, name, output);
+            let failed = !matches!(&output, Ok(output) if !output.is_failed());
+            entries.push(BatchEntry::new(name, output));
+            if failed {
+                break;
+            }
+        }
+        entries
+    });
+    sync.tcp_release_for(&key);
+    entries.map_err(|e| recorded(runner, &host.name, play, e))
+}
+
+/// `e`, which kept `host` from running `play`, after recording it with `runner`
+/// as the result of the play's first module.
+fn recorded(runner: &Runner, host: &str, play: &Play, e: Error) -> Error {
+    let module = match play.modules.first() {
+        Some(module) => module,
+        None => return e,
+    };
+    runner.record_error(host, module, e)
+}
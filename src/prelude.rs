@@ -0,0 +1,8 @@
+//! Types needed by most users of the crate:
+//! ```
+//! use ansible_modules::prelude::*;
+//! ```
+pub use crate::{
+    AuthType, CommandOutput, CommandResult, ConnectionProps, DefaultConnectionProps, Error,
+    ExecutionOptions, Host, ItemResult, Module, ModuleTree, OnError, Outcome, RunReport, Runner,
+};
@@ -0,0 +1,128 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Receives the progress of uploads, see [`crate::Runner::with_upload_progress`].
+/// `file` is the remote path of an uploaded command, or the local `stdin_file`
+/// streamed into a command. Called from the thread uploading.
+pub trait UploadProgress: Send + Sync {
+    fn on_progress(&self, host: &str, file: &str, bytes_sent: u64, total: u64);
+}
+
+impl<F> UploadProgress for F
+where
+    F: Fn(&str, &str, u64, u64) + Send + Sync,
+{
+    fn on_progress(&self, host: &str, file: &str, bytes_sent: u64, total: u64) {
+        self(host, file, bytes_sent, total)
+    }
+}
+
+/// Uploads to a host, summed up, see [`crate::Runner::upload_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct UploadStats {
+    pub files: u64,
+    pub bytes: u64,
+    /// Time spent uploading, from the first byte read to the last
+    pub elapsed: Duration,
+}
+
+impl UploadStats {
+    pub fn bytes_per_sec(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Where the uploads of a run report to: the callback, if there is one,
+/// and the stats of every host.
+#[derive(Clone, Default)]
+pub(crate) struct UploadReporter {
+    callback: Option<(Arc<dyn UploadProgress>, u64)>,
+    host: String,
+    stats: Arc<Mutex<HashMap<String, UploadStats>>>,
+}
+
+impl UploadReporter {
+    /// Calls `callback` whenever another `interval` bytes of a file were sent.
+    pub(crate) fn with_callback(&self, callback: Arc<dyn UploadProgress>, interval: u64) -> Self {
+        UploadReporter {
+            callback: Some((callback, interval.max(1))),
+            ..self.clone()
+        }
+    }
+
+    /// This reporter, for uploads to `host`.
+    pub(crate) fn for_host(&self, host: &str) -> Self {
+        UploadReporter {
+            host: host.to_string(),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn stats(&self) -> HashMap<String, UploadStats> {
+        self.stats.lock().expect("upload stats lock poisoned").clone()
+    }
+
+    /// `reader` of the `total` bytes of `file`, reporting what was read of it
+    /// as sent. Stats are recorded once it is dropped.
+    pub(crate) fn track<R: Read>(&self, file: &str, total: u64, reader: R) -> Tracked<R> {
+        Tracked {
+            inner: reader,
+            reporter: self.clone(),
+            file: file.to_string(),
+            total,
+            sent: 0,
+            next: self.callback.as_ref().map_or(u64::MAX, |(_, interval)| *interval),
+            started: None,
+        }
+    }
+}
+
+/// Reader counting what is read from it, see [`UploadReporter::track`].
+pub(crate) struct Tracked<R> {
+    inner: R,
+    reporter: UploadReporter,
+    file: String,
+    total: u64,
+    sent: u64,
+    /// Bytes sent at which the callback is called next
+    next: u64,
+    started: Option<Instant>,
+}
+
+impl<R: Read> Read for Tracked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.started.get_or_insert_with(Instant::now);
+        let read = self.inner.read(buf)?;
+        self.sent += read as u64;
+        let (callback, interval) = match &self.reporter.callback {
+            Some(callback) => callback,
+            None => return Ok(read),
+        };
+        let finished = read == 0 || self.sent == self.total;
+        if self.sent >= self.next || (finished && self.next != u64::MAX) {
+            callback.on_progress(&self.reporter.host, &self.file, self.sent, self.total);
+            self.next = if finished {
+                u64::MAX
+            } else {
+                (self.sent / interval + 1) * interval
+            };
+        }
+        Ok(read)
+    }
+}
+
+impl<R> Drop for Tracked<R> {
+    fn drop(&mut self) {
+        let mut stats = self.reporter.stats.lock().expect("upload stats lock poisoned");
+        let stats = stats.entry(self.reporter.host.clone()).or_default();
+        stats.files += 1;
+        stats.bytes += self.sent;
+        stats.elapsed += self.started.map_or(Duration::from_secs(0), |started| started.elapsed());
+    }
+}
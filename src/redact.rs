@@ -0,0 +1,141 @@
+use crate::{CommandOutput, CommandResult};
+use anyhow::Error;
+use regex::Regex;
+use serde_json::Value;
+use std::borrow::Cow;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Patterns redacted by every [`Redactor`]: AWS access key ids, AWS secret keys
+/// assigned in `aws_secret_access_key = ...` form and `Bearer` tokens.
+const BUILTIN_PATTERNS: &[&str] = &[
+    r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b",
+    r"(?i)aws_secret_access_key\s*[=:]\s*[A-Za-z0-9/+=]{40}",
+    r"(?i)\bbearer\s+[A-Za-z0-9._~+/-]+=*",
+];
+
+/// Scrubs secrets from command output before anyone sees it, see
+/// [`crate::Runner::with_redacted_output`]: known `secret` values and matches of
+/// the built-in patterns and of added ones are replaced by `[REDACTED]`.
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    secrets: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Redactor {
+            secrets: Vec::new(),
+            patterns: BUILTIN_PATTERNS
+                .iter()
+                .map(|pattern| Regex::new(pattern).expect("built-in pattern is valid"))
+                .collect(),
+        }
+    }
+}
+
+impl Redactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Redacts every occurrence of `value`. Empty values are ignored.
+    pub fn secret(mut self, value: &str) -> Self {
+        if !value.is_empty() {
+            self.secrets.push(value.to_string());
+            // a secret containing another one is redacted whole
+            self.secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        }
+        self
+    }
+
+    /// Redacts matches of `pattern` too.
+    pub fn pattern(mut self, pattern: &str) -> Result<Self, Error> {
+        self.patterns.push(Regex::new(pattern)?);
+        Ok(self)
+    }
+
+    /// `text` with the secrets replaced, borrowed if there were none.
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for secret in &self.secrets {
+            if text.contains(secret.as_str()) {
+                text = Cow::Owned(text.replace(secret.as_str(), REDACTED));
+            }
+        }
+        for pattern in &self.patterns {
+            if let Cow::Owned(replaced) = pattern.replace_all(&text, REDACTED) {
+                text = Cow::Owned(replaced);
+            }
+        }
+        text
+    }
+
+    /// Redacts `text` in place, returning whether anything was redacted.
+    fn scrub(&self, text: &mut String) -> bool {
+        match self.redact(text) {
+            Cow::Owned(redacted) => {
+                *text = redacted;
+                true
+            }
+            Cow::Borrowed(_) => false,
+        }
+    }
+
+    fn scrub_value(&self, value: &mut Value) -> bool {
+        match value {
+            Value::String(text) => self.scrub(text),
+            Value::Array(values) => values.iter_mut().fold(false, |found, value| {
+                self.scrub_value(value) | found
+            }),
+            Value::Object(map) => map
+                .values_mut()
+                .fold(false, |found, value| self.scrub_value(value) | found),
+            _ => false,
+        }
+    }
+
+    /// Redacts everything of `result` a command printed, flagging it if anything was.
+    fn scrub_result(&self, result: &mut CommandResult) {
+        let mut found = self.scrub(&mut result.stdout);
+        if let Some(stderr) = &mut result.stderr {
+            found |= self.scrub(stderr);
+        }
+        for value in result.captures.values_mut() {
+            found |= self.scrub(value);
+        }
+        if let Some(failure) = &mut result.failure {
+            found |= self.scrub(failure);
+        }
+        for warning in &mut result.warnings {
+            found |= self.scrub(warning);
+        }
+        if let Some(parsed) = &mut result.parsed {
+            found |= self.scrub_value(parsed);
+        }
+        result.redacted |= found;
+    }
+
+    pub(crate) fn apply(&self, output: CommandOutput) -> CommandOutput {
+        match output {
+            CommandOutput::Multi(mut results) => {
+                results.values_mut().for_each(|result| self.scrub_result(result));
+                CommandOutput::Multi(results)
+            }
+            CommandOutput::Single(mut result) => {
+                self.scrub_result(&mut result);
+                CommandOutput::Single(result)
+            }
+        }
+    }
+
+    /// `e` with the secrets in its message redacted. Errors without any are kept,
+    /// so typed errors like [`crate::SkipReason`] can still be found.
+    pub(crate) fn apply_error(&self, e: Error) -> Error {
+        match self.redact(&format!("{:#}", e)) {
+            Cow::Owned(redacted) => Error::msg(redacted),
+            Cow::Borrowed(_) => e,
+        }
+    }
+}
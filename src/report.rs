@@ -0,0 +1,90 @@
+use crate::{CommandOutput, Module};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Hosts which returned the same output.
+#[derive(Debug)]
+pub struct OutputGroup<'a, H> {
+    pub output: &'a CommandOutput,
+    pub hosts: Vec<&'a H>,
+}
+
+/// Groups hosts by identical output, largest group first, hosts sorted within a group.
+/// Useful to spot configuration drift: "198 hosts returned X, 2 returned Y".
+pub fn group_by_output<H>(results: &HashMap<H, CommandOutput>) -> Vec<OutputGroup<'_, H>>
+where
+    H: Hash + Eq + Ord,
+{
+    let mut sorted: Vec<_> = results.iter().collect();
+    sorted.sort_by(|a, b| a.0.cmp(b.0));
+    let mut groups: Vec<OutputGroup<H>> = Vec::new();
+    for (host, output) in sorted {
+        match groups.iter_mut().find(|group| group.output == output) {
+            Some(group) => group.hosts.push(host),
+            None => groups.push(OutputGroup {
+                output,
+                hosts: vec![host],
+            }),
+        }
+    }
+    groups.sort_by_key(|group| std::cmp::Reverse(group.hosts.len()));
+    groups
+}
+
+/// Hosts whose output of a command has the same [`Module::output_fingerprint`].
+#[derive(Debug)]
+pub struct FingerprintGroup<'a, H> {
+    pub fingerprint: String,
+    pub hosts: Vec<&'a H>,
+}
+
+/// Result of [`group_by_fingerprint`]: clusters of at least two hosts, largest first,
+/// and the outliers, hosts whose output matched no other host's or which have no
+/// output of the command at all.
+#[derive(Debug)]
+pub struct FingerprintGroups<'a, H> {
+    pub clusters: Vec<FingerprintGroup<'a, H>>,
+    pub outliers: Vec<&'a H>,
+}
+
+/// Groups hosts by the normalized output of `command` of `module`, so that
+/// outputs differing only by noise like timestamps collapse into one cluster.
+pub fn group_by_fingerprint<'a, H>(
+    results: &'a HashMap<H, CommandOutput>,
+    module: &Module,
+    command: &str,
+) -> FingerprintGroups<'a, H>
+where
+    H: Hash + Eq + Ord,
+{
+    let mut sorted: Vec<_> = results.iter().collect();
+    sorted.sort_by(|a, b| a.0.cmp(b.0));
+    let mut groups: Vec<FingerprintGroup<H>> = Vec::new();
+    let mut outliers = Vec::new();
+    for (host, output) in sorted {
+        let result = match output {
+            CommandOutput::Multi(map) => map.get(command),
+            CommandOutput::Single(_) => None,
+        };
+        let fingerprint = match result {
+            Some(result) => module.output_fingerprint(result),
+            None => {
+                outliers.push(host);
+                continue;
+            }
+        };
+        match groups.iter_mut().find(|group| group.fingerprint == fingerprint) {
+            Some(group) => group.hosts.push(host),
+            None => groups.push(FingerprintGroup {
+                fingerprint,
+                hosts: vec![host],
+            }),
+        }
+    }
+    let (mut clusters, singles): (Vec<_>, Vec<_>) =
+        groups.into_iter().partition(|group| group.hosts.len() > 1);
+    outliers.extend(singles.into_iter().flat_map(|group| group.hosts));
+    outliers.sort();
+    clusters.sort_by_key(|group| std::cmp::Reverse(group.hosts.len()));
+    FingerprintGroups { clusters, outliers }
+}
@@ -0,0 +1,317 @@
+use crate::{ConnectionProps, Host};
+use anyhow::Error;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Failed name resolution, telling a hung resolver apart from an unknown name.
+/// Errors carry it, find it with `downcast_ref::<DnsError>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsError {
+    /// The resolver didn't answer within the dns timeout.
+    Timeout { host: String, timeout: Duration },
+    /// The resolver answered with an error, e.g. the name doesn't exist.
+    Failed { host: String, message: String },
+}
+
+impl Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsError::Timeout { host, timeout } => {
+                write!(f, "Resolving {} timed out after {}ms", host, timeout.as_millis())
+            }
+            DnsError::Failed { host, message } => {
+                write!(f, "Resolving {} failed: {}", host, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DnsError {}
+
+/// Resolves `host` the way the system does, on a helper thread if there is a `timeout`,
+/// as std has no way to cancel a lookup. A thread which timed out is left to finish.
+fn resolve_system(host: &str, timeout: Option<Duration>) -> Result<Vec<SocketAddr>, Error> {
+    let failed = |e: std::io::Error, host: &str| DnsError::Failed {
+        host: host.to_string(),
+        message: e.to_string(),
+    };
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => {
+            let addresses = Host::parse(host).to_socket_addrs().map_err(|e| failed(e, host))?;
+            return Ok(addresses.collect());
+        }
+    };
+    let (sender, receiver) = channel();
+    let name = host.to_string();
+    thread::spawn(move || {
+        let addresses = Host::parse(&name).to_socket_addrs().map(Iterator::collect);
+        let _ = sender.send(addresses);
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(addresses) => Ok(addresses.map_err(|e| failed(e, host))?),
+        Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+            Err(DnsError::Timeout {
+                host: host.to_string(),
+                timeout,
+            }
+            .into())
+        }
+    }
+}
+
+/// Finds the addresses to connect to, see [`crate::ConnectionProps::resolver`].
+pub trait Resolver: Send + Sync {
+    /// Addresses of `host`, given as [`Host::parse`] reads it, e.g. `web01:22`
+    /// or `web01[10.0.0.1]:22`. They are tried in order.
+    fn resolve(&self, host: &str) -> Result<Vec<SocketAddr>, Error>;
+
+    /// [`Resolver::resolve`] giving up after `timeout`, with a [`DnsError::Timeout`].
+    /// By default the timeout is ignored, resolvers which can hang should honor it.
+    fn resolve_within(
+        &self,
+        host: &str,
+        _timeout: Option<Duration>,
+    ) -> Result<Vec<SocketAddr>, Error> {
+        self.resolve(host)
+    }
+}
+
+/// Resolves hosts the way the system does.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str) -> Result<Vec<SocketAddr>, Error> {
+        resolve_system(host, None)
+    }
+
+    fn resolve_within(
+        &self,
+        host: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<SocketAddr>, Error> {
+        resolve_system(host, timeout)
+    }
+}
+
+/// Fixed addresses for some hosts, the system resolves the others.
+/// ```
+/// # use ansible_modules::{Resolver, StaticResolver};
+/// let resolver = StaticResolver::new().with("web01", "10.8.3.4");
+/// assert_eq!(resolver.resolve("web01:2222").unwrap(), ["10.8.3.4:2222".parse().unwrap()]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct StaticResolver {
+    addresses: HashMap<String, String>,
+}
+
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connects to `address` for hosts named or addressed `host`.
+    /// `address` may have its own port, e.g. `127.0.0.1:2222`, otherwise the host's is kept.
+    pub fn with(mut self, host: &str, address: &str) -> Self {
+        self.insert(host, address);
+        self
+    }
+
+    pub fn insert(&mut self, host: &str, address: &str) {
+        self.addresses.insert(host.to_string(), address.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    /// Reads overrides like `web01=10.8.3.4,web02=10.8.3.5:2222`.
+    pub fn parse(overrides: &str) -> Result<Self, Error> {
+        let mut resolver = StaticResolver::new();
+        for entry in overrides.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            match entry.split_once('=') {
+                Some((host, address)) if !host.trim().is_empty() && !address.trim().is_empty() => {
+                    resolver.insert(host.trim(), address.trim())
+                }
+                _ => {
+                    return Err(Error::msg(format!(
+                        "Bad address override {:?}, expected host=address",
+                        entry
+                    )))
+                }
+            }
+        }
+        Ok(resolver)
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve(&self, host: &str) -> Result<Vec<SocketAddr>, Error> {
+        self.resolve_within(host, None)
+    }
+
+    /// Overridden hosts are addresses, only the others are resolved within `timeout`.
+    fn resolve_within(
+        &self,
+        host: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<SocketAddr>, Error> {
+        let parsed = Host::parse(host);
+        let address = parsed
+            .name()
+            .and_then(|name| self.addresses.get(name))
+            .or_else(|| self.addresses.get(parsed.address()));
+        match address {
+            Some(address) => {
+                let target = Host::parse(address);
+                let port = explicit_port(address).unwrap_or_else(|| parsed.port());
+                Ok((target.address(), port).to_socket_addrs()?.collect())
+            }
+            None => SystemResolver.resolve_within(host, timeout),
+        }
+    }
+}
+
+/// Keeps what `inner` resolves, so a run resolves every host once, however many
+/// modules it runs there. Failures are kept for a short while only, 5 seconds
+/// by default, so a host which failed doesn't stall every module after it.
+/// ```
+/// # use ansible_modules::{CachingResolver, StaticResolver};
+/// # use std::time::Duration;
+/// let resolver = CachingResolver::new(StaticResolver::new())
+///     .with_negative_ttl(Duration::from_secs(1));
+/// ```
+pub struct CachingResolver<R> {
+    inner: R,
+    negative_ttl: Duration,
+    resolved: Mutex<HashMap<String, Vec<SocketAddr>>>,
+    failed: Mutex<HashMap<String, (Instant, DnsError)>>,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    pub fn new(inner: R) -> Self {
+        CachingResolver {
+            inner,
+            negative_ttl: Duration::from_secs(5),
+            resolved: Mutex::new(HashMap::new()),
+            failed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long failures are kept, zero doesn't keep them at all.
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    /// Forgets everything resolved so far, e.g. between runs.
+    pub fn clear(&self) {
+        self.resolved.lock().expect("dns cache lock poisoned").clear();
+        self.failed.lock().expect("dns cache lock poisoned").clear();
+    }
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn resolve(&self, host: &str) -> Result<Vec<SocketAddr>, Error> {
+        self.resolve_within(host, None)
+    }
+
+    fn resolve_within(
+        &self,
+        host: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<SocketAddr>, Error> {
+        if let Some(addresses) = self.resolved.lock().expect("dns cache lock poisoned").get(host) {
+            return Ok(addresses.clone());
+        }
+        if let Some((at, e)) = self.failed.lock().expect("dns cache lock poisoned").get(host) {
+            if at.elapsed() < self.negative_ttl {
+                return Err(e.clone().into());
+            }
+        }
+        // the locks aren't held while resolving, hosts resolve concurrently
+        match self.inner.resolve_within(host, timeout) {
+            Ok(addresses) => {
+                self.resolved
+                    .lock()
+                    .expect("dns cache lock poisoned")
+                    .insert(host.to_string(), addresses.clone());
+                Ok(addresses)
+            }
+            Err(e) => {
+                if let Some(dns) = e.downcast_ref::<DnsError>() {
+                    self.failed
+                        .lock()
+                        .expect("dns cache lock poisoned")
+                        .insert(host.to_string(), (Instant::now(), dns.clone()));
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Addresses of `host`, from the resolver of `sync` or the system,
+/// giving up after the dns timeout of `sync`.
+pub(crate) fn resolve_host<A: ToSocketAddrs>(
+    ip: &A,
+    host: &str,
+    sync: &dyn ConnectionProps,
+) -> Result<Vec<SocketAddr>, Error> {
+    let timeout = sync
+        .dns_timeout_for(host)
+        .map(|timeout| Duration::from_millis(timeout.max(1) as u64));
+    match (sync.resolver(), timeout) {
+        (Some(resolver), timeout) => resolver.resolve_within(host, timeout),
+        (None, Some(timeout)) => resolve_system(host, Some(timeout)),
+        (None, None) => {
+            let addresses = ip.to_socket_addrs().map_err(|e| DnsError::Failed {
+                host: host.to_string(),
+                message: e.to_string(),
+            })?;
+            Ok(addresses.collect())
+        }
+    }
+}
+
+/// Port given in `address`, as in `10.0.0.1:2222` or `[::1]:2222`.
+fn explicit_port(address: &str) -> Option<u16> {
+    let (host, port) = address.rsplit_once(':')?;
+    if host.contains(':') && !host.ends_with(']') {
+        // a bare v6 address
+        return None;
+    }
+    port.parse().ok()
+}
+
+/// Connects to the first of the `addresses` of `host` which accepts the connection,
+/// waiting at most `timeout` milliseconds for each.
+pub(crate) fn connect_any(
+    host: &str,
+    addresses: &[SocketAddr],
+    timeout: Option<u32>,
+) -> Result<TcpStream, Error> {
+    let mut last = None;
+    for address in addresses {
+        let tcp = match timeout {
+            Some(timeout) => {
+                TcpStream::connect_timeout(address, Duration::from_millis(timeout.max(1) as u64))
+            }
+            None => TcpStream::connect(address),
+        };
+        match tcp {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(match last {
+        Some(e) => e.into(),
+        None => Error::msg(format!("{} resolved to no addresses", host)),
+    })
+}
@@ -0,0 +1,202 @@
+use crate::{AuthType, ConnectionProps, Outcome, Runner, SCHEMA_VERSION};
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Display};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::net::ToSocketAddrs;
+use std::path::Path;
+
+const RESUME_FORMAT: &str = "ansible-modules-resume";
+const RESUME_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+}
+
+/// How a module did on a host, as kept in a resume file.
+/// `error` is set when the module couldn't run at all, e.g. the host was unreachable.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairRecord {
+    pub host: String,
+    pub module: String,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+}
+
+impl PairRecord {
+    fn succeeded(&self) -> bool {
+        self.outcome != Outcome::Failed && self.error.is_none()
+    }
+}
+
+/// Result of [`Runner::run_all_resume`], every pair in the order of hosts, then modules.
+/// `resumed` is set if the resume file had progress of an earlier run,
+/// `skipped` counts the pairs which had succeeded then and didn't run again.
+/// `schema_version` is [`crate::SCHEMA_VERSION`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ResumedRun {
+    pub schema_version: u32,
+    pub resumed: bool,
+    pub skipped: usize,
+    pub records: Vec<PairRecord>,
+}
+
+/// Reads the records of a resume file, none if it doesn't exist or is empty,
+/// and the length of the file up to the last complete record.
+///
+/// A last line without a newline was cut short by a crash, it is dropped and
+/// its pair runs again. Anything else which doesn't parse is an error.
+fn load(path: &Path) -> Result<Option<(Vec<PairRecord>, u64)>, Error> {
+    let corrupted = |what: String| {
+        Error::msg(format!(
+            "Resume file {} is corrupted: {}, remove it to start over",
+            path.display(),
+            what
+        ))
+    };
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let content = String::from_utf8(content).map_err(|_| corrupted("not utf-8".to_string()))?;
+    let mut lines: Vec<&str> = content.split('\n').collect();
+    // the last piece is everything after the last newline
+    let torn = lines.pop().filter(|torn| !torn.is_empty());
+    let complete = (content.len() - torn.map_or(0, str::len)) as u64;
+    let header = match lines.first() {
+        Some(header) => header,
+        None if torn.is_none() => return Ok(None),
+        None => return Err(corrupted("no complete header".to_string())),
+    };
+    let header: Header =
+        serde_json::from_str(header).map_err(|e| corrupted(format!("bad header: {}", e)))?;
+    if header.format != RESUME_FORMAT {
+        return Err(corrupted(format!("unknown format {:?}", header.format)));
+    }
+    if header.version != RESUME_VERSION {
+        return Err(Error::msg(format!(
+            "Resume file {} has version {}, expected {}",
+            path.display(),
+            header.version,
+            RESUME_VERSION
+        )));
+    }
+    let records = lines[1..]
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| corrupted(format!("line {}: {}", i + 2, e)))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Some((records, complete)))
+}
+
+/// Appends records to a resume file, each synced to disk before the next pair runs.
+struct Journal {
+    file: File,
+}
+
+impl Journal {
+    /// Continues the file after its first `complete` bytes, or starts it over without them.
+    fn open(path: &Path, complete: Option<u64>) -> Result<Journal, Error> {
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+        // drops a torn last record
+        file.set_len(complete.unwrap_or(0))?;
+        let mut journal = Journal { file };
+        journal.file.seek(SeekFrom::End(0))?;
+        if complete.is_none() {
+            let header = Header {
+                format: RESUME_FORMAT.to_string(),
+                version: RESUME_VERSION,
+            };
+            journal.append(&serde_json::to_string(&header)?)?;
+        }
+        Ok(journal)
+    }
+
+    fn append(&mut self, line: &str) -> Result<(), Error> {
+        let mut line = line.to_string();
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+impl Runner {
+    /// Runs every module on every host, one pair after another, keeping progress in
+    /// `resume_file` so that a run which died can be picked up where it stopped.
+    ///
+    /// Pairs which succeeded according to the file are skipped, failed and missing
+    /// ones run again. A file which isn't a resume file of this version is rejected,
+    /// rather than starting over. It is created if it doesn't exist. Hosts in the
+    /// [`crate::Exclusions`] are skipped, their pairs run once resumed without them.
+    pub fn run_all_resume<A>(
+        &self,
+        module_names: &[&str],
+        hosts: &[A],
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+        resume_file: &Path,
+    ) -> Result<ResumedRun, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        self.check_plan(module_names)?;
+        let exclusions = self.options().exclusions()?;
+        let (previous, complete) = match load(resume_file)? {
+            Some((records, complete)) => (records, Some(complete)),
+            None => (Vec::new(), None),
+        };
+        let resumed = !previous.is_empty();
+        let mut latest: HashMap<(String, String), PairRecord> = HashMap::new();
+        for record in previous {
+            latest.insert((record.host.clone(), record.module.clone()), record);
+        }
+        let mut journal = Journal::open(resume_file, complete)?;
+        let mut seen = HashSet::new();
+        let mut skipped = 0;
+        let mut records = Vec::new();
+        for host in hosts {
+            let key = host.to_string();
+            for name in module_names {
+                if !seen.insert((key.clone(), name.to_string())) {
+                    continue;
+                }
+                let done = latest.get(&(key.clone(), name.to_string()));
+                if let Some(record) = done.filter(|record| record.succeeded()) {
+                    skipped += 1;
+                    records.push(record.clone());
+                    continue;
+                }
+                let output = match exclusions.matching(&key) {
+                    Some(exclusion) => self.record(&key, name, Err(exclusion.skip_reason().into())),
+                    None => self.run_module(name, host.clone(), auth.clone(), sync),
+                };
+                let (outcome, error) = match output {
+                    Ok(output) => (output.outcome(), None),
+                    Err(e) => (Outcome::Failed, Some(format!("{:#}", e))),
+                };
+                let record = PairRecord {
+                    host: key.clone(),
+                    module: name.to_string(),
+                    outcome,
+                    error,
+                };
+                journal.append(&serde_json::to_string(&record)?)?;
+                records.push(record);
+            }
+        }
+        Ok(ResumedRun {
+            schema_version: SCHEMA_VERSION,
+            resumed,
+            skipped,
+            records,
+        })
+    }
+}
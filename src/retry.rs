@@ -0,0 +1,98 @@
+//! Commands run again when the host says it is busy, see [`RetryHint`].
+use crate::{parse_duration, CommandResult};
+use anyhow::Error;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::time::Duration;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+/// Longest delay a command is retried after, see [`RetryHint`].
+pub(crate) const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// How hosts say a command should run again later rather than fail, e.g.
+/// `retry_hint = { exit_code = 75, delay_regex = "retry after (\\d+)s" }` in a `.mod`
+/// file. A command exiting with `exit_code` whose stderr, or stdout if it merges the
+/// streams, matches `delay_regex` runs again after the delay the first group of the
+/// regex captures, in seconds or as a duration like `1m30s`.
+///
+/// The commands of a module with a hint run one at a time, in order of their names, each
+/// retried before the next one runs. Commands run at most `max_attempts` times, 3 by
+/// default, and aren't retried after more than an hour, or if that would take them past
+/// the module's timeout, counted from their first attempt. A command still asking to be
+/// retried then fails. Every attempt is in [`CommandResult::attempts`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RetryHintSpec")]
+pub struct RetryHint {
+    pub(crate) exit_code: i32,
+    pub(crate) delay_regex: Regex,
+    pub(crate) max_attempts: u32,
+}
+
+#[derive(Deserialize)]
+struct RetryHintSpec {
+    exit_code: i32,
+    delay_regex: String,
+    max_attempts: Option<u32>,
+}
+
+impl TryFrom<RetryHintSpec> for RetryHint {
+    type Error = Error;
+
+    fn try_from(spec: RetryHintSpec) -> Result<Self, Error> {
+        let hint = RetryHint::new(spec.exit_code, &spec.delay_regex)?;
+        Ok(match spec.max_attempts {
+            Some(attempts) => hint.max_attempts(attempts),
+            None => hint,
+        })
+    }
+}
+
+/// Attempt of a command of a module with a [`RetryHint`]. `started_at` is in milliseconds
+/// since the epoch, on the controller's clock. `retry_after_ms` is the delay the attempt
+/// asked for, `None` if it didn't ask to be retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RetryAttempt {
+    pub started_at: i64,
+    pub exit_status: Option<i32>,
+    pub retry_after_ms: Option<u64>,
+}
+
+impl RetryHint {
+    /// Retries commands exiting with `exit_code` whose output matches `delay_regex`,
+    /// which captures the delay in its first group.
+    pub fn new(exit_code: i32, delay_regex: &str) -> Result<Self, Error> {
+        let delay_regex = Regex::new(delay_regex)?;
+        if delay_regex.captures_len() < 2 {
+            return Err(Error::msg(format!(
+                "retry_hint delay_regex {:?} has no group capturing the delay",
+                delay_regex.as_str()
+            )));
+        }
+        Ok(RetryHint {
+            exit_code,
+            delay_regex,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+        })
+    }
+
+    /// Runs commands that many times at most, the first attempt included, at least once.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Delay `result` asks for, if it is to be retried.
+    pub fn delay(&self, result: &CommandResult) -> Option<Duration> {
+        if result.exit_status != Some(self.exit_code) {
+            return None;
+        }
+        let output = result.stderr.as_deref().unwrap_or(&result.stdout);
+        let delay = self.delay_regex.captures(output)?.get(1)?.as_str();
+        match delay.parse() {
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(_) => parse_duration(delay).ok(),
+        }
+    }
+}
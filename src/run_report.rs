@@ -0,0 +1,249 @@
+use crate::artifacts::CLEANUP_TIMEOUT;
+use crate::{
+    AuthType, BatchEntry, ConnectionProps, ExecutionOptions, HostConnection, ModuleTree, SkipReason,
+};
+use anyhow::Error;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Debug, Display};
+use std::net::ToSocketAddrs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Hosts [`ModuleTree::run_all_on_hosts`] runs on at once, unless
+/// [`ExecutionOptions::max_concurrent_hosts`] says otherwise.
+pub const DEFAULT_MAX_CONCURRENT_HOSTS: usize = 16;
+
+/// What [`ModuleTree::run_all`] did on a host: the output of every module in run order,
+/// or why the host couldn't connect. Failures are kept here rather than returned,
+/// see [`RunReport::into_result`] to get them as an error.
+#[derive(Debug)]
+pub struct RunReport {
+    pub host: String,
+    pub entries: Result<Vec<BatchEntry>, Error>,
+}
+
+impl RunReport {
+    /// Whether the host connected and no module failed or was skipped.
+    pub fn is_success(&self) -> bool {
+        self.failed().is_none()
+    }
+
+    /// Modules which failed, with why, in run order. Modules skipped for a failed
+    /// dependency aren't listed, nor are any if the host couldn't connect.
+    pub fn failures(&self) -> Vec<(&str, String)> {
+        let entries = match &self.entries {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        entries
+            .iter()
+            .filter(|entry| entry.skip_reason().is_none())
+            .filter_map(|entry| match &entry.output {
+                Ok(output) => output
+                    .clone()
+                    .into_result()
+                    .err()
+                    .map(|e| (entry.module.as_str(), e.to_string())),
+                Err(e) => Some((entry.module.as_str(), format!("{:#}", e))),
+            })
+            .collect()
+    }
+
+    fn failed(&self) -> Option<RunFailed> {
+        let entries = match &self.entries {
+            Ok(entries) => entries,
+            Err(e) => {
+                return Some(RunFailed {
+                    host: self.host.clone(),
+                    modules: 0,
+                    unreachable: Some(format!("{:#}", e)),
+                    failures: Vec::new(),
+                    skipped: 0,
+                })
+            }
+        };
+        let failures: Vec<_> = self
+            .failures()
+            .into_iter()
+            .map(|(module, why)| (module.to_string(), why))
+            .collect();
+        let skipped = entries.iter().filter(|entry| entry.skip_reason().is_some()).count();
+        if failures.is_empty() && skipped == 0 {
+            return None;
+        }
+        Some(RunFailed {
+            host: self.host.clone(),
+            modules: entries.len(),
+            unreachable: None,
+            failures,
+            skipped,
+        })
+    }
+
+    /// The report, or a [`RunFailed`] error summarizing what failed if anything did,
+    /// for callers which stop at the first failed host.
+    pub fn into_result(self) -> Result<RunReport, Error> {
+        match self.failed() {
+            Some(failed) => Err(failed.into()),
+            None => Ok(self),
+        }
+    }
+}
+
+/// Summary of a failed [`RunReport`], carried by the error of [`RunReport::into_result`],
+/// find it with `downcast_ref::<RunFailed>()`.
+/// `unreachable` says why the host couldn't connect, no module ran then.
+/// `failures` holds each failed module with why, `skipped` counts modules
+/// which didn't run as a module they depend on failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunFailed {
+    pub host: String,
+    pub modules: usize,
+    pub unreachable: Option<String>,
+    pub failures: Vec<(String, String)>,
+    pub skipped: usize,
+}
+
+impl Display for RunFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(why) = &self.unreachable {
+            return write!(f, "No module ran on {}: {}", self.host, why);
+        }
+        write!(
+            f,
+            "{} of {} modules failed on {}",
+            self.failures.len(),
+            self.modules,
+            self.host
+        )?;
+        if !self.failures.is_empty() {
+            let failures: Vec<_> = self
+                .failures
+                .iter()
+                .map(|(module, why)| format!("{} ({})", module, why))
+                .collect();
+            write!(f, ": {}", failures.join(", "))?;
+        }
+        if self.skipped > 0 {
+            write!(f, ", {} skipped", self.skipped)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RunFailed {}
+
+impl ModuleTree {
+    /// Runs every module of the tree on a host over one connection, in
+    /// [`ModuleTree::dependency_order`] of their sorted names. A failed module skips
+    /// those depending on it, with a [`SkipReason`] error, the others still run.
+    ///
+    /// Only what keeps the run from starting is an error: an empty tree, dependency
+    /// cycles and missing dependencies. Everything failing on the host is in the report.
+    /// Files which failed modules left on the host are removed at the end, what couldn't
+    /// be is printed to stderr. Pass [`crate::Runner::options`] to run with those of a runner.
+    pub fn run_all<A>(
+        &self,
+        ip: A,
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+        options: &ExecutionOptions,
+    ) -> Result<RunReport, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        let names = self.module_names();
+        if names.is_empty() {
+            return Err(Error::msg("Module tree has no modules to run"));
+        }
+        let modules = self
+            .dependency_order(&names)?
+            .into_iter()
+            .map(|name| self.get_module(name).map(|module| (name, module)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let host = ip.to_string();
+        let entries = HostConnection::connect(ip, auth, sync).map(|connection| {
+            let mut failed = HashSet::new();
+            let mut entries = Vec::with_capacity(modules.len());
+            for (name, module) in modules {
+                let failed_dependency = module
+                    .depends_on()
+                    .iter()
+                    .find(|dependency| failed.contains(dependency.as_str()));
+                if let Some(dependency) = failed_dependency {
+                    failed.insert(name);
+                    let skipped = SkipReason::DependencyFailed(dependency.clone());
+                    entries.push(BatchEntry::new(name, Err(skipped.into())));
+                    continue;
+                }
+                let output = module
+                    .execute_named_on(Some(name), &connection, options)
+                    .map_err(|e| self.explain_unset_state(e));
+                if !matches!(&output, Ok(output) if !output.is_failed()) {
+                    failed.insert(name);
+                }
+                entries.push(BatchEntry::new(name, output));
+            }
+            let left = connection.clean_up(CLEANUP_TIMEOUT);
+            if !left.is_empty() {
+                eprintln!("warning: left behind on {}, remove by hand: {}", host, left.join(" "));
+            }
+            entries
+        });
+        sync.tcp_release_for(&host);
+        Ok(RunReport { host, entries })
+    }
+
+    /// [`ModuleTree::run_all`] on every host concurrently, at most
+    /// [`ExecutionOptions::max_concurrent_hosts`] at once, with the auth `auth` gives
+    /// for it, returning the report of each host by `to_string()`. Pass `|_| auth.clone()`
+    /// to share one. Connections go through the permits of `sync` as usual.
+    ///
+    /// As with one host, only what keeps the run from starting is an error, checked
+    /// before connecting anywhere. Unreachable hosts and failed modules are in the reports,
+    /// as are hosts in the exclusions of `options`, which are skipped.
+    pub fn run_all_on_hosts<A, F>(
+        &self,
+        hosts: &[A],
+        auth: F,
+        sync: &(dyn ConnectionProps + Sync),
+        options: &ExecutionOptions,
+    ) -> Result<HashMap<String, RunReport>, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+        F: Fn(&A) -> AuthType + Sync,
+    {
+        let names = self.module_names();
+        if names.is_empty() {
+            return Err(Error::msg("Module tree has no modules to run"));
+        }
+        self.dependency_order(&names)?;
+        let exclusions = &options.exclusions()?;
+        let auth = &auth;
+        let run_on = |host: &A| {
+            let report = match exclusions.matching(&host.to_string()) {
+                Some(exclusion) => Err(exclusion.skip_reason().into()),
+                None => self.run_all(host.clone(), auth(host), sync, options),
+            };
+            report.unwrap_or_else(|e| RunReport {
+                host: host.to_string(),
+                entries: Err(e),
+            })
+        };
+        // each worker takes the next host which nobody runs on yet
+        let next = AtomicUsize::new(0);
+        let reports = Mutex::new(HashMap::with_capacity(hosts.len()));
+        thread::scope(|scope| {
+            for _ in 0..options.concurrent_hosts().min(hosts.len()) {
+                scope.spawn(|| {
+                    while let Some(host) = hosts.get(next.fetch_add(1, Ordering::SeqCst)) {
+                        let report = run_on(host);
+                        reports.lock().expect("reports lock poisoned").insert(host.to_string(), report);
+                    }
+                });
+            }
+        });
+        Ok(reports.into_inner().expect("reports lock poisoned"))
+    }
+}
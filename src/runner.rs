@@ -0,0 +1,1501 @@
+use crate::budget::{BudgetStats, OutputBudget};
+use crate::connection::{ConnectionCache, SharedConnection};
+use crate::exec::{exec_staged, DEFAULT_INLINE_LIMIT};
+use crate::exclusion::{Exclusions, DEFAULT_EXCLUSIONS_FILE};
+use crate::modules::{fnv1a_hex, read_channel};
+use crate::progress::UploadReporter;
+use crate::run_report::DEFAULT_MAX_CONCURRENT_HOSTS;
+use crate::shell::{env_prefix, shell_format, ShellSafe};
+use crate::channels::ChannelGate;
+use crate::traffic::TrafficCounter;
+use crate::{
+    check_env_name, check_umask, AuthType, CancelToken, ChannelStats, CommandOutput, CommandResult, ConnectionProps, HostConnection, ItemResult, Module,
+    ModuleRecord, ModuleTree, OnError, Outcome, OutputParser, Redactor, ReportSink, Retain, RunSummary, SCHEMA_VERSION,
+    TrafficStats, ShellCommand, SkipReason, UploadProgress, UploadStats,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use anyhow::Error;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::any::Any;
+use std::fmt::{Debug, Display};
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// User supplied hook, which rewrites every command before it is sent to the host.
+pub type CommandWrapper = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// User supplied hook, which decides from the results of the canaries of a play, by host,
+/// whether the other hosts run it, see [`Runner::with_canary_gate`].
+pub type CanaryGate = Arc<dyn Fn(&BTreeMap<String, Result<Vec<BatchEntry>, Error>>) -> bool + Send + Sync>;
+
+/// Run-level settings, shared by every module executed by a [`Runner`].
+#[derive(Clone, Default)]
+pub struct ExecutionOptions {
+    command_wrapper: Option<CommandWrapper>,
+    env: HashMap<String, String>,
+    umask: Option<String>,
+    locale: Option<String>,
+    parsers: HashMap<String, OutputParser>,
+    strict_parsing: bool,
+    correlation_id: Option<String>,
+    correlation_env: Option<String>,
+    /// Controller identity, set when commands get the run's context exported
+    context_env: Option<String>,
+    context_module: Option<String>,
+    read_only: bool,
+    completion_markers: Option<String>,
+    remote_audit_log: Option<String>,
+    canary_policy: Option<CanaryPolicy>,
+    canary_gate: Option<CanaryGate>,
+    dedup_hosts: bool,
+    clock_drift_threshold: Option<Duration>,
+    override_windows: bool,
+    remote_window_clock: bool,
+    uploads: Option<UploadReporter>,
+    check_free_space: bool,
+    output_budget: Option<Arc<OutputBudget>>,
+    exclusions_file: Option<PathBuf>,
+    cancel: Option<CancelToken>,
+    redactor: Option<Redactor>,
+    /// Output limit per stream, for modules which don't declare `max_output`
+    pub max_output: Option<u64>,
+    /// Read timeout, for modules which don't declare `timeout`
+    pub timeout: Option<Duration>,
+    /// Hard cap on `max_output`, modules can't raise their limit above it
+    pub max_output_ceiling: Option<u64>,
+    /// Hard cap on `timeout`, modules can't raise their timeout above it
+    pub timeout_ceiling: Option<Duration>,
+    /// Channels a shell module may run commands on concurrently, within one session.
+    /// Commands run one after another with 0 or 1, bundled modules always use one channel.
+    pub channels_per_session: usize,
+    /// Fails a command which printed something like a prompt and then no newline
+    /// for this long, instead of letting it wait for input until the timeout
+    pub prompt_timeout: Option<Duration>,
+    /// Commands longer than this, in bytes, are uploaded to `/tmp` and run from there,
+    /// as the host may refuse them as an argument. [`DEFAULT_INLINE_LIMIT`] by default.
+    pub inline_command_limit: Option<usize>,
+    /// Hosts [`ModuleTree::run_all_on_hosts`] runs on at once, the others wait for
+    /// one of them to finish. [`DEFAULT_MAX_CONCURRENT_HOSTS`] by default.
+    pub max_concurrent_hosts: Option<usize>,
+}
+
+/// Limits a module actually runs with, see [`ExecutionOptions::effective_limits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_output: Option<u64>,
+    pub timeout: Option<Duration>,
+}
+
+fn capped<T: Ord + Copy>(value: Option<T>, ceiling: Option<T>) -> Option<T> {
+    match (value, ceiling) {
+        (Some(value), Some(ceiling)) => Some(value.min(ceiling)),
+        (value, ceiling) => value.or(ceiling),
+    }
+}
+
+impl Debug for ExecutionOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExecutionOptions")
+            .field("command_wrapper", &self.command_wrapper.is_some())
+            .field("env", &self.env)
+            .field("umask", &self.umask)
+            .field("locale", &self.locale)
+            .field("parsers", &self.parsers.keys().collect::<Vec<_>>())
+            .field("strict_parsing", &self.strict_parsing)
+            .field("correlation_id", &self.correlation_id)
+            .field("correlation_env", &self.correlation_env)
+            .field("context_env", &self.context_env)
+            .field("context_module", &self.context_module)
+            .field("read_only", &self.read_only)
+            .field("completion_markers", &self.completion_markers)
+            .field("remote_audit_log", &self.remote_audit_log)
+            .field("canary_policy", &self.canary_policy)
+            .field("canary_gate", &self.canary_gate.is_some())
+            .field("dedup_hosts", &self.dedup_hosts)
+            .field("clock_drift_threshold", &self.clock_drift_threshold)
+            .field("override_windows", &self.override_windows)
+            .field("remote_window_clock", &self.remote_window_clock)
+            .field("uploads", &self.uploads.is_some())
+            .field("check_free_space", &self.check_free_space)
+            .field("output_budget", &self.output_budget)
+            .field("exclusions_file", &self.exclusions_file)
+            .field("cancel", &self.cancel)
+            .field("redactor", &self.redactor.is_some())
+            .field("max_output", &self.max_output)
+            .field("timeout", &self.timeout)
+            .field("max_output_ceiling", &self.max_output_ceiling)
+            .field("timeout_ceiling", &self.timeout_ceiling)
+            .field("channels_per_session", &self.channels_per_session)
+            .field("prompt_timeout", &self.prompt_timeout)
+            .field("inline_command_limit", &self.inline_command_limit)
+            .field("max_concurrent_hosts", &self.max_concurrent_hosts)
+            .finish()
+    }
+}
+
+impl ExecutionOptions {
+    /// Module values override the run defaults, but never exceed the ceilings.
+    /// Without a timeout the session keeps the one from [`ConnectionProps`].
+    pub fn effective_limits(&self, module: &Module) -> Limits {
+        Limits {
+            max_output: capped(
+                module.max_output().or(self.max_output),
+                self.max_output_ceiling,
+            ),
+            timeout: capped(module.timeout().or(self.timeout), self.timeout_ceiling),
+        }
+    }
+
+    pub(crate) fn parser(&self, command_name: &str) -> Option<&OutputParser> {
+        self.parsers.get(command_name)
+    }
+
+    /// Remote directory of completion markers, see [`Runner::with_completion_markers`].
+    pub(crate) fn completion_markers(&self) -> Option<&str> {
+        self.completion_markers.as_deref()
+    }
+
+    /// Audit log on the hosts, see [`Runner::with_remote_audit_log`].
+    pub(crate) fn remote_audit_log(&self) -> Option<&str> {
+        self.remote_audit_log.as_deref()
+    }
+
+    /// See [`Runner::with_canary_policy`].
+    #[cfg(feature = "discovery")]
+    pub(crate) fn canary_policy(&self) -> Option<&CanaryPolicy> {
+        self.canary_policy.as_ref()
+    }
+
+    /// See [`Runner::with_canary_gate`].
+    #[cfg(feature = "discovery")]
+    pub(crate) fn canary_gate(&self) -> Option<&CanaryGate> {
+        self.canary_gate.as_ref()
+    }
+
+    /// The options, with the canary policy `policy`.
+    #[cfg(feature = "discovery")]
+    pub(crate) fn with_canary_policy(&self, policy: CanaryPolicy) -> Self {
+        ExecutionOptions {
+            canary_policy: Some(policy),
+            ..self.clone()
+        }
+    }
+
+    /// The options, with the audit log `path` on the hosts.
+    #[cfg(feature = "discovery")]
+    pub(crate) fn audited_to(&self, path: &str) -> Self {
+        ExecutionOptions {
+            remote_audit_log: Some(path.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// See [`Runner::with_host_dedup`].
+    pub(crate) fn dedup_hosts(&self) -> bool {
+        self.dedup_hosts
+    }
+
+    /// See [`Runner::with_clock_drift_threshold`].
+    pub(crate) fn clock_drift_threshold(&self) -> Option<Duration> {
+        self.clock_drift_threshold
+    }
+
+    /// See [`Runner::with_window_override`].
+    pub(crate) fn overrides_windows(&self) -> bool {
+        self.override_windows
+    }
+
+    /// See [`Runner::with_remote_window_clock`].
+    pub(crate) fn remote_window_clock(&self) -> bool {
+        self.remote_window_clock
+    }
+
+    /// Where uploads report to, see [`Runner::with_upload_progress`].
+    pub(crate) fn uploads(&self) -> Option<&UploadReporter> {
+        self.uploads.as_ref()
+    }
+
+    /// See [`Runner::with_free_space_check`].
+    pub(crate) fn checks_free_space(&self) -> bool {
+        self.check_free_space
+    }
+
+    /// See [`Runner::with_redacted_output`].
+    pub(crate) fn redactor(&self) -> Option<&Redactor> {
+        self.redactor.as_ref()
+    }
+
+    /// Budget of the output commands buffer, see [`Runner::with_output_budget`].
+    pub(crate) fn output_budget(&self) -> Option<&OutputBudget> {
+        self.output_budget.as_deref()
+    }
+
+    /// Hosts runs on several hosts skip, read anew every time: those of
+    /// [`Runner::with_exclusions_file`], or of [`DEFAULT_EXCLUSIONS_FILE`] if it exists.
+    /// Lines which ended are printed to stderr as warnings.
+    pub(crate) fn exclusions(&self) -> Result<Exclusions, Error> {
+        let path = match &self.exclusions_file {
+            Some(path) => path.as_path(),
+            None if Path::new(DEFAULT_EXCLUSIONS_FILE).exists() => Path::new(DEFAULT_EXCLUSIONS_FILE),
+            None => return Ok(Exclusions::default()),
+        };
+        let exclusions = Exclusions::load(path)?;
+        for warning in exclusions.warnings() {
+            eprintln!("warning: {} from {}", warning, path.display());
+        }
+        Ok(exclusions)
+    }
+
+    /// Fails with [`crate::Cancelled`] if the run was cancelled, see [`Runner::with_cancel_token`].
+    pub(crate) fn check_cancelled(&self) -> Result<(), Error> {
+        match &self.cancel {
+            Some(cancel) => cancel.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Has uploads report to `host`, for the commands run on it.
+    pub(crate) fn set_upload_host(&mut self, host: &str) {
+        if let Some(uploads) = &mut self.uploads {
+            *uploads = uploads.for_host(host);
+        }
+    }
+
+    pub(crate) fn correlation_id(&self) -> Option<&str> {
+        self.correlation_id.as_deref()
+    }
+
+    pub(crate) fn strict_parsing(&self) -> bool {
+        self.strict_parsing
+    }
+
+    /// See [`ExecutionOptions::inline_command_limit`].
+    pub(crate) fn inline_limit(&self) -> usize {
+        self.inline_command_limit.unwrap_or(DEFAULT_INLINE_LIMIT)
+    }
+
+    /// See [`ExecutionOptions::max_concurrent_hosts`], at least one.
+    pub(crate) fn concurrent_hosts(&self) -> usize {
+        self.max_concurrent_hosts.unwrap_or(DEFAULT_MAX_CONCURRENT_HOSTS).max(1)
+    }
+
+    /// Whether commands get the run's context exported, see [`Runner::with_context_env`].
+    pub fn context_env(&self) -> bool {
+        self.context_env.is_some()
+    }
+
+    /// These options for the commands of `module`, which is exported as `AM_MODULE`
+    /// if the context is.
+    pub(crate) fn in_module(&self, module: Option<&str>) -> Cow<'_, ExecutionOptions> {
+        match module.filter(|_| self.context_env()) {
+            Some(module) => Cow::Owned(ExecutionOptions {
+                context_module: Some(module.to_string()),
+                ..self.clone()
+            }),
+            None => Cow::Borrowed(self),
+        }
+    }
+
+    /// `export` of `AM_COMMAND`, for a command of a bundled script, if the context is exported.
+    pub(crate) fn command_context(&self, command_name: &str) -> String {
+        if !self.context_env() {
+            return String::new();
+        }
+        env_prefix(&[("AM_COMMAND", command_name)].iter().copied().collect()).into_string()
+    }
+
+    /// Builds the command line which is actually executed on the host.
+    ///
+    /// Prefixes come in this order, module settings winning over run-level ones:
+    /// 1. `umask`
+    /// 2. one `export` with `LC_ALL` from `locale`, then the run-level env,
+    ///    the correlation id and the run's context, then the module's env,
+    ///    later entries overriding earlier ones
+    ///
+    /// The command wrapper is the outermost layer: it receives the command
+    /// after every prefix added by the crate itself, and whatever it returns
+    /// is executed verbatim.
+    pub fn prepare_command(&self, command: &str, module: &Module) -> String {
+        self.prefix_command(command, Some(module), None)
+    }
+
+    /// [`ExecutionOptions::prepare_command`] for the command `command_name` of the module,
+    /// exported as `AM_COMMAND` if the context is.
+    pub(crate) fn prepare_named(
+        &self,
+        command: &str,
+        module: &Module,
+        command_name: &str,
+    ) -> String {
+        self.prefix_command(command, Some(module), Some(command_name))
+    }
+
+    /// [`ExecutionOptions::prepare_command`] for commands which don't belong to a module,
+    /// or whose name isn't known.
+    pub(crate) fn prefix_command(
+        &self,
+        command: &str,
+        module: Option<&Module>,
+        command_name: Option<&str>,
+    ) -> String {
+        let command = self.prefixed(command, module, command_name);
+        match &self.command_wrapper {
+            Some(wrapper) => wrapper(&command),
+            None => command,
+        }
+    }
+
+    /// [`ExecutionOptions::prepare_named`] for `local` commands, which don't get the wrapper.
+    pub(crate) fn prepare_local(
+        &self,
+        command: &str,
+        module: &Module,
+        command_name: &str,
+    ) -> String {
+        self.prefixed(command, Some(module), Some(command_name))
+    }
+
+    /// `command` with every prefix added by the crate itself.
+    fn prefixed(
+        &self,
+        command: &str,
+        module: Option<&Module>,
+        command_name: Option<&str>,
+    ) -> String {
+        let mut env = BTreeMap::new();
+        if let Some(locale) = module.and_then(Module::locale).or(self.locale.as_deref()) {
+            env.insert("LC_ALL", locale);
+        }
+        env.extend(self.env.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+        if let (Some(name), Some(id)) = (&self.correlation_env, &self.correlation_id) {
+            env.insert(name, id);
+        }
+        if let Some(controller) = &self.context_env {
+            env.insert("AM_RUN_ID", self.correlation_id.as_deref().unwrap_or_default());
+            env.insert("AM_CONTROLLER", controller);
+            if let Some(module) = &self.context_module {
+                env.insert("AM_MODULE", module);
+            }
+            if let Some(command_name) = command_name {
+                env.insert("AM_COMMAND", command_name);
+            }
+        }
+        if let Some(module) = module {
+            env.extend(module.env().iter().map(|(k, v)| (k.as_str(), v.as_str())));
+        }
+        let umask = match module.and_then(Module::umask).or(self.umask.as_deref()) {
+            Some(umask) => shell_format!("umask {}; ", ShellSafe::word(umask)),
+            None => ShellSafe::command(""),
+        };
+        shell_format!("{}{}{}", umask, env_prefix(&env), ShellSafe::command(command)).into_string()
+    }
+}
+
+/// `user@host` of the controller, from the environment and the kernel's host name.
+pub(crate) fn controller_identity() -> String {
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .unwrap_or_else(|_| "unknown".to_string());
+    let host = ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| {
+            let name = std::fs::read_to_string(path).ok()?;
+            Some(name.trim().to_string()).filter(|name| !name.is_empty())
+        })
+        .unwrap_or_else(|| "localhost".to_string());
+    format!("{}@{}", user, host)
+}
+
+/// Id unlikely to repeat across runs, from the time, the process and a counter.
+fn generate_correlation_id() -> String {
+    static RUNS: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64);
+    let seed = format!("{} {} {}", nanos, std::process::id(), RUNS.fetch_add(1, Ordering::Relaxed));
+    fnv1a_hex(&seed)
+}
+
+/// Message of a caught panic, for the error standing in for it.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map_or("unknown panic", String::as_str),
+    }
+}
+
+/// Commands run on a host around a batch of modules, see [`Runner::run_batch`]:
+/// ```toml
+/// pre_run = ["touch /etc/maintenance"]
+/// post_run = ["rm -f /etc/maintenance"]
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HostHooks {
+    #[serde(default)]
+    pub pre_run: Vec<String>,
+    #[serde(default)]
+    pub post_run: Vec<String>,
+}
+
+/// What a play does once its canaries ran it, the hosts with `canary=true` in the
+/// inventory, which run every play as its first batch, before the other hosts:
+/// ```toml
+/// canary_policy = { pause_on_failure = true, require_ok = true }
+/// ```
+/// With `pause_on_failure`, if a canary couldn't connect or failed a module, the other
+/// hosts are skipped with a [`SkipReason::CanaryFailed`]. With `require_ok` too, canaries
+/// also fail with a module which warned. See [`Runner::with_canary_policy`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct CanaryPolicy {
+    #[serde(default)]
+    pub pause_on_failure: bool,
+    #[serde(default)]
+    pub require_ok: bool,
+}
+
+impl CanaryPolicy {
+    /// Whether the canary with `entries` passed.
+    pub fn passed(&self, entries: &Result<Vec<BatchEntry>, Error>) -> bool {
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(_) => return false,
+        };
+        let worst = if self.require_ok { Outcome::Changed } else { Outcome::Warning };
+        entries.iter().all(|entry| match &entry.output {
+            Ok(output) => !output.is_failed() && output.outcome() <= worst,
+            Err(_) => false,
+        })
+    }
+}
+
+/// Output of one module, or hook, of [`Runner::run_batch`].
+#[derive(Debug)]
+pub struct BatchEntry {
+    pub module: String,
+    pub output: Result<CommandOutput, Error>,
+}
+
+impl BatchEntry {
+    pub(crate) fn new(module: &str, output: Result<CommandOutput, Error>) -> Self {
+        BatchEntry {
+            module: module.to_string(),
+            output,
+        }
+    }
+
+    /// Why the module didn't run, if it was skipped.
+    pub fn skip_reason(&self) -> Option<&SkipReason> {
+        self.output.as_ref().err()?.downcast_ref()
+    }
+}
+
+/// Outputs of running a module twice in a row, see [`Runner::check_idempotency`].
+pub struct IdempotencyCheck {
+    pub first: CommandOutput,
+    pub second: CommandOutput,
+}
+
+impl IdempotencyCheck {
+    /// True if the second run changed nothing.
+    pub fn is_idempotent(&self) -> bool {
+        !self.second.is_changed()
+    }
+}
+
+/// Executes modules from a [`ModuleTree`] with run-level [`ExecutionOptions`].
+///
+/// Connections are cached per host (by its `to_string()`) and reused by later runs.
+/// A cached connection holds no tcp permit of [`ConnectionProps`] while idle,
+/// a permit is taken only for the time a module runs over it.
+/// By default nothing is evicted, see [`Runner::with_connection_cache`]
+/// and [`Runner::with_liveness_probe`].
+pub struct Runner {
+    tree: ModuleTree,
+    options: ExecutionOptions,
+    connections: Arc<Mutex<ConnectionCache>>,
+    hooks: HashMap<String, HostHooks>,
+    probe: Option<LivenessProbe>,
+    sink: Option<(Arc<dyn ReportSink>, Retain)>,
+    /// Modules recorded since the last [`Runner::finish_run`]
+    summary: Mutex<RunSummary>,
+    /// Counters of every connection the runner opened, by host
+    traffic: Mutex<HashMap<String, Vec<Arc<TrafficCounter>>>>,
+    /// Channels of every connection the runner opened, by host
+    channels: Mutex<HashMap<String, Vec<Arc<ChannelGate>>>>,
+    #[cfg(feature = "testing")]
+    faults: Option<crate::FaultInjector>,
+}
+
+/// Background thread probing idle cached connections, stops when dropped.
+struct LivenessProbe {
+    interval: Duration,
+    _stop: Sender<()>,
+}
+
+impl Runner {
+    pub fn new(tree: ModuleTree) -> Self {
+        Runner {
+            tree,
+            options: ExecutionOptions {
+                correlation_id: Some(generate_correlation_id()),
+                ..ExecutionOptions::default()
+            },
+            connections: Arc::new(Mutex::new(ConnectionCache::default())),
+            hooks: HashMap::new(),
+            probe: None,
+            sink: None,
+            summary: Mutex::new(RunSummary::new("")),
+            traffic: Mutex::new(HashMap::new()),
+            channels: Mutex::new(HashMap::new()),
+            #[cfg(feature = "testing")]
+            faults: None,
+        }
+    }
+
+    /// Closes cached connections idle for longer than `idle_ttl`,
+    /// and the least recently used ones above `max_connections`.
+    pub fn with_connection_cache(
+        self,
+        idle_ttl: Option<Duration>,
+        max_connections: Option<usize>,
+    ) -> Self {
+        {
+            let mut connections = self.connections.lock().expect("connections lock poisoned");
+            connections.idle_ttl = idle_ttl;
+            connections.max_connections = max_connections;
+        }
+        self
+    }
+
+    /// Runs `true` every `interval` on each cached connection which isn't in use,
+    /// closing those which fail, so a stale session is dropped before a module needs it.
+    /// Probes run on a background thread and take no permits of [`ConnectionProps`].
+    pub fn with_liveness_probe(mut self, interval: Duration) -> Self {
+        let (stop, stopped) = channel::<()>();
+        let connections = self.connections.clone();
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                ConnectionCache::probe_idle(&connections);
+            }
+        });
+        self.probe = Some(LivenessProbe {
+            interval,
+            _stop: stop,
+        });
+        self
+    }
+
+    /// Interval of the liveness probe, if there is one.
+    pub fn liveness_probe_interval(&self) -> Option<Duration> {
+        self.probe.as_ref().map(|probe| probe.interval)
+    }
+
+    /// Registers a hook, which wraps every command before exec,
+    /// e.g. `|c| format!("timeout 60 {}", c)`.
+    /// See [`ExecutionOptions::prepare_command`] for where it sits in the chain.
+    pub fn with_command_wrapper<F>(mut self, wrapper: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.options.command_wrapper = Some(Arc::new(wrapper));
+        self
+    }
+
+    /// Sets a variable in the environment of every command of the run.
+    /// A module's own `env` overrides it.
+    pub fn with_env(mut self, name: &str, value: &str) -> Result<Self, Error> {
+        check_env_name(name)?;
+        self.options.env.insert(name.to_string(), value.to_string());
+        Ok(self)
+    }
+
+    /// Replaces the id of the run, generated by [`Runner::new`],
+    /// e.g. with one shared by the runs of a deploy.
+    pub fn with_correlation_id(mut self, id: &str) -> Self {
+        self.options.correlation_id = Some(id.to_string());
+        self
+    }
+
+    /// Id of the run, to put in log lines about it and find them with the remote ones.
+    pub fn correlation_id(&self) -> &str {
+        self.options.correlation_id.as_deref().unwrap_or_default()
+    }
+
+    /// Exports the correlation id to every command as the variable `name`,
+    /// e.g. `DEPLOY_ID`, so remote logs can carry it too.
+    pub fn with_correlation_env(mut self, name: &str) -> Result<Self, Error> {
+        check_env_name(name)?;
+        self.options.correlation_env = Some(name.to_string());
+        Ok(self)
+    }
+
+    /// Exports the context of the run to every command, so remote audit logs can be
+    /// matched with it: `AM_RUN_ID` is the [`Runner::correlation_id`], `AM_CONTROLLER`
+    /// `user@host` of this machine, `AM_MODULE` and `AM_COMMAND` the names of the
+    /// module and the command, when they are known. Values are quoted, any name is fine.
+    pub fn with_context_env(mut self) -> Self {
+        self.options.context_env = Some(controller_identity());
+        self
+    }
+
+    /// Sets the umask of every command, unless the module declares its own.
+    pub fn with_umask(mut self, umask: &str) -> Result<Self, Error> {
+        check_umask(umask)?;
+        self.options.umask = Some(umask.to_string());
+        Ok(self)
+    }
+
+    /// Sets `LC_ALL` for every command, unless the module declares its own `locale`.
+    pub fn with_locale(mut self, locale: &str) -> Self {
+        self.options.locale = Some(locale.to_string());
+        self
+    }
+
+    /// Registers an output parser for every command named `command_name`,
+    /// e.g. `|out| Ok(Value::from(out.lines().count()))`.
+    /// A `parser` declared by the command itself takes precedence.
+    pub fn with_parser<F>(mut self, command_name: &str, parser: F) -> Self
+    where
+        F: Fn(&str) -> Result<Value, Error> + Send + Sync + 'static,
+    {
+        self.options
+            .parsers
+            .insert(command_name.to_string(), Arc::new(parser));
+        self
+    }
+
+    /// Fails commands whose output can't be parsed, instead of recording a warning.
+    pub fn with_strict_parsing(mut self) -> Self {
+        self.options.strict_parsing = true;
+        self
+    }
+
+    /// Refuses to run anything not declared read-only, see [`Module::is_read_only`]:
+    /// modules are checked before connecting, and hooks, raw commands and scheduled
+    /// jobs are refused altogether. Unlike a dry run this doesn't simulate, it refuses.
+    pub fn with_read_only(mut self) -> Self {
+        self.options.read_only = true;
+        self
+    }
+
+    /// Fails with the list of `module_names` which a read-only run refuses
+    /// to run, if this is one. Unknown modules are an error either way.
+    pub fn check_read_only(&self, module_names: &[&str]) -> Result<(), Error> {
+        let mut refused = Vec::new();
+        for name in module_names {
+            if !self.tree.get_module(name)?.is_read_only() {
+                refused.push(*name);
+            }
+        }
+        if !self.options.read_only || refused.is_empty() {
+            return Ok(());
+        }
+        Err(Error::msg(format!(
+            "Read-only run refuses modules not declared read_only: {}",
+            refused.join(", ")
+        )))
+    }
+
+    /// Fails with the first [`crate::Conflict`] between `module_names` and the options
+    /// of the run, like a `prompt_timeout` which bundled modules can't honor.
+    /// Unknown modules are an error either way.
+    pub fn check_conflicts(&self, module_names: &[&str]) -> Result<(), Error> {
+        for name in module_names {
+            let module = self.tree.get_module(name)?;
+            if let Some(conflict) = module.run_conflicts(name, &self.options).into_iter().next() {
+                return Err(conflict.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks `module_names` before anything runs: that the run wasn't cancelled,
+    /// [`Runner::check_read_only`], then [`Runner::check_conflicts`].
+    pub(crate) fn check_plan(&self, module_names: &[&str]) -> Result<(), Error> {
+        self.options.check_cancelled()?;
+        self.check_read_only(module_names)?;
+        self.check_conflicts(module_names)
+    }
+
+    /// Fails if this is a read-only run, for work which can't be declared read-only.
+    pub(crate) fn refuse_if_read_only(&self, what: &str) -> Result<(), Error> {
+        if self.options.read_only {
+            return Err(Error::msg(format!("Read-only run refuses {}", what)));
+        }
+        Ok(())
+    }
+
+    /// Makes [`Runner::run_module_on_hosts`] leave a marker with the
+    /// [`Runner::correlation_id`] in `dir` on every host a module succeeded on,
+    /// and skip hosts which already have the marker of this id and module.
+    pub fn with_completion_markers(mut self, dir: &str) -> Self {
+        self.options.completion_markers = Some(dir.to_string());
+        self
+    }
+
+    /// Makes plays of [`crate::Playbook::run`] with canaries go on to the other hosts
+    /// only when `policy` lets them, the canary policy of the playbook wins over it.
+    pub fn with_canary_policy(mut self, policy: CanaryPolicy) -> Self {
+        self.options.canary_policy = Some(policy);
+        self
+    }
+
+    /// Registers a hook, which is asked after the canaries of a play ran it whether the
+    /// other hosts should run it too. If it declines they are skipped, with a
+    /// [`SkipReason::CanaryFailed`] listing no canaries. It is asked only when the
+    /// [`CanaryPolicy`] lets the play go on.
+    pub fn with_canary_gate<F>(mut self, gate: F) -> Self
+    where
+        F: Fn(&BTreeMap<String, Result<Vec<BatchEntry>, Error>>) -> bool + Send + Sync + 'static,
+    {
+        self.options.canary_gate = Some(Arc::new(gate));
+        self
+    }
+
+    /// Appends a line of JSON to the file `path` on the host after every module run
+    /// there, with the [`Runner::correlation_id`], the module's name and fingerprint,
+    /// its outcome, the controller and the time, see [`crate::AuditEntry`]. The line is
+    /// appended with `sudo -n` if the user can't write to `path`. A log which can't be
+    /// written to doesn't fail the module, its results get a warning about it instead.
+    pub fn with_remote_audit_log(mut self, path: &str) -> Self {
+        self.options.remote_audit_log = Some(path.to_string());
+        self
+    }
+
+    /// Makes [`Runner::run_module_on_hosts`] identify every host first, by its
+    /// `/etc/machine-id` or host key, and skip hosts which are the same machine as a host
+    /// listed before them, e.g. `web01` and its address, with a
+    /// [`SkipReason::DuplicateHost`] error and a warning.
+    /// Off by default, as running once per interface is sometimes meant.
+    pub fn with_host_dedup(mut self) -> Self {
+        self.options.dedup_hosts = true;
+        self
+    }
+
+    /// Flags hosts whose clock is off by more than `threshold`,
+    /// see [`Runner::gather_clock_facts`].
+    pub fn with_clock_drift_threshold(mut self, threshold: Duration) -> Self {
+        self.options.clock_drift_threshold = Some(threshold);
+        self
+    }
+
+    /// Runs modules outside their [`crate::MaintenanceWindow`] too. Records of such
+    /// modules passed to the report sink have `window_overridden` set.
+    pub fn with_window_override(mut self) -> Self {
+        self.options.override_windows = true;
+        self
+    }
+
+    /// Checks maintenance windows against the clock of the host, read before every
+    /// module with a window, rather than the controller's.
+    pub fn with_remote_window_clock(mut self) -> Self {
+        self.options.remote_window_clock = true;
+        self
+    }
+
+    /// Calls `progress` during uploads, whenever another `interval` bytes of a file
+    /// were sent to a host, and once a file was sent completely. Uploads are staged
+    /// commands, see [`ExecutionOptions::inline_command_limit`], and `stdin_file`s
+    /// streamed to commands. Without it uploads aren't tracked at all.
+    pub fn with_upload_progress<P>(mut self, interval: u64, progress: P) -> Self
+    where
+        P: UploadProgress + 'static,
+    {
+        let uploads = UploadReporter::default().with_callback(Arc::new(progress), interval);
+        self.options.uploads = Some(uploads);
+        self
+    }
+
+    /// Checks with `df` that the filesystem a binary is uploaded to has room for it,
+    /// before uploading, failing the module with an [`crate::InsufficientDisk`]
+    /// otherwise. Costs a round-trip per upload.
+    pub fn with_free_space_check(mut self) -> Self {
+        self.options.check_free_space = true;
+        self
+    }
+
+    /// Caps the captured output all commands of the runner buffer at once, on every
+    /// host, at `bytes`. Before a shell command reads its output it reserves twice its
+    /// `max_output`, or all of the budget without one. A command which doesn't fit
+    /// writes its output to files in `spill_dir` instead, keeping the same limit, and
+    /// has them in [`CommandResult::spilled`]. It is evaluated on the first 64 KiB of each
+    /// stream, which its `stdout` and `stderr` hold, and the files are redacted line by
+    /// line, see [`Runner::with_redacted_output`]. Commands whose output is looked at
+    /// while they run, for a `tail`, prompts or `stdin`, and bundled modules keep it in
+    /// memory, they wait for room in the budget then. One needing more than all of it
+    /// gets half of it as its limit, with a warning.
+    pub fn with_output_budget(mut self, bytes: u64, spill_dir: &Path) -> Result<Self, Error> {
+        fs::create_dir_all(spill_dir)
+            .map_err(|e| Error::msg(format!("Creating spill directory {}: {}", spill_dir.display(), e)))?;
+        self.options.output_budget = Some(Arc::new(OutputBudget::new(bytes, spill_dir)));
+        Ok(self)
+    }
+
+    /// How much of the output budget is in use, and what didn't fit so far,
+    /// see [`Runner::with_output_budget`].
+    pub fn output_budget_stats(&self) -> Option<BudgetStats> {
+        self.options.output_budget().map(OutputBudget::stats)
+    }
+
+    /// Reads the hosts to skip from the exclusions file `path` instead of
+    /// [`DEFAULT_EXCLUSIONS_FILE`], see [`Exclusions`]. Runs on several hosts fail if it
+    /// can't be read.
+    pub fn with_exclusions_file(mut self, path: &Path) -> Self {
+        self.options.exclusions_file = Some(path.to_path_buf());
+        self
+    }
+
+    /// Stops runs once `token` is cancelled, before their next module or command, with a
+    /// [`crate::Cancelled`] error. Files the unfinished modules created on the host, like
+    /// staged commands and uploaded binaries, are removed before the run returns, for at
+    /// most a few seconds. What couldn't be removed is listed by a [`crate::LeftBehind`]
+    /// context of the error. The same cleanup follows any other error of a connection.
+    pub fn with_cancel_token(mut self, token: CancelToken) -> Self {
+        self.options.cancel = Some(token);
+        self
+    }
+
+    /// Uploads of this runner so far, by host, see [`Runner::with_upload_progress`].
+    pub fn upload_stats(&self) -> HashMap<String, UploadStats> {
+        self.options.uploads().map(UploadReporter::stats).unwrap_or_default()
+    }
+
+    /// Passes the result of every module to `sink` as soon as it finished on a host,
+    /// before it is returned: by [`Runner::run_module`], [`Runner::run_batch`], including
+    /// its hooks, and [`Runner::run_module_on_hosts`]. With [`Retain::SummariesOnly`]
+    /// the returned results keep no outputs, so long-running callers don't pile them up.
+    /// A failing sink doesn't fail the module, a warning is printed to stderr.
+    pub fn with_report_sink<S>(mut self, sink: S, retain: Retain) -> Self
+    where
+        S: ReportSink + 'static,
+    {
+        self.sink = Some((Arc::new(sink), retain));
+        self
+    }
+
+    /// Redacts secrets from every result with `redactor` before it's passed to the report
+    /// sink or returned. Results with redactions are flagged, see [`CommandResult`].
+    /// Errors whose message holds a secret lose their type, they become the redacted message.
+    pub fn with_redacted_output(mut self, redactor: Redactor) -> Self {
+        self.options.redactor = Some(redactor);
+        self
+    }
+
+    /// Injects the failures scripted in `faults` into every connection the runner opens.
+    #[cfg(feature = "testing")]
+    pub fn with_fault_injector(mut self, faults: crate::FaultInjector) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// The fault injector, if there is one.
+    #[cfg(feature = "testing")]
+    pub fn fault_injector(&self) -> Option<&crate::FaultInjector> {
+        self.faults.as_ref()
+    }
+
+    /// Connects to the host, with the faults of [`Runner::with_fault_injector`].
+    fn connect<A>(&self, ip: A, auth: AuthType, sync: &dyn ConnectionProps) -> Result<HostConnection, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        #[cfg(feature = "testing")]
+        if let Some(faults) = &self.faults {
+            let faults = faults.next_attempt(&ip.to_string());
+            return HostConnection::connect_with_faults(ip, auth, sync, &faults);
+        }
+        HostConnection::connect(ip, auth, sync)
+    }
+
+    /// Hands a finished result to the report sink, if there is one.
+    /// An [`crate::UnsetState`] error gets the modules writing the key first,
+    /// then secrets are redacted.
+    pub(crate) fn record(
+        &self,
+        host: &str,
+        module: &str,
+        output: Result<CommandOutput, Error>,
+    ) -> Result<CommandOutput, Error> {
+        match output {
+            Ok(output) => Ok(self.record_output(host, module, output)),
+            Err(e) => Err(self.record_error(host, module, e)),
+        }
+    }
+
+    /// [`Runner::record`] for a module which ran, returning its output as the sink
+    /// retains it.
+    fn record_output(&self, host: &str, module: &str, output: CommandOutput) -> CommandOutput {
+        let output = match &self.options.redactor {
+            Some(redactor) => redactor.apply(output),
+            None => output,
+        };
+        self.report(host, module, Ok(&output));
+        match &self.sink {
+            Some((_, retain)) => retain.apply(output),
+            None => output,
+        }
+    }
+
+    /// [`Runner::record`] for a module which failed to run with `e`, returning `e` as
+    /// it was recorded.
+    pub(crate) fn record_error(&self, host: &str, module: &str, e: Error) -> Error {
+        let e = self.tree.explain_unset_state(e);
+        let e = match &self.options.redactor {
+            Some(redactor) => redactor.apply_error(e),
+            None => e,
+        };
+        self.report(host, module, Err(&e));
+        e
+    }
+
+    /// Adds the record of `module` on `host` to the summary, and passes it to the sink.
+    fn report(&self, host: &str, module: &str, output: Result<&CommandOutput, &Error>) {
+        let record = ModuleRecord {
+            schema_version: SCHEMA_VERSION,
+            correlation_id: self.correlation_id(),
+            host,
+            module,
+            output: output.ok(),
+            error: output.err().map(|e| format!("{:#}", e)),
+            window_overridden: self.options.override_windows
+                && self.tree.module(module).is_some_and(|module| module.window.is_some()),
+        };
+        self.summary.lock().expect("summary lock poisoned").add(&record);
+        if let Some((sink, _)) = &self.sink {
+            if let Err(e) = sink.record(&record) {
+                eprintln!("warning: report sink failed for {} on {}: {:#}", module, host, e);
+            }
+        }
+    }
+
+    /// Ends the run: returns the [`RunSummary`] of the modules which finished since the
+    /// runner was made or the last call, and passes it to the report sink's
+    /// [`ReportSink::finish`]. A failing sink doesn't fail the run, a warning is
+    /// printed to stderr.
+    pub fn finish_run(&self) -> RunSummary {
+        let fresh = RunSummary::new(self.correlation_id());
+        let mut summary = std::mem::replace(&mut *self.summary.lock().expect("summary lock poisoned"), fresh);
+        summary.correlation_id = self.correlation_id().to_string();
+        if let Some((sink, _)) = &self.sink {
+            if let Err(e) = sink.finish(&summary) {
+                eprintln!("warning: report sink failed to finish run {}: {:#}", summary.correlation_id, e);
+            }
+        }
+        summary
+    }
+
+    /// Registers commands to run on `host` (as given to [`Runner::run_batch`])
+    /// before and after every batch of modules.
+    pub fn with_hooks(mut self, host: &str, hooks: HostHooks) -> Self {
+        self.hooks.insert(host.to_string(), hooks);
+        self
+    }
+
+    /// Command line a shell module's command would be executed as,
+    /// templates left unrendered.
+    pub fn plan_command(&self, module_name: &str, command_name: &str) -> Result<String, Error> {
+        let module = self.tree.get_module(module_name)?;
+        let command = module.command(command_name).ok_or_else(|| {
+            Error::msg(format!(
+                "Module {} has no command {}",
+                module_name, command_name
+            ))
+        })?;
+        let options = self.options.in_module(Some(module_name));
+        Ok(options.prepare_named(command, module, command_name))
+    }
+
+    /// What running every module of the tree would execute, without connecting anywhere:
+    /// each module in [`ModuleTree::dependency_order`] with its type, then each command
+    /// of a shell module by name, as [`Runner::plan_command`] gives it. Every prefix the
+    /// runner adds, like `umask`, the locale and env, is shown.
+    pub fn plan(&self) -> Result<String, Error> {
+        let names = self.tree.module_names();
+        let mut plan = String::new();
+        for module_name in self.tree.dependency_order(&names)? {
+            let module = self.tree.get_module(module_name)?;
+            plan.push_str(&format!("{} ({})\n", module_name, module.module_type()));
+            for command_name in module.command_names() {
+                let command = self.plan_command(module_name, command_name)?;
+                plan.push_str(&format!("  {}: {}\n", command_name, command));
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Script running the module's commands as this runner would, see [`Module::to_shell_script`].
+    pub fn shell_script(
+        &self,
+        module_name: &str,
+        vars: Option<&HashMap<String, String>>,
+    ) -> Result<String, Error> {
+        self.tree
+            .get_module(module_name)?
+            .to_shell_script(&self.options, vars)
+    }
+
+    /// Replaces the output limits and timeouts, keeping the env and command wrapper.
+    pub fn with_limits(
+        mut self,
+        max_output: Option<u64>,
+        timeout: Option<Duration>,
+        max_output_ceiling: Option<u64>,
+        timeout_ceiling: Option<Duration>,
+    ) -> Self {
+        self.options.max_output = max_output;
+        self.options.timeout = timeout;
+        self.options.max_output_ceiling = max_output_ceiling;
+        self.options.timeout_ceiling = timeout_ceiling;
+        self
+    }
+
+    /// Runs up to `channels` commands of a shell module at once, over one session.
+    /// Keep it below the server's `MaxSessions`, 10 by default for OpenSSH.
+    pub fn with_channels_per_session(mut self, channels: usize) -> Self {
+        self.options.channels_per_session = channels;
+        self
+    }
+
+    /// Runs [`ModuleTree::run_all_on_hosts`] on up to `hosts` hosts at once.
+    pub fn with_max_concurrent_hosts(mut self, hosts: usize) -> Self {
+        self.options.max_concurrent_hosts = Some(hosts);
+        self
+    }
+
+    /// Fails commands stuck at an unexpected prompt, like `[y/N]` or `password:`,
+    /// after `timeout` without a newline, with an [`crate::InteractivePromptDetected`],
+    /// closing their channel. Prompts listed in a command's `responses` are answered regardless.
+    pub fn with_prompt_timeout(mut self, timeout: Duration) -> Self {
+        self.options.prompt_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits the module would run with.
+    pub fn effective_limits(&self, module_name: &str) -> Result<Limits, Error> {
+        Ok(self
+            .options
+            .effective_limits(self.tree.get_module(module_name)?))
+    }
+
+    pub fn options(&self) -> &ExecutionOptions {
+        &self.options
+    }
+
+    pub fn tree(&self) -> &ModuleTree {
+        &self.tree
+    }
+
+    pub fn run_module<A>(
+        &self,
+        module_name: &str,
+        ip: A,
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+    ) -> Result<CommandOutput, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        self.check_plan(&[module_name])?;
+        let module = self.tree.get_module(module_name)?;
+        let host = ip.to_string();
+        let output = self.with_connection(ip, auth, sync, |connection| {
+            module.execute_named_on(Some(module_name), connection, &self.options)
+        });
+        self.record(&host, module_name, output)
+    }
+
+    /// Runs `module_name` once per item of `items` over one connection, see
+    /// [`Module::execute_foreach`]. Each item's result is recorded like the result
+    /// of [`Runner::run_module`].
+    pub fn run_module_foreach<A>(
+        &self,
+        module_name: &str,
+        ip: A,
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+        items: &[HashMap<String, String>],
+        on_error: OnError,
+    ) -> Result<Vec<ItemResult>, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        self.check_plan(&[module_name])?;
+        let module = self.tree.get_module(module_name)?;
+        let host = ip.to_string();
+        let results = self.with_connection(ip, auth, sync, |connection| {
+            module.execute_foreach_on(connection, &self.options, items, on_error)
+        });
+        match results {
+            Ok(results) => Ok(results
+                .into_iter()
+                .map(|result| ItemResult {
+                    output: self.record(&host, module_name, result.output),
+                    ..result
+                })
+                .collect()),
+            // recording an error gives it back, redacted
+            Err(e) => self.record(&host, module_name, Err(e)).map(|_| Vec::new()),
+        }
+    }
+
+    /// Runs modules one after another over a single connection, between the host's hooks.
+    ///
+    /// Modules run in [`ModuleTree::dependency_order`], with the modules they depend on.
+    /// `pre_run` commands run first, in order, and stop at the first one exiting non-zero,
+    /// in which case no module runs. `post_run` commands always run afterwards, as far as
+    /// the connection allows. With [`OnError::Abort`] a failed module skips the rest,
+    /// otherwise only modules depending on it are skipped, with a [`SkipReason`] error.
+    /// Hooks show up as pseudo-modules `pre_run` and `post_run`, results are in run order.
+    pub fn run_batch<A>(
+        &self,
+        module_names: &[&str],
+        ip: A,
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+        on_error: OnError,
+    ) -> Result<Vec<BatchEntry>, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        let order = self.tree.dependency_order(module_names)?;
+        self.check_plan(&order)?;
+        let modules = order
+            .into_iter()
+            .map(|name| self.tree.get_module(name).map(|module| (name, module)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let key = ip.to_string();
+        let hooks = self.hooks.get(&key).cloned().unwrap_or_default();
+        if !hooks.pre_run.is_empty() || !hooks.post_run.is_empty() {
+            self.refuse_if_read_only("host hooks")?;
+        }
+        let results = self.with_connection(ip, auth, sync, |connection| {
+            let mut results = Vec::new();
+            let mut ready = true;
+            if !hooks.pre_run.is_empty() {
+                let output = self.run_hooks(connection, &hooks.pre_run);
+                ready = matches!(&output, Ok(output) if !output.is_failed());
+                results.push(BatchEntry::new("pre_run", output));
+            }
+            let mut failed_modules = HashSet::new();
+            for (name, module) in modules.into_iter().filter(|_| ready) {
+                let failed_dependency = module
+                    .depends_on()
+                    .iter()
+                    .find(|dependency| failed_modules.contains(dependency.as_str()));
+                if let Some(dependency) = failed_dependency {
+                    failed_modules.insert(name);
+                    let skipped = SkipReason::DependencyFailed(dependency.clone());
+                    results.push(BatchEntry::new(name, Err(skipped.into())));
+                    continue;
+                }
+                let output = module.execute_named_on(Some(name), connection, &self.options);
+                let failed = !matches!(&output, Ok(output) if !output.is_failed());
+                results.push(BatchEntry::new(name, output));
+                if failed {
+                    if on_error == OnError::Abort {
+                        break;
+                    }
+                    failed_modules.insert(name);
+                }
+            }
+            if !hooks.post_run.is_empty() {
+                let output = self.run_hooks(connection, &hooks.post_run);
+                results.push(BatchEntry::new("post_run", output));
+            }
+            Ok(results)
+        })?;
+        if results
+            .iter()
+            .any(|entry| entry.output.is_err() && entry.skip_reason().is_none())
+        {
+            self.connections
+                .lock()
+                .expect("connections lock poisoned")
+                .remove(&key);
+        }
+        Ok(results
+            .into_iter()
+            .map(|entry| {
+                let output = self.record(&key, &entry.module, entry.output);
+                BatchEntry { output, ..entry }
+            })
+            .collect())
+    }
+
+    /// Runs a single command exactly as given, without the env, umask, locale or wrapper
+    /// of the run, for appliances and restricted shells which only accept known commands.
+    /// A non-zero exit status counts as a failure.
+    pub fn raw_exec<A>(
+        &self,
+        ip: A,
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+        command: &str,
+    ) -> Result<CommandResult, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        self.refuse_if_read_only("raw commands")?;
+        self.with_connection(ip, auth, sync, |connection| {
+            let (stdout, stderr, status) = self.run_exact(connection, command)?;
+            let mut result = ShellCommand::new(command).evaluate(&stdout, Some(&stderr))?;
+            result.exit_status = Some(status);
+            if status != 0 {
+                result.failure = Some(format!("exited with status {}", status));
+                result.changed = false;
+            }
+            Ok(result)
+        })
+    }
+
+    /// Runs hook commands in order, stopping at the first one which exits non-zero.
+    fn run_hooks(&self, connection: &HostConnection, commands: &[String]) -> Result<CommandOutput, Error> {
+        let mut results = HashMap::new();
+        for command in commands {
+            let (stdout, stderr, status) = self.run_command(connection, command)?;
+            let mut result = ShellCommand::new(command).evaluate(&stdout, Some(&stderr))?;
+            result.exit_status = Some(status);
+            if status != 0 {
+                result.failure = Some(format!("exited with status {}", status));
+                result.changed = false;
+            }
+            results.insert(command.to_string(), result);
+            if status != 0 {
+                break;
+            }
+        }
+        Ok(CommandOutput::Multi(results))
+    }
+
+    /// Runs a command which doesn't belong to any module, with the run-level prefixes
+    /// and wrapper. Returns its stdout, stderr and exit status.
+    pub(crate) fn run_command(
+        &self,
+        connection: &HostConnection,
+        command: &str,
+    ) -> Result<(String, String, i32), Error> {
+        self.run_exact(connection, &self.options.prefix_command(command, None, None))
+    }
+
+    /// Runs `command` exactly as given.
+    fn run_exact(
+        &self,
+        connection: &HostConnection,
+        command: &str,
+    ) -> Result<(String, String, i32), Error> {
+        let mut channel = connection.open_channel()?;
+        let uploads = self.options.uploads().map(|uploads| uploads.for_host(connection.host()));
+        let (session, exec) = channel.split();
+        let ledger = connection.ledger();
+        exec_staged(session, exec, command, self.options.inline_limit(), uploads.as_ref(), ledger)?;
+        let (stdout, stderr, _) = read_channel(&mut channel, self.options.max_output)?;
+        channel.wait_close()?;
+        let status = channel.exit_status()?;
+        Ok((
+            String::from_utf8_lossy(&stdout).into_owned(),
+            String::from_utf8_lossy(&stderr).into_owned(),
+            status,
+        ))
+    }
+
+    /// Runs `f` over the cached connection to the host, connecting first if there is none.
+    /// The connection is dropped from the cache if `f` fails, its state is unknown then,
+    /// after removing the files unfinished modules left, see [`HostConnection::clean_up`].
+    pub(crate) fn with_connection<A, T, F>(
+        &self,
+        ip: A,
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+        f: F,
+    ) -> Result<T, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+        F: FnOnce(&HostConnection) -> Result<T, Error>,
+    {
+        let key = ip.to_string();
+        let cached = self.cached_connection(&key);
+        let connection = match cached {
+            Some(connection) => {
+                sync.tcp_synchronization_for(&key);
+                connection
+            }
+            None => match self.connect(ip, auth, sync) {
+                Ok(connection) => {
+                    self.track_traffic(&key, &connection);
+                    Arc::new(Mutex::new(connection))
+                }
+                Err(e) => {
+                    sync.tcp_release_for(&key);
+                    return Err(e);
+                }
+            },
+        };
+        let result = {
+            let connection = connection.lock().expect("connection lock poisoned");
+            f(&connection).map_err(|e| connection.clean_up_after(e))
+        };
+        sync.tcp_release_for(&key);
+        let mut connections = self.connections.lock().expect("connections lock poisoned");
+        if result.is_ok() {
+            connections.insert(key, connection);
+        } else {
+            connections.remove(&key);
+        }
+        result
+    }
+
+    fn track_traffic(&self, key: &str, connection: &HostConnection) {
+        if let Some(counter) = connection.traffic_counter() {
+            let mut traffic = self.traffic.lock().expect("traffic lock poisoned");
+            traffic.entry(key.to_string()).or_default().push(counter);
+        }
+        let mut channels = self.channels.lock().expect("channels lock poisoned");
+        channels.entry(key.to_string()).or_default().push(connection.channel_gate());
+    }
+
+    /// Bytes written to and read from each host (by `to_string()`) over all connections
+    /// the runner opened to it, closed ones included, see [`HostConnection::traffic_stats`].
+    /// Only hosts [`ConnectionProps::count_traffic_for`] are listed.
+    pub fn traffic_stats(&self) -> HashMap<String, TrafficStats> {
+        let traffic = self.traffic.lock().expect("traffic lock poisoned");
+        traffic
+            .iter()
+            .map(|(host, counters)| {
+                let total = counters
+                    .iter()
+                    .fold(TrafficStats::default(), |total, counter| total + counter.stats());
+                (host.clone(), total)
+            })
+            .collect()
+    }
+
+    /// Channels opened to each host (by `to_string()`) over all connections the runner
+    /// opened to it, closed ones included, see [`HostConnection::channel_stats`].
+    /// `limit` is the lowest of them, `peak` the highest.
+    pub fn channel_stats(&self) -> HashMap<String, ChannelStats> {
+        let channels = self.channels.lock().expect("channels lock poisoned");
+        channels
+            .iter()
+            .map(|(host, gates)| {
+                let stats = gates.iter().map(|gate| gate.stats());
+                let total = stats.fold(None, |total: Option<ChannelStats>, stats| {
+                    Some(match total {
+                        None => stats,
+                        Some(total) => ChannelStats {
+                            limit: total.limit.min(stats.limit),
+                            open: total.open + stats.open,
+                            peak: total.peak.max(stats.peak),
+                            opened: total.opened + stats.opened,
+                            queued: total.queued + stats.queued,
+                            refused: total.refused + stats.refused,
+                            overflowed: total.overflowed + stats.overflowed,
+                            waited: total.waited + stats.waited,
+                        },
+                    })
+                });
+                (host.clone(), total.unwrap_or_default())
+            })
+            .collect()
+    }
+
+    fn cached_connection(&self, key: &str) -> Option<SharedConnection> {
+        self.connections
+            .lock()
+            .expect("connections lock poisoned")
+            .get(key)
+    }
+
+    /// Connects to every host up front, concurrently, and caches the connections.
+    ///
+    /// At most [`Runner::with_max_concurrent_hosts`] hosts are connected to at once, and
+    /// connection attempts are throttled by the tcp permits of `sync` too, a permit is
+    /// released as soon as its connection is established.
+    /// Returns the time it took to connect to each host, or why it failed, a panic
+    /// while connecting included, so unreachable hosts can be dropped before running
+    /// anything.
+    pub fn warm_up<A>(
+        &self,
+        hosts: &[A],
+        auth: AuthType,
+        sync: &(dyn ConnectionProps + Sync),
+    ) -> HashMap<String, Result<Duration, Error>>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        let connect = |host: &A, key: &str| {
+            if self.cached_connection(key).is_some() {
+                return Ok(Duration::from_secs(0));
+            }
+            let started = Instant::now();
+            let connection = self.connect(host.clone(), auth.clone(), sync);
+            sync.tcp_release_for(key);
+            connection.map(|connection| {
+                self.track_traffic(key, &connection);
+                self.connections
+                    .lock()
+                    .expect("connections lock poisoned")
+                    .insert(key.to_string(), Arc::new(Mutex::new(connection)));
+                started.elapsed()
+            })
+        };
+        // each worker takes the next host which nobody connects to yet
+        let next = AtomicUsize::new(0);
+        let readiness = Mutex::new(HashMap::with_capacity(hosts.len()));
+        thread::scope(|scope| {
+            for _ in 0..self.options.concurrent_hosts().min(hosts.len()) {
+                scope.spawn(|| {
+                    while let Some(host) = hosts.get(next.fetch_add(1, Ordering::SeqCst)) {
+                        let key = host.to_string();
+                        let ready = panic::catch_unwind(AssertUnwindSafe(|| connect(host, &key))).unwrap_or_else(|panic| {
+                            Err(Error::msg(format!("Connecting panicked: {}", panic_message(&*panic))))
+                        });
+                        readiness.lock().expect("readiness lock poisoned").insert(key, ready);
+                    }
+                });
+            }
+        });
+        readiness.into_inner().expect("readiness lock poisoned")
+    }
+
+    /// Closes every cached connection.
+    pub fn close_connections(&self) {
+        self.connections
+            .lock()
+            .expect("connections lock poisoned")
+            .clear();
+    }
+
+    /// Runs a module twice, so the caller can check that the second pass changed nothing.
+    /// Fails if either run fails.
+    pub fn check_idempotency<A>(
+        &self,
+        module_name: &str,
+        ip: A,
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+    ) -> Result<IdempotencyCheck, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        let mut runs = Vec::with_capacity(2);
+        for _ in 0..2 {
+            let output = self.run_module(module_name, ip.clone(), auth.clone(), sync)?;
+            if output.is_failed() {
+                return Err(Error::msg(format!("Module {} failed", module_name)));
+            }
+            runs.push(output);
+        }
+        let second = runs.pop().expect("two runs");
+        let first = runs.pop().expect("two runs");
+        Ok(IdempotencyCheck { first, second })
+    }
+}
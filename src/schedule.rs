@@ -0,0 +1,144 @@
+use crate::shell::{shell_format, ShellSafe};
+use crate::{AuthType, ConnectionProps, Runner};
+use anyhow::Error;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Display};
+use std::net::ToSocketAddrs;
+
+/// When a scheduled module runs on the host, see [`Runner::schedule_module`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    /// Time spec understood by `at`, e.g. `02:00 tomorrow`.
+    At(String),
+    /// `OnCalendar=` spec of a one-shot systemd timer, e.g. `*-*-* 02:00:00`.
+    Timer(String),
+}
+
+/// Scheduled job, as reported by the host. Keep it around to cancel the job later.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScheduledJob {
+    /// `at` job number.
+    At(String),
+    /// Name of the transient `.timer` unit.
+    Timer(String),
+}
+
+impl Schedule {
+    /// Command queueing `script` on the host.
+    pub fn remote_command(&self, script: &str) -> Result<String, Error> {
+        match self {
+            Schedule::At(time) if !time.trim().is_empty() => {
+                let time: Vec<_> = time.split_whitespace().map(|word| ShellSafe::quote(word).into_string()).collect();
+                Ok(shell_format!(
+                    "printf '%s' {} | at {} 2>&1",
+                    ShellSafe::quote(script),
+                    ShellSafe::command(&time.join(" "))
+                )
+                .into_string())
+            }
+            Schedule::Timer(spec) if !spec.trim().is_empty() => Ok(shell_format!(
+                "systemd-run --on-calendar={} --timer-property=AccuracySec=1s /bin/sh -c {} 2>&1",
+                ShellSafe::quote(spec.trim()),
+                ShellSafe::quote(script)
+            )
+            .into_string()),
+            _ => Err(Error::msg("Empty schedule time spec")),
+        }
+    }
+
+    /// Picks the job id out of what `at` or `systemd-run` printed.
+    fn job(&self, output: &str) -> Option<ScheduledJob> {
+        let (pattern, job): (_, fn(String) -> ScheduledJob) = match self {
+            Schedule::At(_) => (r"(?m)^job (\d+) at", ScheduledJob::At),
+            Schedule::Timer(_) => (r"timer as unit:? (\S+)", ScheduledJob::Timer),
+        };
+        Regex::new(pattern)
+            .expect("valid job regex")
+            .captures(output)
+            .map(|captures| job(captures[1].to_string()))
+    }
+}
+
+impl ScheduledJob {
+    /// Command removing the job from the host's queue.
+    pub fn cancel_command(&self) -> String {
+        let command = match self {
+            ScheduledJob::At(id) => shell_format!("atrm {} 2>&1", ShellSafe::path(id)),
+            ScheduledJob::Timer(unit) => shell_format!("systemctl stop {} 2>&1", ShellSafe::path(unit)),
+        };
+        command.into_string()
+    }
+
+    /// Whether a failed cancel command failed because there was no such job.
+    fn was_gone(&self, status: i32, output: &str) -> bool {
+        match self {
+            ScheduledJob::At(_) => output.contains("Cannot find jobid"),
+            // 5 is systemctl's "unit not loaded"
+            ScheduledJob::Timer(_) => status == 5,
+        }
+    }
+}
+
+impl Runner {
+    /// Queues a module to run later on the host instead of running it now.
+    ///
+    /// The module is sent as the script of [`Runner::shell_script`], so it runs with
+    /// this runner's env, umask and wrapper, but its output isn't collected.
+    pub fn schedule_module<A>(
+        &self,
+        module_name: &str,
+        ip: A,
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+        schedule: &Schedule,
+    ) -> Result<ScheduledJob, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        self.refuse_if_read_only("scheduling jobs")?;
+        let command = schedule.remote_command(&self.shell_script(module_name, None)?)?;
+        self.with_connection(ip, auth, sync, |connection| {
+            let (stdout, stderr, status) = self.run_command(connection, &command)?;
+            let output = format!("{}{}", stdout, stderr);
+            match schedule.job(&output) {
+                Some(job) if status == 0 => Ok(job),
+                _ => Err(Error::msg(format!(
+                    "Scheduling {} failed with status {}: {}",
+                    module_name,
+                    status,
+                    output.trim_end()
+                ))),
+            }
+        })
+    }
+
+    /// Removes a job queued by [`Runner::schedule_module`] before it runs.
+    /// Returns `false` if the host has no such job, e.g. because it already ran.
+    pub fn cancel_job<A>(
+        &self,
+        ip: A,
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+        job: &ScheduledJob,
+    ) -> Result<bool, Error>
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        self.refuse_if_read_only("cancelling jobs")?;
+        self.with_connection(ip, auth, sync, |connection| {
+            let (stdout, stderr, status) = self.run_command(connection, &job.cancel_command())?;
+            let output = format!("{}{}", stdout, stderr);
+            match status {
+                0 => Ok(true),
+                _ if job.was_gone(status, &output) => Ok(false),
+                _ => Err(Error::msg(format!(
+                    "Cancelling {:?} failed with status {}: {}",
+                    job,
+                    status,
+                    output.trim_end()
+                ))),
+            }
+        })
+    }
+}
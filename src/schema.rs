@@ -0,0 +1,49 @@
+use anyhow::Error;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::convert::TryFrom;
+
+/// Version of the layout of what the crate serializes: [`crate::ModuleRecord`]s of report
+/// sinks, [`crate::drift::Manifest`]s, [`crate::drift::DriftReport`]s, [`crate::ResumedRun`]s
+/// and [`crate::AuditEntry`]s, each has it in its `schema_version` field.
+/// It is bumped whenever a field changes meaning or goes away.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Version of artifacts written before they had a `schema_version`.
+pub(crate) fn unversioned() -> u32 {
+    1
+}
+
+fn check_version(version: u64) -> Result<u32, Error> {
+    match u32::try_from(version) {
+        Ok(version) if version <= SCHEMA_VERSION => Ok(version),
+        _ => Err(Error::msg(format!(
+            "Schema version {} is newer than {}, the latest this version of ansible-modules reads",
+            version, SCHEMA_VERSION
+        ))),
+    }
+}
+
+/// Rejects artifacts of a newer schema when deserializing, rather than dropping fields.
+pub(crate) fn deserialize_schema_version<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    check_version(u64::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+/// Parses a serialized artifact, e.g. a line of a [`crate::JsonlSink`] file, failing
+/// if its `schema_version` is newer than [`SCHEMA_VERSION`]. A missing one counts as 1.
+pub fn parse_versioned(json: &str) -> Result<Value, Error> {
+    let value: Value = serde_json::from_str(json)?;
+    match value.get("schema_version") {
+        None => {}
+        Some(Value::Number(version)) if version.as_u64().is_some() => {
+            check_version(version.as_u64().unwrap_or_default())?;
+        }
+        Some(version) => {
+            return Err(Error::msg(format!("Invalid schema_version {}", version)));
+        }
+    }
+    Ok(value)
+}
@@ -0,0 +1,316 @@
+use crate::bundle::bundle_token;
+use crate::facts::{epoch_ms, CLOCK_COMMAND};
+use crate::resolve::{connect_any, resolve_host};
+use crate::{
+    AuthType, ClockFacts, CommandOutput, CommandResult, ConnectionProps, ExecutionOptions,
+    HostConnection, ModuleTree, ShellModuleBuilder,
+};
+use anyhow::Error;
+use ssh2::{OpenFlags, OpenType, Session};
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::SystemTime;
+
+const ECHO_PROBE: &str = "am_selftest\n";
+const STDIN_PROBE: &str = "am_selftest stdin\n";
+const OK_MARKER: &str = "am_selftest_ok";
+/// Clocks further off than this are reported
+const MAX_DRIFT_MS: i64 = 1000;
+
+/// Verdict of one check of [`ModuleTree::run_selftest`].
+/// `Warn` is for what only some modules need, like sudo.
+/// `Skipped` checks couldn't run, as an earlier one failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+    Skipped,
+}
+
+impl Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Warn => "WARN",
+            CheckStatus::Fail => "FAIL",
+            CheckStatus::Skipped => "SKIP",
+        })
+    }
+}
+
+/// One check of [`ModuleTree::run_selftest`], with what it found
+/// and, unless it passed, what to look at.
+#[derive(Debug, Clone)]
+pub struct SelftestCheck {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+    pub hint: Option<&'static str>,
+}
+
+/// Diagnosis of a host by [`ModuleTree::run_selftest`], printable as is.
+#[derive(Debug, Clone)]
+pub struct SelftestReport {
+    pub host: String,
+    pub checks: Vec<SelftestCheck>,
+}
+
+impl SelftestReport {
+    /// Whether no check failed, warnings allowed.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.status != CheckStatus::Fail)
+    }
+
+    pub fn check(&self, name: &str) -> Option<&SelftestCheck> {
+        self.checks.iter().find(|check| check.name == name)
+    }
+
+    fn push(
+        &mut self,
+        name: &'static str,
+        status: CheckStatus,
+        detail: String,
+        hint: &'static str,
+    ) {
+        let hint = Some(hint).filter(|hint| !hint.is_empty() && status != CheckStatus::Pass);
+        self.checks.push(SelftestCheck {
+            name,
+            status,
+            detail,
+            hint,
+        });
+    }
+
+    fn skip(&mut self, names: &[&'static str]) {
+        for name in names {
+            self.push(name, CheckStatus::Skipped, String::new(), "");
+        }
+    }
+}
+
+impl Display for SelftestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "am_selftest on {}", self.host)?;
+        for check in &self.checks {
+            write!(f, "  {} {}", check.status, check.name)?;
+            if !check.detail.is_empty() {
+                write!(f, ": {}", check.detail)?;
+            }
+            writeln!(f)?;
+            if let Some(hint) = check.hint {
+                writeln!(f, "       {}", hint)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Results of the commands of a shell pseudo-module, from its `output`.
+fn command_results(output: Result<CommandOutput, Error>) -> Result<HashMap<String, CommandResult>, Error> {
+    match output? {
+        CommandOutput::Multi(results) => Ok(results),
+        CommandOutput::Single(_) => Err(Error::msg("Shell module returned a single result")),
+    }
+}
+
+/// Verdict on a command of the pseudo-module, which passes if `pass` accepts its stdout.
+fn judge(
+    results: &HashMap<String, CommandResult>,
+    name: &str,
+    pass: impl Fn(&str) -> bool,
+) -> (bool, String) {
+    match results.get(name) {
+        Some(result) => (pass(&result.stdout), result.stdout.trim().to_string()),
+        None => (false, "no result".to_string()),
+    }
+}
+
+/// Writes, reads back and deletes a file in `/tmp` over sftp. The file gets a fresh
+/// random name and must not exist yet.
+fn sftp_round_trip(session: &Session) -> Result<String, Error> {
+    let sftp = session.sftp()?;
+    let path = format!("/tmp/am_selftest-{}", bundle_token());
+    let path = Path::new(&path);
+    let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE;
+    let written = sftp.open_mode(path, flags, 0o600, OpenType::File)?.write_all(STDIN_PROBE.as_bytes());
+    if let Err(e) = written {
+        let _ = sftp.unlink(path);
+        return Err(e.into());
+    }
+    let mut content = String::new();
+    let read = sftp
+        .open(path)
+        .map_err(Error::from)
+        .and_then(|mut file| Ok(file.read_to_string(&mut content)?));
+    sftp.unlink(path)?;
+    read?;
+    if content != STDIN_PROBE {
+        return Err(Error::msg(format!("read back {:?}", content)));
+    }
+    Ok(format!("wrote, read and deleted {}", path.display()))
+}
+
+impl ModuleTree {
+    /// Diagnoses what the crate needs from a host, one check after another: `connect`,
+    /// `handshake` and `auth`, then `exec`, `stdin`, `sudo` and `python3` of the built-in
+    /// `am_selftest` pseudo-module, `sftp` in `/tmp` and the `clock`. A failed check
+    /// skips the checks depending on it, the report says what to look at.
+    ///
+    /// Timeouts and the resolver come from `sync`, host key pinning and `known_hosts`
+    /// aren't checked, nor are permits taken.
+    pub fn run_selftest<A>(
+        &self,
+        ip: A,
+        auth: AuthType,
+        sync: &dyn ConnectionProps,
+    ) -> SelftestReport
+    where
+        A: Display + ToSocketAddrs + Send + Sync + Clone + Debug + Eq + std::hash::Hash + ToString,
+    {
+        let host = ip.to_string();
+        let mut report = SelftestReport {
+            host: host.clone(),
+            checks: Vec::new(),
+        };
+        let later = ["handshake", "auth", "exec", "stdin", "sudo", "python3", "sftp", "clock"];
+        let tcp = resolve_host(&ip, &host, sync).and_then(|addresses| {
+            connect_any(&host, &addresses, sync.connect_timeout_for(&host))
+        });
+        let tcp: TcpStream = match tcp {
+            Ok(tcp) => {
+                let peer = tcp.peer_addr().map_or_else(|_| host.clone(), |peer| peer.to_string());
+                report.push("connect", CheckStatus::Pass, format!("connected to {}", peer), "");
+                tcp
+            }
+            Err(e) => {
+                let hint = "Check the address and port, and that no firewall drops ssh traffic";
+                report.push("connect", CheckStatus::Fail, format!("{:#}", e), hint);
+                report.skip(&later);
+                return report;
+            }
+        };
+
+        let session = Session::new().map_err(Error::from).and_then(|mut session| {
+            session.set_tcp_stream(tcp);
+            session.set_timeout(sync.handshake_timeout_for(&host));
+            session.handshake()?;
+            Ok(session)
+        });
+        let session = match session {
+            Ok(session) => {
+                let kind = session.host_key().map(|(_, kind)| format!("{:?}", kind));
+                let detail = format!("host key {}", kind.unwrap_or_else(|| "unknown".to_string()));
+                report.push("handshake", CheckStatus::Pass, detail, "");
+                session
+            }
+            Err(e) => {
+                let hint =
+                    "The port may not be ssh, or the host offers no algorithm libssh2 supports";
+                report.push("handshake", CheckStatus::Fail, format!("{:#}", e), hint);
+                report.skip(&later[1..]);
+                return report;
+            }
+        };
+
+        session.set_timeout(sync.auth_timeout_for(&host));
+        if let Err(e) = auth.auth(&session) {
+            let hint = "Is ssh-agent running (SSH_AUTH_SOCK), holding a key the host accepts \
+                        for this user? `ssh-add -l` lists its keys";
+            report.push("auth", CheckStatus::Fail, format!("{:#}", e), hint);
+            report.skip(&later[2..]);
+            return report;
+        }
+        report.push("auth", CheckStatus::Pass, String::new(), "");
+        session.set_timeout(sync.read_timeout_for(&host));
+        let connection = match HostConnection::from_session(session, &host) {
+            Ok(connection) => connection,
+            Err(e) => {
+                report.push("exec", CheckStatus::Fail, format!("{:#}", e), "");
+                report.skip(&later[3..]);
+                return report;
+            }
+        };
+
+        let module = ShellModuleBuilder::new()
+            .cmd("exec", &format!("echo {}", ECHO_PROBE.trim()))
+            .cmd_with("stdin", "cat", |c| c.stdin(STDIN_PROBE))
+            .cmd("sudo", &format!("sudo -n true 2>&1 && echo {}", OK_MARKER))
+            .cmd("python3", "python3 --version 2>&1")
+            .build()
+            .and_then(|module| module.execute_on(&connection, &ExecutionOptions::default()));
+        let results = match command_results(module) {
+            Ok(results) => results,
+            Err(e) => {
+                let hint = "The host accepts logins but not commands, check for a restricted \
+                            shell or a ForceCommand";
+                report.push("exec", CheckStatus::Fail, format!("{:#}", e), hint);
+                report.skip(&later[3..]);
+                return report;
+            }
+        };
+        let (passed, detail) = judge(&results, "exec", |stdout| stdout == ECHO_PROBE);
+        let status = if passed { CheckStatus::Pass } else { CheckStatus::Fail };
+        let hint = "Something else is printed along, check the rc files of the user's shell";
+        report.push("exec", status, detail, hint);
+        let (passed, _) = judge(&results, "stdin", |stdout| stdout == STDIN_PROBE);
+        let status = if passed { CheckStatus::Pass } else { CheckStatus::Fail };
+        let hint = "Commands don't get their stdin, modules with stdin will misbehave";
+        report.push("stdin", status, String::new(), hint);
+        let (passed, detail) =
+            judge(&results, "sudo", |stdout| stdout.trim_end().ends_with(OK_MARKER));
+        let (status, detail) = if passed {
+            (CheckStatus::Pass, "passwordless sudo works".to_string())
+        } else {
+            (CheckStatus::Warn, detail)
+        };
+        let hint = "No passwordless sudo, modules needing root will fail or wait for a password";
+        report.push("sudo", status, detail, hint);
+        let (passed, detail) = judge(&results, "python3", |stdout| stdout.starts_with("Python 3"));
+        let status = if passed { CheckStatus::Pass } else { CheckStatus::Warn };
+        report.push("python3", status, detail, "There is no python3 on the host's PATH");
+
+        match sftp_round_trip(connection.session()) {
+            Ok(detail) => report.push("sftp", CheckStatus::Pass, detail, ""),
+            Err(e) => {
+                let hint = "The sftp subsystem is disabled, or /tmp isn't writable";
+                report.push("sftp", CheckStatus::Fail, format!("{:#}", e), hint);
+            }
+        }
+
+        let sent = epoch_ms(SystemTime::now());
+        let clock = ShellModuleBuilder::new()
+            .cmd("clock", CLOCK_COMMAND)
+            .build()
+            .and_then(|module| module.execute_on(&connection, &ExecutionOptions::default()));
+        let received = epoch_ms(SystemTime::now());
+        let facts = command_results(clock).and_then(|results| {
+            ClockFacts::parse(
+                results.get("clock").map_or("", |result| result.stdout.as_str()),
+                sent + (received - sent) / 2,
+            )
+        });
+        let hint = "Sync the clock with NTP, schedules and certificates depend on it";
+        match facts {
+            Ok(facts) => {
+                let status = if facts.clock_drift_ms.abs() > MAX_DRIFT_MS {
+                    CheckStatus::Warn
+                } else {
+                    CheckStatus::Pass
+                };
+                let synced = match facts.ntp_synchronized {
+                    Some(true) => ", NTP synchronized",
+                    Some(false) => ", NTP not synchronized",
+                    None => "",
+                };
+                let detail = format!("off by {}ms{}", facts.clock_drift_ms, synced);
+                report.push("clock", status, detail, hint);
+            }
+            Err(e) => report.push("clock", CheckStatus::Warn, format!("{:#}", e), hint),
+        }
+        report
+    }
+}
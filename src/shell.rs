@@ -0,0 +1,148 @@
+use anyhow::Error;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Quotes `s` as a single shell word.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Text which runs nothing but what it says when spliced into a command line: a quoted
+/// word, a word which needs no quotes, a number, or a command written to be run, like
+/// the `cmd` of a module. Command lines the crate builds around such commands take
+/// their parts as `ShellSafe`, so names, paths and values from modules, inventories and
+/// callers can't end a word, a line or a comment early.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ShellSafe(String);
+
+impl ShellSafe {
+    /// `s` as a single word, see [`shell_quote`].
+    pub fn quote(s: &str) -> Self {
+        ShellSafe(shell_quote(s))
+    }
+
+    /// `s` as is if it only has letters, digits and `_ . / : @ % + , -`, and doesn't
+    /// start with `-`, quoted otherwise. For names and modes which are checked when they
+    /// are set, so lines read the same as before they were checked again.
+    pub fn word(s: &str) -> Self {
+        if is_inert(s) {
+            ShellSafe(s.to_string())
+        } else {
+            ShellSafe::quote(s)
+        }
+    }
+
+    /// `path` as a single word which commands don't take for an option,
+    /// a relative `-rf` becoming `./-rf`.
+    pub fn path(path: &str) -> Self {
+        if path.starts_with('-') {
+            ShellSafe::quote(&format!("./{}", path))
+        } else {
+            ShellSafe::quote(path)
+        }
+    }
+
+    /// `text` as the rest of a `#` comment, line breaks becoming spaces.
+    pub fn comment(text: &str) -> Self {
+        ShellSafe(text.replace(['\n', '\r'], " "))
+    }
+
+    /// `command` to run as it is, because it was written as a command, like the `cmd` of
+    /// a module, or built of `ShellSafe` parts.
+    pub(crate) fn command(command: &str) -> Self {
+        ShellSafe(command.to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+/// Whether `s` only has letters, digits and `_ . / : @ % + , -`, which the shell reads
+/// as they are, and is a word which commands don't take for an option: it isn't empty
+/// and doesn't start with `-`.
+pub(crate) fn is_inert(s: &str) -> bool {
+    !s.is_empty() && !s.starts_with('-') && s.chars().all(|c| c.is_ascii_alphanumeric() || "_./:@%+,-".contains(c))
+}
+
+impl fmt::Display for ShellSafe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+macro_rules! shell_safe_number {
+    ($($number:ty),*) => {
+        $(impl From<$number> for ShellSafe {
+            fn from(number: $number) -> Self {
+                ShellSafe(number.to_string())
+            }
+        })*
+    };
+}
+
+shell_safe_number!(u16, u32, u64, i32);
+
+impl ShellSafe {
+    /// `mode` in octal, like `755` for `chmod`.
+    pub fn octal(mode: i32) -> Self {
+        ShellSafe(format!("{:o}", mode))
+    }
+}
+
+/// `format!` for command lines, taking only [`ShellSafe`] arguments, so building one of
+/// a raw string doesn't compile. Arguments are positional.
+macro_rules! shell_format {
+    ($format:literal $(, $arg:expr)* $(,)?) => {
+        $crate::shell::ShellSafe::command(&format!(
+            $format $(, $crate::shell::ShellSafe::as_str(&$arg))*
+        ))
+    };
+}
+
+pub(crate) use shell_format;
+
+/// Checks that `name` can be used as an environment variable name.
+pub fn check_env_name(name: &str) -> Result<(), Error> {
+    let mut chars = name.chars();
+    let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::msg(format!("Invalid environment variable name: {}", name)))
+    }
+}
+
+/// Checks that `umask` is an octal mask like `022`.
+pub fn check_umask(umask: &str) -> Result<(), Error> {
+    let valid = (3..=4).contains(&umask.len()) && umask.chars().all(|c| ('0'..='7').contains(&c));
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::msg(format!("Invalid umask: {}", umask)))
+    }
+}
+
+/// `export` statement for `env`, empty for an empty map. Names are checked when they
+/// are set, one which slipped through is quoted and fails the `export`.
+pub(crate) fn env_prefix(env: &BTreeMap<&str, &str>) -> ShellSafe {
+    if env.is_empty() {
+        return ShellSafe::command("");
+    }
+    let assignments: Vec<_> = env
+        .iter()
+        .map(|(name, value)| {
+            let name = match check_env_name(name) {
+                Ok(()) => ShellSafe::word(name),
+                Err(_) => ShellSafe::quote(name),
+            };
+            shell_format!("{}={}", name, ShellSafe::quote(value)).into_string()
+        })
+        .collect();
+    shell_format!("export {}; ", ShellSafe::command(&assignments.join(" ")))
+}
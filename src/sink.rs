@@ -0,0 +1,175 @@
+use crate::schema::{deserialize_schema_version, unversioned};
+use crate::{CommandOutput, CommandResult, Outcome, SCHEMA_VERSION};
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Result of a module on a host, as it is passed to a [`ReportSink`].
+/// `error` says why the module couldn't run, `output` is set otherwise.
+/// `window_overridden` is set if the module has a maintenance window which the run
+/// overrode, see [`crate::Runner::with_window_override`].
+/// `schema_version` is [`crate::SCHEMA_VERSION`].
+#[derive(Debug, Serialize)]
+pub struct ModuleRecord<'a> {
+    pub schema_version: u32,
+    pub correlation_id: &'a str,
+    pub host: &'a str,
+    pub module: &'a str,
+    pub output: Option<&'a CommandOutput>,
+    pub error: Option<String>,
+    pub window_overridden: bool,
+}
+
+/// Receives every module result as soon as it is finished, see [`crate::Runner::with_report_sink`].
+/// Closures taking a [`ModuleRecord`] are sinks too.
+pub trait ReportSink: Send + Sync {
+    fn record(&self, record: &ModuleRecord<'_>) -> Result<(), Error>;
+
+    /// Called by [`crate::Runner::finish_run`] with what the run did, for sinks which
+    /// report once per run. Does nothing by default.
+    fn finish(&self, _summary: &RunSummary) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<F> ReportSink for F
+where
+    F: Fn(&ModuleRecord<'_>) -> Result<(), Error> + Send + Sync,
+{
+    fn record(&self, record: &ModuleRecord<'_>) -> Result<(), Error> {
+        self(record)
+    }
+}
+
+/// Module which failed or couldn't run, as a [`RunSummary`] lists it. `error` says why
+/// it couldn't run, `outcome` is `None` then.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModuleFailure {
+    pub host: String,
+    pub module: String,
+    pub outcome: Option<Outcome>,
+    pub error: Option<String>,
+}
+
+/// What a run did, made of the [`ModuleRecord`]s of its modules, see
+/// [`crate::Runner::finish_run`]. `outcomes` counts the modules which ran by their
+/// outcome, `errors` those which couldn't run or were skipped, `failures` lists both
+/// kinds in the order they finished.
+/// `schema_version` is [`crate::SCHEMA_VERSION`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunSummary {
+    #[serde(default = "unversioned", deserialize_with = "deserialize_schema_version")]
+    pub schema_version: u32,
+    pub correlation_id: String,
+    pub hosts: BTreeSet<String>,
+    pub outcomes: BTreeMap<Outcome, usize>,
+    pub errors: usize,
+    pub failures: Vec<ModuleFailure>,
+}
+
+impl RunSummary {
+    pub fn new(correlation_id: &str) -> Self {
+        RunSummary {
+            schema_version: SCHEMA_VERSION,
+            correlation_id: correlation_id.to_string(),
+            hosts: BTreeSet::new(),
+            outcomes: BTreeMap::new(),
+            errors: 0,
+            failures: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, record: &ModuleRecord<'_>) {
+        self.hosts.insert(record.host.to_string());
+        let outcome = record.output.map(CommandOutput::outcome);
+        match outcome {
+            Some(outcome) => *self.outcomes.entry(outcome).or_default() += 1,
+            None => self.errors += 1,
+        }
+        if matches!(outcome, None | Some(Outcome::Failed)) {
+            self.failures.push(ModuleFailure {
+                host: record.host.to_string(),
+                module: record.module.to_string(),
+                outcome,
+                error: record.error.clone(),
+            });
+        }
+    }
+
+    /// Modules run, and those which couldn't.
+    pub fn modules(&self) -> usize {
+        self.outcomes.values().sum::<usize>() + self.errors
+    }
+
+    /// Whether every module ran and none failed.
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Appends every record to a file as one line of JSON.
+pub struct JsonlSink {
+    file: Mutex<BufWriter<File>>,
+}
+
+impl JsonlSink {
+    /// Appends to `path`, creating it if it doesn't exist.
+    pub fn append(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| Error::msg(format!("Opening {}: {}", path.display(), e)))?;
+        Ok(JsonlSink {
+            file: Mutex::new(BufWriter::new(file)),
+        })
+    }
+}
+
+impl ReportSink for JsonlSink {
+    fn record(&self, record: &ModuleRecord<'_>) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().expect("sink lock poisoned");
+        file.write_all(&line)?;
+        // a record is complete once it is in the file
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// What results returned by the [`crate::Runner`] keep, once a [`ReportSink`] has them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retain {
+    Full,
+    /// Outcomes, byte counts and captures, without stdout, stderr and parsed output.
+    SummariesOnly,
+}
+
+fn summarize(result: &mut CommandResult) {
+    result.stdout.clear();
+    if let Some(stderr) = &mut result.stderr {
+        stderr.clear();
+    }
+    result.parsed = None;
+}
+
+impl Retain {
+    pub(crate) fn apply(self, output: CommandOutput) -> CommandOutput {
+        match (self, output) {
+            (Retain::Full, output) => output,
+            (Retain::SummariesOnly, CommandOutput::Multi(mut results)) => {
+                results.values_mut().for_each(summarize);
+                CommandOutput::Multi(results)
+            }
+            (Retain::SummariesOnly, CommandOutput::Single(mut result)) => {
+                summarize(&mut result);
+                CommandOutput::Single(result)
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use crate::{parse_duration, AuthType, DefaultConnectionProps, Host, StaticResolver};
+use anyhow::Error;
+use std::path::PathBuf;
+
+/// What to run where, parsed from command line arguments, see [`RunSpec::from_args`].
+#[derive(Debug, Clone)]
+pub struct RunSpec {
+    pub module: String,
+    pub hosts: Vec<Host>,
+    pub auth: AuthType,
+    pub props: DefaultConnectionProps,
+}
+
+impl RunSpec {
+    /// Parses arguments like `--module restart --hosts a,b:2222 --user deploy`,
+    /// without the program name. Values may also be attached with `=`.
+    ///
+    /// `--module`, `--hosts` and `--user` are required.
+    /// `--key-name` picks the agent key to authenticate with, `--key-file` a private key
+    /// file without a passphrase to authenticate with instead of the agent,
+    /// `--timeout` sets the session timeout, e.g. `30s`, `--dns-timeout` that of resolving hosts.
+    /// `--resolve` overrides addresses of hosts, e.g. `web01=10.8.3.4,web02=10.8.3.5`.
+    pub fn from_args(args: &[String]) -> Result<RunSpec, Error> {
+        let mut module = None;
+        let mut hosts = None;
+        let mut user = None;
+        let mut key_name = None;
+        let mut key_file = None;
+        let mut timeout = None;
+        let mut dns_timeout = None;
+        let mut resolve = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            let slot = match flag {
+                "--module" => &mut module,
+                "--hosts" => &mut hosts,
+                "--user" => &mut user,
+                "--key-name" => &mut key_name,
+                "--key-file" => &mut key_file,
+                "--timeout" => &mut timeout,
+                "--dns-timeout" => &mut dns_timeout,
+                "--resolve" => &mut resolve,
+                _ => return Err(Error::msg(format!("Unknown argument {}", arg))),
+            };
+            let value = match inline {
+                Some(value) => value,
+                None => args
+                    .next()
+                    .cloned()
+                    .ok_or_else(|| Error::msg(format!("{} needs a value", flag)))?,
+            };
+            if slot.replace(value).is_some() {
+                return Err(Error::msg(format!("{} given more than once", flag)));
+            }
+        }
+
+        let module = module.ok_or_else(|| Error::msg("--module is required"))?;
+        let hosts: Vec<Host> = hosts
+            .ok_or_else(|| Error::msg("--hosts is required"))?
+            .split(',')
+            .map(str::trim)
+            .filter(|host| !host.is_empty())
+            .map(Host::parse)
+            .collect();
+        if hosts.is_empty() {
+            return Err(Error::msg("--hosts lists no hosts"));
+        }
+        let user = user.ok_or_else(|| Error::msg("--user is required"))?;
+        let auth = match (key_name, key_file) {
+            (Some(_), Some(_)) => return Err(Error::msg("--key-name and --key-file can't both be given")),
+            (Some(key_name), None) => AuthType::AgentWithKeyName(user, key_name),
+            (None, Some(key_file)) => AuthType::KeyFile {
+                username: user,
+                private_key: PathBuf::from(key_file),
+                passphrase: None,
+            },
+            (None, None) => AuthType::AgentFirst(user),
+        };
+        let mut props = DefaultConnectionProps::default();
+        if let Some(timeout) = timeout {
+            let timeout = parse_duration(&timeout)?;
+            props.timeout = timeout.as_millis().min(u32::MAX as u128) as u32;
+        }
+        if let Some(dns_timeout) = dns_timeout {
+            let dns_timeout = parse_duration(&dns_timeout)?;
+            props.dns_timeout = Some(dns_timeout.as_millis().min(u32::MAX as u128) as u32);
+        }
+        if let Some(resolve) = resolve {
+            props.resolve = StaticResolver::parse(&resolve)?;
+        }
+        Ok(RunSpec {
+            module,
+            hosts,
+            auth,
+            props,
+        })
+    }
+}
@@ -0,0 +1,125 @@
+use crate::modules::ModuleContent;
+use crate::template::substitute;
+use crate::ModuleTree;
+use anyhow::Error;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::sync::Mutex;
+
+/// Where the captures of a command are kept, see [`crate::ShellCommand`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegisterScope {
+    /// In the command's result only.
+    #[default]
+    Command,
+    /// Also in the [`StateStore`] of the connection, for later modules.
+    Connection,
+}
+
+/// Values modules leave for later modules on the same connection, e.g. the id of a
+/// container one of them started. Commands registering to the connection write their
+/// captures here, templates read them as `{{ state.key }}`.
+/// It lives as long as the connection, closing it clears the state.
+#[derive(Debug, Default)]
+pub struct StateStore {
+    values: Mutex<BTreeMap<String, String>>,
+}
+
+impl StateStore {
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.values.lock().expect("state lock poisoned").get(key).cloned()
+    }
+
+    pub fn set(&self, key: &str, value: &str) {
+        self.values
+            .lock()
+            .expect("state lock poisoned")
+            .insert(key.to_string(), value.to_string());
+    }
+
+    /// Every key and its value, as they are now.
+    pub fn snapshot(&self) -> BTreeMap<String, String> {
+        self.values.lock().expect("state lock poisoned").clone()
+    }
+}
+
+/// State key a template read before any module set it.
+/// `writers` are the modules which would, if known to whoever failed.
+/// Errors carry it, find it with `downcast_ref::<UnsetState>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsetState {
+    pub key: String,
+    pub writers: Option<Vec<String>>,
+}
+
+impl Display for UnsetState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "State key {} is unset", self.key)?;
+        match &self.writers {
+            None => Ok(()),
+            Some(writers) if writers.is_empty() => write!(f, ", no module writes it"),
+            Some(writers) => write!(f, ", {} should run first", writers.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for UnsetState {}
+
+/// Substitutes the `{{ state.key }}` placeholders of `template`, leaving others.
+pub(crate) fn render_state(
+    template: &str,
+    state: &BTreeMap<String, String>,
+) -> Result<String, Error> {
+    substitute(template, |name| {
+        let key = match name.strip_prefix("state.") {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        match state.get(key) {
+            Some(value) => Ok(Some(value.clone())),
+            None => Err(UnsetState {
+                key: key.to_string(),
+                writers: None,
+            }
+            .into()),
+        }
+    })
+}
+
+impl ModuleTree {
+    /// Modules with a command registering the state key `key` to the connection.
+    pub fn state_writers(&self, key: &str) -> Vec<&str> {
+        self.module_names()
+            .into_iter()
+            .filter(|name| {
+                let commands = match self.module(name).map(|module| &*module.module_content) {
+                    Some(ModuleContent::Shell(commands)) => commands,
+                    _ => return false,
+                };
+                commands.values().any(|command| {
+                    command.register_scope == RegisterScope::Connection
+                        && command.capture.as_ref().is_some_and(|capture| {
+                            capture.capture_names().any(|group| group == Some(key))
+                        })
+                })
+            })
+            .collect()
+    }
+
+    /// Adds the writers of the key to an [`UnsetState`] error, other errors are kept.
+    pub(crate) fn explain_unset_state(&self, e: Error) -> Error {
+        match e.downcast::<UnsetState>() {
+            Ok(unset) => {
+                let writers = self.state_writers(&unset.key);
+                UnsetState {
+                    writers: Some(writers.into_iter().map(str::to_string).collect()),
+                    ..unset
+                }
+                .into()
+            }
+            Err(e) => e,
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Operating system of a host, which decides how the crate builds the paths and
+//! command lines of its own uploads and binaries there.
+use crate::exec::exec_command;
+use crate::modules::read_channel;
+use crate::shell::ShellSafe;
+use crate::HostConnection;
+use anyhow::Error;
+use serde::Serialize;
+
+/// Prints `Windows_NT` through cmd.exe, the name of the kernel through a POSIX shell.
+pub(crate) const OS_COMMAND: &str = "uname -s || echo %OS%";
+
+/// Operating system family of a host, see [`HostConnection::detect_target_os`].
+/// Hosts are taken as POSIX until detected otherwise.
+///
+/// On Windows, [`HostConnection::upload_dir`] builds `\` separated destination paths and
+/// uploads file by file, and binary modules are run with `cmd /s /c` from `C:\Windows\Temp`
+/// unless they declare a `remote_dir`. Shell and python modules run what they declare
+/// either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetOs {
+    Posix,
+    Windows,
+}
+
+/// Whether `path` is absolute on a POSIX host, like `/tmp`, or on Windows, like `C:\Temp`.
+pub(crate) fn is_absolute(path: &str) -> bool {
+    let mut chars = path.chars();
+    let drive = matches!(
+        (chars.next(), chars.next(), chars.next()),
+        (Some(letter), Some(':'), Some('\\' | '/')) if letter.is_ascii_alphabetic()
+    );
+    path.starts_with('/') || drive
+}
+
+impl TargetOs {
+    /// Reads the output of the detection command. Only cmd.exe prints `Windows_NT`,
+    /// which the OpenSSH server of Windows runs commands with unless configured otherwise.
+    pub fn parse(output: &str) -> Self {
+        match output.lines().any(|line| line.trim() == "Windows_NT") {
+            true => TargetOs::Windows,
+            false => TargetOs::Posix,
+        }
+    }
+
+    /// `posix` or `windows`, as in the `facts.os` template variable.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TargetOs::Posix => "posix",
+            TargetOs::Windows => "windows",
+        }
+    }
+
+    /// Path of `relative`, `/` separated, below `dir` on the host. On Windows every
+    /// separator becomes a `\`.
+    pub fn join(self, dir: &str, relative: &str) -> String {
+        match self {
+            TargetOs::Posix => format!("{}/{}", dir.trim_end_matches('/'), relative),
+            TargetOs::Windows => {
+                format!("{}\\{}", dir.trim_end_matches(['/', '\\']), relative).replace('/', "\\")
+            }
+        }
+    }
+
+    /// Directory `path` is in on the host, `None` for a root or a name without one.
+    pub(crate) fn parent(self, path: &str) -> Option<&str> {
+        let separators: &[char] = match self {
+            TargetOs::Posix => &['/'],
+            TargetOs::Windows => &['/', '\\'],
+        };
+        let trimmed = path.trim_end_matches(separators);
+        let parent = &trimmed[..trimmed.rfind(separators)?];
+        match parent.is_empty() || parent.ends_with(':') {
+            // `/name` or `C:\name`, the root keeps its separator
+            true => Some(&path[..=parent.len()]).filter(|root| *root != path),
+            false => Some(parent),
+        }
+    }
+
+    /// Where binary modules are uploaded to unless they declare a `remote_dir`.
+    pub(crate) fn temp_dir(self) -> &'static str {
+        match self {
+            TargetOs::Posix => "/tmp",
+            TargetOs::Windows => "C:\\Windows\\Temp",
+        }
+    }
+
+    /// `arg` as one argument of a command line of the host. cmd.exe expands `%` and
+    /// can't escape `"` within quotes, arguments with either are refused on Windows.
+    pub fn quote(self, arg: &str) -> Result<String, Error> {
+        match self {
+            TargetOs::Posix => Ok(ShellSafe::quote(arg).into_string()),
+            TargetOs::Windows => {
+                if arg.contains(['"', '%', '\n', '\r', '\0']) {
+                    return Err(Error::msg(format!("{:?} can't be passed through cmd.exe", arg)));
+                }
+                // backslashes before the closing quote would escape it
+                let trailing = arg.len() - arg.trim_end_matches('\\').len();
+                Ok(format!("\"{}{}\"", arg, "\\".repeat(trailing)))
+            }
+        }
+    }
+
+    /// `command` run through the shell of the host: as is on POSIX, with `cmd /s /c`
+    /// on Windows, whatever shell its sshd would run it with.
+    pub fn command(self, command: &str) -> String {
+        match self {
+            TargetOs::Posix => command.to_string(),
+            TargetOs::Windows => format!("cmd /s /c \"{}\"", command),
+        }
+    }
+}
+
+impl HostConnection {
+    /// Tells the operating system of the host, running `uname -s || echo %OS%`, and
+    /// remembers it, see [`HostConnection::target_os`]. [`crate::Runner::gather_host_facts`]
+    /// runs it too.
+    pub fn detect_target_os(&self) -> Result<TargetOs, Error> {
+        let mut channel = self.open_channel()?;
+        let (session, exec) = channel.split();
+        exec_command(session, exec, OS_COMMAND)?;
+        let (stdout, _, _) = read_channel(&mut channel, Some(4096))?;
+        channel.wait_close()?;
+        let os = TargetOs::parse(&String::from_utf8_lossy(&stdout));
+        self.set_target_os(os);
+        Ok(os)
+    }
+}
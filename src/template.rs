@@ -0,0 +1,124 @@
+use crate::shell::is_inert;
+use crate::shell_quote;
+use anyhow::Error;
+use std::collections::HashMap;
+
+pub(crate) fn is_variable(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Substitutes `{{ name }}` placeholders with values from `vars`.
+///
+/// Only identifiers are treated as placeholders, so foreign templates
+/// like docker's `{{.State.Status}}` are left untouched.
+/// A placeholder without a value is an error.
+/// Values are put in as they are if they only have letters, digits and
+/// `_ . / : @ % + , -`, and aren't empty and don't start with `-`. Other values are an
+/// error, so one can't run anything, drop a word or become an option.
+/// `{{ name | quote }}` puts a value in as a single, quoted shell word, for values
+/// which come from inventories or callers, `{{ name | raw }}` puts it in as it is,
+/// for values which are shell code.
+pub fn render_template(template: &str, vars: &HashMap<String, String>) -> Result<String, Error> {
+    substitute(template, |name| lookup(vars, name))
+}
+
+/// Substitutes `{{ name }}` placeholders with values from `vars` as they are,
+/// for text which isn't run, like messages.
+#[cfg(feature = "http")]
+pub(crate) fn render_text(template: &str, vars: &HashMap<String, String>) -> Result<String, Error> {
+    splice(template, false, |name| lookup(vars, name))
+}
+
+/// `template` with its placeholders without a filter made `| raw`, for templates of
+/// data which isn't run, like `stdin`.
+pub(crate) fn raw_placeholders(template: &str) -> String {
+    let mut raw = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        let name = rest[start + 2..end].trim();
+        raw.push_str(&rest[..start]);
+        match is_variable(name) {
+            true => raw.push_str(&format!("{{{{ {} | raw }}}}", name)),
+            false => raw.push_str(&rest[start..end + 2]),
+        }
+        rest = &rest[end + 2..];
+    }
+    raw.push_str(rest);
+    raw
+}
+
+fn lookup(vars: &HashMap<String, String>, name: &str) -> Result<Option<String>, Error> {
+    match vars.get(name) {
+        Some(value) => Ok(Some(value.clone())),
+        None => Err(Error::msg(format!("Undefined template variable {}", name))),
+    }
+}
+
+/// Substitutes the placeholders `value` returns a value for, leaving the others,
+/// see [`render_template`].
+pub(crate) fn substitute<F>(template: &str, value: F) -> Result<String, Error>
+where
+    F: FnMut(&str) -> Result<Option<String>, Error>,
+{
+    splice(template, true, value)
+}
+
+/// How a placeholder puts its value in.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Filter {
+    None,
+    Quote,
+    Raw,
+}
+
+/// Substitutes the placeholders `value` returns a value for, failing on values
+/// without a filter which aren't inert if `checked`.
+fn splice<F>(template: &str, checked: bool, mut value: F) -> Result<String, Error>
+where
+    F: FnMut(&str) -> Result<Option<String>, Error>,
+{
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        let (name, filter) = match rest[start + 2..end].split_once('|') {
+            Some((name, filter)) if filter.trim() == "quote" => (name.trim(), Filter::Quote),
+            Some((name, filter)) if filter.trim() == "raw" => (name.trim(), Filter::Raw),
+            _ => (rest[start + 2..end].trim(), Filter::None),
+        };
+        rendered.push_str(&rest[..start]);
+        let substituted = match is_variable(name) {
+            true => value(name)?,
+            false => None,
+        };
+        let substituted = match (substituted, filter) {
+            (Some(value), Filter::Quote) => Some(shell_quote(&value)),
+            (Some(value), Filter::None) if checked && !is_inert(&value) => {
+                return Err(Error::msg(format!(
+                    "Template variable {} is {:?}, put it in with `| quote`, or `| raw` if it is shell code",
+                    name, value
+                )))
+            }
+            (substituted, _) => substituted,
+        };
+        match substituted {
+            Some(substituted) => rendered.push_str(&substituted),
+            None => rendered.push_str(&rest[start..end + 2]),
+        }
+        rest = &rest[end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
@@ -0,0 +1,111 @@
+use serde::Serialize;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::ops::Add;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Bytes a connection moved over the network, ssh framing, uploads and keepalives
+/// included, see [`crate::HostConnection::traffic_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TrafficStats {
+    pub bytes_written: u64,
+    pub bytes_read: u64,
+}
+
+impl Add for TrafficStats {
+    type Output = TrafficStats;
+
+    fn add(self, other: TrafficStats) -> TrafficStats {
+        TrafficStats {
+            bytes_written: self.bytes_written + other.bytes_written,
+            bytes_read: self.bytes_read + other.bytes_read,
+        }
+    }
+}
+
+/// Counters of one connection, bumped by its relay threads.
+#[derive(Debug, Default)]
+pub(crate) struct TrafficCounter {
+    written: AtomicU64,
+    read: AtomicU64,
+}
+
+impl TrafficCounter {
+    pub(crate) fn stats(&self) -> TrafficStats {
+        TrafficStats {
+            bytes_written: self.written.load(Ordering::Relaxed),
+            bytes_read: self.read.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Copies `from` into `to`, adding what was copied to `count`, then shuts `to` down.
+/// After `limit` bytes both are shut down, as if the network dropped the connection.
+fn relay(
+    mut from: TcpStream,
+    mut to: TcpStream,
+    count: impl Fn(u64),
+    end: Shutdown,
+    mut limit: Option<u64>,
+) {
+    let mut buffer = [0; 32 * 1024];
+    loop {
+        if limit == Some(0) {
+            let _ = from.shutdown(Shutdown::Both);
+            let _ = to.shutdown(Shutdown::Both);
+            return;
+        }
+        let size = limit.map_or(buffer.len(), |limit| buffer.len().min(limit as usize));
+        let read = match from.read(&mut buffer[..size]) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        if to.write_all(&buffer[..read]).is_err() {
+            break;
+        }
+        count(read as u64);
+        limit = limit.map(|limit| limit - read as u64);
+    }
+    let _ = to.shutdown(end);
+}
+
+/// Puts a loopback socket between `tcp` and the session, relaying and counting every
+/// byte, as libssh2 reads and writes the socket it is given itself. Returns the socket
+/// to hand to the session. The relay ends when either side closes, or when `drop_after`
+/// bytes were read from the host.
+pub(crate) fn count_traffic(
+    tcp: TcpStream,
+    drop_after: Option<u64>,
+) -> io::Result<(TcpStream, Arc<TrafficCounter>)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let session_side = TcpStream::connect(listener.local_addr()?)?;
+    let (relay_side, peer) = listener.accept()?;
+    // anyone on the host could have connected first
+    if peer != session_side.local_addr()? {
+        return Err(io::Error::other("unexpected connection to relay"));
+    }
+    session_side.set_nodelay(true)?;
+    relay_side.set_nodelay(true)?;
+    let counter = Arc::new(TrafficCounter::default());
+    let (outgoing, incoming) = (relay_side.try_clone()?, tcp.try_clone()?);
+    let written = counter.clone();
+    thread::spawn(move || {
+        let count = |bytes| {
+            written.written.fetch_add(bytes, Ordering::Relaxed);
+        };
+        // the session is gone, nothing needs to be read from the host anymore
+        relay(outgoing, incoming, count, Shutdown::Both, None)
+    });
+    let read = counter.clone();
+    thread::spawn(move || {
+        let count = |bytes| {
+            read.read.fetch_add(bytes, Ordering::Relaxed);
+        };
+        relay(tcp, relay_side, count, Shutdown::Write, drop_after)
+    });
+    Ok((session_side, counter))
+}
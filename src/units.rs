@@ -0,0 +1,61 @@
+use anyhow::Error;
+use std::time::Duration;
+
+fn split_number(s: &str) -> Result<(u64, &str), Error> {
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let number = s[..digits]
+        .parse()
+        .map_err(|_| Error::msg(format!("Expected a number in {:?}", s)))?;
+    Ok((number, &s[digits..]))
+}
+
+/// Parses a byte size like `1024`, `512K`, `64MiB` or `1GB`.
+/// `K`, `M`, `G` and the `iB` forms are binary, `KB`, `MB`, `GB` are decimal.
+pub fn parse_size(s: &str) -> Result<u64, Error> {
+    let (number, unit) = split_number(s.trim())?;
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        unit => return Err(Error::msg(format!("Unknown size unit {:?} in {:?}", unit, s))),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| Error::msg(format!("Size {:?} is too large", s)))
+}
+
+/// Parses a duration like `500ms`, `45m` or `1h30m`.
+/// Units are `ms`, `s`, `m`, `h` and `d`.
+pub fn parse_duration(s: &str) -> Result<Duration, Error> {
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(Error::msg("Empty duration"));
+    }
+    let too_large = || Error::msg(format!("Duration {:?} is too large", s));
+    let mut total = Duration::from_secs(0);
+    while !rest.is_empty() {
+        let (number, tail) = split_number(rest)?;
+        let unit_len = tail.find(|c: char| c.is_ascii_digit()).unwrap_or(tail.len());
+        let seconds = |multiplier: u64| number.checked_mul(multiplier).map(Duration::from_secs);
+        let part = match &tail[..unit_len] {
+            "ms" => Some(Duration::from_millis(number)),
+            "s" => Some(Duration::from_secs(number)),
+            "m" => seconds(60),
+            "h" => seconds(60 * 60),
+            "d" => seconds(60 * 60 * 24),
+            unit => {
+                return Err(Error::msg(format!(
+                    "Unknown duration unit {:?} in {:?}",
+                    unit, s
+                )))
+            }
+        };
+        total = part.and_then(|part| total.checked_add(part)).ok_or_else(too_large)?;
+        rest = &tail[unit_len..];
+    }
+    Ok(total)
+}
@@ -0,0 +1,386 @@
+use crate::disk::upload_failed;
+use crate::exec::exec_command;
+use crate::modules::read_channel;
+use crate::pipe::{idle_within, read_timeout, would_block};
+use crate::shell::{shell_format, ShellSafe};
+use crate::{HostConnection, TargetOs};
+use anyhow::Error;
+use ssh2::{Channel, FileStat, OpenFlags, OpenType, Sftp};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// Files below which [`UploadStrategy::Auto`] uploads over sftp by default, as the
+/// round-trips of a few files cost less than checking for `tar`.
+pub const DEFAULT_TAR_MIN_FILES: usize = 16;
+
+/// Files this large don't fit the size field of a tar header.
+const TAR_MAX_SIZE: u64 = 1 << 33;
+
+/// How [`HostConnection::upload_dir`] sends files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadStrategy {
+    /// One sftp write per file and directory.
+    PerFile,
+    /// One tar archive over a single channel, extracted by `tar` on the host.
+    Tar,
+    /// [`UploadStrategy::Tar`] for at least `min_files` files if the host has `tar`,
+    /// [`UploadStrategy::PerFile`] otherwise, or for files of 8GiB and more.
+    Auto { min_files: usize },
+}
+
+impl Default for UploadStrategy {
+    fn default() -> Self {
+        UploadStrategy::Auto {
+            min_files: DEFAULT_TAR_MIN_FILES,
+        }
+    }
+}
+
+/// What [`HostConnection::upload_dir`] sent, `strategy` being the one it used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirUpload {
+    pub strategy: UploadStrategy,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// File or directory below the directory uploaded.
+struct Entry {
+    path: PathBuf,
+    /// Path below the directory, `/` separated
+    relative: String,
+    dir: bool,
+    mode: u32,
+    mtime: u64,
+    size: u64,
+}
+
+/// Identity of a directory, to notice a symlink leading back into one being walked.
+#[cfg(unix)]
+type DirId = (u64, u64);
+#[cfg(not(unix))]
+type DirId = PathBuf;
+
+#[cfg(unix)]
+fn dir_id(_path: &Path, metadata: &fs::Metadata) -> io::Result<DirId> {
+    use std::os::unix::fs::MetadataExt;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn dir_id(path: &Path, _metadata: &fs::Metadata) -> io::Result<DirId> {
+    fs::canonicalize(path)
+}
+
+/// Files and directories below `dir`, each directory before its content, sorted by name.
+/// Symlinks are followed, unless they lead back into a directory they are in, which
+/// is refused like other special files.
+fn walk(dir: &Path) -> Result<Vec<Entry>, Error> {
+    let mut entries = Vec::new();
+    let mut ancestors = vec![dir_id(dir, &fs::metadata(dir)?)?];
+    walk_into(dir, "", &mut ancestors, &mut entries)?;
+    Ok(entries)
+}
+
+/// [`walk`] below `dir`, the last of the directories `ancestors` walks through.
+fn walk_into(dir: &Path, relative: &str, ancestors: &mut Vec<DirId>, entries: &mut Vec<Entry>) -> Result<(), Error> {
+    let mut children: Vec<_> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+    children.sort_by_key(|child| child.file_name());
+    for child in children {
+        let path = child.path();
+        let name = child
+            .file_name()
+            .into_string()
+            .map_err(|name| Error::msg(format!("{:?} isn't a UTF-8 file name", name)))?;
+        let relative = if relative.is_empty() { name } else { format!("{}/{}", relative, name) };
+        let metadata = fs::metadata(&path)?;
+        if !metadata.is_file() && !metadata.is_dir() {
+            return Err(Error::msg(format!("{} is neither a file nor a directory", path.display())));
+        }
+        let id = match metadata.is_dir() {
+            true => Some(dir_id(&path, &metadata)?),
+            false => None,
+        };
+        if id.as_ref().is_some_and(|id| ancestors.contains(id)) {
+            return Err(Error::msg(format!("{} links back to a directory it is in", path.display())));
+        }
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs());
+        entries.push(Entry {
+            path: path.clone(),
+            relative: relative.clone(),
+            dir: metadata.is_dir(),
+            mode: mode_of(&metadata),
+            mtime,
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+        });
+        if let Some(id) = id {
+            ancestors.push(id);
+            walk_into(&path, &relative, ancestors, entries)?;
+            ancestors.pop();
+        }
+    }
+    Ok(())
+}
+
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        match (metadata.is_dir(), metadata.permissions().readonly()) {
+            (true, _) => 0o755,
+            (false, true) => 0o444,
+            (false, false) => 0o644,
+        }
+    }
+}
+
+/// Writes `value` as a NUL terminated octal number filling `field`.
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = width);
+    field[..width].copy_from_slice(&digits.as_bytes()[digits.len() - width..]);
+}
+
+/// ustar header of a file `name` of `kind` `0`, a directory `5`, or a long name `L`.
+fn header(name: &[u8], kind: u8, mode: u32, size: u64, mtime: u64) -> [u8; 512] {
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name);
+    octal(&mut header[100..108], mode.into());
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], mtime);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[148..156].copy_from_slice(b"        ");
+    let sum: u32 = header.iter().map(|byte| u32::from(*byte)).sum();
+    octal(&mut header[148..155], sum.into());
+    header[155] = b' ';
+    header
+}
+
+fn pad(out: &mut dyn Write, written: u64) -> io::Result<()> {
+    let rest = (512 - written % 512) % 512;
+    out.write_all(&vec![0; rest as usize])
+}
+
+/// Writes the files and directories below `dir` as a tar archive to `out`, as
+/// [`UploadStrategy::Tar`] streams it. Entries keep their permission bits and
+/// modification time, owners are left out. Returns the number of files.
+pub fn tar_dir(dir: &Path, out: &mut dyn Write) -> Result<usize, Error> {
+    let entries = walk(dir)?;
+    write_tar(&entries, out)?;
+    Ok(entries.iter().filter(|entry| !entry.dir).count())
+}
+
+fn write_tar(entries: &[Entry], out: &mut dyn Write) -> Result<(), Error> {
+    for entry in entries {
+        if entry.size >= TAR_MAX_SIZE {
+            return Err(Error::msg(format!(
+                "{} is larger than the 8GiB a tar entry holds, upload it per file",
+                entry.path.display()
+            )));
+        }
+        let mut name = entry.relative.clone().into_bytes();
+        if entry.dir {
+            name.push(b'/');
+        }
+        if name.len() > 100 {
+            // GNU long name, understood by GNU tar, bsdtar and busybox
+            out.write_all(&header(b"././@LongLink", b'L', 0, name.len() as u64 + 1, 0))?;
+            out.write_all(&name)?;
+            out.write_all(&[0])?;
+            pad(out, name.len() as u64 + 1)?;
+            name.truncate(100);
+        }
+        let kind = if entry.dir { b'5' } else { b'0' };
+        out.write_all(&header(&name, kind, entry.mode, entry.size, entry.mtime))?;
+        if !entry.dir {
+            let copied = io::copy(&mut File::open(&entry.path)?.take(entry.size), out)?;
+            if copied != entry.size {
+                return Err(Error::msg(format!("{} shrank while it was uploaded", entry.path.display())));
+            }
+            pad(out, entry.size)?;
+        }
+    }
+    out.write_all(&[0; 1024])?;
+    Ok(())
+}
+
+/// Writes the input of a command to its channel.
+type WriteInput<'a> = &'a dyn Fn(&mut dyn Write) -> Result<(), Error>;
+
+/// Input of a command, which reads the command's stderr whenever the channel can't take
+/// more, so a command complaining at length can't stall the writes. The session of the
+/// channel must be non-blocking.
+struct DrainingInput<'a> {
+    channel: &'a mut Channel,
+    stderr: Vec<u8>,
+    timeout: Option<Duration>,
+}
+
+impl DrainingInput<'_> {
+    /// `attempt` on the channel, once it doesn't block, reading stderr until then.
+    fn retry<T>(&mut self, mut attempt: impl FnMut(&mut Channel) -> io::Result<T>) -> io::Result<T> {
+        let mut buf = [0; 32 * 1024];
+        let mut quiet_since = Instant::now();
+        loop {
+            if let Some(done) = would_block(attempt(self.channel))? {
+                return Ok(done);
+            }
+            match would_block(self.channel.stderr().read(&mut buf))? {
+                Some(read) if read > 0 => {
+                    self.stderr.extend_from_slice(&buf[..read]);
+                    quiet_since = Instant::now();
+                }
+                _ => idle_within(quiet_since, self.timeout)?,
+            }
+        }
+    }
+
+    /// Ends the input, returning the stderr read so far.
+    fn finish(mut self) -> io::Result<Vec<u8>> {
+        self.retry(|channel| channel.send_eof().map_err(Into::into))?;
+        Ok(self.stderr)
+    }
+}
+
+impl Write for DrainingInput<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.retry(|channel| channel.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.retry(|channel| channel.flush())
+    }
+}
+
+/// Runs `command` on the host with the input `stdin` writes, failing with its stderr
+/// unless it exits with 0.
+fn run(connection: &HostConnection, command: &str, stdin: Option<WriteInput>) -> Result<(), Error> {
+    let mut channel = connection.open_channel()?;
+    let (session, exec) = channel.split();
+    exec_command(session, exec, command)?;
+    let mut stderr = Vec::new();
+    if let Some(write) = stdin {
+        session.set_blocking(false);
+        let mut input = DrainingInput {
+            channel: exec,
+            stderr: Vec::new(),
+            timeout: read_timeout(session),
+        };
+        let written = write(&mut input).and_then(|_| Ok(input.finish()?));
+        session.set_blocking(true);
+        stderr = written?;
+    }
+    let (_, rest, _) = read_channel(&mut channel, None)?;
+    stderr.extend(rest);
+    channel.wait_close()?;
+    match channel.exit_status()? {
+        0 => Ok(()),
+        status => Err(Error::msg(format!(
+            "{} exited with status {}: {}",
+            command,
+            status,
+            String::from_utf8_lossy(&stderr).trim()
+        ))),
+    }
+}
+
+/// Creates `path` and its missing parents over sftp, on a host of `os`.
+fn sftp_mkdir_all(sftp: &Sftp, os: TargetOs, path: &str) -> Result<(), Error> {
+    if sftp.stat(Path::new(path)).is_ok() {
+        return Ok(());
+    }
+    if let Some(parent) = os.parent(path) {
+        sftp_mkdir_all(sftp, os, parent)?;
+    }
+    sftp.mkdir(Path::new(path), 0o755)
+        .map_err(|e| Error::msg(format!("Creating {} failed: {}", path, e)))
+}
+
+fn upload_per_file(connection: &HostConnection, entries: &[Entry], remote: &str) -> Result<(), Error> {
+    let sftp = connection.session().sftp()?;
+    let os = connection.target_os();
+    sftp_mkdir_all(&sftp, os, remote)?;
+    let target = |entry: &Entry| PathBuf::from(os.join(remote, &entry.relative));
+    for entry in entries {
+        let target = target(entry);
+        if entry.dir {
+            if sftp.stat(&target).is_err() {
+                sftp.mkdir(&target, 0o700)?;
+            }
+        } else {
+            let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+            let mut file = sftp.open_mode(&target, flags, 0o600, OpenType::File)?;
+            io::copy(&mut File::open(&entry.path)?.take(entry.size), &mut file)?;
+        }
+    }
+    // like tar, after the content, so read-only directories can be filled
+    for entry in entries.iter().rev() {
+        let stat = FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            // Windows has no permission bits to set
+            perm: Some(entry.mode).filter(|_| os == TargetOs::Posix),
+            atime: Some(entry.mtime),
+            mtime: Some(entry.mtime),
+        };
+        sftp.setstat(&target(entry), stat)?;
+    }
+    Ok(())
+}
+
+impl HostConnection {
+    /// Uploads the files and directories below `local` into `remote`, which is created
+    /// if it is missing. With either strategy they keep their permission bits, ignoring
+    /// the umask, and their modification time, and are owned by the user logged in as.
+    /// Symlinks are followed, other special files and symlinks back into a directory they
+    /// are in fail the upload before anything is sent.
+    /// On a Windows host, see [`HostConnection::target_os`], `remote` is a Windows path like
+    /// `C:\deploy` and files are always sent one by one, [`UploadStrategy::Tar`] fails.
+    /// Running out of space on the host fails it with a [`crate::RemoteDiskFull`],
+    /// [`HostConnection::check_free_space`] tells before anything is sent.
+    pub fn upload_dir(&self, local: &Path, remote: &str, strategy: UploadStrategy) -> Result<DirUpload, Error> {
+        let entries = walk(local)?;
+        let files = entries.iter().filter(|entry| !entry.dir).count();
+        let bytes = entries.iter().map(|entry| entry.size).sum();
+        let windows = self.target_os() == TargetOs::Windows;
+        let strategy = match strategy {
+            UploadStrategy::Tar if windows => {
+                return Err(Error::msg(format!("Uploading to {} as tar needs a POSIX host", remote)))
+            }
+            UploadStrategy::Auto { .. } if windows => UploadStrategy::PerFile,
+            UploadStrategy::Auto { min_files }
+                if files >= min_files && entries.iter().all(|entry| entry.size < TAR_MAX_SIZE) =>
+            {
+                match run(self, "command -v tar", None) {
+                    Ok(()) => UploadStrategy::Tar,
+                    Err(_) => UploadStrategy::PerFile,
+                }
+            }
+            UploadStrategy::Auto { .. } => UploadStrategy::PerFile,
+            strategy => strategy,
+        };
+        if strategy == UploadStrategy::Tar {
+            let command = shell_format!("mkdir -p {0} && tar -x -p -o -f - -C {0}", ShellSafe::path(remote));
+            run(self, command.as_str(), Some(&|channel| write_tar(&entries, channel)))
+                .map_err(|e| upload_failed(self, remote, remote, e))?;
+        } else {
+            upload_per_file(self, &entries, remote).map_err(|e| upload_failed(self, remote, remote, e))?;
+        }
+        Ok(DirUpload { strategy, files, bytes })
+    }
+}
@@ -0,0 +1,190 @@
+use crate::modules::read_channel;
+use crate::shell::{shell_format, ShellSafe};
+use crate::{parse_duration, CommandResult, ExecutionOptions, HostConnection, Limits, Module};
+use crate::{PumpOutput, ShellCommand};
+use anyhow::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let duration = String::deserialize(deserializer)?;
+    parse_duration(&duration).map_err(serde::de::Error::custom)
+}
+
+fn default_timeout() -> Duration {
+    DEFAULT_TIMEOUT
+}
+
+fn default_interval() -> Duration {
+    DEFAULT_INTERVAL
+}
+
+/// Probe a command polls until it succeeds, instead of a `sleep`:
+/// ```toml
+/// healthy = { wait_for = { port = 8080, timeout = "60s", interval = "2s" } }
+/// ready = { wait_for = { cmd = "curl -sf localhost/health" } }
+/// ```
+/// A `port` probe connects to `host`, by default `localhost`, with bash's `/dev/tcp`.
+/// A `cmd` probe succeeds when it exits with 0 and passes the command's own checks,
+/// like `require_output`. Probes run every `interval`, by default 1s, until `timeout`,
+/// by default 60s, the command fails with the output of the last probe then.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WaitFor {
+    #[serde(default)]
+    port: Option<u16>,
+    #[serde(default)]
+    host: Option<String>,
+    #[serde(default)]
+    cmd: Option<String>,
+    #[serde(default = "default_timeout", deserialize_with = "deserialize_duration")]
+    timeout: Duration,
+    #[serde(default = "default_interval", deserialize_with = "deserialize_duration")]
+    interval: Duration,
+}
+
+impl WaitFor {
+    /// Waits for something to listen on `port` of `localhost`.
+    pub fn port(port: u16) -> Self {
+        WaitFor {
+            port: Some(port),
+            ..WaitFor::cmd("")
+        }
+    }
+
+    /// Waits for `cmd` to succeed.
+    pub fn cmd(cmd: &str) -> Self {
+        WaitFor {
+            port: None,
+            host: None,
+            cmd: Some(cmd.to_string()).filter(|cmd| !cmd.is_empty()),
+            timeout: DEFAULT_TIMEOUT,
+            interval: DEFAULT_INTERVAL,
+        }
+    }
+
+    /// Host whose `port` to probe, as the remote host resolves it.
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_string());
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Command line of one probe, `None` unless exactly one of `port` and `cmd` is set.
+    pub(crate) fn probe(&self) -> Option<String> {
+        match (&self.port, &self.cmd) {
+            (Some(port), None) => {
+                let host = self.host.as_deref().unwrap_or("localhost");
+                // bash parses the host again, so it is a single word for it too
+                let connect = shell_format!("exec 3<>/dev/tcp/{}/{}", ShellSafe::word(host), ShellSafe::from(*port));
+                Some(shell_format!("bash -c {} 2>&1", ShellSafe::quote(connect.as_str())).into_string())
+            }
+            (None, Some(cmd)) => Some(cmd.clone()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if self.probe().is_none() {
+            return Err(Error::msg("wait_for needs either a port or a cmd"));
+        }
+        if self.host.is_some() && self.port.is_none() {
+            return Err(Error::msg("wait_for host only applies to a port"));
+        }
+        Ok(())
+    }
+}
+
+/// How long a [`WaitFor`] command polled, in [`CommandResult::waited`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Waited {
+    pub attempts: u32,
+    pub elapsed_ms: u64,
+}
+
+impl ShellCommand {
+    /// Sets the command line of a `wait_for` command without one to its probe.
+    pub(crate) fn resolve_wait_for(&mut self) {
+        let probe = self.wait_for.as_ref().and_then(WaitFor::probe);
+        if let (true, Some(probe)) = (self.cmd.is_empty(), probe) {
+            self.cmd = probe;
+        }
+    }
+}
+
+impl Module {
+    /// Runs the probe of a `wait_for` command until it succeeds or the wait times out.
+    /// The result is the last probe's, a wait never changes anything.
+    pub(crate) fn run_wait_for(
+        &self,
+        connection: &HostConnection,
+        options: &ExecutionOptions,
+        command_name: &str,
+        command: &ShellCommand,
+        render: &dyn Fn(&str) -> Result<String, Error>,
+        limits: Limits,
+    ) -> Result<CommandResult, Error> {
+        let wait = command.wait_for.as_ref().expect("command doesn't wait_for");
+        let cmd = render(&command.cmd)?;
+        let started = Instant::now();
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let (mut channel, uploaded) =
+                self.open_channel(connection, options, command_name, command, &cmd)?;
+            let (stdout, stderr, truncated) = read_channel(&mut channel, limits.max_output)?;
+            channel.wait_close()?;
+            let status = channel.exit_status()?;
+            let output = PumpOutput {
+                stdout_bytes: stdout.len() as u64,
+                stderr_bytes: stderr.len() as u64,
+                stdout,
+                stderr,
+                truncated,
+                prompt: None,
+            };
+            let mut result =
+                self.finish_command(command_name, command, options, limits, output, Some(status))?;
+            result.uploaded = uploaded;
+            result.changed = false;
+            let elapsed = Duration::from_millis(started.elapsed().as_millis() as u64);
+            let ready = status == 0 && !result.is_failed();
+            if ready || elapsed + wait.interval > wait.timeout {
+                if !ready {
+                    let stdout = result.stdout.trim();
+                    let last = match result.failure.take() {
+                        Some(failure) => failure,
+                        None if !stdout.is_empty() => stdout.to_string(),
+                        None => result.stderr.as_deref().unwrap_or_default().trim().to_string(),
+                    };
+                    result.failure = Some(format!(
+                        "not ready after {} attempts in {:?}, last probe exited with {}: {}",
+                        attempts, elapsed, status, last
+                    ));
+                }
+                result.waited = Some(Waited {
+                    attempts,
+                    elapsed_ms: elapsed.as_millis().min(u64::MAX as u128) as u64,
+                });
+                return Ok(result);
+            }
+            thread::sleep(wait.interval);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Posting the summary of a run to a web hook, see [`WebhookReporter`].
+use crate::template::render_text;
+use crate::{ModuleRecord, Outcome, ReportSink, RunSummary};
+use anyhow::Error;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// Longest wait between attempts the doubling grows to, see [`WebhookReporter::retry_delay`].
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+const DEFAULT_MESSAGE: &str =
+    "Run {{ correlation_id }} {{ status }}: {{ failed }} of {{ modules }} modules failed on {{ hosts }} hosts";
+
+/// [`ReportSink`] posting the [`RunSummary`] of a run as JSON to a web hook once
+/// [`crate::Runner::finish_run`] is called, for Slack and hooks taking any JSON:
+/// ```json
+/// {"text": "Run 4b1f… succeeded: 0 of 12 modules failed on 3 hosts", "summary": {…}}
+/// ```
+/// `summary` is the [`RunSummary`] as it serializes anywhere else. The post goes through
+/// `curl` on the controller, failed attempts are retried. If every attempt fails the
+/// run isn't affected, the runner prints a warning.
+#[derive(Debug, Clone)]
+pub struct WebhookReporter {
+    url: String,
+    message: String,
+    attempts: u32,
+    timeout: Duration,
+    retry_delay: Duration,
+}
+
+impl WebhookReporter {
+    /// Posts to the `http://` or `https://` `url`, 3 attempts of at most 10 seconds each.
+    pub fn new(url: &str) -> Result<Self, Error> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(Error::msg(format!("Web hook URL must start with http:// or https://: {}", url)));
+        }
+        Ok(WebhookReporter {
+            url: url.to_string(),
+            message: DEFAULT_MESSAGE.to_string(),
+            attempts: 3,
+            timeout: Duration::from_secs(10),
+            retry_delay: Duration::from_secs(1),
+        })
+    }
+
+    /// Template of the `text` of the payload, see [`crate::render_template`], values are put in
+    /// as they are without a filter. It gets
+    /// `correlation_id`, `status` (`succeeded` or `failed`), `hosts`, `modules`, `failed`,
+    /// `errors`, and `ok`, `changed` and `warning`, counting modules by outcome.
+    pub fn message(mut self, template: &str) -> Result<Self, Error> {
+        render_text(template, &message_vars(&RunSummary::new("")))?;
+        self.message = template.to_string();
+        Ok(self)
+    }
+
+    /// Tries that many times, at least once.
+    pub fn attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// How long one attempt may take, connecting included.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Wait before the second attempt, doubled before each further one up to a minute,
+    /// or `delay` if that is longer.
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// What is posted for `summary`.
+    pub fn payload(&self, summary: &RunSummary) -> Result<Value, Error> {
+        Ok(json!({
+            "text": render_text(&self.message, &message_vars(summary))?,
+            "summary": summary,
+        }))
+    }
+
+    /// Scheme and host of the URL, as hook URLs often carry their secret in the path.
+    fn endpoint(&self) -> &str {
+        let host = self.url.find("://").map_or(0, |scheme| scheme + 3);
+        match self.url[host..].find('/') {
+            Some(path) => &self.url[..host + path],
+            None => &self.url,
+        }
+    }
+
+    /// Posts `body` with `curl`, which gets the URL and `body` in a config on its stdin,
+    /// as its arguments are visible to every user of the controller.
+    fn post(&self, body: &str) -> Result<(), String> {
+        let config = format!(
+            "url = {}\ndata-binary = {}\n",
+            config_string(&self.url),
+            config_string(body)
+        );
+        let secs = format!("{:.3}", self.timeout.as_secs_f64());
+        let mut curl = Command::new("curl")
+            .args(["-sS", "-f", "-o", "/dev/null", "--max-time", secs.as_str(), "-X", "POST"])
+            .args(["-H", "Content-Type: application/json", "-K", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Running curl: {}", e))?;
+        let written = curl.stdin.take().expect("stdin is piped").write_all(config.as_bytes());
+        let output = curl.wait_with_output().map_err(|e| format!("Running curl: {}", e))?;
+        if output.status.success() {
+            return written.map_err(|e| format!("Writing the curl config: {}", e));
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr).replace(&self.url, self.endpoint());
+        Err(match stderr.trim() {
+            "" => format!("curl {}", output.status),
+            stderr => stderr.to_string(),
+        })
+    }
+}
+
+/// `s` as a quoted string of a curl config.
+fn config_string(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    format!("\"{}\"", escaped)
+}
+
+/// Variables of the message, see [`WebhookReporter::message`].
+fn message_vars(summary: &RunSummary) -> HashMap<String, String> {
+    let count = |outcome| summary.outcomes.get(&outcome).copied().unwrap_or_default();
+    let status = if summary.is_success() { "succeeded" } else { "failed" };
+    let vars = [
+        ("correlation_id", summary.correlation_id.clone()),
+        ("status", status.to_string()),
+        ("hosts", summary.hosts.len().to_string()),
+        ("modules", summary.modules().to_string()),
+        ("failed", summary.failures.len().to_string()),
+        ("errors", summary.errors.to_string()),
+        ("ok", count(Outcome::Ok).to_string()),
+        ("changed", count(Outcome::Changed).to_string()),
+        ("warning", count(Outcome::Warning).to_string()),
+    ];
+    vars.iter().map(|(name, value)| (name.to_string(), value.clone())).collect()
+}
+
+impl ReportSink for WebhookReporter {
+    fn record(&self, _record: &ModuleRecord<'_>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn finish(&self, summary: &RunSummary) -> Result<(), Error> {
+        let body = serde_json::to_string(&self.payload(summary)?)?;
+        let mut delay = self.retry_delay;
+        let mut failure = String::new();
+        for attempt in 0..self.attempts {
+            if attempt > 0 {
+                thread::sleep(delay);
+                delay = delay.saturating_mul(2).min(MAX_RETRY_DELAY.max(self.retry_delay));
+            }
+            match self.post(&body) {
+                Ok(()) => return Ok(()),
+                Err(e) => failure = e,
+            }
+        }
+        Err(Error::msg(format!(
+            "Posting to {} failed after {} attempts: {}",
+            self.endpoint(),
+            self.attempts,
+            failure
+        )))
+    }
+}
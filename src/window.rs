@@ -0,0 +1,255 @@
+use crate::exec::exec_command;
+use crate::facts::{epoch_ms, CLOCK_COMMAND};
+use crate::{ClockFacts, HostConnection};
+use anyhow::Error;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::io::Read;
+use std::time::SystemTime;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const DAY_NAMES: [&str; 7] = ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"];
+
+/// Days and time of day a module may run in, e.g.
+/// `window = { days = ["sat", "sun"], start = "02:00", end = "05:00", tz = "UTC" }`
+/// in a `.mod` file. Outside it, the module is skipped with a
+/// [`crate::SkipReason::OutsideMaintenanceWindow`] error, unless the run
+/// overrides windows, see [`crate::Runner::with_window_override`].
+///
+/// A window ending before it starts ends the next day, `22:00` to `02:00` on `sat`
+/// is open until sunday 02:00. No days means every day. `tz` is `UTC`, the default,
+/// or a fixed offset like `+02:00` or `UTC-5`, there is no daylight saving.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "WindowSpec")]
+pub struct MaintenanceWindow {
+    /// Days the window opens on, from monday
+    days: [bool; 7],
+    /// Minutes into the day
+    start: i64,
+    end: i64,
+    /// Minutes ahead of UTC
+    offset: i64,
+    tz: String,
+}
+
+#[derive(Deserialize)]
+struct WindowSpec {
+    #[serde(default)]
+    days: Vec<String>,
+    start: String,
+    end: String,
+    tz: Option<String>,
+}
+
+impl TryFrom<WindowSpec> for MaintenanceWindow {
+    type Error = Error;
+
+    fn try_from(spec: WindowSpec) -> Result<Self, Error> {
+        let days: Vec<_> = spec.days.iter().map(String::as_str).collect();
+        MaintenanceWindow::new(&days, &spec.start, &spec.end, spec.tz.as_deref().unwrap_or("UTC"))
+    }
+}
+
+/// Minutes into the day of `HH:MM`, where `24:00` is the end of the day.
+fn parse_time(time: &str) -> Result<i64, Error> {
+    let bad = || Error::msg(format!("Bad window time {:?}, expected HH:MM", time));
+    let (hours, minutes) = time.trim().split_once(':').ok_or_else(bad)?;
+    let hours: i64 = hours.parse().map_err(|_| bad())?;
+    let minutes: i64 = minutes.parse().map_err(|_| bad())?;
+    if minutes >= 60 || hours > 24 || (hours == 24 && minutes > 0) {
+        return Err(bad());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+/// Minutes ahead of UTC of `UTC` or an offset, like `+02:00`, `-5` or `UTC+5:30`.
+fn parse_tz(tz: &str) -> Result<i64, Error> {
+    let bad = || {
+        Error::msg(format!(
+            "Unsupported timezone {:?}, expected UTC or an offset like +02:00",
+            tz
+        ))
+    };
+    let offset = tz.trim();
+    let offset = offset
+        .strip_prefix("UTC")
+        .or_else(|| offset.strip_prefix("GMT"))
+        .unwrap_or(offset);
+    if offset.is_empty() || offset == "Z" {
+        return Ok(0);
+    }
+    let (sign, offset) = match offset.split_at(1) {
+        ("+", offset) => (1, offset),
+        ("-", offset) => (-1, offset),
+        _ => return Err(bad()),
+    };
+    let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "0"));
+    let hours: i64 = hours.parse().map_err(|_| bad())?;
+    let minutes: i64 = minutes.parse().map_err(|_| bad())?;
+    if hours > 14 || minutes >= 60 {
+        return Err(bad());
+    }
+    Ok(sign * (hours * 60 + minutes))
+}
+
+/// Day of the week of the `day`th day since the epoch, monday being 0.
+fn weekday(day: i64) -> usize {
+    // 1970-01-01 was a thursday
+    (day + 3).rem_euclid(7) as usize
+}
+
+/// Year, month and day of the `day`th day since the epoch.
+fn civil(day: i64) -> (i64, i64, i64) {
+    let z = day + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + (m <= 2) as i64, m, d)
+}
+
+/// Days since the epoch of the date `year-month-day`, the inverse of [`civil`].
+fn epoch_day(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Milliseconds since the epoch of an RFC 3339 time, like `2026-10-20T06:00:00Z` or
+/// `2026-10-20T08:00+02:00`, or of a date like `2026-10-20`, at its start in UTC.
+pub(crate) fn parse_utc_timestamp(timestamp: &str) -> Result<i64, Error> {
+    let bad = || {
+        Error::msg(format!(
+            "Bad time {:?}, expected a date like 2026-10-20 or an RFC 3339 time",
+            timestamp
+        ))
+    };
+    let (date, time) = timestamp.split_once('T').unwrap_or((timestamp, "00:00Z"));
+    let date: Vec<i64> = date.split('-').map(str::parse).collect::<Result<_, _>>().map_err(|_| bad())?;
+    let (year, month, day) = match date[..] {
+        [year, month, day] if (1..=12).contains(&month) && (1..=31).contains(&day) => (year, month, day),
+        _ => return Err(bad()),
+    };
+    let (clock, tz) = time.split_at(time.find(['Z', '+', '-']).ok_or_else(bad)?);
+    let offset = parse_tz(tz).map_err(|_| bad())?;
+    let clock = clock.split_once('.').map_or(clock, |(clock, _)| clock);
+    let clock: Vec<i64> = clock.split(':').map(str::parse).collect::<Result<_, _>>().map_err(|_| bad())?;
+    let (hours, minutes, seconds) = match clock[..] {
+        [hours, minutes] => (hours, minutes, 0),
+        [hours, minutes, seconds] => (hours, minutes, seconds),
+        _ => return Err(bad()),
+    };
+    if hours >= 24 || minutes >= 60 || seconds > 60 {
+        return Err(bad());
+    }
+    let secs = epoch_day(year, month, day) * 86_400 + hours * 3600 + minutes * 60 + seconds - offset * 60;
+    Ok(secs * 1000)
+}
+
+/// `epoch_ms` as an RFC 3339 time in UTC, to the second.
+pub(crate) fn utc_timestamp(epoch_ms: i64) -> String {
+    let secs = epoch_ms.div_euclid(1000);
+    let (year, month, day) = civil(secs.div_euclid(86_400));
+    let time = secs.rem_euclid(86_400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+impl MaintenanceWindow {
+    /// Window open on `days`, like `sat` or `saturday`, from `start` to `end`, as `HH:MM`
+    /// in the timezone `tz`.
+    pub fn new(days: &[&str], start: &str, end: &str, tz: &str) -> Result<Self, Error> {
+        let mut open = [days.is_empty(); 7];
+        for day in days {
+            let name = day.trim().to_lowercase();
+            let index = (0..7)
+                .find(|index| DAYS[*index] == name || DAY_NAMES[*index] == name)
+                .ok_or_else(|| Error::msg(format!("Unknown window day {:?}", day)))?;
+            open[index] = true;
+        }
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end {
+            return Err(Error::msg("Window starts when it ends"));
+        }
+        Ok(MaintenanceWindow {
+            days: open,
+            start,
+            end,
+            offset: parse_tz(tz)?,
+            tz: tz.trim().to_string(),
+        })
+    }
+
+    /// Minutes since the epoch of `epoch_ms`, in the window's timezone.
+    fn local_minutes(&self, epoch_ms: i64) -> i64 {
+        epoch_ms.div_euclid(60_000) + self.offset
+    }
+
+    /// Whether the window is open at `epoch_ms`.
+    pub fn contains(&self, epoch_ms: i64) -> bool {
+        let minutes = self.local_minutes(epoch_ms);
+        let (day, minute) = (minutes.div_euclid(1440), minutes.rem_euclid(1440));
+        if self.start < self.end {
+            return self.days[weekday(day)] && self.start <= minute && minute < self.end;
+        }
+        (self.days[weekday(day)] && minute >= self.start) || (self.days[weekday(day - 1)] && minute < self.end)
+    }
+
+    /// When the window opens next after `epoch_ms`, in milliseconds since the epoch.
+    pub fn next_opening(&self, epoch_ms: i64) -> i64 {
+        let minutes = self.local_minutes(epoch_ms);
+        let today = minutes.div_euclid(1440);
+        let opening = (today..=today + 7)
+            .map(|day| day * 1440 + self.start)
+            .find(|opening| *opening > minutes && self.days[weekday(opening / 1440)])
+            .expect("windows open at least once a week");
+        (opening - self.offset) * 60_000
+    }
+
+    /// `epoch_ms` as the window's local time, like `sat 2026-10-17 02:00 UTC`.
+    pub fn local_time(&self, epoch_ms: i64) -> String {
+        let minutes = self.local_minutes(epoch_ms);
+        let day = minutes.div_euclid(1440);
+        let (year, month, date) = civil(day);
+        let minute = minutes.rem_euclid(1440);
+        format!(
+            "{} {:04}-{:02}-{:02} {:02}:{:02} {}",
+            DAYS[weekday(day)],
+            year,
+            month,
+            date,
+            minute / 60,
+            minute % 60,
+            self.tz
+        )
+    }
+}
+
+/// Time on the controller, or on the host of `connection` if `remote`.
+pub(crate) fn window_clock(connection: &HostConnection, remote: bool) -> Result<i64, Error> {
+    let now = epoch_ms(SystemTime::now());
+    if !remote {
+        return Ok(now);
+    }
+    let mut channel = connection.open_channel()?;
+    let (session, exec) = channel.split();
+    exec_command(session, exec, CLOCK_COMMAND)?;
+    let mut output = String::new();
+    channel.read_to_string(&mut output)?;
+    channel.wait_close()?;
+    Ok(ClockFacts::parse(&output, now)?.epoch_ms)
+}
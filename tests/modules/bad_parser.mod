@@ -0,0 +1,3 @@
+module_type = "bash"
+exec_path = "merged.toml"
+parser = "yaml"
@@ -0,0 +1,9 @@
+module_type = "bash"
+exec_path = "deploy.toml"
+umask = "027"
+locale = "C.UTF-8"
+timeout = "10m"
+max_output = "1MiB"
+
+[env]
+DEBIAN_FRONTEND = "noninteractive"
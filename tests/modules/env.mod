@@ -0,0 +1,5 @@
+module_type = "bash"
+exec_path = "merged.toml"
+
+[env]
+LANG = "en_US.UTF-8"
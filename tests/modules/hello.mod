@@ -0,0 +1,3 @@
+module_type = "python"
+exec_path = "hello.py"
+precompile_check = true
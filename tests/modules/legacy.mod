@@ -0,0 +1,4 @@
+module_type = "bash"
+exec_path = "merged.toml"
+deprecated = true
+replaced_by = "merged.mod"
@@ -0,0 +1,4 @@
+module_type = "bash"
+exec_path = "merged.toml"
+max_output = "64MiB"
+timeout = "45m"
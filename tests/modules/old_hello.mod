@@ -0,0 +1 @@
+replaced_by = "hello.mod"
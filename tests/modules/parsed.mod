@@ -0,0 +1,3 @@
+module_type = "bash"
+exec_path = "parsed.toml"
+parser = "key_value"
@@ -0,0 +1,2 @@
+module_type = "bash"
+exec_path = "script.toml"
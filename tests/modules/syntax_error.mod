@@ -0,0 +1,3 @@
+module_type = "python"
+exec_path = "syntax_error.py"
+precompile_check = true
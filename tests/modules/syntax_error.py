@@ -0,0 +1,4 @@
+import os
+
+def report(:
+    print(os.getcwd())
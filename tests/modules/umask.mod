@@ -0,0 +1,4 @@
+module_type = "bash"
+exec_path = "merged.toml"
+umask = "077"
+locale = "en_US.UTF-8"